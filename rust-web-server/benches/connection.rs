@@ -0,0 +1,161 @@
+//! End-to-end cost of serving a connection (parsing, routing, the handler
+//! and writing the response) over an in-memory stream, so no sockets or
+//! kernel time are involved. Metrics go to `NoopMetrics`.
+//!
+//! `large_body` serves an 8 MiB body into a sink that only counts bytes,
+//! and prints the peak heap the server used for it, as seen by a counting
+//! global allocator. That peak is about 8.0 MiB (the body itself) with the
+//! body written after the head, and about 16.0 MiB when `INLINE_BODY_BYTES`
+//! is raised so the body is copied behind the head instead.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{self, Cursor, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rust_web_server::{Config, ConnectionHandler, Context, Method, NoopMetrics, Response, Server, StatusCode};
+
+/// The system allocator, keeping track of the most ever allocated at once.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let now = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(now, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const LARGE_BODY_BYTES: usize = 8 * 1024 * 1024;
+
+/// Reads the requests from a fixed buffer and collects what is written.
+struct MemoryStream<'a> {
+    input: Cursor<&'a [u8]>,
+    output: Vec<u8>,
+}
+
+impl Read for MemoryStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for MemoryStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn handler() -> ConnectionHandler {
+    let mut server = Server::new(Config::default());
+    server.metrics(NoopMetrics);
+    server.register(Method::Get, "/", |_: &mut Context| {
+        Response::new(StatusCode::Ok)
+            .with_header("Content-Type", "text/plain")
+            .with_body("Hello, world!")
+    });
+    server.register(Method::Get, "/users/:id", |context: &mut Context| {
+        let id = context.param("id").unwrap_or_default().to_string();
+        Response::new(StatusCode::Ok).with_body(id)
+    });
+    server.register(Method::Get, "/large", |_: &mut Context| {
+        Response::new(StatusCode::Ok).with_body(vec![b'x'; LARGE_BODY_BYTES])
+    });
+    server.connection_handler()
+}
+
+/// Reads the requests from a fixed buffer and counts what is written, so
+/// the output of a large response takes no memory of its own.
+struct CountingStream<'a> {
+    input: Cursor<&'a [u8]>,
+    written: usize,
+}
+
+impl Read for CountingStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for CountingStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn serve_counted(handler: &ConnectionHandler, input: &[u8]) -> usize {
+    let mut stream = CountingStream {
+        input: Cursor::new(input),
+        written: 0,
+    };
+    handler.serve(&mut stream);
+    assert!(stream.written > LARGE_BODY_BYTES, "unexpected response");
+    stream.written
+}
+
+/// The most heap the server held at once, beyond what was already
+/// allocated, while serving `input`.
+fn peak_allocation(handler: &ConnectionHandler, input: &[u8]) -> usize {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    serve_counted(handler, input);
+    PEAK.load(Ordering::Relaxed) - before
+}
+
+fn serve(handler: &ConnectionHandler, input: &[u8]) -> usize {
+    let mut stream = MemoryStream {
+        input: Cursor::new(input),
+        output: Vec::with_capacity(4096),
+    };
+    handler.serve(&mut stream);
+    assert!(stream.output.starts_with(b"HTTP/1.1 200 OK"), "unexpected response");
+    stream.output.len()
+}
+
+fn connection_throughput(c: &mut Criterion) {
+    let handler = handler();
+    let mut group = c.benchmark_group("connection");
+
+    let single = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("single_request", |b| b.iter(|| serve(&handler, single)));
+
+    // Ten pipelined keep-alive requests, on a route with a parameter.
+    let pipelined = b"GET /users/42 HTTP/1.1\r\nHost: localhost\r\n\r\n".repeat(10);
+    group.throughput(Throughput::Elements(10));
+    group.bench_function("pipelined_keep_alive", |b| b.iter(|| serve(&handler, &pipelined)));
+
+    let large = b"GET /large HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    let peak = peak_allocation(&handler, large);
+    println!("connection/large_body: peak allocation {:.1} MiB", peak as f64 / (1024.0 * 1024.0));
+    group.throughput(Throughput::Bytes(LARGE_BODY_BYTES as u64));
+    group.bench_function("large_body", |b| b.iter(|| serve_counted(&handler, large)));
+
+    group.finish();
+}
+
+criterion_group!(benches, connection_throughput);
+criterion_main!(benches);
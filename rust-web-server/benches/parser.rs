@@ -0,0 +1,50 @@
+//! Request-parser throughput on representative requests: a bare GET, a
+//! browser-style GET with a full set of headers, and a small JSON POST
+//! including its body.
+
+use std::hint::black_box;
+use std::io::Cursor;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rust_web_server::Request;
+
+const MINIMAL: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+const BROWSER: &[u8] = b"GET /static/app.js?v=3 HTTP/1.1\r\n\
+Host: example.com\r\n\
+User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0\r\n\
+Accept: text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8\r\n\
+Accept-Language: en-US,en;q=0.5\r\n\
+Accept-Encoding: gzip, deflate, br\r\n\
+Referer: https://example.com/\r\n\
+Cookie: session=0123456789abcdef; theme=dark; consent=yes\r\n\
+Connection: keep-alive\r\n\
+If-None-Match: \"5d8c72a5edda8d6a\"\r\n\
+Cache-Control: max-age=0\r\n\
+\r\n";
+
+const JSON_POST: &[u8] = b"POST /api/items HTTP/1.1\r\n\
+Host: example.com\r\n\
+Content-Type: application/json\r\n\
+Content-Length: 55\r\n\
+\r\n\
+{\"name\":\"widget\",\"quantity\":12,\"tags\":[\"blue\",\"large\"]}";
+
+fn parse(input: &[u8]) -> Request {
+    let mut reader = Cursor::new(input);
+    let mut request = Request::parse(&mut reader).expect("benchmark request should parse");
+    request.read_body(&mut reader, 1024 * 1024).expect("benchmark body should read");
+    request
+}
+
+fn parser_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parser");
+    for (name, input) in [("minimal", MINIMAL), ("browser", BROWSER), ("json_post", JSON_POST)] {
+        group.throughput(Throughput::Bytes(input.len() as u64));
+        group.bench_function(name, |b| b.iter(|| parse(black_box(input))));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parser_throughput);
+criterion_main!(benches);
@@ -0,0 +1,86 @@
+//! Job throughput of `ThreadPool` at several worker counts: how fast a
+//! burst of small jobs gets through submission, the queue and the workers.
+//! A second group submits from several threads at once, as the acceptors
+//! do, and checks that every job ran exactly once.
+
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_web_server::{PoolConfig, QueueFullPolicy, ThreadPool};
+
+const JOBS: usize = 1000;
+
+fn job_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("thread_pool");
+    group.throughput(Throughput::Elements(JOBS as u64));
+    for workers in [1, 2, 4, 8] {
+        // Blocking on a full queue keeps every job, so each run does the same work.
+        let pool = ThreadPool::with_config(PoolConfig {
+            queue_full_policy: QueueFullPolicy::Block,
+            ..PoolConfig::new(workers)
+        });
+        pool.warm_up();
+        group.bench_with_input(BenchmarkId::new("jobs", workers), &workers, |b, _| {
+            b.iter(|| {
+                let (done, finished) = mpsc::channel();
+                for i in 0..JOBS {
+                    let done = done.clone();
+                    pool.execute(move || {
+                        let _ = done.send(black_box(i));
+                    })
+                    .expect("pool rejected a job");
+                }
+                drop(done);
+                assert_eq!(finished.iter().count(), JOBS);
+            });
+        });
+    }
+    group.finish();
+}
+
+fn concurrent_submitters(c: &mut Criterion) {
+    const JOBS: usize = 20_000;
+    let mut group = c.benchmark_group("concurrent_submitters");
+    group.throughput(Throughput::Elements(JOBS as u64));
+    group.sample_size(20);
+    // A small queue so submitters keep running into each other and into a
+    // full queue, not just into the workers.
+    let pool = ThreadPool::with_config(PoolConfig {
+        queue_capacity: 64,
+        queue_full_policy: QueueFullPolicy::Block,
+        ..PoolConfig::new(4)
+    });
+    for submitters in [1, 4, 16, 64] {
+        group.bench_with_input(BenchmarkId::new("threads", submitters), &submitters, |b, &submitters| {
+            b.iter(|| {
+                let runs: Arc<Vec<AtomicU32>> = Arc::new((0..JOBS).map(|_| AtomicU32::new(0)).collect());
+                let (done, finished) = mpsc::channel();
+                thread::scope(|scope| {
+                    for submitter in 0..submitters {
+                        let (pool, runs, done) = (&pool, &runs, done.clone());
+                        scope.spawn(move || {
+                            for job in (submitter..JOBS).step_by(submitters) {
+                                let (runs, done) = (Arc::clone(runs), done.clone());
+                                pool.execute(move || {
+                                    runs[job].fetch_add(1, Ordering::Relaxed);
+                                    let _ = done.send(());
+                                })
+                                .expect("pool rejected a job");
+                            }
+                        });
+                    }
+                });
+                drop(done);
+                assert_eq!(finished.iter().count(), JOBS);
+                assert!(runs.iter().all(|runs| runs.load(Ordering::Relaxed) == 1), "a job ran more than once");
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, job_throughput, concurrent_submitters);
+criterion_main!(benches);
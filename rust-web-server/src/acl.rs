@@ -0,0 +1,145 @@
+//! Restricting path prefixes to client networks (`PATH_ACL`), e.g. keeping
+//! `/admin` reachable only from inside `10.0.0.0/8`. Checked against the
+//! client address worked out from trusted proxies' forwarding headers, so
+//! behind a proxy it is the client that is checked and not the proxy.
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use crate::forwarded::{canonical, parse_networks};
+use crate::query::percent_decode;
+
+/// Which networks may reach which path prefixes. A path under a listed
+/// prefix is only served to clients inside one of its networks, the
+/// longest matching prefix deciding; paths no rule covers are open to all.
+/// A prefix matches itself and anything below it, so `/admin` covers
+/// `/admin/config` but not `/administrator`; a trailing `/*` is accepted and
+/// means the same.
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    rules: Vec<(String, Vec<IpNet>)>,
+}
+
+impl AccessControl {
+    pub fn new() -> AccessControl {
+        AccessControl::default()
+    }
+
+    pub fn rule(mut self, prefix: &str, networks: Vec<IpNet>) -> AccessControl {
+        let prefix = prefix.trim_end_matches('*').trim_end_matches('/');
+        self.rules.push((prefix.to_string(), networks));
+        self
+    }
+
+    /// Adds rules written as `prefix=networks` pairs separated by `;`, the
+    /// networks a comma-separated list of CIDR ranges and addresses, e.g.
+    /// `/admin=10.0.0.0/8,127.0.0.1; /internal=192.168.0.0/16`. Malformed
+    /// entries (and malformed networks within them) are returned so the
+    /// caller can report them; a rule left with no valid network allows no one.
+    pub fn with_rules(mut self, rules: &str) -> (AccessControl, Vec<String>) {
+        let mut invalid = Vec::new();
+        for entry in rules.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+            match entry.split_once('=').map(|(prefix, networks)| (prefix.trim(), networks)) {
+                Some((prefix, networks)) if prefix.starts_with('/') => {
+                    let (networks, bad) = parse_networks(networks);
+                    invalid.extend(bad);
+                    self = self.rule(prefix, networks);
+                }
+                _ => invalid.push(entry.to_string()),
+            }
+        }
+        (self, invalid)
+    }
+
+    /// Rules as `(prefix, networks)` pairs, in the order they were added.
+    pub fn rules(&self) -> &[(String, Vec<IpNet>)] {
+        &self.rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The prefix of the rule keeping `client` away from `path`, if one does.
+    /// A client whose address isn't known is kept away from every rule's
+    /// prefix. The path is compared percent-decoded with repeated slashes
+    /// collapsed, so `/%61dmin` and `//admin` are held to `/admin`'s rule.
+    pub fn denied_by(&self, path: &str, client: Option<IpAddr>) -> Option<&str> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let path = normalize(path);
+        let (prefix, networks) = self
+            .rules
+            .iter()
+            .filter(|(prefix, _)| match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            })
+            .max_by_key(|(prefix, _)| prefix.len())?;
+        let allowed = client.is_some_and(|ip| {
+            let ip = canonical(ip);
+            networks.iter().any(|network| network.contains(&ip))
+        });
+        (!allowed).then_some(prefix.as_str())
+    }
+}
+
+fn normalize(path: &str) -> String {
+    let decoded = percent_decode(path, false);
+    let mut normalized = String::with_capacity(decoded.len());
+    for c in decoded.chars() {
+        if !(c == '/' && normalized.ends_with('/')) {
+            normalized.push(c);
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    fn acl() -> AccessControl {
+        let (acl, invalid) = AccessControl::new().with_rules("/admin/*=10.0.0.0/8,127.0.0.1; /admin/public=0.0.0.0/0; nope; /x=bogus");
+        assert_eq!(invalid, ["nope", "bogus"]);
+        acl
+    }
+
+    #[test]
+    fn clients_inside_the_networks_are_allowed() {
+        assert_eq!(acl().denied_by("/admin", ip("10.1.2.3")), None);
+        assert_eq!(acl().denied_by("/admin/config", ip("127.0.0.1")), None);
+        assert_eq!(acl().denied_by("/admin/config", ip("::ffff:10.0.0.1")), None);
+    }
+
+    #[test]
+    fn clients_outside_the_networks_are_denied() {
+        assert_eq!(acl().denied_by("/admin/config", ip("203.0.113.9")), Some("/admin"));
+        assert_eq!(acl().denied_by("/admin", None), Some("/admin"));
+        // A rule without a valid network allows no one.
+        assert_eq!(acl().denied_by("/x", ip("10.0.0.1")), Some("/x"));
+    }
+
+    #[test]
+    fn the_longest_prefix_decides() {
+        assert_eq!(acl().denied_by("/admin/public/page", ip("203.0.113.9")), None);
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        assert_eq!(acl().denied_by("/administrator", ip("203.0.113.9")), None);
+        assert_eq!(acl().denied_by("/", ip("203.0.113.9")), None);
+    }
+
+    #[test]
+    fn encoded_and_doubled_slashes_do_not_get_around_a_rule() {
+        assert_eq!(acl().denied_by("/%61dmin", ip("203.0.113.9")), Some("/admin"));
+        assert_eq!(acl().denied_by("//admin//config", ip("203.0.113.9")), Some("/admin"));
+    }
+}
@@ -0,0 +1,112 @@
+use tracing::warn;
+use metrics::counter;
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::maintenance::Maintenance;
+use crate::request::Method;
+use crate::response::Response;
+use crate::router::Router;
+use crate::status::StatusCode;
+use crate::{Context, HandlerError, HandlerOutput};
+
+/// Wraps `handler` so it only runs for requests carrying
+/// `Authorization: Bearer <token>`; anything else gets a 401.
+pub fn protect<F, R>(token: &str, handler: F) -> impl Fn(&mut Context) -> Result<Response, HandlerError> + Send + Sync + 'static
+where
+    F: Fn(&mut Context) -> R + Send + Sync + 'static,
+    R: HandlerOutput,
+{
+    let token = token.to_string();
+    move |context: &mut Context| {
+        let request = context.request;
+        if bearer_matches(request.header("Authorization"), &token) {
+            handler(context).into_result()
+        } else {
+            warn!(path = %request.path, "Rejected admin request without a valid token");
+            counter!("admin_auth_failures_total", 1);
+            Ok(Response::new(StatusCode::Unauthorized).with_header("WWW-Authenticate", "Bearer"))
+        }
+    }
+}
+
+/// Whether an `Authorization` header value is `Bearer <token>`, compared in
+/// constant time.
+pub fn bearer_matches(authorization: Option<&str>, token: &str) -> bool {
+    authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .is_some_and(|presented| constant_time_eq(presented.as_bytes(), token.as_bytes()))
+}
+
+/// `GET /admin/config`: the effective configuration, with secrets masked.
+/// Rendered once up front since the config doesn't change while running.
+pub fn config(config: &Config) -> impl Fn(&mut Context) -> Response + Send + Sync + 'static {
+    let body = config.redacted();
+    move |_: &mut Context| Response::json(&body)
+}
+
+/// `GET /admin/maintenance` reports whether maintenance mode is on;
+/// `POST /admin/maintenance?enabled=true` (or `false`) switches it.
+pub fn maintenance(maintenance: Maintenance) -> impl Fn(&mut Context) -> Result<Response, HandlerError> + Send + Sync + 'static {
+    move |context: &mut Context| {
+        if context.request.method == Method::Post {
+            let enabled = context.request.query_params().remove("enabled");
+            match enabled.as_deref().map(str::parse::<bool>) {
+                Some(Ok(enabled)) => maintenance.set(enabled),
+                _ => return Err(HandlerError::BadRequest("expected ?enabled=true or ?enabled=false".to_string())),
+            }
+        }
+        Ok(Response::json(&json!({ "enabled": maintenance.is_enabled() })))
+    }
+}
+
+/// `GET /admin/routes`: the registered (method, path) pairs, sorted. The
+/// list is taken when the handler is built, so build it after the other
+/// routes are registered; `/admin/routes` itself is included.
+pub fn routes(router: &Router) -> impl Fn(&mut Context) -> Response + Send + Sync + 'static {
+    let mut routes: Vec<(Method, String)> = router
+        .routes()
+        .into_iter()
+        .map(|(method, path)| (method, path.to_string()))
+        .collect();
+    if !routes.iter().any(|(method, path)| *method == Method::Get && path == "/admin/routes") {
+        routes.push((Method::Get, "/admin/routes".to_string()));
+        routes.sort_by(|a, b| (a.1.as_str(), a.0.as_str()).cmp(&(b.1.as_str(), b.0.as_str())));
+    }
+    let body: Vec<Value> = routes
+        .iter()
+        .map(|(method, path)| json!({ "method": method.as_str(), "path": path }))
+        .collect();
+    move |_: &mut Context| Response::json(&body)
+}
+
+/// Compares without stopping at the first mismatch, so response timing
+/// doesn't reveal how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_exact_bearer_token_matches() {
+        assert!(bearer_matches(Some("Bearer s3cret"), "s3cret"));
+        assert!(bearer_matches(Some("Bearer s3cret "), "s3cret"));
+        for authorization in [None, Some(""), Some("Bearer"), Some("Bearer "), Some("s3cret"), Some("bearer s3cret")] {
+            assert!(!bearer_matches(authorization, "s3cret"), "{authorization:?}");
+        }
+        for presented in ["Bearer s3cre", "Bearer s3cret2", "Bearer S3CRET", "Basic czNjcmV0"] {
+            assert!(!bearer_matches(Some(presented), "s3cret"), "{presented:?}");
+        }
+    }
+
+    #[test]
+    fn metrics_are_local_and_open_by_default() {
+        let config = Config::default();
+        assert!(config.metrics_bind.is_loopback());
+        assert_eq!(config.metrics_token, None);
+    }
+}
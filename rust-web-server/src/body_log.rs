@@ -0,0 +1,183 @@
+//! Debug logging of request and response bodies (`LOG_BODIES`).
+//!
+//! Request headers are logged end-to-end only, without the hop-by-hop ones
+//! a proxy would drop. Credential headers are replaced by `[redacted]`, as
+//! are the values of JSON and form fields whose names look like secrets.
+//! Each body is cut off at `LOG_BODY_MAX_BYTES` and logged quoted, so line
+//! breaks in it can't forge log lines.
+
+use std::borrow::Cow;
+use serde_json::Value;
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::headers::Headers;
+use crate::request::Request;
+use crate::response::Response;
+use crate::server::CREDENTIAL_HEADERS;
+
+const REDACTED: &str = "[redacted]";
+
+/// Field names containing any of these have their values redacted.
+const SECRET_FIELDS: [&str; 4] = ["password", "secret", "token", "api_key"];
+
+pub(crate) fn log_exchange(request: &Request, response: &Response, request_id: Uuid, max_bytes: usize) {
+    let mut end_to_end = request.headers.clone();
+    end_to_end.remove_hop_by_hop();
+    debug!(
+        request_id = ?request_id,
+        headers = %headers(&end_to_end, &CREDENTIAL_HEADERS),
+        body = ?body(&request.body, &request.headers, max_bytes),
+        "Request body for {} {}",
+        request.method,
+        request.path
+    );
+    let response_body = if response.is_streamed() {
+        Cow::Borrowed("(streamed)")
+    } else {
+        Cow::Owned(body(&response.body, &response.headers, max_bytes))
+    };
+    debug!(
+        request_id = ?request_id,
+        headers = %headers(&response.headers, &["Set-Cookie"]),
+        body = ?response_body,
+        "Response body for {} {} ({})",
+        request.method,
+        request.path,
+        response.status
+    );
+}
+
+fn headers(headers: &Headers, sensitive: &[&str]) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if sensitive.iter().any(|s| name.eq_ignore_ascii_case(s)) { REDACTED } else { value };
+            format!("{}: {}", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Renders a body for the log: redacted, lossily decoded and truncated.
+/// Encoded (compressed) bodies are only described.
+fn body(bytes: &[u8], headers: &Headers, max_bytes: usize) -> String {
+    if bytes.is_empty() {
+        return String::new();
+    }
+    if let Some(encoding) = headers.get("Content-Encoding") {
+        return format!("({} bytes, {}-encoded)", bytes.len(), encoding);
+    }
+    let content_type = headers.get("Content-Type").unwrap_or("");
+    let redacted = redact_fields(bytes, content_type);
+    let shown = String::from_utf8_lossy(&redacted[..redacted.len().min(max_bytes)]).into_owned();
+    if redacted.len() > max_bytes {
+        format!("{}... ({} bytes total)", shown, redacted.len())
+    } else {
+        shown
+    }
+}
+
+fn is_secret(field: &str) -> bool {
+    let field = field.to_ascii_lowercase();
+    SECRET_FIELDS.iter().any(|secret| field.contains(secret))
+}
+
+fn redact_fields<'a>(bytes: &'a [u8], content_type: &str) -> Cow<'a, [u8]> {
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    if media_type.eq_ignore_ascii_case("application/json") {
+        if let Ok(mut value) = serde_json::from_slice::<Value>(bytes) {
+            redact_json(&mut value);
+            if let Ok(redacted) = serde_json::to_vec(&value) {
+                return Cow::Owned(redacted);
+            }
+        }
+    } else if media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        let form = String::from_utf8_lossy(bytes);
+        let redacted = form
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, _)) if is_secret(name) => format!("{}={}", name, REDACTED),
+                _ => pair.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        return Cow::Owned(redacted.into_bytes());
+    }
+    Cow::Borrowed(bytes)
+}
+
+fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                if is_secret(name) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(content_type: &str) -> Headers {
+        let mut headers = Headers::new();
+        headers.insert("Content-Type", content_type);
+        headers
+    }
+
+    #[test]
+    fn credential_headers_are_redacted() {
+        let mut request = Headers::new();
+        request.insert("Authorization", "Bearer t0ken");
+        request.insert("Cookie", "session=abc");
+        request.insert("Accept", "text/html");
+        let logged = headers(&request, &CREDENTIAL_HEADERS);
+        assert_eq!(logged, "Authorization: [redacted], Cookie: [redacted], Accept: text/html");
+
+        let mut response = Headers::new();
+        response.insert("set-cookie", "session=abc");
+        assert_eq!(headers(&response, &["Set-Cookie"]), "set-cookie: [redacted]");
+    }
+
+    #[test]
+    fn long_bodies_are_truncated_with_their_length() {
+        let long = "x".repeat(100);
+        assert_eq!(body(long.as_bytes(), &typed("text/plain"), 10), "xxxxxxxxxx... (100 bytes total)");
+        assert_eq!(body(b"short", &typed("text/plain"), 10), "short");
+        assert_eq!(body(b"exactly10!", &typed("text/plain"), 10), "exactly10!");
+        // A cut through a multibyte character doesn't panic.
+        assert_eq!(body("ééé".as_bytes(), &Headers::new(), 3), "é\u{fffd}... (6 bytes total)");
+    }
+
+    #[test]
+    fn secret_fields_are_redacted_in_json_and_forms() {
+        let json = br#"{"user":"ana","Password":"hunter2","nested":[{"api_key":"k"}]}"#;
+        let logged = body(json, &typed("application/json; charset=utf-8"), 1024);
+        let logged: Value = serde_json::from_str(&logged).unwrap();
+        assert_eq!(logged, serde_json::json!({"user": "ana", "Password": REDACTED, "nested": [{"api_key": REDACTED}]}));
+
+        let form = b"user=ana&access_token=abc&note=hi";
+        let logged = body(form, &typed("application/x-www-form-urlencoded"), 1024);
+        assert_eq!(logged, "user=ana&access_token=[redacted]&note=hi");
+
+        // Redaction happens before truncation, so a secret can't leak by
+        // being cut in half.
+        let logged = body(br#"{"password":"hunter2hunter2"}"#, &typed("application/json"), 16);
+        assert!(!logged.contains("hunter"), "{logged}");
+    }
+
+    #[test]
+    fn encoded_bodies_are_only_described() {
+        let mut headers = typed("text/plain");
+        headers.insert("Content-Encoding", "gzip");
+        assert_eq!(body(&[0x1f, 0x8b, 0, 1], &headers, 1024), "(4 bytes, gzip-encoded)");
+    }
+}
@@ -0,0 +1,113 @@
+use std::path::Path;
+
+/// One year, the conventional lifetime for content-addressed assets.
+const IMMUTABLE_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+/// Picks the `Cache-Control` value for a static response.
+///
+/// Operator rules are tried first, in order; the first whose pattern matches
+/// the request path wins. Patterns are matched against the whole path and
+/// `*` matches any run of characters, `/` included (`*.css`, `/assets/*`).
+/// Without a matching rule HTML is `no-cache` (always revalidated, so a
+/// deploy shows up straight away), files with a content hash in their name
+/// (`app.3f9a2c1b.js`) are `immutable`, and anything else gets the default
+/// `max-age`.
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+    rules: Vec<(String, String)>,
+    default_max_age: u64,
+}
+
+impl CachePolicy {
+    pub fn new(default_max_age: u64) -> CachePolicy {
+        CachePolicy {
+            rules: Vec::new(),
+            default_max_age,
+        }
+    }
+
+    pub fn rule(mut self, pattern: &str, cache_control: &str) -> CachePolicy {
+        self.rules.push((pattern.to_string(), cache_control.to_string()));
+        self
+    }
+
+    /// Adds rules written as `pattern=value` pairs separated by `;`, e.g.
+    /// `/assets/*=public, max-age=86400; *.pdf=no-store`. Malformed entries
+    /// are returned so the caller can report them.
+    pub fn with_rules(mut self, rules: &str) -> (CachePolicy, Vec<String>) {
+        let mut invalid = Vec::new();
+        for entry in rules.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+            match entry.split_once('=') {
+                Some((pattern, value)) if !pattern.trim().is_empty() && !value.trim().is_empty() => {
+                    self = self.rule(pattern.trim(), value.trim());
+                }
+                _ => invalid.push(entry.to_string()),
+            }
+        }
+        (self, invalid)
+    }
+
+    /// Operator rules as `(pattern, value)` pairs, in the order they are tried.
+    pub fn rules(&self) -> &[(String, String)] {
+        &self.rules
+    }
+
+    pub fn default_max_age(&self) -> u64 {
+        self.default_max_age
+    }
+
+    pub fn header_for(&self, request_path: &str, file: &Path) -> String {
+        if let Some((_, value)) = self.rules.iter().find(|(pattern, _)| glob_match(pattern, request_path)) {
+            return value.clone();
+        }
+        let extension = file.extension().and_then(|e| e.to_str()).unwrap_or("");
+        if extension.eq_ignore_ascii_case("html") || extension.eq_ignore_ascii_case("htm") {
+            "no-cache".to_string()
+        } else if is_hashed(file) {
+            format!("public, max-age={}, immutable", IMMUTABLE_MAX_AGE)
+        } else {
+            format!("public, max-age={}", self.default_max_age)
+        }
+    }
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        CachePolicy::new(3600)
+    }
+}
+
+/// Whether a file name has a content-hash segment: eight or more hex digits
+/// between dots or after a dash (`app.3f9a2c1b.js`, `chunk-5d41402abc4b.css`).
+fn is_hashed(file: &Path) -> bool {
+    let name = match file.file_stem().and_then(|stem| stem.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    name.split(['.', '-'])
+        .skip(1)
+        .any(|segment| segment.len() >= 8 && segment.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Matches `path` against a pattern where `*` stands for any (possibly empty)
+/// run of characters.
+fn glob_match(pattern: &str, path: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match path.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard at all: the pattern must match exactly.
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
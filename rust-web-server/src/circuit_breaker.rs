@@ -0,0 +1,115 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use metrics::counter;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    /// Consecutive failures within `window` that open the circuit.
+    pub failure_threshold: u32,
+    pub window: Duration,
+    /// How long the circuit stays open before letting a probe through.
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            failure_threshold: 5,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: u32, first_failure: Option<Instant> },
+    Open { until: Instant },
+    HalfOpen { probing: bool },
+}
+
+/// Fails calls fast after repeated failures, then lets a single probe through
+/// once the cooldown has passed to check whether things have recovered.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: &'static str,
+    config: BreakerConfig,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, config: BreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            name,
+            config,
+            state: Mutex::new(State::Closed {
+                failures: 0,
+                first_failure: None,
+            }),
+        }
+    }
+
+    /// Whether a call may go ahead. While half-open only one probe is allowed
+    /// at a time.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if Instant::now() >= until => {
+                info!("{} circuit half-open, probing", self.name);
+                *state = State::HalfOpen { probing: true };
+                true
+            }
+            State::Open { .. } => false,
+            State::HalfOpen { probing: true } => false,
+            State::HalfOpen { probing: false } => {
+                *state = State::HalfOpen { probing: true };
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let State::HalfOpen { .. } = *state {
+            info!("{} circuit closed again", self.name);
+        }
+        *state = State::Closed {
+            failures: 0,
+            first_failure: None,
+        };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let trip = match *state {
+            State::Closed { failures, first_failure } => {
+                let (failures, first_failure) = match first_failure {
+                    Some(first) if now.duration_since(first) <= self.config.window => (failures + 1, first),
+                    _ => (1, now),
+                };
+                if failures >= self.config.failure_threshold {
+                    true
+                } else {
+                    *state = State::Closed {
+                        failures,
+                        first_failure: Some(first_failure),
+                    };
+                    false
+                }
+            }
+            State::HalfOpen { .. } => true,
+            State::Open { .. } => false,
+        };
+
+        if trip {
+            warn!("{} circuit open for {:?}", self.name, self.config.cooldown);
+            counter!(format!("{}_circuit_open_total", self.name), 1);
+            *state = State::Open {
+                until: now + self.config.cooldown,
+            };
+        }
+    }
+}
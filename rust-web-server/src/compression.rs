@@ -0,0 +1,284 @@
+//! On-the-fly compression of response bodies, negotiated from the client's
+//! `Accept-Encoding` header against the encodings the server is configured
+//! to use.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use flate2::write::GzEncoder;
+use metrics::counter;
+use tracing::warn;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// Brotli quality (0-11). Higher levels compress better but are far too slow
+/// to run on every response.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// The content-coding token, as used in `Content-Encoding`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    pub fn compress(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut output = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut output, 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                    writer.write_all(body)?;
+                }
+                Ok(output)
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "br" | "brotli" => Ok(Encoding::Brotli),
+            "gzip" => Ok(Encoding::Gzip),
+            _ => Err(format!("unknown encoding: {}", s)),
+        }
+    }
+}
+
+/// Picks the encoding to use from `preferred`, going by the client's
+/// q-values; among equally acceptable encodings the earlier one in
+/// `preferred` wins. `*` covers any encoding the client doesn't name, and
+/// `q=0` rules one out. Returns `None` when none of them is acceptable.
+pub fn negotiate(accept_encoding: &str, preferred: &[Encoding]) -> Option<Encoding> {
+    let codings: Vec<(&str, f32)> = accept_encoding
+        .split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let coding = params.next()?.trim();
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!coding.is_empty()).then_some((coding, q))
+        })
+        .collect();
+    let quality = |encoding: &Encoding| {
+        let named = codings
+            .iter()
+            .find(|(coding, _)| coding.eq_ignore_ascii_case(encoding.as_str()));
+        named
+            .or_else(|| codings.iter().find(|(coding, _)| *coding == "*"))
+            .map_or(0.0, |(_, q)| *q)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in preferred {
+        let q = quality(encoding);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((*encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// The encoding a response to `request` would be compressed with, if it
+/// turns out to be compressible and big enough.
+pub(crate) fn negotiated(request: &Request, preferred: &[Encoding]) -> Option<Encoding> {
+    request
+        .header("Accept-Encoding")
+        .and_then(|accept_encoding| negotiate(accept_encoding, preferred))
+}
+
+/// Whether a body of this type is worth compressing: text, JSON, XML,
+/// JavaScript and SVG. Images, fonts and archives are already compressed.
+pub fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/json" | "application/javascript" | "application/xml" | "application/wasm"
+        )
+}
+
+/// Compresses a response's body when the client accepts one of `preferred`
+/// and it is at least `min_bytes` long. Responses that are streamed, already
+/// encoded, partial or of a type that doesn't compress are passed through
+/// unchanged. Every other response gets `Vary: Accept-Encoding`, compressed
+/// this time or not (too small, or the client accepts none of `preferred`),
+/// since a cache keeping it must not hand it to a client that asked differently.
+pub(crate) fn compress_response(
+    request: &Request,
+    mut response: Response,
+    preferred: &[Encoding],
+    min_bytes: usize,
+) -> Response {
+    if preferred.is_empty()
+        || response.status != StatusCode::Ok
+        || response.is_streamed()
+        || response.headers.get("Content-Encoding").is_some()
+        || !response.headers.get("Content-Type").is_some_and(is_compressible)
+    {
+        return response;
+    }
+    let varies = response
+        .headers
+        .get_all("Vary")
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("Accept-Encoding")
+        });
+    if !varies {
+        response = response.append_header("Vary", "Accept-Encoding");
+    }
+    if response.body.is_empty() || response.body.len() < min_bytes {
+        return response;
+    }
+    let Some(encoding) = negotiated(request, preferred) else {
+        return response;
+    };
+
+    let compressed = match encoding.compress(&response.body) {
+        Ok(compressed) => compressed,
+        Err(e) => {
+            warn!("Failed to {} response body: {}", encoding, e);
+            return response;
+        }
+    };
+    counter!("responses_compressed_total", 1, "encoding" => encoding.as_str());
+    counter!("compression_saved_bytes_total", response.body.len().saturating_sub(compressed.len()) as u64);
+    // The compressed body is a different representation, so a strong
+    // validator for the original must not be reused for it.
+    if let Some(etag) = response.headers.get("ETag").and_then(|etag| etag.strip_suffix('"')) {
+        let etag = format!("{}-{}\"", etag, encoding);
+        response.headers.insert("ETag", &etag);
+    }
+    response.body = compressed;
+    response.with_header("Content-Encoding", encoding.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    const BOTH: &[Encoding] = &[Encoding::Brotli, Encoding::Gzip];
+
+    #[test]
+    fn the_configured_order_breaks_ties() {
+        assert_eq!(negotiate("gzip, br", BOTH), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip, br", &[Encoding::Gzip, Encoding::Brotli]), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*", BOTH), Some(Encoding::Brotli));
+    }
+
+    #[test]
+    fn client_q_values_beat_the_configured_order() {
+        assert_eq!(negotiate("br;q=0.5, gzip;q=0.9", BOTH), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0.2, *;q=0.8", BOTH), Some(Encoding::Brotli));
+        assert_eq!(negotiate("GZIP; q=1.0, br; q=0.999", BOTH), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn only_acceptable_configured_encodings_are_chosen() {
+        assert_eq!(negotiate("br", &[Encoding::Gzip]), None);
+        assert_eq!(negotiate("gzip", BOTH), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, gzip", BOTH), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0", BOTH), None);
+        assert_eq!(negotiate("identity", BOTH), None);
+        assert_eq!(negotiate("", BOTH), None);
+        assert_eq!(negotiate("br", &[]), None);
+    }
+
+    fn compressed(accept_encoding: &str, body: &str) -> Response {
+        let raw = format!("GET / HTTP/1.1\r\nAccept-Encoding: {accept_encoding}\r\n\r\n");
+        let request = Request::parse(&mut raw.as_bytes()).unwrap();
+        let response = Response::new(StatusCode::Ok)
+            .with_header("Content-Type", "text/plain")
+            .with_body(body);
+        compress_response(&request, response, BOTH, 16)
+    }
+
+    #[test]
+    fn brotli_bodies_decode_to_the_original() {
+        let body = "the quick brown fox ".repeat(100);
+        let response = compressed("gzip, br", &body);
+        assert_eq!(response.headers.get("Content-Encoding"), Some("br"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert!(response.body.len() < body.len());
+        let mut decoded = String::new();
+        brotli::Decompressor::new(response.body.as_slice(), 4096).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, body);
+
+        let response = compressed("br;q=0.1, gzip", &body);
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(response.body.as_slice()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, body);
+    }
+
+    fn vary(response: &Response) -> Vec<&str> {
+        response.headers.get_all("Vary").collect()
+    }
+
+    #[test]
+    fn bodies_below_the_threshold_still_vary() {
+        let response = compressed("gzip", "short");
+        assert_eq!(response.headers.get("Content-Encoding"), None);
+        assert_eq!(response.body, b"short");
+        assert_eq!(vary(&response), ["Accept-Encoding"]);
+    }
+
+    #[test]
+    fn uncompressed_answers_to_other_clients_vary_too() {
+        let body = "the quick brown fox ".repeat(100);
+        let response = compressed("identity", &body);
+        assert_eq!(response.headers.get("Content-Encoding"), None);
+        assert_eq!(vary(&response), ["Accept-Encoding"]);
+    }
+
+    #[test]
+    fn an_existing_vary_is_kept_and_not_repeated() {
+        let request = Request::parse(&mut &b"GET / HTTP/1.1\r\n\r\n"[..]).unwrap();
+        let text = || Response::new(StatusCode::Ok).with_header("Content-Type", "text/plain").with_body("x");
+        let response = compress_response(&request, text().with_header("Vary", "Origin"), BOTH, 16);
+        assert_eq!(vary(&response), ["Origin", "Accept-Encoding"]);
+        let response = compress_response(&request, text().with_header("Vary", "origin, accept-encoding"), BOTH, 16);
+        assert_eq!(vary(&response), ["origin, accept-encoding"]);
+    }
+
+    #[test]
+    fn responses_that_never_compress_do_not_vary() {
+        let request = Request::parse(&mut &b"GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"[..]).unwrap();
+        let image = Response::new(StatusCode::Ok).with_header("Content-Type", "image/png").with_body(vec![0; 4096]);
+        assert!(vary(&compress_response(&request, image, BOTH, 16)).is_empty());
+        let text = Response::new(StatusCode::Ok).with_header("Content-Type", "text/plain").with_body("x".repeat(4096));
+        assert!(vary(&compress_response(&request, text, &[], 16)).is_empty());
+    }
+}
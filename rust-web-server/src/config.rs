@@ -0,0 +1,735 @@
+use std::env;
+use std::io;
+use std::num::NonZeroUsize;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+use serde_json::{json, Value};
+
+use crate::acl::AccessControl;
+use crate::cache_control::CachePolicy;
+use crate::circuit_breaker::BreakerConfig;
+use crate::compression::Encoding;
+use crate::forwarded::TrustedProxies;
+use crate::limits::CapMode;
+use crate::not_found::{NotFoundMode, NotFoundPolicy};
+use crate::proxy::Upstream;
+use crate::request::DEFAULT_MAX_HEADER_BYTES;
+use crate::router::TrailingSlash;
+use crate::QueueFullPolicy;
+
+/// Runtime settings, read from the environment with defaults for anything unset.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Worker threads; defaults to one per CPU, see [`default_pool_size`].
+    pub pool_size: usize,
+    pub queue_capacity: usize,
+    pub queue_full_policy: QueueFullPolicy,
+    pub pin_workers: bool,
+    /// Run a no-op job on every worker before accepting connections.
+    pub warm_up_workers: bool,
+    /// Workers in a second pool for `blocking_routes`, so slow requests
+    /// can't occupy the workers answering fast ones. Zero (the default)
+    /// means no second pool; blocking routes then share the main one.
+    pub blocking_pool_size: usize,
+    /// Routes run on the blocking pool, by registered path.
+    pub blocking_routes: Vec<String>,
+    /// Routes that answer 503 while the pool is overloaded.
+    pub shed_routes: Vec<String>,
+    /// Queue depth at which shedding starts; zero disables the check.
+    pub shed_queue_depth: usize,
+    /// Worker utilization (0.0-1.0) at which shedding starts; zero disables it.
+    pub shed_utilization: f64,
+    /// Default deadline for handling a request, from when it was read.
+    pub request_timeout: Duration,
+    /// Per-route overrides of `request_timeout`, by registered path.
+    pub route_timeouts: Vec<(String, Duration)>,
+    /// Routes forwarded to upstream servers, as `(route, upstream)` with the
+    /// route a prefix pattern such as `/api/*`. GET, HEAD and POST are
+    /// forwarded; see [`crate::proxy`].
+    pub proxy_routes: Vec<(String, Upstream)>,
+    /// How long a proxied request may wait on its upstream: to connect, and
+    /// for each write or read to make progress. Zero leaves only the
+    /// request's deadline.
+    pub proxy_timeout: Duration,
+    pub max_body_bytes: usize,
+    /// Cap on the request line and headers together; larger heads get a 431.
+    pub max_header_bytes: usize,
+    /// How long the whole request line and headers may take to arrive,
+    /// however steadily they trickle in; slower clients get a 408. Zero
+    /// disables the limit.
+    pub header_timeout: Duration,
+    /// How long a single response write may stall before the client is
+    /// treated as too slow and the connection dropped.
+    pub write_timeout: Duration,
+    /// How long a connection may sit idle waiting for a request to start,
+    /// the first one or the next on a kept-alive connection.
+    pub keepalive_timeout: Duration,
+    /// How long a request that has started arriving may go without sending
+    /// more, while its body is read (and after, for a WebSocket session).
+    /// The head is bounded by `header_timeout` instead.
+    pub read_timeout: Duration,
+    /// How long an HTTP/1.x connection may stay open in all, from accept to
+    /// its final flush, after which it is closed whatever it is doing. Checked
+    /// between phases (reading, handling, each streamed chunk, the keep-alive
+    /// wait), so a running handler isn't interrupted; `request_timeout` bounds
+    /// that. Zero disables the limit.
+    pub connection_timeout: Duration,
+    /// Requests served on one connection before it is closed.
+    pub keepalive_max_requests: usize,
+    /// Have the OS probe accepted connections that go quiet, so a client
+    /// that vanished without closing (a dropped network, a crashed host)
+    /// frees its worker instead of holding it until a timeout. Off by default.
+    pub tcp_keepalive: bool,
+    /// Idle time before the first keepalive probe.
+    pub tcp_keepalive_idle: Duration,
+    /// Time between unanswered probes, and how many go unanswered before the
+    /// connection is dropped. Not settable per socket on every OS; where it
+    /// isn't, the system defaults apply.
+    pub tcp_keepalive_interval: Duration,
+    pub tcp_keepalive_retries: u32,
+    /// Answer TRACE with an echo of the request instead of a 405. Credentials
+    /// are never echoed.
+    pub enable_trace: bool,
+    /// Registers the `/sleep` route, which holds a worker for `test_sleep`
+    /// before answering, for exercising timeouts and keep-alive under load,
+    /// and the `/events` demo stream, which pushes the time every second.
+    /// Off by default; it's scaffolding, not something to expose.
+    pub enable_test_routes: bool,
+    pub test_sleep: Duration,
+    /// Charset added to text and JSON `Content-Type`s that don't name one;
+    /// `DEFAULT_CHARSET=none` leaves them alone.
+    pub default_charset: Option<String>,
+    /// Whether `/about` and `/about/` are the same route, or redirect to one form.
+    pub trailing_slash: TrailingSlash,
+    /// Directory served for requests no route matches; unset disables it.
+    pub static_root: Option<PathBuf>,
+    /// `Cache-Control` for static responses, by path pattern.
+    pub static_cache_policy: CachePolicy,
+    /// Total bytes of static files kept in memory; zero disables the cache.
+    pub static_cache_bytes: usize,
+    /// Invalidate cached static files when they change on disk. Needs inotify
+    /// (or the platform equivalent), so it can be switched off.
+    pub static_watch: bool,
+    /// Static requests under this prefix that match no file, and don't look
+    /// like assets (no extension), get `spa_index` instead of a 404.
+    pub spa_prefix: Option<String>,
+    /// The single-page app's entry point, as a path under `static_root`.
+    pub spa_index: String,
+    /// What requests nothing answers get: the 404 page, a JSON error or a
+    /// redirect, chosen per path prefix.
+    pub not_found: NotFoundPolicy,
+    /// Where the JSON stats snapshot is served; unset (or empty) disables it.
+    pub stats_path: Option<String>,
+    /// Where the health check is served; unset (or empty) disables it.
+    pub health_path: Option<String>,
+    /// When set, the health check also has the worker pool run a no-op job
+    /// and answers 503 if it isn't done within this long. Off (zero) by
+    /// default, since the probe takes a worker while it waits.
+    pub health_probe_timeout: Duration,
+    /// Circuit breaker around static file reads.
+    pub fs_breaker: BreakerConfig,
+    /// Static reads slower than this count as breaker failures.
+    pub fs_slow_read: Duration,
+    /// PEM certificate chain and key. When both are set (and the server is
+    /// built with the `http2` feature) connections are served over TLS.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Streams an HTTP/2 client may have open on one connection at a time,
+    /// each handled on a blocking thread of its own.
+    pub h2_max_concurrent_streams: u32,
+    /// Port for the Prometheus endpoint.
+    pub metrics_port: u16,
+    /// Interface the Prometheus endpoint listens on. Loopback by default, so
+    /// metrics are only reachable from the host unless opted in.
+    pub metrics_bind: IpAddr,
+    /// Bearer token scrapers must present; unset leaves the endpoint open to
+    /// anyone who can reach `metrics_bind`.
+    pub metrics_token: Option<String>,
+    /// Ports tried, counting up from `metrics_port`, while they're in use.
+    /// If none is free the server runs without a metrics endpoint.
+    pub metrics_port_attempts: u16,
+    /// How long the metrics endpoint stays up after the server has drained,
+    /// so a final scrape can pick up the shutdown counters.
+    pub metrics_linger: Duration,
+    /// Bucket bounds, in seconds, for the `job_queue_wait_seconds` histogram:
+    /// how long connections wait for a worker. Empty leaves it a summary,
+    /// like the other histograms.
+    pub job_queue_wait_buckets: Vec<f64>,
+    /// Workers busy with one job for longer than this are logged and counted
+    /// in the `stuck_workers` gauge; zero disables the check. A job is a
+    /// whole connection, so this should be well above the keep-alive timeout.
+    pub stuck_worker_threshold: Duration,
+    /// How long shutdown waits for in-flight work before detaching workers
+    /// that are still busy; zero waits indefinitely.
+    pub shutdown_timeout: Duration,
+    /// Requests taking at least this long get a warning and are counted in
+    /// `slow_requests_total`; zero disables it.
+    pub slow_request_threshold: Duration,
+    /// Log one in this many successful requests; failures are always logged.
+    pub log_sample_rate: u64,
+    /// Log request and response bodies at debug level, with credentials
+    /// redacted. For debugging only: bodies can carry personal data.
+    pub log_bodies: bool,
+    /// How much of each body `log_bodies` logs.
+    pub log_body_max_bytes: usize,
+    /// Connections served at once, across all clients; zero means no cap.
+    pub max_connections: usize,
+    /// Whether connections past `max_connections` are answered 503 or left
+    /// waiting in the kernel backlog until one finishes.
+    pub max_connections_mode: CapMode,
+    /// Concurrent connections allowed per client IP; zero means no cap.
+    /// Behind a trusted proxy this caps concurrent requests per forwarded
+    /// client instead, since every connection comes from the proxy.
+    pub max_connections_per_ip: usize,
+    /// Peers whose `Forwarded`/`X-Forwarded-For` headers are believed when
+    /// working out the client's IP, for the per-IP cap and the access log.
+    /// Empty (the default) trusts no one.
+    pub trusted_proxies: TrustedProxies,
+    /// Path prefixes only clients inside given networks may request; others
+    /// get a 403 before routing. Empty (the default) restricts nothing.
+    pub access_control: AccessControl,
+    /// Threads calling accept() on the listener.
+    pub accept_threads: usize,
+    /// Length of the kernel's queue of connections waiting for accept().
+    /// The kernel caps it (`net.core.somaxconn` on Linux), so raising it
+    /// past that limit needs the sysctl raised too.
+    pub listen_backlog: i32,
+    /// New connections accepted per second across all clients; zero means no cap.
+    pub accept_rate: f64,
+    /// Connections that may be accepted back to back before the rate applies;
+    /// zero means one second's worth.
+    pub accept_burst: f64,
+    /// How long a connection over the rate may wait for a slot before it is
+    /// turned away with a 503.
+    pub accept_max_delay: Duration,
+    /// Bearer token for the `/admin` endpoints; unset leaves them unregistered.
+    pub admin_token: Option<String>,
+    /// Start in maintenance mode; it can be switched at runtime through
+    /// `Server::maintenance` or `/admin/maintenance`.
+    pub maintenance: bool,
+    /// Page sent with the maintenance 503.
+    pub maintenance_page: PathBuf,
+    /// `Retry-After` seconds sent with the maintenance 503.
+    pub maintenance_retry_after: u64,
+    /// Encodings used to compress textual responses, in order of preference
+    /// for when the client accepts several equally. Empty disables compression.
+    pub compression: Vec<Encoding>,
+    /// Bodies smaller than this are sent uncompressed: the saving is a few
+    /// bytes at best, and tiny bodies can grow once encoded.
+    pub compression_min_bytes: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            pool_size: default_pool_size(),
+            queue_capacity: 1024,
+            queue_full_policy: QueueFullPolicy::RejectNew,
+            pin_workers: false,
+            warm_up_workers: false,
+            blocking_pool_size: 0,
+            blocking_routes: Vec::new(),
+            shed_routes: Vec::new(),
+            shed_queue_depth: 0,
+            shed_utilization: 0.0,
+            request_timeout: Duration::from_secs(30),
+            route_timeouts: Vec::new(),
+            proxy_routes: Vec::new(),
+            proxy_timeout: Duration::from_secs(30),
+            max_body_bytes: 1024 * 1024,
+            max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+            header_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            keepalive_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(10),
+            connection_timeout: Duration::ZERO,
+            keepalive_max_requests: 100,
+            tcp_keepalive: false,
+            tcp_keepalive_idle: Duration::from_secs(60),
+            tcp_keepalive_interval: Duration::from_secs(10),
+            tcp_keepalive_retries: 5,
+            enable_trace: false,
+            enable_test_routes: false,
+            test_sleep: Duration::from_secs(5),
+            default_charset: Some("utf-8".to_string()),
+            trailing_slash: TrailingSlash::Strict,
+            static_root: None,
+            static_cache_policy: CachePolicy::default(),
+            static_cache_bytes: 0,
+            static_watch: true,
+            spa_prefix: None,
+            spa_index: "/index.html".to_string(),
+            not_found: NotFoundPolicy::default(),
+            stats_path: Some("/stats".to_string()),
+            health_path: Some("/health".to_string()),
+            health_probe_timeout: Duration::ZERO,
+            fs_breaker: BreakerConfig::default(),
+            fs_slow_read: Duration::from_secs(1),
+            tls_cert: None,
+            tls_key: None,
+            h2_max_concurrent_streams: 8,
+            metrics_port: 9091,
+            metrics_bind: Ipv4Addr::LOCALHOST.into(),
+            metrics_token: None,
+            metrics_port_attempts: 1,
+            metrics_linger: Duration::from_secs(5),
+            job_queue_wait_buckets: vec![0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0],
+            stuck_worker_threshold: Duration::from_secs(60),
+            shutdown_timeout: Duration::from_secs(30),
+            slow_request_threshold: Duration::from_secs(1),
+            log_sample_rate: 1,
+            log_bodies: false,
+            log_body_max_bytes: 1024,
+            max_connections: 0,
+            max_connections_mode: CapMode::Reject,
+            max_connections_per_ip: 0,
+            trusted_proxies: TrustedProxies::default(),
+            access_control: AccessControl::default(),
+            accept_threads: 1,
+            listen_backlog: 1024,
+            accept_rate: 0.0,
+            accept_burst: 0.0,
+            accept_max_delay: Duration::from_millis(50),
+            admin_token: None,
+            maintenance: false,
+            maintenance_page: PathBuf::from("maintenance.html"),
+            maintenance_retry_after: 300,
+            compression: Vec::new(),
+            compression_min_bytes: 1024,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        let defaults = Config::default();
+        Config {
+            pool_size: env_nonzero("POOL_SIZE", defaults.pool_size),
+            queue_capacity: env_nonzero("QUEUE_CAPACITY", defaults.queue_capacity),
+            queue_full_policy: env_or("QUEUE_FULL_POLICY", defaults.queue_full_policy),
+            pin_workers: env_or("PIN_WORKERS", defaults.pin_workers),
+            warm_up_workers: env_or("WARM_UP_WORKERS", defaults.warm_up_workers),
+            blocking_pool_size: env_or("BLOCKING_POOL_SIZE", defaults.blocking_pool_size),
+            blocking_routes: env_list("BLOCKING_ROUTES").unwrap_or(defaults.blocking_routes),
+            shed_routes: env_list("SHED_ROUTES").unwrap_or(defaults.shed_routes),
+            shed_queue_depth: env_or("SHED_QUEUE_DEPTH", defaults.shed_queue_depth),
+            shed_utilization: env_or("SHED_UTILIZATION", defaults.shed_utilization),
+            request_timeout: Duration::from_secs(env_or("REQUEST_TIMEOUT_SECS", defaults.request_timeout.as_secs())),
+            route_timeouts: route_timeouts(),
+            proxy_routes: proxy_routes(),
+            proxy_timeout: Duration::from_secs(env_or("PROXY_TIMEOUT_SECS", defaults.proxy_timeout.as_secs())),
+            max_body_bytes: env_or("MAX_BODY_BYTES", defaults.max_body_bytes),
+            max_header_bytes: env_or("MAX_HEADER_BYTES", defaults.max_header_bytes),
+            header_timeout: Duration::from_secs(env_or("HEADER_TIMEOUT_SECS", defaults.header_timeout.as_secs())),
+            write_timeout: Duration::from_secs(env_or("WRITE_TIMEOUT_SECS", defaults.write_timeout.as_secs())),
+            keepalive_timeout: Duration::from_secs(env_or("KEEPALIVE_TIMEOUT_SECS", defaults.keepalive_timeout.as_secs())),
+            read_timeout: Duration::from_secs(env_or("READ_TIMEOUT_SECS", defaults.read_timeout.as_secs())),
+            connection_timeout: Duration::from_secs(env_or("CONNECTION_TIMEOUT_SECS", defaults.connection_timeout.as_secs())),
+            keepalive_max_requests: env_or("KEEPALIVE_MAX_REQUESTS", defaults.keepalive_max_requests),
+            tcp_keepalive: env_or("TCP_KEEPALIVE", defaults.tcp_keepalive),
+            tcp_keepalive_idle: Duration::from_secs(env_or("TCP_KEEPALIVE_IDLE_SECS", defaults.tcp_keepalive_idle.as_secs())),
+            tcp_keepalive_interval: Duration::from_secs(env_or(
+                "TCP_KEEPALIVE_INTERVAL_SECS",
+                defaults.tcp_keepalive_interval.as_secs(),
+            )),
+            tcp_keepalive_retries: env_or("TCP_KEEPALIVE_RETRIES", defaults.tcp_keepalive_retries),
+            enable_trace: env_or("ENABLE_TRACE", defaults.enable_trace),
+            enable_test_routes: env_or("ENABLE_TEST_ROUTES", defaults.enable_test_routes),
+            test_sleep: Duration::from_millis(env_or("TEST_SLEEP_MS", defaults.test_sleep.as_millis() as u64)),
+            default_charset: match env::var("DEFAULT_CHARSET") {
+                Ok(charset) if charset.is_empty() || charset.eq_ignore_ascii_case("none") => None,
+                Ok(charset) => Some(charset),
+                Err(_) => defaults.default_charset,
+            },
+            trailing_slash: env_or("TRAILING_SLASH", defaults.trailing_slash),
+            static_root: env::var("STATIC_ROOT").ok().map(PathBuf::from),
+            static_cache_policy: static_cache_policy(),
+            static_cache_bytes: env_or("STATIC_CACHE_BYTES", defaults.static_cache_bytes),
+            static_watch: env_or("STATIC_WATCH", defaults.static_watch),
+            spa_prefix: env::var("SPA_PREFIX").ok(),
+            spa_index: env::var("SPA_INDEX").unwrap_or(defaults.spa_index),
+            not_found: not_found_policy(),
+            stats_path: match env::var("STATS_PATH") {
+                Ok(path) => (!path.is_empty()).then_some(path),
+                Err(_) => defaults.stats_path,
+            },
+            health_path: match env::var("HEALTH_PATH") {
+                Ok(path) => (!path.is_empty()).then_some(path),
+                Err(_) => defaults.health_path,
+            },
+            health_probe_timeout: Duration::from_millis(env_or(
+                "HEALTH_PROBE_TIMEOUT_MS",
+                defaults.health_probe_timeout.as_millis() as u64,
+            )),
+            fs_breaker: BreakerConfig {
+                failure_threshold: env_or("FS_BREAKER_THRESHOLD", defaults.fs_breaker.failure_threshold),
+                window: Duration::from_secs(env_or("FS_BREAKER_WINDOW_SECS", defaults.fs_breaker.window.as_secs())),
+                cooldown: Duration::from_secs(env_or("FS_BREAKER_COOLDOWN_SECS", defaults.fs_breaker.cooldown.as_secs())),
+            },
+            fs_slow_read: Duration::from_millis(env_or("FS_SLOW_READ_MS", defaults.fs_slow_read.as_millis() as u64)),
+            tls_cert: env::var("TLS_CERT_FILE").ok().map(PathBuf::from),
+            tls_key: env::var("TLS_KEY_FILE").ok().map(PathBuf::from),
+            h2_max_concurrent_streams: env_or("H2_MAX_CONCURRENT_STREAMS", defaults.h2_max_concurrent_streams),
+            metrics_port: env_or("METRICS_PORT", defaults.metrics_port),
+            metrics_bind: env_or("METRICS_BIND", defaults.metrics_bind),
+            metrics_token: env::var("METRICS_TOKEN").ok().filter(|token| !token.is_empty()),
+            metrics_port_attempts: env_or("METRICS_PORT_ATTEMPTS", defaults.metrics_port_attempts),
+            metrics_linger: Duration::from_secs(env_or("METRICS_LINGER_SECS", defaults.metrics_linger.as_secs())),
+            job_queue_wait_buckets: job_queue_wait_buckets().unwrap_or(defaults.job_queue_wait_buckets),
+            stuck_worker_threshold: Duration::from_secs(env_or("STUCK_WORKER_SECS", defaults.stuck_worker_threshold.as_secs())),
+            shutdown_timeout: Duration::from_secs(env_or("SHUTDOWN_TIMEOUT_SECS", defaults.shutdown_timeout.as_secs())),
+            slow_request_threshold: Duration::from_millis(env_or("SLOW_REQUEST_MS", defaults.slow_request_threshold.as_millis() as u64)),
+            log_sample_rate: env_or("LOG_SAMPLE_RATE", defaults.log_sample_rate),
+            log_bodies: env_or("LOG_BODIES", defaults.log_bodies),
+            log_body_max_bytes: env_or("LOG_BODY_MAX_BYTES", defaults.log_body_max_bytes),
+            max_connections: env_or("MAX_CONNECTIONS", defaults.max_connections),
+            max_connections_mode: env_or("MAX_CONNECTIONS_MODE", defaults.max_connections_mode),
+            max_connections_per_ip: env_or("MAX_CONNECTIONS_PER_IP", defaults.max_connections_per_ip),
+            trusted_proxies: trusted_proxies(),
+            access_control: access_control(),
+            accept_threads: env_or("ACCEPT_THREADS", defaults.accept_threads),
+            listen_backlog: env_or("LISTEN_BACKLOG", defaults.listen_backlog),
+            accept_rate: env_or("ACCEPT_RATE", defaults.accept_rate),
+            accept_burst: env_or("ACCEPT_BURST", defaults.accept_burst),
+            accept_max_delay: Duration::from_millis(env_or("ACCEPT_MAX_DELAY_MS", defaults.accept_max_delay.as_millis() as u64)),
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            maintenance: env_or("MAINTENANCE", defaults.maintenance),
+            maintenance_page: env::var("MAINTENANCE_PAGE").map(PathBuf::from).unwrap_or(defaults.maintenance_page),
+            maintenance_retry_after: env_or("MAINTENANCE_RETRY_AFTER_SECS", defaults.maintenance_retry_after),
+            compression: compression(),
+            compression_min_bytes: env_or("COMPRESSION_MIN_BYTES", defaults.compression_min_bytes),
+        }
+    }
+
+    /// The settings as JSON, for `/admin/config`. Every field is listed by
+    /// hand so a new one has to be looked at before it shows up; secrets only
+    /// say whether they are set. Paths are not secret and are shown as-is.
+    pub fn redacted(&self) -> Value {
+        json!({
+            "pool_size": self.pool_size,
+            "queue_capacity": self.queue_capacity,
+            "queue_full_policy": self.queue_full_policy.as_str(),
+            "pin_workers": self.pin_workers,
+            "warm_up_workers": self.warm_up_workers,
+            "blocking_pool_size": self.blocking_pool_size,
+            "blocking_routes": self.blocking_routes,
+            "shed_routes": self.shed_routes,
+            "shed_queue_depth": self.shed_queue_depth,
+            "shed_utilization": self.shed_utilization,
+            "request_timeout": format!("{:?}", self.request_timeout),
+            "route_timeouts": self
+                .route_timeouts
+                .iter()
+                .map(|(path, timeout)| (path.clone(), Value::from(format!("{:?}", timeout))))
+                .collect::<serde_json::Map<_, _>>(),
+            "proxy_routes": self
+                .proxy_routes
+                .iter()
+                .map(|(route, upstream)| (route.clone(), Value::from(upstream.to_string())))
+                .collect::<serde_json::Map<_, _>>(),
+            "proxy_timeout": format!("{:?}", self.proxy_timeout),
+            "max_body_bytes": self.max_body_bytes,
+            "max_header_bytes": self.max_header_bytes,
+            "header_timeout": format!("{:?}", self.header_timeout),
+            "write_timeout": format!("{:?}", self.write_timeout),
+            "keepalive_timeout": format!("{:?}", self.keepalive_timeout),
+            "read_timeout": format!("{:?}", self.read_timeout),
+            "connection_timeout": format!("{:?}", self.connection_timeout),
+            "keepalive_max_requests": self.keepalive_max_requests,
+            "tcp_keepalive": self.tcp_keepalive,
+            "tcp_keepalive_idle": format!("{:?}", self.tcp_keepalive_idle),
+            "tcp_keepalive_interval": format!("{:?}", self.tcp_keepalive_interval),
+            "tcp_keepalive_retries": self.tcp_keepalive_retries,
+            "enable_trace": self.enable_trace,
+            "enable_test_routes": self.enable_test_routes,
+            "test_sleep": format!("{:?}", self.test_sleep),
+            "default_charset": self.default_charset,
+            "trailing_slash": self.trailing_slash.as_str(),
+            "static_root": self.static_root,
+            "static_cache_policy": {
+                "default_max_age": self.static_cache_policy.default_max_age(),
+                "rules": self.static_cache_policy.rules(),
+            },
+            "static_cache_bytes": self.static_cache_bytes,
+            "static_watch": self.static_watch,
+            "spa_prefix": self.spa_prefix,
+            "spa_index": self.spa_index,
+            "stats_path": self.stats_path,
+            "health_path": self.health_path,
+            "health_probe_timeout": format!("{:?}", self.health_probe_timeout),
+            "not_found": {
+                "default": self.not_found.default_mode().to_string(),
+                "rules": self
+                    .not_found
+                    .rules()
+                    .iter()
+                    .map(|(prefix, mode)| (prefix.clone(), mode.to_string()))
+                    .collect::<Vec<_>>(),
+            },
+            "fs_breaker": {
+                "failure_threshold": self.fs_breaker.failure_threshold,
+                "window": format!("{:?}", self.fs_breaker.window),
+                "cooldown": format!("{:?}", self.fs_breaker.cooldown),
+            },
+            "fs_slow_read": format!("{:?}", self.fs_slow_read),
+            "tls_cert": self.tls_cert,
+            "tls_key": self.tls_key,
+            "h2_max_concurrent_streams": self.h2_max_concurrent_streams,
+            "metrics_port": self.metrics_port,
+            "metrics_bind": self.metrics_bind,
+            "metrics_token": redact(&self.metrics_token),
+            "metrics_port_attempts": self.metrics_port_attempts,
+            "metrics_linger": format!("{:?}", self.metrics_linger),
+            "job_queue_wait_buckets": self.job_queue_wait_buckets,
+            "stuck_worker_threshold": format!("{:?}", self.stuck_worker_threshold),
+            "shutdown_timeout": format!("{:?}", self.shutdown_timeout),
+            "slow_request_threshold": format!("{:?}", self.slow_request_threshold),
+            "log_sample_rate": self.log_sample_rate,
+            "log_bodies": self.log_bodies,
+            "log_body_max_bytes": self.log_body_max_bytes,
+            "max_connections": self.max_connections,
+            "max_connections_mode": self.max_connections_mode.as_str(),
+            "max_connections_per_ip": self.max_connections_per_ip,
+            "trusted_proxies": self.trusted_proxies.networks().iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "access_control": self
+                .access_control
+                .rules()
+                .iter()
+                .map(|(prefix, networks)| (prefix.clone(), Value::from(networks.iter().map(ToString::to_string).collect::<Vec<_>>())))
+                .collect::<serde_json::Map<_, _>>(),
+            "accept_threads": self.accept_threads,
+            "listen_backlog": self.listen_backlog,
+            "accept_rate": self.accept_rate,
+            "accept_burst": self.accept_burst,
+            "accept_max_delay": format!("{:?}", self.accept_max_delay),
+            "admin_token": redact(&self.admin_token),
+            "maintenance": self.maintenance,
+            "maintenance_page": self.maintenance_page,
+            "maintenance_retry_after": self.maintenance_retry_after,
+            "compression": self.compression.iter().map(Encoding::as_str).collect::<Vec<_>>(),
+            "compression_min_bytes": self.compression_min_bytes,
+        })
+    }
+}
+
+fn redact(secret: &Option<String>) -> Option<&'static str> {
+    secret.as_ref().map(|_| "[redacted]")
+}
+
+/// NOT_FOUND sets the default mode (`page`, `json` or `redirect:<url>`),
+/// NOT_FOUND_RULES per-prefix overrides, e.g. `/api=json; /old=redirect:/`.
+fn not_found_policy() -> NotFoundPolicy {
+    let policy = NotFoundPolicy::new(env_or("NOT_FOUND", NotFoundMode::default()));
+    let rules = match env::var("NOT_FOUND_RULES") {
+        Ok(rules) => rules,
+        Err(_) => return policy,
+    };
+    let (policy, invalid) = policy.with_rules(&rules);
+    for entry in invalid {
+        warn!("Ignoring invalid NOT_FOUND_RULES entry {:?}", entry);
+    }
+    policy
+}
+
+/// TRUSTED_PROXIES as a comma-separated list of CIDR ranges and addresses.
+fn trusted_proxies() -> TrustedProxies {
+    let list = match env::var("TRUSTED_PROXIES") {
+        Ok(list) => list,
+        Err(_) => return TrustedProxies::default(),
+    };
+    let (proxies, invalid) = TrustedProxies::parse(&list);
+    for entry in invalid {
+        warn!("Ignoring invalid TRUSTED_PROXIES entry {:?}", entry);
+    }
+    proxies
+}
+
+/// PATH_ACL, e.g. `/admin=10.0.0.0/8,127.0.0.1; /internal=192.168.0.0/16`.
+fn access_control() -> AccessControl {
+    let rules = match env::var("PATH_ACL") {
+        Ok(rules) => rules,
+        Err(_) => return AccessControl::default(),
+    };
+    let (acl, invalid) = AccessControl::new().with_rules(&rules);
+    for entry in invalid {
+        warn!("Ignoring invalid PATH_ACL entry {:?}", entry);
+    }
+    acl
+}
+
+/// STATIC_MAX_AGE_SECS for the default lifetime, plus STATIC_CACHE_RULES as
+/// `pattern=value` pairs separated by `;`.
+fn static_cache_policy() -> CachePolicy {
+    let policy = CachePolicy::new(env_or("STATIC_MAX_AGE_SECS", 3600));
+    let rules = match env::var("STATIC_CACHE_RULES") {
+        Ok(rules) => rules,
+        Err(_) => return policy,
+    };
+    let (policy, invalid) = policy.with_rules(&rules);
+    for entry in invalid {
+        warn!("Ignoring invalid STATIC_CACHE_RULES entry {:?}", entry);
+    }
+    policy
+}
+
+/// Parses an environment variable, falling back to the default (with a
+/// warning) when it is set to something unparseable.
+pub(crate) fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                warn!("Ignoring invalid value {:?} for {}", value, name);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+/// Like [`env_or`], for counts the server can't run with none of, such as
+/// the worker count: zero is refused the same way as an unparseable value.
+fn env_nonzero(name: &str, default: usize) -> usize {
+    match env_or(name, default) {
+        0 => {
+            warn!("Ignoring {}=0: it must be at least 1, using {}", name, default);
+            default
+        }
+        value => value,
+    }
+}
+
+/// ROUTE_TIMEOUTS_MS, e.g. `/sleep=10000,/api/:id=200`: the registered
+/// path, then its deadline in milliseconds. Other entries are skipped with a
+/// warning.
+fn route_timeouts() -> Vec<(String, Duration)> {
+    let mut timeouts = Vec::new();
+    for entry in env_list("ROUTE_TIMEOUTS_MS").unwrap_or_default() {
+        match entry.split_once('=').map(|(path, ms)| (path.trim(), ms.trim().parse::<u64>())) {
+            Some((path, Ok(ms))) if path.starts_with('/') => timeouts.push((path.to_string(), Duration::from_millis(ms))),
+            _ => warn!("Ignoring invalid ROUTE_TIMEOUTS_MS entry {:?}", entry),
+        }
+    }
+    timeouts
+}
+
+/// PROXY_ROUTES, e.g. `/api=http://localhost:3000,/legacy/*=http://10.0.0.5:8080`.
+/// Each prefix becomes a route covering it and everything below it.
+fn proxy_routes() -> Vec<(String, Upstream)> {
+    let mut routes = Vec::new();
+    for entry in env_list("PROXY_ROUTES").unwrap_or_default() {
+        match entry.split_once('=').map(|(prefix, upstream)| (prefix.trim(), upstream.trim().parse::<Upstream>())) {
+            Some((prefix, Ok(upstream))) if prefix.starts_with('/') => {
+                let prefix = prefix.trim_end_matches('*').trim_end_matches('/');
+                routes.push((format!("{}/*", prefix), upstream));
+            }
+            Some((_, Err(e))) => warn!("Ignoring PROXY_ROUTES entry {:?}: {}", entry, e),
+            _ => warn!("Ignoring invalid PROXY_ROUTES entry {:?}", entry),
+        }
+    }
+    routes
+}
+
+/// JOB_QUEUE_WAIT_BUCKETS, e.g. `0.01,0.1,1`. Entries that aren't
+/// non-negative numbers are skipped with a warning; the bounds are sorted.
+fn job_queue_wait_buckets() -> Option<Vec<f64>> {
+    let mut buckets = Vec::new();
+    for entry in env_list("JOB_QUEUE_WAIT_BUCKETS")? {
+        match entry.parse::<f64>() {
+            Ok(bound) if bound.is_finite() && bound >= 0.0 => buckets.push(bound),
+            _ => warn!("Ignoring invalid JOB_QUEUE_WAIT_BUCKETS entry {:?}", entry),
+        }
+    }
+    buckets.sort_by(f64::total_cmp);
+    buckets.dedup();
+    Some(buckets)
+}
+
+/// Workers used when the CPU count can't be determined, and the least the
+/// default gives: a worker is held for a whole connection, so even a single
+/// CPU needs several to serve more than one client at a time.
+pub const FALLBACK_POOL_SIZE: usize = 4;
+
+/// One worker per CPU, but no fewer than [`FALLBACK_POOL_SIZE`]. Some
+/// sandboxes and containers hide the CPU count, in which case this warns
+/// (once) and uses `FALLBACK_POOL_SIZE`.
+pub fn default_pool_size() -> usize {
+    static DETECTED: OnceLock<usize> = OnceLock::new();
+    *DETECTED.get_or_init(|| pool_size_for(thread::available_parallelism()))
+}
+
+fn pool_size_for(parallelism: io::Result<NonZeroUsize>) -> usize {
+    match parallelism {
+        Ok(cpus) => cpus.get().max(FALLBACK_POOL_SIZE),
+        Err(e) => {
+            warn!(
+                "Couldn't determine the CPU count ({}), defaulting to {} workers; set POOL_SIZE to override",
+                e, FALLBACK_POOL_SIZE
+            );
+            FALLBACK_POOL_SIZE
+        }
+    }
+}
+
+/// COMPRESSION, e.g. `br,gzip`. Unknown encodings are skipped with a warning.
+fn compression() -> Vec<Encoding> {
+    let mut encodings = Vec::new();
+    for name in env_list("COMPRESSION").unwrap_or_default() {
+        match name.parse() {
+            Ok(encoding) if !encodings.contains(&encoding) => encodings.push(encoding),
+            Ok(_) => {}
+            Err(_) => warn!("Ignoring unknown COMPRESSION encoding {:?}", name),
+        }
+    }
+    encodings
+}
+
+/// Reads a comma-separated list, skipping empty entries.
+pub(crate) fn env_list(name: &str) -> Option<Vec<String>> {
+    let value = env::var(name).ok()?;
+    Some(
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_pool_sizes_fall_back_to_the_default() {
+        // Names no other test reads, since the environment is shared.
+        env::set_var("TEST_ENV_NONZERO_ZERO", "0");
+        env::set_var("TEST_ENV_NONZERO_SET", "3");
+        env::set_var("TEST_ENV_NONZERO_JUNK", "many");
+        assert_eq!(env_nonzero("TEST_ENV_NONZERO_ZERO", 8), 8);
+        assert_eq!(env_nonzero("TEST_ENV_NONZERO_SET", 8), 3);
+        assert_eq!(env_nonzero("TEST_ENV_NONZERO_JUNK", 8), 8);
+        assert_eq!(env_nonzero("TEST_ENV_NONZERO_UNSET", 8), 8);
+    }
+
+    #[test]
+    fn route_timeouts_are_parsed_in_milliseconds() {
+        // No other test reads ROUTE_TIMEOUTS_MS.
+        env::set_var("ROUTE_TIMEOUTS_MS", "/sleep=10000, /api/:id=200,nopath=5,/bad=soon,/blank=");
+        assert_eq!(
+            route_timeouts(),
+            vec![
+                ("/sleep".to_string(), Duration::from_secs(10)),
+                ("/api/:id".to_string(), Duration::from_millis(200)),
+            ]
+        );
+        env::remove_var("ROUTE_TIMEOUTS_MS");
+        assert!(route_timeouts().is_empty());
+    }
+}
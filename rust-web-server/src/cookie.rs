@@ -0,0 +1,296 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::query::percent_decode;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// A cookie to send to the client in a `Set-Cookie` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub max_age: Option<i64>,
+    pub http_only: bool,
+    pub secure: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            max_age: None,
+            http_only: false,
+            secure: false,
+            same_site: None,
+        }
+    }
+
+    pub fn path(mut self, path: &str) -> Cookie {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn max_age(mut self, seconds: i64) -> Cookie {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    pub fn http_only(mut self) -> Cookie {
+        self.http_only = true;
+        self
+    }
+
+    pub fn secure(mut self) -> Cookie {
+        self.secure = true;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+/// Serializes as a `Set-Cookie` value. Bytes the header can't carry in the
+/// name (anything but a token), the value (CTLs, whitespace, `"`, `,`, `;`,
+/// `\` and `%` itself) or the path (CTLs and `;`) are percent-encoded, so
+/// none of them can end the pair early, add attributes or break the header
+/// line; [`parse_cookies`] decodes them again.
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", encode(&self.name, is_token_byte), encode(&self.value, is_cookie_octet))?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", encode(path, is_path_byte))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age)?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$&'*+-.^_`|~".contains(&b)
+}
+
+/// `cookie-octet` (RFC 6265 section 4.1.1), less `%`, which marks escapes.
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e) && b != b'%'
+}
+
+/// `av-octet` for the `Path` attribute: any CHAR but CTLs and `;`. A `%`
+/// is left alone, since paths are percent-encoded already.
+fn is_path_byte(b: u8) -> bool {
+    (0x20..0x7f).contains(&b) && b != b';'
+}
+
+fn encode(s: &str, allowed: fn(u8) -> bool) -> Cow<'_, str> {
+    if s.bytes().all(allowed) {
+        return Cow::Borrowed(s);
+    }
+    let mut encoded = String::with_capacity(s.len() + 8);
+    for b in s.bytes() {
+        if allowed(b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    Cow::Owned(encoded)
+}
+
+/// Parses a `Cookie` request header into name/value pairs, decoding `%XX`
+/// escapes. Pairs without a `=` or with an empty name are skipped rather
+/// than failing the request.
+pub fn parse_cookies(header: &str) -> HashMap<String, String> {
+    header
+        .split(';')
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            let name = name.trim();
+            if name.is_empty() {
+                return None;
+            }
+            let value = value.trim().trim_matches('"');
+            Some((percent_decode(name, false), percent_decode(value, false)))
+        })
+        .collect()
+}
+
+/// Signs cookie values with HMAC-SHA256 so the server can detect tampering.
+/// Signed values look like `<value>.<hex signature>`.
+#[derive(Clone)]
+pub struct CookieSigner {
+    key: Vec<u8>,
+}
+
+impl fmt::Debug for CookieSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CookieSigner { key: <redacted> }")
+    }
+}
+
+impl CookieSigner {
+    pub fn new(secret: &[u8]) -> CookieSigner {
+        CookieSigner { key: secret.to_vec() }
+    }
+
+    /// Reads the key from `SESSION_SECRET`. There is deliberately no default
+    /// key: returns `None` when the variable is unset or empty.
+    pub fn from_env() -> Option<CookieSigner> {
+        match env::var("SESSION_SECRET") {
+            Ok(secret) if !secret.is_empty() => Some(CookieSigner::new(secret.as_bytes())),
+            _ => None,
+        }
+    }
+
+    fn mac(&self, value: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    pub fn sign(&self, value: &str) -> String {
+        let signature = self.mac(value).finalize().into_bytes();
+        let mut signed = String::with_capacity(value.len() + 1 + signature.len() * 2);
+        signed.push_str(value);
+        signed.push('.');
+        for byte in signature {
+            signed.push_str(&format!("{:02x}", byte));
+        }
+        signed
+    }
+
+    /// Returns the original value if the signature is valid. The comparison
+    /// is constant-time.
+    pub fn verify(&self, signed: &str) -> Option<String> {
+        let (value, signature) = signed.rsplit_once('.')?;
+        let signature = decode_hex(signature)?;
+        self.mac(value).verify_slice(&signature).ok()?;
+        Some(value.to_string())
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_several_cookies_and_skips_malformed_pairs() {
+        let cookies = parse_cookies("session=abc123; theme=\"dark\";flag; =orphan; empty=; lang = en ");
+        assert_eq!(cookies.len(), 4);
+        assert_eq!(cookies["session"], "abc123");
+        assert_eq!(cookies["theme"], "dark");
+        assert_eq!(cookies["empty"], "");
+        assert_eq!(cookies["lang"], "en");
+        assert!(parse_cookies("").is_empty());
+    }
+
+    #[test]
+    fn serializes_every_attribute() {
+        let cookie = Cookie::new("id", "42")
+            .path("/")
+            .max_age(3600)
+            .http_only()
+            .secure()
+            .same_site(SameSite::Lax);
+        assert_eq!(cookie.to_string(), "id=42; Path=/; Max-Age=3600; HttpOnly; Secure; SameSite=Lax");
+        assert_eq!(Cookie::new("a", "b").to_string(), "a=b");
+    }
+
+    #[test]
+    fn set_cookie_round_trips_through_the_parser() {
+        let sent = [
+            Cookie::new("session", "s3cr3t").http_only(),
+            Cookie::new("theme", "dark").path("/app"),
+            Cookie::new("note", "a; Domain=evil.example, b \"c\" \\ 100% caf\u{e9}"),
+            Cookie::new("split", "x\r\nSet-Cookie: evil=1"),
+            Cookie::new("odd name;", "=v="),
+        ];
+        // A browser echoes back only the name=value part of each Set-Cookie.
+        let header = sent
+            .iter()
+            .map(|cookie| cookie.to_string().split(';').next().unwrap().to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+        let parsed = parse_cookies(&header);
+        for cookie in &sent {
+            assert_eq!(parsed[&cookie.name], cookie.value);
+        }
+    }
+
+    #[test]
+    fn unsafe_bytes_cannot_add_attributes_or_break_the_header() {
+        let cookie = Cookie::new("a b", "1; HttpOnly\r\nX: y").path("/p; Secure\n").secure();
+        let serialized = cookie.to_string();
+        assert_eq!(serialized, "a%20b=1%3B%20HttpOnly%0D%0AX:%20y; Path=/p%3B Secure%0A; Secure");
+        assert!(serialized.bytes().all(|b| (0x20..0x7f).contains(&b)));
+        assert_eq!(serialized.matches(';').count(), 2);
+        // Values that are already fine are sent as they are.
+        assert_eq!(Cookie::new("id", "abc.123-_~").path("/a%20b").to_string(), "id=abc.123-_~; Path=/a%20b");
+    }
+
+    #[test]
+    fn response_helper_appends_one_header_per_cookie() {
+        let response = crate::Response::new(crate::StatusCode::Ok)
+            .set_cookie(&Cookie::new("a", "1"))
+            .set_cookie(&Cookie::new("b", "2").secure());
+        assert_eq!(response.headers.get_all("Set-Cookie").collect::<Vec<_>>(), ["a=1", "b=2; Secure"]);
+    }
+
+    #[test]
+    fn signed_values_verify_and_tampering_is_detected() {
+        let signer = CookieSigner::new(b"key");
+        let signed = signer.sign("user=7");
+        assert_eq!(signer.verify(&signed).as_deref(), Some("user=7"));
+        assert_eq!(signer.verify(&signed.replace("user=7", "user=8")), None);
+        assert_eq!(CookieSigner::new(b"other").verify(&signed), None);
+        assert_eq!(signer.verify("no-signature"), None);
+    }
+}
@@ -0,0 +1,182 @@
+//! Recovering the client's address behind a reverse proxy.
+//!
+//! A proxy's `peer_addr` is the proxy, with the client's address passed
+//! along in `Forwarded` (RFC 7239) or `X-Forwarded-For`. Those headers are
+//! only believed when the peer is a configured trusted proxy; anyone else
+//! could write whatever they like in them.
+
+use std::net::{IpAddr, SocketAddr};
+
+use ipnet::IpNet;
+
+use crate::headers::Headers;
+
+/// The networks whose forwarding headers are believed.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    pub fn new(networks: Vec<IpNet>) -> TrustedProxies {
+        TrustedProxies { networks }
+    }
+
+    /// Parses a comma-separated list of CIDR ranges and bare addresses,
+    /// e.g. `10.0.0.0/8, 192.168.1.5`. Malformed entries are returned so the
+    /// caller can report them.
+    pub fn parse(list: &str) -> (TrustedProxies, Vec<String>) {
+        let (networks, invalid) = parse_networks(list);
+        (TrustedProxies { networks }, invalid)
+    }
+
+    pub fn networks(&self) -> &[IpNet] {
+        &self.networks
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        self.networks.iter().any(|network| network.contains(&ip))
+    }
+
+    /// The address of the client behind `peer`. Unless `peer` is trusted,
+    /// that is `peer` itself. Otherwise the forwarded chain (`Forwarded` if
+    /// present, else `X-Forwarded-For`) is walked from the nearest hop
+    /// outwards, and the first address that isn't a trusted proxy is the
+    /// client; each proxy appends the address it heard from, so only the
+    /// hops added by trusted proxies can be relied on. A hop that isn't an
+    /// address (`unknown`, an obfuscated name) ends the walk at the last
+    /// address known good.
+    pub fn client_ip(&self, peer: IpAddr, headers: &Headers) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+        let chain: Vec<&str> = if headers.contains("Forwarded") {
+            headers.get_all("Forwarded").flat_map(forwarded_for).collect()
+        } else {
+            headers.get_all("X-Forwarded-For").flat_map(|value| value.split(',')).map(str::trim).collect()
+        };
+        let mut client = peer;
+        for hop in chain.iter().rev() {
+            match parse_hop(hop) {
+                Some(ip) => {
+                    client = ip;
+                    if !self.contains(ip) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// The `for=` values of one `Forwarded` header, in order.
+fn forwarded_for(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').filter_map(|element| {
+        element.split(';').find_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            name.trim().eq_ignore_ascii_case("for").then(|| value.trim().trim_matches('"'))
+        })
+    })
+}
+
+/// An address as proxies write it: bare, with a port, or bracketed IPv6.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let ip = hop
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| hop.strip_prefix('[')?.strip_suffix(']')?.parse().ok())?;
+    Some(canonical(ip))
+}
+
+/// Parses a comma-separated list of CIDR ranges and bare addresses, with
+/// the malformed entries returned alongside.
+pub(crate) fn parse_networks(list: &str) -> (Vec<IpNet>, Vec<String>) {
+    let mut networks = Vec::new();
+    let mut invalid = Vec::new();
+    for entry in list.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        match entry.parse::<IpNet>().or_else(|_| entry.parse::<IpAddr>().map(IpNet::from)) {
+            Ok(network) => networks.push(network),
+            Err(_) => invalid.push(entry.to_string()),
+        }
+    }
+    (networks, invalid)
+}
+
+/// IPv4 clients of a dual-stack listener show up as `::ffff:a.b.c.d`;
+/// compare and report them as the IPv4 addresses they are.
+pub(crate) fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Headers {
+        let mut headers = Headers::new();
+        for (name, value) in pairs {
+            headers.append(name, value);
+        }
+        headers
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn proxies() -> TrustedProxies {
+        let (proxies, invalid) = TrustedProxies::parse("10.0.0.0/8, 192.168.1.5, bogus");
+        assert_eq!(invalid, ["bogus"]);
+        proxies
+    }
+
+    #[test]
+    fn untrusted_peers_cannot_spoof_their_address() {
+        let spoofed = headers(&[("X-Forwarded-For", "1.2.3.4"), ("Forwarded", "for=5.6.7.8")]);
+        assert_eq!(proxies().client_ip(ip("203.0.113.9"), &spoofed), ip("203.0.113.9"));
+        assert_eq!(TrustedProxies::default().client_ip(ip("10.0.0.1"), &spoofed), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn a_trusted_proxy_passes_on_the_client() {
+        let forwarded = headers(&[("X-Forwarded-For", "198.51.100.7")]);
+        assert_eq!(proxies().client_ip(ip("10.1.2.3"), &forwarded), ip("198.51.100.7"));
+        assert_eq!(proxies().client_ip(ip("::ffff:192.168.1.5"), &forwarded), ip("198.51.100.7"));
+        // No header: the proxy is all there is to go on.
+        assert_eq!(proxies().client_ip(ip("10.1.2.3"), &Headers::new()), ip("10.1.2.3"));
+    }
+
+    #[test]
+    fn the_walk_stops_at_the_first_untrusted_hop() {
+        // The client made up the first entry; 203.0.113.9 is what the
+        // first trusted proxy saw.
+        let chain = headers(&[("X-Forwarded-For", "6.6.6.6, 203.0.113.9, 10.0.0.2")]);
+        assert_eq!(proxies().client_ip(ip("10.0.0.1"), &chain), ip("203.0.113.9"));
+
+        let unknown = headers(&[("X-Forwarded-For", "6.6.6.6, unknown, 10.0.0.2")]);
+        assert_eq!(proxies().client_ip(ip("10.0.0.1"), &unknown), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn forwarded_takes_precedence_and_accepts_ports_and_brackets() {
+        let both = headers(&[
+            ("X-Forwarded-For", "6.6.6.6"),
+            ("Forwarded", "for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2"),
+        ]);
+        assert_eq!(proxies().client_ip(ip("10.0.0.1"), &both), ip("2001:db8::1"));
+
+        let with_port = headers(&[("Forwarded", "for=198.51.100.7:8080")]);
+        assert_eq!(proxies().client_ip(ip("10.0.0.1"), &with_port), ip("198.51.100.7"));
+    }
+}
@@ -0,0 +1,201 @@
+use std::io::{self, Write};
+
+use tracing::warn;
+
+use crate::request::is_token;
+
+/// Headers that describe one connection rather than the message, so a proxy
+/// mustn't pass them on (RFC 9110 section 7.6.1), whether or not `Connection`
+/// lists them.
+pub const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// An ordered header list. Names compare case-insensitively and may repeat,
+/// so multi-value headers such as `Set-Cookie` keep their insertion order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers { entries: Vec::new() }
+    }
+
+    /// Sets a header, replacing any existing values for the name. The first
+    /// existing entry keeps its position so output order stays stable.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        match self.entries.iter().position(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            Some(index) => {
+                self.entries[index].1 = value.to_string();
+                let mut i = 0;
+                self.entries.retain(|(n, _)| {
+                    let keep = i <= index || !n.eq_ignore_ascii_case(name);
+                    i += 1;
+                    keep
+                });
+            }
+            None => self.append(name, value),
+        }
+    }
+
+    /// Adds a header after the existing entries without touching other values.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// The comma-separated tokens of every value of a list header such as
+    /// `Connection`, trimmed, with empty list elements skipped.
+    pub fn tokens<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+    }
+
+    /// Whether a list header has the token, compared case-insensitively.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.tokens(name).any(|t| t.eq_ignore_ascii_case(token))
+    }
+
+    /// Removes the [`HOP_BY_HOP`] headers and any others `Connection` names,
+    /// leaving the end-to-end ones a proxy would forward.
+    pub fn remove_hop_by_hop(&mut self) {
+        let listed: Vec<String> = self.tokens("Connection").map(str::to_string).collect();
+        self.entries.retain(|(n, _)| {
+            !HOP_BY_HOP.iter().any(|h| n.eq_ignore_ascii_case(h)) && !listed.iter().any(|l| n.eq_ignore_ascii_case(l))
+        });
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Serializes every entry as `Name: value\r\n` in insertion order.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.write_except(writer, &[])
+    }
+
+    /// Like [`Headers::write_to`], leaving out the names in `skip` (such as
+    /// framing headers the writer sets itself). An entry whose name isn't a
+    /// token, or whose value holds a CR, LF or NUL, is dropped with a
+    /// warning: written out it could end the head early or forge headers.
+    pub(crate) fn write_except<W: Write>(&self, writer: &mut W, skip: &[&str]) -> io::Result<()> {
+        for (name, value) in &self.entries {
+            if skip.iter().any(|s| name.eq_ignore_ascii_case(s)) {
+                continue;
+            }
+            if !is_token(name) || value.bytes().any(|b| matches!(b, b'\r' | b'\n' | b'\0')) {
+                warn!("Dropping header \"{}\" with an invalid name or value", name.escape_debug());
+                continue;
+            }
+            write!(writer, "{name}: {value}\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Headers {
+        let mut headers = Headers::new();
+        for (name, value) in pairs {
+            headers.append(name, value);
+        }
+        headers
+    }
+
+    #[test]
+    fn entries_are_written_in_insertion_order_except_skipped_names() {
+        let headers = headers(&[("Set-Cookie", "a=1"), ("Content-Length", "9"), ("X-Id", "7"), ("set-cookie", "b=2")]);
+        let mut written = Vec::new();
+        headers.write_except(&mut written, &["content-length"]).unwrap();
+        assert_eq!(written, b"Set-Cookie: a=1\r\nX-Id: 7\r\nset-cookie: b=2\r\n");
+    }
+
+    #[test]
+    fn entries_that_could_break_the_head_are_dropped() {
+        let headers = headers(&[
+            ("X-Split", "a\r\nSet-Cookie: evil=1"),
+            ("X-Lf", "a\nb"),
+            ("X-Nul", "a\0b"),
+            ("Bad Name", "x"),
+            ("X-Injected\r\nSet-Cookie", "x"),
+            ("", "x"),
+            ("X-Ok", "tab\tand \u{e9}"),
+        ]);
+        let mut written = Vec::new();
+        headers.write_to(&mut written).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), "X-Ok: tab\tand \u{e9}\r\n");
+    }
+
+    #[test]
+    fn tokens_span_every_value_of_a_list_header() {
+        let headers = headers(&[("Connection", "keep-alive, Upgrade"), ("connection", " ,X-Trace ,, close ")]);
+        let tokens: Vec<&str> = headers.tokens("Connection").collect();
+        assert_eq!(tokens, ["keep-alive", "Upgrade", "X-Trace", "close"]);
+        assert!(headers.has_token("Connection", "CLOSE"));
+        assert!(headers.has_token("CONNECTION", "upgrade"));
+        assert!(!headers.has_token("Connection", "clo"));
+        assert!(!headers.has_token("Upgrade", "close"));
+    }
+
+    #[test]
+    fn hop_by_hop_headers_and_the_ones_connection_lists_are_removed() {
+        let mut headers = headers(&[
+            ("Host", "a"),
+            ("Connection", "X-Trace, x-debug"),
+            ("Connection", "keep-alive"),
+            ("Keep-Alive", "timeout=5"),
+            ("X-Trace", "1"),
+            ("Transfer-Encoding", "chunked"),
+            ("upgrade", "websocket"),
+            ("X-DEBUG", "on"),
+            ("Accept", "*/*"),
+        ]);
+        headers.remove_hop_by_hop();
+        let left: Vec<(&str, &str)> = headers.iter().collect();
+        assert_eq!(left, [("Host", "a"), ("Accept", "*/*")]);
+    }
+}
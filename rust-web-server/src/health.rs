@@ -0,0 +1,39 @@
+//! The health check endpoint (`HEALTH_PATH`).
+//!
+//! By default it answers 200 whenever a worker can run its handler at all.
+//! With `HEALTH_PROBE_TIMEOUT_MS` set it also queues a no-op job on the
+//! worker pool and answers 503 if that isn't run in time: the process is up
+//! but a new connection would wait behind stuck or saturated workers. The
+//! probe queues behind everything already waiting and takes a worker of its
+//! own, so with a pool of one it can never succeed.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use metrics::counter;
+use serde_json::json;
+use tracing::warn;
+
+use crate::response::Response;
+use crate::stats::ServerStats;
+use crate::status::StatusCode;
+use crate::Context;
+
+/// A handler for the health check. A zero `probe_timeout` skips the probe.
+pub fn handler(stats: Arc<ServerStats>, probe_timeout: Duration) -> impl Fn(&mut Context) -> Response + Send + Sync + 'static {
+    move |_: &mut Context| {
+        let probed = (!probe_timeout.is_zero()).then(|| stats.probe_pool(probe_timeout)).flatten();
+        let response = match probed {
+            Some(false) => {
+                warn!("Health probe job wasn't run within {:?}", probe_timeout);
+                counter!("health_probe_failures_total", 1);
+                let mut response = Response::json(&json!({ "status": "unavailable", "workers": "unresponsive" }));
+                response.status = StatusCode::ServiceUnavailable;
+                response
+            }
+            Some(true) => Response::json(&json!({ "status": "ok", "workers": "ok" })),
+            None => Response::json(&json!({ "status": "ok" })),
+        };
+        response.with_header("Cache-Control", "no-store")
+    }
+}
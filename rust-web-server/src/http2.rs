@@ -0,0 +1,607 @@
+//! TLS termination with HTTP/2, behind the `http2` feature.
+//!
+//! ALPN decides the protocol for each connection: `h2` is served with the
+//! `h2` crate, while `http/1.1` (or a client that doesn't use ALPN) falls back
+//! to the ordinary HTTP/1.1 loop running over the TLS stream. Either way
+//! requests go through the same routing and handlers as plain connections.
+//!
+//! Current limitations of the HTTP/2 side:
+//!
+//! - Only GET and HEAD are supported; other methods are answered with 501
+//!   and request bodies are never read.
+//! - Handlers run on the connection's own runtime, a blocking thread per
+//!   in-flight stream. A client may open `h2_max_concurrent_streams` streams
+//!   at once, and as many handlers run at a time even if it resets streams
+//!   to open new ones.
+//! - Responses are sent once the handler returns. A streamed body (proxied,
+//!   or server-sent events) is then relayed as it is produced, as fast as
+//!   the client's flow-control window allows; there is no server push or
+//!   stream prioritisation.
+//! - A connection that opens no new stream for `keepalive_timeout` is sent a
+//!   GOAWAY; streams already in flight are allowed to finish.
+
+use std::fs::File;
+use std::future::poll_fn;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use uuid::Uuid;
+
+use crate::body_log;
+use crate::headers::Headers;
+use crate::request::{self, Method, Request};
+use crate::response::{BodyStream, Response};
+use crate::server::{count_response, dispatch, log_completion, write_failed, Deadline, Progress, serve_http1, ServerState};
+use crate::status::StatusCode;
+
+/// Loads a PEM certificate chain and private key, advertising `h2` and
+/// `http/1.1` over ALPN.
+pub(crate) fn load_tls_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let mut key = None;
+    for item in rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(bytes)
+            | rustls_pemfile::Item::RSAKey(bytes)
+            | rustls_pemfile::Item::ECKey(bytes) => {
+                key = Some(PrivateKey(bytes));
+                break;
+            }
+            _ => {}
+        }
+    }
+    let key = key.ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "no private key found"))?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Terminates TLS on an accepted connection and serves it with whichever
+/// protocol ALPN settled on.
+pub(crate) fn handle_tls_connection(
+    stream: TcpStream,
+    connection_id: Uuid,
+    accepted: Instant,
+    state: Arc<ServerState>,
+    tls: Arc<ServerConfig>,
+) {
+    let timeout = state.config.keepalive_timeout;
+    let _active = state.stats.connection_opened();
+    let remote_addr = stream.peer_addr().ok();
+    // Lets shutdown close the connection while it idles between requests.
+    let socket = stream.try_clone().ok();
+    let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!(connection_id = ?connection_id, "Failed to start connection runtime: {}", e);
+            return;
+        }
+    };
+
+    let acceptor = TlsAcceptor::from(tls);
+    let handshake = runtime.block_on(async {
+        stream.set_nonblocking(true)?;
+        let stream = tokio::net::TcpStream::from_std(stream)?;
+        match tokio::time::timeout(timeout, acceptor.accept(stream)).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::from(ErrorKind::TimedOut)),
+        }
+    });
+    let tls_stream = match handshake {
+        Ok(tls_stream) => tls_stream,
+        Err(e) => {
+            warn!(connection_id = ?connection_id, "TLS handshake failed: {}", e);
+            state.metrics.counter("tls_handshake_errors_total", 1, &[]);
+            return;
+        }
+    };
+
+    if tls_stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+        state.metrics.counter("connections_by_protocol_total", 1, &[("protocol", "h2")]);
+        runtime.block_on(serve_h2(tls_stream, connection_id, remote_addr, state));
+    } else {
+        state.metrics.counter("connections_by_protocol_total", 1, &[("protocol", "http/1.1")]);
+        // The socket itself never blocks; its read timeout is only where
+        // `wait_for_request` and `BlockingTls` agree on the current one.
+        if let Some(socket) = &socket {
+            let _ = socket.set_read_timeout(Some(state.config.read_timeout));
+        }
+        let io = BlockingTls {
+            stream: tls_stream,
+            runtime: &runtime,
+            read_timeout: state.config.read_timeout,
+            write_timeout: state.config.write_timeout,
+        };
+        let deadline = Deadline::after(accepted, state.config.connection_timeout);
+        let mut reader = BufReader::new(io);
+        serve_http1(&mut reader, connection_id, remote_addr, socket.as_ref(), deadline, &mut Progress::default(), &state);
+        let mut io = reader.into_inner();
+        // Sends close_notify; the client may already be gone.
+        let _ = runtime.block_on(io.stream.shutdown());
+    }
+}
+
+/// Blocking `Read`/`Write` over the async TLS stream, so the HTTP/1.1 code can
+/// run on it unchanged. Calls give up after the read or write timeout, like
+/// socket timeouts would; reads use the one set on the socket, so switching
+/// between idle and request timeouts works as it does without TLS.
+struct BlockingTls<'a> {
+    stream: TlsStream<tokio::net::TcpStream>,
+    runtime: &'a Runtime,
+    /// Used if the socket's timeout can't be read.
+    read_timeout: Duration,
+    write_timeout: Duration,
+}
+
+impl Read for BlockingTls<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = SockRef::from(self.stream.get_ref().0).read_timeout().ok().flatten().unwrap_or(self.read_timeout);
+        let stream = &mut self.stream;
+        self.runtime
+            .block_on(async move { tokio::time::timeout(timeout, stream.read(buf)).await })
+            .unwrap_or_else(|_| Err(io::Error::from(ErrorKind::TimedOut)))
+    }
+}
+
+impl Write for BlockingTls<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (stream, timeout) = (&mut self.stream, self.write_timeout);
+        self.runtime
+            .block_on(async move { tokio::time::timeout(timeout, stream.write(buf)).await })
+            .unwrap_or_else(|_| Err(io::Error::from(ErrorKind::TimedOut)))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let (stream, timeout) = (&mut self.stream, self.write_timeout);
+        self.runtime
+            .block_on(async move { tokio::time::timeout(timeout, stream.flush()).await })
+            .unwrap_or_else(|_| Err(io::Error::from(ErrorKind::TimedOut)))
+    }
+}
+
+async fn serve_h2<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    connection_id: Uuid,
+    remote_addr: Option<SocketAddr>,
+    state: Arc<ServerState>,
+) {
+    let max_streams = state.config.h2_max_concurrent_streams.max(1);
+    let mut connection = match h2::server::Builder::new().max_concurrent_streams(max_streams).handshake(stream).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!("HTTP/2 handshake failed: {}", e);
+            return;
+        }
+    };
+
+    // A reset stream closes at once, but its handler runs on; without this
+    // a client resetting and reopening streams could start any number.
+    let handlers = Arc::new(Semaphore::new(max_streams as usize));
+    let idle_timeout = state.config.keepalive_timeout;
+    let mut closing = false;
+    let mut streams = Vec::new();
+    loop {
+        let accepted = if closing {
+            connection.accept().await
+        } else {
+            match tokio::time::timeout(idle_timeout, connection.accept()).await {
+                Ok(accepted) => accepted,
+                Err(_) => {
+                    connection.graceful_shutdown();
+                    closing = true;
+                    continue;
+                }
+            }
+        };
+        match accepted {
+            Some(Ok((request, respond))) => {
+                streams.retain(|stream: &JoinHandle<()>| !stream.is_finished());
+                let stream = answer_stream(request, respond, connection_id, remote_addr, Arc::clone(&handlers), Arc::clone(&state));
+                streams.push(tokio::spawn(stream));
+            }
+            // Clients commonly hang up without a GOAWAY once they are done.
+            Some(Err(e)) if e.get_io().is_some_and(|io| io.kind() == ErrorKind::UnexpectedEof) => break,
+            Some(Err(e)) => {
+                warn!("HTTP/2 connection error: {}", e);
+                break;
+            }
+            None => break,
+        }
+    }
+    for stream in streams {
+        let _ = stream.await;
+    }
+}
+
+async fn answer_stream(
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    connection_id: Uuid,
+    remote_addr: Option<SocketAddr>,
+    handlers: Arc<Semaphore>,
+    state: Arc<ServerState>,
+) {
+    let request_id = Uuid::new_v4();
+    let start = Instant::now();
+    let mut request = match convert_request(&request) {
+        Some(request) => request,
+        None => {
+            let _ = send(&mut respond, &mut Response::new(StatusCode::NotImplemented));
+            state.metrics.counter("requests_total", 1, &[("status", "501"), ("path", "unsupported")]);
+            return;
+        }
+    };
+    // The h2 crate already refuses raw control characters in the path.
+    if request::has_encoded_control(&request.path) {
+        let _ = send(&mut respond, &mut Response::new(StatusCode::BadRequest));
+        state.metrics.counter("requests_total", 1, &[("status", "400"), ("path", "malformed")]);
+        return;
+    }
+    let (request, route, mut response, permit) = if request.method == Method::Get || request.method == Method::Head {
+        let dispatch_state = Arc::clone(&state);
+        let Ok(permit) = handlers.acquire_owned().await else { return };
+        // The permit is kept while a streamed body is produced, which is
+        // as much the handler's work as building the response.
+        let dispatched = tokio::task::spawn_blocking(move || {
+            let (route, response) = dispatch(&mut request, start, None, request_id, remote_addr, &dispatch_state);
+            (request, route, response, Some(permit))
+        })
+        .await;
+        match dispatched {
+            Ok(dispatched) => dispatched,
+            Err(e) => {
+                error!(request_id = ?request_id, "HTTP/2 handler task failed: {}", e);
+                let _ = send(&mut respond, &mut Response::new(StatusCode::InternalServerError));
+                return;
+            }
+        }
+    } else {
+        let response = Response::new(StatusCode::NotImplemented);
+        (request, "unsupported".to_string(), response, None)
+    };
+
+    count_response(&request, &response, &route, request_id, &state);
+    if state.config.log_bodies {
+        body_log::log_exchange(&request, &response, request_id, state.config.log_body_max_bytes);
+    }
+    let outgoing = match send(&mut respond, &mut response) {
+        Ok(outgoing) => outgoing,
+        Err(e) => {
+            error!(request_id = ?request_id, "Failed to write HTTP/2 response: {}", e);
+            state.metrics.counter("response_errors_total", 1, &[]);
+            return;
+        }
+    };
+    if let Some(chunks) = response.take_stream() {
+        let mut sent = 0;
+        let streamed = send_stream(outgoing, chunks, request.deadline, permit, &mut sent).await;
+        // Only known now; `count_response` saw an empty body.
+        state.metrics.counter("response_bytes_total", sent, &[("path", &route)]);
+        if let Err(e) = streamed {
+            write_failed(&e, request_id, &state);
+            return;
+        }
+    }
+    log_completion(&request, &response, route, start.elapsed(), request_id, connection_id, &state);
+}
+
+/// Builds a `Request` from an h2 request head, or `None` for a method the
+/// server doesn't know.
+fn convert_request(request: &http::Request<RecvStream>) -> Option<Request> {
+    let method = Method::parse(request.method().as_str())?;
+    let uri = request.uri();
+
+    let mut headers = Headers::new();
+    if let Some(authority) = uri.authority() {
+        headers.insert("Host", authority.as_str());
+    }
+    // HTTP/2 may split cookies across several fields; HTTP/1.1 code expects one.
+    let mut cookies = Vec::new();
+    for (name, value) in request.headers() {
+        let Ok(value) = value.to_str() else { continue };
+        if name == http::header::COOKIE {
+            cookies.push(value);
+        } else {
+            headers.append(name.as_str(), value);
+        }
+    }
+    if !cookies.is_empty() {
+        headers.insert("Cookie", &cookies.join("; "));
+    }
+
+    Some(Request {
+        method,
+        path: uri.path().to_string(),
+        query: uri.query().map(String::from),
+        version: "HTTP/2.0".to_string(),
+        headers,
+        deadline: None,
+        client_ip: None,
+        body: Vec::new(),
+    })
+}
+
+/// Headers that only mean something to an HTTP/1.x connection and are
+/// forbidden in HTTP/2.
+const CONNECTION_SPECIFIC: [&str; 6] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+fn send(respond: &mut SendResponse<Bytes>, response: &mut Response) -> Result<SendStream<Bytes>, h2::Error> {
+    let mut head = http::Response::builder().status(response.status.as_u16());
+    for (name, value) in response.headers.iter() {
+        if !CONNECTION_SPECIFIC.iter().any(|h| name.eq_ignore_ascii_case(h)) {
+            head = head.header(name, value);
+        }
+    }
+    // A streamed body's length isn't known; its last DATA frame ends it.
+    if !response.is_streamed() {
+        head = head.header("content-length", response.content_length());
+    }
+    let head = match head.body(()) {
+        Ok(head) => head,
+        Err(e) => {
+            error!("Handler produced a response HTTP/2 can't carry: {}", e);
+            *response = Response::new(StatusCode::InternalServerError);
+            http::Response::builder()
+                .status(500)
+                .header("content-length", 0)
+                .body(())
+                .expect("static response head is valid")
+        }
+    };
+
+    let body = mem::take(&mut response.body);
+    let streamed = response.is_streamed();
+    let mut stream = respond.send_response(head, body.is_empty() && !streamed)?;
+    if !body.is_empty() {
+        stream.send_data(Bytes::from(body), !streamed)?;
+    }
+    Ok(stream)
+}
+
+/// How many chunks of a streamed body may wait for the client at once.
+const STREAM_CHUNKS_BUFFERED: usize = 4;
+
+/// Sends a streamed body on `outgoing`, adding the bytes sent to `sent`.
+/// The chunks are produced on a blocking thread (a proxied or event-stream
+/// body blocks between them) and handed over through a small channel, so a
+/// client that stops reading holds the producer back. A chunk that fails to
+/// be produced, or `deadline` passing, resets the stream rather than ending
+/// it, so the client can't take a cut-off body for a whole one.
+async fn send_stream(
+    mut outgoing: SendStream<Bytes>,
+    chunks: BodyStream,
+    deadline: Option<Instant>,
+    permit: Option<OwnedSemaphorePermit>,
+    sent: &mut u64,
+) -> io::Result<()> {
+    let (sender, mut receiver) = mpsc::channel(STREAM_CHUNKS_BUFFERED);
+    let producer = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        chunks.write_unframed(&mut ChunkSender(sender), deadline, &mut 0)
+    });
+    while let Some(chunk) = receiver.recv().await {
+        // Dropping the receiver on failure stops the producer at its next chunk.
+        send_chunk(&mut outgoing, Bytes::from(chunk), sent).await?;
+    }
+    let produced = producer.await.unwrap_or_else(|e| Err(io::Error::other(e)));
+    if let Err(e) = produced {
+        outgoing.send_reset(h2::Reason::INTERNAL_ERROR);
+        return Err(e);
+    }
+    outgoing.send_data(Bytes::new(), true).map_err(io::Error::other)
+}
+
+/// Sends `data` as the client's flow-control window opens up.
+async fn send_chunk(outgoing: &mut SendStream<Bytes>, mut data: Bytes, sent: &mut u64) -> io::Result<()> {
+    while !data.is_empty() {
+        outgoing.reserve_capacity(data.len());
+        let granted = match poll_fn(|cx| outgoing.poll_capacity(cx)).await {
+            Some(granted) => granted.map_err(io::Error::other)?,
+            None => return Err(io::Error::from(ErrorKind::BrokenPipe)),
+        };
+        if granted == 0 {
+            continue;
+        }
+        let part = data.split_to(granted.min(data.len()));
+        let len = part.len() as u64;
+        outgoing.send_data(part, false).map_err(io::Error::other)?;
+        *sent += len;
+    }
+    Ok(())
+}
+
+/// Hands each write over to [`send_stream`] as one chunk.
+struct ChunkSender(mpsc::Sender<Vec<u8>>);
+
+impl Write for ChunkSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(buf.to_vec())
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use crate::{Config, Context, Server};
+
+    /// Handlers running and the most seen running at once.
+    #[derive(Default)]
+    struct Running {
+        now: AtomicUsize,
+        peak: Mutex<usize>,
+        started: AtomicUsize,
+    }
+
+    fn slow_server(running: Arc<Running>) -> Arc<ServerState> {
+        let config = Config {
+            h2_max_concurrent_streams: 2,
+            ..Config::default()
+        };
+        let mut server = Server::new(config);
+        server.metrics(crate::NoopMetrics);
+        server.register(Method::Get, "/slow", move |_: &mut Context| {
+            running.started.fetch_add(1, Ordering::SeqCst);
+            let now = running.now.fetch_add(1, Ordering::SeqCst) + 1;
+            let mut peak = running.peak.lock().unwrap();
+            *peak = (*peak).max(now);
+            drop(peak);
+            std::thread::sleep(Duration::from_millis(300));
+            running.now.fetch_sub(1, Ordering::SeqCst);
+            Response::new(StatusCode::Ok)
+        });
+        Arc::clone(&server.connection_handler().state)
+    }
+
+    /// Reads a whole response body, giving the window back as it goes.
+    async fn read_body(body: &mut RecvStream) -> Result<Vec<u8>, h2::Error> {
+        let mut all = Vec::new();
+        while let Some(data) = body.data().await {
+            let data = data?;
+            body.flow_control().release_capacity(data.len())?;
+            all.extend_from_slice(&data);
+        }
+        Ok(all)
+    }
+
+    #[test]
+    fn streamed_bodies_are_relayed_whole() {
+        // Bigger than the default 64 KiB window, so flow control is needed.
+        let chunk = vec![b'x'; 40 * 1024];
+        let mut server = Server::new(Config::default());
+        server.metrics(crate::NoopMetrics);
+        let chunks = chunk.clone();
+        server.register(Method::Get, "/stream", move |_: &mut Context| {
+            Response::new(StatusCode::Ok).with_stream(vec![chunks.clone(); 3].into_iter())
+        });
+        server.register(Method::Get, "/broken", |_: &mut Context| {
+            let chunks = vec![Ok(b"partial".to_vec()), Err(io::Error::other("upstream went away"))];
+            Response::new(StatusCode::Ok).with_fallible_stream(chunks.into_iter())
+        });
+        let state = Arc::clone(&server.connection_handler().state);
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap();
+        runtime.block_on(async move {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(serve_h2(server, Uuid::new_v4(), None, state));
+            let (requests, connection) = h2::client::handshake(client).await.unwrap();
+            tokio::spawn(connection);
+
+            let mut requests = requests.ready().await.unwrap();
+            let (response, _) = requests.send_request(get("/stream"), true).unwrap();
+            let response = response.await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert!(response.headers().get("content-length").is_none());
+            let body = read_body(&mut response.into_body()).await.unwrap();
+            assert_eq!(body, chunk.repeat(3));
+
+            let mut requests = requests.ready().await.unwrap();
+            let (response, _) = requests.send_request(get("/broken"), true).unwrap();
+            let response = response.await.unwrap();
+            let error = read_body(&mut response.into_body()).await.unwrap_err();
+            assert_eq!(error.reason(), Some(h2::Reason::INTERNAL_ERROR));
+        });
+    }
+
+    fn get(path: &str) -> http::Request<()> {
+        http::Request::get(format!("https://localhost{path}")).body(()).unwrap()
+    }
+
+    #[test]
+    fn streams_reset_by_the_client_still_count_until_their_handler_returns() {
+        let running = Arc::new(Running::default());
+        let state = slow_server(Arc::clone(&running));
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap();
+        runtime.block_on(async move {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let served = tokio::spawn(serve_h2(server, Uuid::new_v4(), None, state));
+            let (mut requests, connection) = h2::client::handshake(client).await.unwrap();
+            tokio::spawn(connection);
+
+            // Open a stream, give its handler time to start, and reset it,
+            // freeing the stream for the next one straight away.
+            for _ in 0..6 {
+                requests = requests.ready().await.unwrap();
+                let (_, mut stream) = requests.send_request(get("/slow"), true).unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                stream.send_reset(h2::Reason::CANCEL);
+            }
+            requests = requests.ready().await.unwrap();
+            let (response, _) = requests.send_request(get("/slow"), true).unwrap();
+            assert_eq!(response.await.unwrap().status(), 200);
+            drop(requests);
+            let _ = tokio::time::timeout(Duration::from_secs(10), served).await;
+        });
+        assert!(running.started.load(Ordering::SeqCst) >= 3, "the streams never reached their handlers");
+        assert_eq!(*running.peak.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn concurrent_streams_are_capped() {
+        let running = Arc::new(Running::default());
+        let state = slow_server(Arc::clone(&running));
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap();
+        runtime.block_on(async move {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(serve_h2(server, Uuid::new_v4(), None, state));
+            let (requests, mut connection) = h2::client::handshake(client).await.unwrap();
+            // Drive the connection by hand until the server's settings are in.
+            let (first, _) = requests.clone().ready().await.unwrap().send_request(get("/slow"), true).unwrap();
+            tokio::select! {
+                response = first => assert_eq!(response.unwrap().status(), 200),
+                result = &mut connection => panic!("connection ended: {result:?}"),
+            }
+            assert_eq!(connection.max_concurrent_send_streams(), 2);
+            tokio::spawn(connection);
+            let mut responses = Vec::new();
+            for _ in 0..6 {
+                let mut requests = requests.clone().ready().await.unwrap();
+                responses.push(requests.send_request(get("/slow"), true).unwrap().0);
+            }
+            for response in responses {
+                assert_eq!(response.await.unwrap().status(), 200);
+            }
+        });
+        assert_eq!(running.started.load(Ordering::SeqCst), 7);
+        assert_eq!(*running.peak.lock().unwrap(), 2);
+    }
+}
@@ -1,68 +1,717 @@
-use std::sync::mpsc;
+// `Config::redacted` lists every setting in one `json!` invocation.
+#![recursion_limit = "256"]
+
+pub mod acl;
+pub mod admin;
+mod body_log;
+pub mod cache_control;
+pub mod circuit_breaker;
+pub mod compression;
+pub mod config;
+pub mod cookie;
+pub mod date;
+pub mod forwarded;
+pub mod headers;
+pub mod health;
+#[cfg(feature = "http2")]
+mod http2;
+pub mod limits;
+pub mod maintenance;
+pub mod metrics_backend;
+pub mod mime;
+pub mod multipart;
+pub mod not_found;
+pub mod otlp;
+pub mod proxy;
+pub mod query;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod server;
+pub mod session;
+pub mod sse;
+pub mod static_cache;
+pub mod static_files;
+pub mod stats;
+pub mod status;
+pub mod trace_ids;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+pub use config::Config;
+pub use cookie::{Cookie, CookieSigner, SameSite};
+pub use headers::Headers;
+pub use metrics_backend::{Metrics, NoopMetrics};
+pub use request::{Method, Request};
+pub use response::Response;
+pub use router::{Router, TrailingSlash};
+pub use server::{bind, ConnectionHandler, Server, ShutdownHandle};
+pub use session::{MemorySessionStore, SessionData, SessionError, SessionStore, Sessions};
+pub use static_files::StaticFiles;
+pub use status::StatusCode;
+
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{mpsc, Barrier, Condvar, Mutex, PoisonError, Weak};
 use std::thread;
+use std::time::{Duration, Instant};
 use tracing::instrument;
-use tracing::info;
+use tracing::Span;
 use tracing::error;
-use metrics::counter;
+use tracing::info;
+use tracing::warn;
+use metrics::{counter, gauge, histogram};
+use uuid::Uuid;
+
+/// Everything a handler is given for one request.
+pub struct Context<'a> {
+    pub request: &'a Request,
+    /// Segments captured by `:name` parts of the matched route.
+    pub params: HashMap<String, String>,
+    /// The client's address, when the transport knows it.
+    pub remote_addr: Option<SocketAddr>,
+    pub request_id: Uuid,
+    /// Headers added to whatever response the handler returns, so wrappers
+    /// can contribute (a cookie, say) without building the response
+    /// themselves. Values are appended, never replacing the handler's own.
+    pub response_headers: Headers,
+    body: Option<&'a mut (dyn Read + 'a)>,
+}
+
+impl fmt::Debug for Context<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("request", &self.request)
+            .field("params", &self.params)
+            .field("remote_addr", &self.remote_addr)
+            .field("request_id", &self.request_id)
+            .field("response_headers", &self.response_headers)
+            .field("streaming_body", &self.body.is_some())
+            .finish()
+    }
+}
+
+impl<'a> Context<'a> {
+    pub fn new(request: &'a Request, request_id: Uuid) -> Context<'a> {
+        Context {
+            request,
+            params: HashMap::new(),
+            remote_addr: None,
+            request_id,
+            response_headers: Headers::new(),
+            body: None,
+        }
+    }
+
+    pub(crate) fn with_body(mut self, body: Option<&'a mut (dyn Read + 'a)>) -> Context<'a> {
+        self.body = body;
+        self
+    }
+
+    /// The body as it arrives, for routes registered with
+    /// [`Router::register_streaming`]; `None` elsewhere, where the body has
+    /// already been read into `request.body`. Whatever the handler leaves
+    /// unread is discarded by closing the connection.
+    pub fn body_reader(&mut self) -> Option<&mut (dyn Read + 'a)> {
+        self.body.as_deref_mut()
+    }
+
+    /// Splits a `multipart/form-data` body into its parts. On a streaming
+    /// route they are parsed straight off the connection, so a large upload
+    /// goes to temp files without ever being held whole; elsewhere they come
+    /// from the buffered `request.body`.
+    pub fn multipart(&mut self, limits: &multipart::MultipartLimits) -> Result<Vec<multipart::Part>, request::ParseError> {
+        let request = self.request;
+        match self.body.as_deref_mut() {
+            Some(body) => multipart::parse_stream(request.header("Content-Type"), body, limits),
+            None => request.multipart(limits),
+        }
+    }
+
+    /// A captured route parameter, e.g. `id` for `/users/:id`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// Applies `response_headers` to the handler's response.
+    pub(crate) fn finish(self, mut response: Response) -> Response {
+        for (name, value) in self.response_headers.iter() {
+            response.headers.append(name, value);
+        }
+        response
+    }
+}
+
+/// A failure a handler can return instead of building the error response
+/// itself. The server turns it into one: by default with
+/// [`HandlerError::into_response`], or with `Server::error_handler`.
+#[derive(Debug)]
+pub enum HandlerError {
+    NotFound,
+    /// The message is sent to the client as the body.
+    BadRequest(String),
+    Unauthorized,
+    /// Logged, but never shown to the client.
+    Internal(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl HandlerError {
+    pub fn internal(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> HandlerError {
+        HandlerError::Internal(error.into())
+    }
+
+    /// The default mapping: the 404 page, a plain-text 400, an empty 401
+    /// or an empty 500.
+    pub fn into_response(&self) -> Response {
+        match self {
+            HandlerError::NotFound => Response::from_file(StatusCode::NotFound, "404.html"),
+            HandlerError::BadRequest(message) => Response::new(StatusCode::BadRequest)
+                .with_header("Content-Type", "text/plain")
+                .with_body(message.as_str()),
+            HandlerError::Unauthorized => Response::new(StatusCode::Unauthorized),
+            HandlerError::Internal(_) => Response::new(StatusCode::InternalServerError),
+        }
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerError::NotFound => f.write_str("not found"),
+            HandlerError::BadRequest(message) => write!(f, "bad request: {}", message),
+            HandlerError::Unauthorized => f.write_str("unauthorized"),
+            HandlerError::Internal(e) => write!(f, "internal error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+/// Lets `request.json()?` and friends end a handler with a 400.
+impl From<request::ParseError> for HandlerError {
+    fn from(e: request::ParseError) -> Self {
+        HandlerError::BadRequest(e.to_string())
+    }
+}
+
+/// What a handler may return: a `Response`, or a `Result` whose error the
+/// server maps to a response.
+pub trait HandlerOutput {
+    fn into_result(self) -> Result<Response, HandlerError>;
+}
+
+impl HandlerOutput for Response {
+    fn into_result(self) -> Result<Response, HandlerError> {
+        Ok(self)
+    }
+}
+
+impl HandlerOutput for Result<Response, HandlerError> {
+    fn into_result(self) -> Result<Response, HandlerError> {
+        self
+    }
+}
+
+/// Why a startup hook refused to let the server start.
+pub type HookError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Setup run by `Server::run` before it accepts anything, in the order the
+/// hooks were registered. The first to fail stops the server from starting.
+pub type StartupHook = Box<dyn FnOnce() -> Result<(), HookError> + Send>;
+
+/// Teardown run by `Server::run` once the pool has drained, or once startup
+/// has failed, last registered first.
+pub type ShutdownHook = Box<dyn FnOnce() + Send>;
+
+/// What `ThreadPool::execute` does when the job queue is already full.
+///
+/// - `RejectNew` (the default) fails the new job straight away, so the caller
+///   can answer it (e.g. with a 503) while queued work keeps its place.
+/// - `Block` makes the caller wait for space. Nothing is dropped, but a
+///   blocked acceptor stops accepting and the kernel backlog fills up instead.
+/// - `DropOldest` evicts the job that has waited longest to make room. That
+///   favours fresh requests, whose clients are less likely to have given up,
+///   but the evicted job is discarded without running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueueFullPolicy {
+    #[default]
+    RejectNew,
+    Block,
+    DropOldest,
+}
+
+impl QueueFullPolicy {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            QueueFullPolicy::RejectNew => "reject-new",
+            QueueFullPolicy::Block => "block",
+            QueueFullPolicy::DropOldest => "drop-oldest",
+        }
+    }
+}
+
+impl FromStr for QueueFullPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject-new" | "reject" => Ok(QueueFullPolicy::RejectNew),
+            "block" => Ok(QueueFullPolicy::Block),
+            "drop-oldest" => Ok(QueueFullPolicy::DropOldest),
+            _ => Err(format!("unknown queue-full policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConfig {
+    pub size: usize,
+    pub queue_capacity: usize,
+    pub queue_full_policy: QueueFullPolicy,
+    /// Pin worker `n` to CPU core `n` (wrapping around the available cores).
+    pub pin_workers: bool,
+    /// How long dropping the pool waits for workers to finish. Workers still
+    /// busy after that are left running detached; zero waits indefinitely.
+    pub shutdown_timeout: Duration,
+    /// A worker running one job for longer than this is reported as stuck
+    /// by a monitor thread; zero disables the monitor.
+    pub stuck_threshold: Duration,
+}
+
+impl PoolConfig {
+    pub fn new(size: usize) -> PoolConfig {
+        PoolConfig {
+            size,
+            queue_capacity: 1024,
+            queue_full_policy: QueueFullPolicy::default(),
+            pin_workers: false,
+            shutdown_timeout: Duration::from_secs(30),
+            stuck_threshold: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+    QueueFull,
+    /// The pool has been closed and takes no new jobs.
+    ShuttingDown,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::QueueFull => f.write_str("job queue is full"),
+            ExecuteError::ShuttingDown => f.write_str("thread pool is shutting down"),
+        }
+    }
+}
+
+impl std::error::Error for ExecuteError {}
+
+/// Live pool occupancy, readable from any thread.
+#[derive(Debug)]
+pub struct PoolStats {
+    size: usize,
+    queued: AtomicUsize,
+    active: AtomicUsize,
+}
+
+impl PoolStats {
+    pub(crate) fn new(size: usize) -> PoolStats {
+        PoolStats {
+            size,
+            queued: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Workers currently running a job.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Fraction of workers busy, from 0.0 to 1.0.
+    pub fn utilization(&self) -> f64 {
+        self.active() as f64 / self.size as f64
+    }
+
+    fn set_queued(&self, queued: usize) {
+        self.queued.store(queued, Ordering::Relaxed);
+        gauge!("thread_pool_queue_depth", queued as f64);
+    }
+}
 
+/// A fixed set of worker threads taking jobs from one bounded queue.
+///
+/// `execute` may be called concurrently from any number of threads (the
+/// server's acceptors share one pool). Submitters and workers meet at a
+/// single mutex, held only to push or pop a job, never while one runs; the
+/// `concurrent_submitters` benchmark measures how that scales.
 #[derive(Debug)]
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    shared: Arc<Shared>,
+    shutdown_timeout: Duration,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+// Acceptor threads share the pool by reference, so losing `Sync` (say, to a
+// non-`Sync` sender) must fail to build rather than quietly changing design.
+const _: fn() = || {
+    fn shareable<T: Send + Sync>() {}
+    shareable::<ThreadPool>();
+};
+
+struct Queue {
+    jobs: VecDeque<Queued>,
+    closed: bool,
+}
+
+/// A job and when it was queued, so the worker that takes it can record
+/// how long it waited (`job_queue_wait_seconds`).
+struct Queued {
+    job: Job,
+    at: Instant,
+}
+
+impl Queue {
+    fn push(&mut self, job: Job) {
+        self.jobs.push_back(Queued { job, at: Instant::now() });
+    }
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    job_available: Condvar,
+    space_available: Condvar,
+    capacity: usize,
+    policy: QueueFullPolicy,
+    stats: Arc<PoolStats>,
+    heartbeats: Vec<Heartbeat>,
+    /// Reference point for the heartbeat timestamps.
+    started: Instant,
+}
+
+/// What a worker was last seen doing, in milliseconds since `Shared::started`.
+#[derive(Debug, Default)]
+struct Heartbeat {
+    /// When the worker last picked up or finished a job.
+    last_active: AtomicU64,
+    /// When the current job started, plus one so zero can mean idle.
+    busy_since: AtomicU64,
+}
+
+impl fmt::Debug for Shared {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Shared")
+            .field("capacity", &self.capacity)
+            .field("policy", &self.policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Shared {
+    fn elapsed_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+}
+
+/// Checks the worker heartbeats every half `threshold`, warning once per job
+/// about any worker that has been on it longer than `threshold` and keeping
+/// the `stuck_workers` gauge current. Only reads atomics, so it never holds
+/// up the workers. Exits once the pool is closed.
+fn spawn_monitor(shared: Weak<Shared>, threshold: Duration) {
+    let threshold_ms = threshold.as_millis() as u64;
+    let spawned = thread::Builder::new().name("pool-monitor".into()).spawn(move || {
+        // The job each worker was last reported for, by its start time.
+        let mut reported: Vec<u64> = Vec::new();
+        loop {
+            thread::sleep(threshold / 2);
+            let Some(shared) = shared.upgrade() else { break };
+            if shared.queue.lock().unwrap_or_else(PoisonError::into_inner).closed {
+                break;
+            }
+            reported.resize(shared.heartbeats.len(), 0);
+
+            let now = shared.elapsed_ms();
+            let mut stuck = 0;
+            for (id, heartbeat) in shared.heartbeats.iter().enumerate() {
+                let busy_since = heartbeat.busy_since.load(Ordering::Relaxed);
+                if busy_since == 0 || now.saturating_sub(busy_since - 1) < threshold_ms {
+                    continue;
+                }
+                stuck += 1;
+                if reported[id] != busy_since {
+                    reported[id] = busy_since;
+                    warn!("Worker {} has been running the same job for {}ms", id, now - (busy_since - 1));
+                }
+            }
+            gauge!("stuck_workers", stuck as f64);
+        }
+    });
+    if let Err(e) = spawned {
+        warn!("Failed to start pool monitor, stuck workers won't be reported: {}", e);
+    }
+}
+
 impl ThreadPool {
-    #[instrument]
     pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
-        info!("Creating thread pool with {} workers", size);
+        ThreadPool::with_config(PoolConfig::new(size))
+    }
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers = Vec::with_capacity(size);
+    #[instrument]
+    pub fn with_config(config: PoolConfig) -> ThreadPool {
+        assert!(config.size > 0, "a thread pool needs at least one worker");
+        assert!(config.queue_capacity > 0, "a thread pool needs room for at least one queued job");
+        info!(
+            "Creating thread pool with {} workers, queue capacity {} ({})",
+            config.size,
+            config.queue_capacity,
+            config.queue_full_policy.as_str()
+        );
+
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::with_capacity(config.queue_capacity),
+                closed: false,
+            }),
+            job_available: Condvar::new(),
+            space_available: Condvar::new(),
+            capacity: config.queue_capacity,
+            policy: config.queue_full_policy,
+            stats: Arc::new(PoolStats::new(config.size)),
+            heartbeats: (0..config.size).map(|_| Heartbeat::default()).collect(),
+            started: Instant::now(),
+        });
+        let mut workers = Vec::with_capacity(config.size);
+
+        let core_ids = if config.pin_workers {
+            let core_ids = core_affinity::get_core_ids().unwrap_or_default();
+            if core_ids.is_empty() {
+                warn!("CPU affinity is not supported here, workers will not be pinned");
+            }
+            core_ids
+        } else {
+            Vec::new()
+        };
 
-        for id in 0..size {
+        for id in 0..config.size {
             info!("Creating worker {}", id);
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            let core = if core_ids.is_empty() {
+                None
+            } else {
+                Some(core_ids[id % core_ids.len()])
+            };
+            workers.push(Worker::new(id, Arc::clone(&shared), core));
+        }
+
+        if !config.stuck_threshold.is_zero() {
+            spawn_monitor(Arc::downgrade(&shared), config.stuck_threshold);
         }
 
         ThreadPool {
             workers,
-            sender: Some(sender),
+            shared,
+            shutdown_timeout: config.shutdown_timeout,
         }
     }
 
-    #[instrument(skip(f))]
-    pub fn execute<F>(&self, f: F)
+    /// Queues a job. Fails with `ShuttingDown` once the pool has been closed,
+    /// including for a caller blocked on a full queue when that happens.
+    #[instrument(skip(self, f))]
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
-        if let Err(e) = self.sender.as_ref().unwrap().send(job) {
-            error!("Failed to send job to worker: {}", e);
-            counter!("job_send_errors_total", 1);
+        // Re-enter the caller's span on the worker so logs from offloaded
+        // work keep fields like request_id.
+        let span = Span::current();
+        let job = Box::new(move || span.in_scope(f));
+        let mut queue = self.shared.queue.lock().unwrap();
+
+        if queue.closed {
+            counter!("jobs_rejected_total", 1);
+            return Err(ExecuteError::ShuttingDown);
+        }
+
+        if queue.jobs.len() >= self.shared.capacity {
+            match self.shared.policy {
+                QueueFullPolicy::RejectNew => {
+                    warn!("Job queue full, rejecting new job");
+                    counter!("jobs_rejected_total", 1);
+                    return Err(ExecuteError::QueueFull);
+                }
+                QueueFullPolicy::Block => {
+                    counter!("job_queue_blocked_total", 1);
+                    while queue.jobs.len() >= self.shared.capacity && !queue.closed {
+                        queue = self.shared.space_available.wait(queue).unwrap();
+                    }
+                    if queue.closed {
+                        counter!("jobs_rejected_total", 1);
+                        return Err(ExecuteError::ShuttingDown);
+                    }
+                }
+                QueueFullPolicy::DropOldest => {
+                    warn!("Job queue full, dropping oldest job");
+                    counter!("jobs_dropped_total", 1);
+                    queue.jobs.pop_front();
+                }
+            }
+        }
+
+        queue.push(job);
+        self.shared.stats.set_queued(queue.jobs.len());
+        drop(queue);
+        self.shared.job_available.notify_one();
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Arc<PoolStats> {
+        Arc::clone(&self.shared.stats)
+    }
+
+    /// A handle for queueing jobs from anywhere, workers included, without
+    /// owning (and so on drop joining) the workers.
+    pub(crate) fn handle(&self) -> PoolHandle {
+        PoolHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Has every worker run one no-op job so its stack and thread-locals are
+    /// touched before real traffic arrives. Blocks until all are done.
+    pub fn warm_up(&self) {
+        let size = self.workers.len();
+        // Each job waits at the barrier, so no worker can pick up two of them.
+        let barrier = Arc::new(Barrier::new(size + 1));
+        {
+            let mut queue = self.shared.queue.lock().unwrap();
+            for _ in 0..size {
+                let barrier = Arc::clone(&barrier);
+                queue.push(Box::new(move || {
+                    std::hint::black_box([0u8; 64 * 1024]);
+                    barrier.wait();
+                }));
+            }
+            self.shared.stats.set_queued(queue.jobs.len());
+        }
+        self.shared.job_available.notify_all();
+        barrier.wait();
+        info!("All {} workers warmed up and ready", size);
+    }
+
+    /// Stops taking new jobs. Jobs already queued still run, and workers exit
+    /// once the queue is empty; dropping the pool waits for that.
+    pub fn close(&self) {
+        // Also called from drop, so a poisoned lock must not panic here.
+        self.shared.queue.lock().unwrap_or_else(PoisonError::into_inner).closed = true;
+        self.shared.job_available.notify_all();
+        self.shared.space_available.notify_all();
+    }
+}
+
+/// Queues jobs on a [`ThreadPool`] it doesn't own.
+#[derive(Debug, Clone)]
+pub(crate) struct PoolHandle {
+    shared: Arc<Shared>,
+}
+
+impl PoolHandle {
+    /// Queues `job(value)` if there is room right now, whatever the
+    /// queue-full policy, and hands `value` back otherwise (or once the pool
+    /// is closed). It never waits, so two pools handing work to each other
+    /// can't deadlock.
+    pub(crate) fn try_execute<T: Send + 'static>(&self, value: T, job: fn(T)) -> Result<(), T> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.closed || queue.jobs.len() >= self.shared.capacity {
+            return Err(value);
         }
+        let span = Span::current();
+        queue.push(Box::new(move || span.in_scope(|| job(value))));
+        self.shared.stats.set_queued(queue.jobs.len());
+        drop(queue);
+        self.shared.job_available.notify_one();
+        Ok(())
+    }
+
+    /// Whether a no-op job queued now gets run within `timeout`. False at
+    /// once if the queue is full or the pool closed.
+    pub(crate) fn probe(&self, timeout: Duration) -> bool {
+        let (done, finished) = mpsc::channel();
+        self.try_execute(done, |done: mpsc::Sender<()>| {
+            let _ = done.send(());
+        })
+        .is_ok()
+            && finished.recv_timeout(timeout).is_ok()
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
         info!("Shutting down thread pool");
-        drop(self.sender.take());
+        self.close();
 
+        let deadline = (!self.shutdown_timeout.is_zero()).then(|| Instant::now() + self.shutdown_timeout);
+        let mut stuck = Vec::new();
         for worker in &mut self.workers {
             info!("Shutting down worker {}", worker.id);
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+            let Some(thread) = worker.thread.take() else { continue };
+            if let Some(deadline) = deadline {
+                while !thread.is_finished() && Instant::now() < deadline {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                if !thread.is_finished() {
+                    // Dropping the handle detaches the thread; it dies with the process.
+                    stuck.push(worker.id);
+                    continue;
+                }
+            }
+            // A panicked worker is reported and the rest are still joined;
+            // unwrapping here would panic inside drop.
+            if let Err(payload) = thread.join() {
+                error!("Worker {} panicked: {}", worker.id, panic_message(payload.as_ref()));
+                counter!("worker_panics_total", 1);
             }
         }
+
+        if !stuck.is_empty() {
+            warn!(
+                "Gave up waiting for {} worker(s) after {:?}, detaching: {:?}",
+                stuck.len(),
+                self.shutdown_timeout,
+                stuck
+            );
+            counter!("workers_detached_total", stuck.len() as u64);
+        }
     }
 }
 
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload")
+}
+
 #[derive(Debug)]
 struct Worker {
     id: usize,
@@ -71,26 +720,359 @@ struct Worker {
 
 impl Worker {
     #[instrument]
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
+    fn new(id: usize, shared: Arc<Shared>, core: Option<core_affinity::CoreId>) -> Worker {
+        let thread = thread::spawn(move || {
+            if let Some(core) = core {
+                if core_affinity::set_for_current(core) {
+                    info!("Worker {id} pinned to core {}", core.id);
+                } else {
+                    warn!("Failed to pin worker {id} to core {}", core.id);
+                }
+            }
+            Worker::run(id, &shared);
+        });
 
-            match message {
-                Ok(job) => {
+        Worker {
+            id,
+            thread: Some(thread),
+        }
+    }
+
+    fn run(id: usize, shared: &Shared) {
+        loop {
+            // Workers finish whatever is still queued before honouring shutdown.
+            let job = {
+                let mut queue = shared.queue.lock().unwrap();
+                loop {
+                    if let Some(job) = queue.jobs.pop_front() {
+                        shared.stats.set_queued(queue.jobs.len());
+                        break Some(job);
+                    }
+                    if queue.closed {
+                        break None;
+                    }
+                    queue = shared.job_available.wait(queue).unwrap();
+                }
+            };
+
+            match job {
+                Some(Queued { job, at }) => {
+                    shared.space_available.notify_one();
+                    // Waits that keep growing mean the pool is too small for the load.
+                    histogram!("job_queue_wait_seconds", at.elapsed().as_secs_f64());
                     info!("Worker {id} processing job");
                     counter!("worker_jobs_total", 1, "worker_id" => id.to_string());
-                    job();
+                    let active = shared.stats.active.fetch_add(1, Ordering::Relaxed) + 1;
+                    gauge!("thread_pool_active_workers", active as f64);
+                    let heartbeat = &shared.heartbeats[id];
+                    let now = shared.elapsed_ms();
+                    heartbeat.last_active.store(now, Ordering::Relaxed);
+                    heartbeat.busy_since.store(now + 1, Ordering::Relaxed);
+                    // Handler panics are answered with a 500 where they happen;
+                    // this catches the rest, so a bug elsewhere costs one
+                    // connection rather than a worker.
+                    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                        error!("Worker {id} job panicked: {}", panic_message(payload.as_ref()));
+                        counter!("job_panics_total", 1);
+                    }
+                    heartbeat.busy_since.store(0, Ordering::Relaxed);
+                    heartbeat.last_active.store(shared.elapsed_ms(), Ordering::Relaxed);
+                    let active = shared.stats.active.fetch_sub(1, Ordering::Relaxed) - 1;
+                    gauge!("thread_pool_active_workers", active as f64);
                 }
-                Err(_) => {
+                None => {
                     info!("Worker {id} shutting down");
                     break;
                 }
             }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    /// Waits up to a few seconds for `condition`, which the workers update
+    /// after the job that caused it has returned.
+    fn eventually(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        condition()
+    }
+
+    /// A one-worker pool with room for two queued jobs, its worker held
+    /// busy until the returned sender is dropped.
+    fn saturated(policy: QueueFullPolicy) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::with_config(PoolConfig {
+            queue_capacity: 2,
+            queue_full_policy: policy,
+            ..PoolConfig::new(1)
         });
+        let (release, held) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = held.recv();
+        })
+        .unwrap();
+        running.recv_timeout(Duration::from_secs(5)).unwrap();
+        (pool, release)
+    }
 
-        Worker {
-            id,
-            thread: Some(thread),
+    /// Queues a job that reports `n` when it runs.
+    fn report(pool: &ThreadPool, ran: &mpsc::Sender<u32>, n: u32) -> Result<(), ExecuteError> {
+        let ran = ran.clone();
+        pool.execute(move || ran.send(n).unwrap())
+    }
+
+    fn ran(rx: &mpsc::Receiver<u32>, expected: usize) -> Vec<u32> {
+        (0..expected).map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap()).collect()
+    }
+
+    #[test]
+    fn reject_new_refuses_jobs_past_capacity() {
+        let (pool, release) = saturated(QueueFullPolicy::RejectNew);
+        let (tx, rx) = mpsc::channel();
+        report(&pool, &tx, 1).unwrap();
+        report(&pool, &tx, 2).unwrap();
+        assert!(matches!(report(&pool, &tx, 3), Err(ExecuteError::QueueFull)));
+        assert_eq!(pool.stats().queued(), 2);
+
+        drop(release);
+        assert_eq!(ran(&rx, 2), [1, 2]);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    /// Counts, per job, how often it ran and how often it was discarded
+    /// without running (refused, or dropped from the queue).
+    #[derive(Clone)]
+    struct Tally {
+        runs: Arc<Vec<AtomicUsize>>,
+        discarded: Arc<Vec<AtomicUsize>>,
+    }
+
+    impl Tally {
+        fn new(jobs: usize) -> Tally {
+            Tally {
+                runs: Arc::new((0..jobs).map(|_| AtomicUsize::new(0)).collect()),
+                discarded: Arc::new((0..jobs).map(|_| AtomicUsize::new(0)).collect()),
+            }
+        }
+
+        fn settled(&self) -> usize {
+            let sum = |counts: &[AtomicUsize]| counts.iter().map(|c| c.load(Ordering::Relaxed)).sum::<usize>();
+            sum(&self.runs) + sum(&self.discarded)
+        }
+    }
+
+    /// Carried by a job: records a run, or a discard if dropped unrun.
+    struct Ticket {
+        id: usize,
+        tally: Tally,
+        ran: bool,
+    }
+
+    impl Ticket {
+        fn run(mut self) {
+            self.ran = true;
+            self.tally.runs[self.id].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl Drop for Ticket {
+        fn drop(&mut self) {
+            if !self.ran {
+                self.tally.discarded[self.id].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_submitters_never_run_a_job_twice() {
+        const THREADS: usize = 8;
+        const PER_THREAD: usize = 2_000;
+        const JOBS: usize = THREADS * PER_THREAD;
+
+        for policy in [QueueFullPolicy::Block, QueueFullPolicy::RejectNew, QueueFullPolicy::DropOldest] {
+            let pool = ThreadPool::with_config(PoolConfig {
+                queue_capacity: 64,
+                queue_full_policy: policy,
+                ..PoolConfig::new(4)
+            });
+            let tally = Tally::new(JOBS);
+            let rejected = AtomicUsize::new(0);
+            thread::scope(|scope| {
+                for t in 0..THREADS {
+                    let (pool, tally, rejected) = (&pool, &tally, &rejected);
+                    scope.spawn(move || {
+                        for id in t * PER_THREAD..(t + 1) * PER_THREAD {
+                            let ticket = Ticket { id, tally: tally.clone(), ran: false };
+                            match pool.execute(move || ticket.run()) {
+                                Ok(()) => {}
+                                Err(ExecuteError::QueueFull) => {
+                                    rejected.fetch_add(1, Ordering::Relaxed);
+                                }
+                                Err(e) => panic!("{}: {e}", policy.as_str()),
+                            }
+                        }
+                    });
+                }
+            });
+            assert!(eventually(|| tally.settled() == JOBS), "{}: jobs went missing", policy.as_str());
+
+            for id in 0..JOBS {
+                let runs = tally.runs[id].load(Ordering::Relaxed);
+                let discarded = tally.discarded[id].load(Ordering::Relaxed);
+                assert_eq!(runs + discarded, 1, "{}: job {id} ran {runs} times", policy.as_str());
+            }
+            let discarded: usize = tally.discarded.iter().map(|c| c.load(Ordering::Relaxed)).sum();
+            match policy {
+                QueueFullPolicy::Block => assert_eq!(discarded, 0),
+                QueueFullPolicy::RejectNew => assert_eq!(discarded, rejected.load(Ordering::Relaxed)),
+                QueueFullPolicy::DropOldest => assert_eq!(rejected.load(Ordering::Relaxed), 0),
+            }
+        }
+    }
+
+    #[test]
+    fn drop_oldest_makes_room_by_discarding_the_front_job() {
+        let (pool, release) = saturated(QueueFullPolicy::DropOldest);
+        let (tx, rx) = mpsc::channel();
+        for n in 1..=4 {
+            report(&pool, &tx, n).unwrap();
         }
+        assert_eq!(pool.stats().queued(), 2);
+
+        drop(release);
+        assert_eq!(ran(&rx, 2), [3, 4]);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn block_waits_for_room_instead_of_failing() {
+        let (pool, release) = saturated(QueueFullPolicy::Block);
+        let pool = Arc::new(pool);
+        let (tx, rx) = mpsc::channel();
+        report(&pool, &tx, 1).unwrap();
+        report(&pool, &tx, 2).unwrap();
+
+        let (queued, queued_rx) = mpsc::channel();
+        let submitter = {
+            let (pool, tx) = (Arc::clone(&pool), tx.clone());
+            thread::spawn(move || {
+                let result = report(&pool, &tx, 3);
+                queued.send(()).unwrap();
+                result
+            })
+        };
+        // Still full, so the third submission is parked.
+        assert!(queued_rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        drop(release);
+        queued_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        submitter.join().unwrap().unwrap();
+        assert_eq!(ran(&rx, 3), [1, 2, 3]);
+    }
+
+    #[test]
+    fn execute_after_close_is_an_error_not_a_panic() {
+        let (pool, release) = saturated(QueueFullPolicy::RejectNew);
+        let (tx, rx) = mpsc::channel();
+        report(&pool, &tx, 1).unwrap();
+        pool.close();
+        assert!(matches!(report(&pool, &tx, 2), Err(ExecuteError::ShuttingDown)));
+
+        // What was queued before the close still runs.
+        drop(release);
+        assert_eq!(ran(&rx, 1), [1]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn blocked_submitters_are_released_by_shutdown() {
+        let (pool, release) = saturated(QueueFullPolicy::Block);
+        let pool = Arc::new(pool);
+        let (tx, _rx) = mpsc::channel();
+        report(&pool, &tx, 1).unwrap();
+        report(&pool, &tx, 2).unwrap();
+
+        let submitter = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || report(&pool, &tx, 3))
+        };
+        thread::sleep(Duration::from_millis(50));
+        pool.close();
+        assert!(matches!(submitter.join().unwrap(), Err(ExecuteError::ShuttingDown)));
+        drop(release);
+    }
+
+    #[test]
+    fn submissions_racing_shutdown_either_queue_or_fail_cleanly() {
+        let pool = Arc::new(ThreadPool::new(2));
+        let submitters: Vec<_> = (0..4)
+            .map(|_| {
+                let pool = Arc::clone(&pool);
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        match pool.execute(|| {}) {
+                            Ok(()) | Err(ExecuteError::ShuttingDown) => {}
+                            Err(e) => panic!("unexpected {e:?}"),
+                        }
+                    }
+                })
+            })
+            .collect();
+        pool.close();
+        for submitter in submitters {
+            submitter.join().expect("execute panicked during shutdown");
+        }
+        assert!(matches!(pool.execute(|| {}), Err(ExecuteError::ShuttingDown)));
+    }
+
+    /// A panic payload that panics again when dropped, which is after the
+    /// worker's `catch_unwind`, so it takes the worker thread down.
+    struct Grenade;
+
+    impl Drop for Grenade {
+        fn drop(&mut self) {
+            panic!("payload dropped");
+        }
+    }
+
+    #[test]
+    fn the_pool_drops_cleanly_after_a_worker_thread_dies() {
+        let pool = ThreadPool::new(2);
+        pool.execute(|| panic::panic_any(Grenade)).unwrap();
+        let worker_died = eventually(|| pool.workers.iter().any(|w| w.thread.as_ref().is_some_and(|t| t.is_finished())));
+        assert!(worker_died);
+
+        // The other worker still takes jobs.
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(()).unwrap()).unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Joining the dead worker reports it instead of panicking in drop.
+        drop(pool);
+    }
+
+    #[test]
+    fn a_panicking_job_does_not_cost_the_worker() {
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic!("job bug")).unwrap();
+
+        // Only one worker, so this runs only if it survived.
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(()).unwrap()).unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).expect("worker died with the job");
+
+        let stats = pool.stats();
+        assert!(eventually(|| stats.active() == 0));
+        assert_eq!(pool.shared.heartbeats[0].busy_since.load(Ordering::Relaxed), 0);
+    }
+}
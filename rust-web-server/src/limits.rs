@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Caps how many connections a single client IP may have in flight.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    max_per_ip: usize,
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnectionLimiter {
+    /// A `max_per_ip` of zero disables the cap.
+    pub fn new(max_per_ip: usize) -> ConnectionLimiter {
+        ConnectionLimiter {
+            max_per_ip,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Claims a slot for `ip`, or returns `None` if it is already at the cap.
+    /// The slot is released when the guard drops, including during a panic.
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(ip).or_insert(0);
+        if self.max_per_ip > 0 && *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard {
+            limiter: Arc::clone(self),
+            ip,
+        })
+    }
+
+    pub fn active(&self, ip: IpAddr) -> usize {
+        self.active.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut active = match self.limiter.active.lock() {
+            Ok(active) => active,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(count) = active.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// What happens to a connection that arrives while [`ConnectionCap`] is full.
+///
+/// - `Reject` (the default) accepts it and answers 503 straight away.
+/// - `Block` stops calling accept() until a connection finishes, so new ones
+///   wait in the kernel backlog and are served late rather than turned away.
+///   Once the backlog is full too, the kernel refuses or drops them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CapMode {
+    #[default]
+    Reject,
+    Block,
+}
+
+impl CapMode {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            CapMode::Reject => "reject",
+            CapMode::Block => "block",
+        }
+    }
+}
+
+impl FromStr for CapMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(CapMode::Reject),
+            "block" => Ok(CapMode::Block),
+            _ => Err(format!("unknown connection cap mode: {}", s)),
+        }
+    }
+}
+
+/// Caps how many connections are being served at once, across all clients.
+#[derive(Debug)]
+pub struct ConnectionCap {
+    max: usize,
+    active: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl ConnectionCap {
+    /// A `max` of zero disables the cap.
+    pub fn new(max: usize) -> ConnectionCap {
+        ConnectionCap {
+            max,
+            active: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Claims a slot, or returns `None` if all are taken. The slot is freed
+    /// when the guard drops.
+    pub fn try_acquire(self: &Arc<Self>) -> Option<CapGuard> {
+        let mut active = self.active.lock().unwrap();
+        if self.max > 0 && *active >= self.max {
+            return None;
+        }
+        *active += 1;
+        Some(CapGuard { cap: Arc::clone(self) })
+    }
+
+    /// Like [`ConnectionCap::try_acquire`], but waits up to `timeout` for a
+    /// slot to come free.
+    pub fn acquire_timeout(self: &Arc<Self>, timeout: Duration) -> Option<CapGuard> {
+        let active = self.active.lock().unwrap();
+        let (mut active, _) = self
+            .freed
+            .wait_timeout_while(active, timeout, |active| self.max > 0 && *active >= self.max)
+            .unwrap();
+        if self.max > 0 && *active >= self.max {
+            return None;
+        }
+        *active += 1;
+        Some(CapGuard { cap: Arc::clone(self) })
+    }
+
+    pub fn active(&self) -> usize {
+        *self.active.lock().unwrap()
+    }
+}
+
+#[derive(Debug)]
+pub struct CapGuard {
+    cap: Arc<ConnectionCap>,
+}
+
+impl Drop for CapGuard {
+    fn drop(&mut self) {
+        let mut active = match self.cap.active.lock() {
+            Ok(active) => active,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *active -= 1;
+        self.cap.freed.notify_one();
+    }
+}
+
+/// A token bucket capping how fast new connections are taken on: `rate`
+/// tokens a second, holding at most `burst`.
+#[derive(Debug)]
+pub struct AcceptRateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// What `AcceptRateLimiter::acquire` decided for a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Accepted,
+    /// Accepted after waiting this long for a token.
+    Delayed(Duration),
+    Rejected,
+}
+
+impl AcceptRateLimiter {
+    /// A `rate` of zero disables the limit. A `burst` of zero defaults to
+    /// `rate`, and it is never less than one.
+    pub fn new(rate: f64, burst: f64) -> AcceptRateLimiter {
+        let burst = if burst > 0.0 { burst } else { rate }.max(1.0);
+        AcceptRateLimiter {
+            rate,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token, sleeping up to `max_wait` for one to come free.
+    /// Connections that would have to wait longer are rejected.
+    pub fn acquire(&self, max_wait: Duration) -> Admission {
+        if self.rate <= 0.0 {
+            return Admission::Accepted;
+        }
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+            bucket.refilled = now;
+
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                return Admission::Accepted;
+            }
+            let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate);
+            if wait > max_wait {
+                return Admission::Rejected;
+            }
+            // Claim the token now so concurrent callers queue up behind it.
+            bucket.tokens -= 1.0;
+            wait
+        };
+        thread::sleep(wait);
+        Admission::Delayed(wait)
+    }
+}
@@ -1,44 +1,108 @@
 use std::{
-    fs,
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
-use tracing::{info, warn, error, instrument};
-use metrics::{counter, histogram};
+use tracing::{error, info, instrument, warn};
+use metrics::counter;
 use opentelemetry::global;
-use opentelemetry_sdk::{trace as sdktrace, Resource};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::{runtime, trace as sdktrace, Resource};
 use opentelemetry_otlp::WithExportConfig;
+use tracing_subscriber::fmt::format::JsonFields;
 use tracing_subscriber::prelude::*;
-use uuid::Uuid;
 
-use rust_web_server::ThreadPool;
+use rust_web_server::admin;
+use rust_web_server::circuit_breaker::BreakerConfig;
+use rust_web_server::date;
+use rust_web_server::health;
+use rust_web_server::otlp::{self, GuardedExporter};
+use rust_web_server::proxy;
+use rust_web_server::sse::{self, Event};
+use rust_web_server::static_cache::{self, FileCache};
+use rust_web_server::stats;
+use rust_web_server::trace_ids::TraceIds;
+use rust_web_server::{Config, Context, HandlerError, Method, Response, Server, StaticFiles, StatusCode};
 
-async fn init_telemetry() {
-    use std::net::SocketAddr;
+/// The Prometheus endpoint, kept running until the rest of the server has shut down.
+struct MetricsServer {
+    addr: SocketAddr,
+    shutdown: tokio::sync::oneshot::Sender<()>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+/// Binds the Prometheus endpoint on the first free port of
+/// `metrics_port_attempts` ports starting at `metrics_port`. If they are all
+/// taken, metrics are disabled with a warning rather than taking the whole
+/// service down. With `metrics_token` set, scrapes without it get a 401.
+fn start_metrics_server(config: &Config) -> Option<MetricsServer> {
+    use std::io::ErrorKind;
     use hyper::{Body, Response, Server};
     use hyper::service::{make_service_fn, service_fn};
     use std::convert::Infallible;
-    use std::sync::Arc;
 
-    // Initialize prometheus metrics endpoint binding to all interfaces
-    let addr: SocketAddr = ([0, 0, 0, 0], 9091).into();
-    
+    // Bind to the configured interface, moving on to the next port while they're in use
+    let (port, attempts) = (config.metrics_port, config.metrics_port_attempts);
+    let mut bound = None;
+    for candidate in (0..attempts.max(1)).filter_map(|offset| port.checked_add(offset)) {
+        let addr = SocketAddr::new(config.metrics_bind, candidate);
+        match TcpListener::bind(addr) {
+            Ok(listener) => {
+                bound = Some(listener);
+                break;
+            }
+            Err(e) if e.kind() == ErrorKind::AddrInUse => warn!("Metrics port {} is in use", candidate),
+            Err(e) => {
+                warn!("Failed to bind metrics port {}: {}", candidate, e);
+                break;
+            }
+        }
+    }
+    let Some(listener) = bound else {
+        warn!("No metrics port available, metrics endpoint disabled");
+        return None;
+    };
+    let server = match Server::from_tcp(listener) {
+        Ok(server) => server,
+        Err(e) => {
+            warn!("Failed to start metrics server, metrics endpoint disabled: {}", e);
+            return None;
+        }
+    };
+
     // Set up a recorder and wrap it in Arc for sharing
-    let recorder = Arc::new(
-        metrics_exporter_prometheus::PrometheusBuilder::new()
-            .install_recorder()
-            .expect("failed to install Prometheus recorder")
-    );
+    let mut builder = metrics_exporter_prometheus::PrometheusBuilder::new();
+    if !config.job_queue_wait_buckets.is_empty() {
+        let matcher = metrics_exporter_prometheus::Matcher::Full("job_queue_wait_seconds".to_string());
+        builder = builder
+            .set_buckets_for_metric(matcher, &config.job_queue_wait_buckets)
+            .expect("bucket list is non-empty");
+    }
+    let recorder = Arc::new(builder.install_recorder().expect("failed to install Prometheus recorder"));
 
     // Create a metrics service
+    let token: Option<Arc<str>> = config.metrics_token.as_deref().map(Arc::from);
     let make_svc = make_service_fn(move |_conn| {
         let recorder = Arc::clone(&recorder);
+        let token = token.clone();
         async move {
-            Ok::<_, Infallible>(service_fn(move |_req| {
+            Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
                 let recorder = Arc::clone(&recorder);
+                let authorized = token.as_deref().is_none_or(|token| {
+                    let authorization = req.headers().get(hyper::header::AUTHORIZATION);
+                    admin::bearer_matches(authorization.and_then(|value| value.to_str().ok()), token)
+                });
                 async move {
+                    if !authorized {
+                        warn!("Rejected metrics scrape without a valid token");
+                        counter!("metrics_auth_failures_total", 1);
+                        return Ok::<_, Infallible>(Response::builder()
+                            .status(401)
+                            .header("www-authenticate", "Bearer")
+                            .body(Body::empty())
+                            .unwrap());
+                    }
                     let metrics = recorder.render();
                     Ok::<_, Infallible>(Response::builder()
                         .header("content-type", "text/plain; version=0.0.4; charset=utf-8")
@@ -49,154 +113,227 @@ async fn init_telemetry() {
         }
     });
 
-    // Spawn the server in a separate task
-    tokio::spawn(async move {
-        let server = Server::bind(&addr).serve(make_svc);
+    let server = server.serve(make_svc);
+    let addr = server.local_addr();
+    info!("Metrics server listening on {}", addr);
+
+    // Spawn the server in a separate task, stopped through `shutdown`
+    let (shutdown, stopped) = tokio::sync::oneshot::channel::<()>();
+    let task = tokio::spawn(async move {
+        let server = server.with_graceful_shutdown(async {
+            let _ = stopped.await;
+        });
         if let Err(e) = server.await {
-            eprintln!("server error: {}", e);
+            warn!("Metrics server error: {}", e);
         }
     });
 
-    // Initialize OpenTelemetry OTLP exporter
-    let tracer = opentelemetry_otlp::new_pipeline()
-        .tracing()
-        .with_exporter(
-            opentelemetry_otlp::new_exporter()
-                .http()
-                .with_endpoint("http://localhost:4318")
-                .with_protocol(opentelemetry_otlp::Protocol::HttpBinary)
-        )
-        .with_trace_config(sdktrace::config().with_resource(
+    Some(MetricsServer { addr, shutdown, task })
+}
+
+fn init_telemetry() {
+    // Initialize OpenTelemetry OTLP exporter. An unreachable collector costs
+    // dropped traces, not memory or a stream of errors: see `otlp`.
+    otlp::install_error_handler();
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint("http://localhost:4318")
+        .with_protocol(opentelemetry_otlp::Protocol::HttpBinary)
+        .build_span_exporter()
+        .expect("failed to build OTLP span exporter");
+    let breaker = BreakerConfig {
+        failure_threshold: 3,
+        window: Duration::from_secs(60),
+        cooldown: Duration::from_secs(60),
+    };
+    // Spans ended while the queue is full are dropped.
+    let batch = sdktrace::BatchConfig::default()
+        .with_max_queue_size(2048)
+        .with_max_export_timeout(Duration::from_secs(10));
+    let processor = sdktrace::BatchSpanProcessor::builder(GuardedExporter::new(exporter, breaker), runtime::Tokio)
+        .with_batch_config(batch)
+        .build();
+    let provider = sdktrace::TracerProvider::builder()
+        .with_span_processor(processor)
+        .with_config(sdktrace::config().with_resource(
             Resource::new(vec![opentelemetry::KeyValue::new(
                 "service.name",
                 "rust-web-server",
             )])
         ))
-        .install_batch(opentelemetry_sdk::runtime::Tokio)
-        .expect("failed to initialize OpenTelemetry tracer");
+        .build();
+    let tracer = provider.tracer("rust-web-server");
+    let _ = global::set_tracer_provider(provider);
 
-    // Initialize tracing subscriber with OpenTelemetry
+    // Initialize tracing subscriber with OpenTelemetry. LOG_FORMAT=json swaps
+    // the human-readable output for one JSON object per line, with the
+    // fields of the enclosing spans included.
+    let json = std::env::var("LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("json"));
+    // Body logging is at debug level, so LOG_BODIES has to let it through.
+    let log_bodies = std::env::var("LOG_BODIES").is_ok_and(|enabled| enabled == "true");
+    let filter = if log_bodies { "info,rust_web_server::body_log=debug" } else { "info" };
     let telemetry = tracing_opentelemetry::layer().with_tracer(tracer);
     tracing_subscriber::registry()
         .with(telemetry)
-        .with(tracing_subscriber::EnvFilter::new("info"))
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::EnvFilter::new(filter))
+        .with((!json).then(|| {
+            tracing_subscriber::fmt::layer().event_format(TraceIds::text(tracing_subscriber::fmt::format()))
+        }))
+        .with(json.then(|| {
+            let format = tracing_subscriber::fmt::format().json().with_current_span(true).with_span_list(true);
+            tracing_subscriber::fmt::layer()
+                .fmt_fields(JsonFields::new())
+                .event_format(TraceIds::json(format))
+        }))
         .init();
 }
 
+/// Resolves on Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
 #[tokio::main]
 #[instrument]
 async fn main() {
-    init_telemetry().await;
-
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    info!("Server started on port 7878");
-    
-    let pool = ThreadPool::new(16);
-    counter!("thread_pool_size", 16);
-
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                counter!("connections_total", 1);
-                let request_id = Uuid::new_v4();
-                
-                info!(request_id = ?request_id, "New connection accepted");
-                
-                pool.execute(move || {
-                    handle_connection(stream, request_id);
-                });
-            }
-            Err(e) => {
-                error!("Failed to establish connection: {}", e);
-                counter!("connection_errors_total", 1);
+    let started = Instant::now();
+    init_telemetry();
+
+    let mut config = Config::from_env();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 7878));
+    let listener = rust_web_server::bind(addr, config.listen_backlog).unwrap();
+    info!("Server started on port 7878 (listen backlog {})", config.listen_backlog);
+
+    let metrics_server = start_metrics_server(&config);
+    // It may have had to move up from the configured port.
+    if let Some(metrics_server) = &metrics_server {
+        config.metrics_port = metrics_server.addr.port();
+    }
+    let static_root = config.static_root.clone();
+    let (fs_breaker, fs_slow_read) = (config.fs_breaker, config.fs_slow_read);
+    let (static_cache_bytes, static_watch) = (config.static_cache_bytes, config.static_watch);
+    let metrics_linger = config.metrics_linger;
+    let static_cache_policy = config.static_cache_policy.clone();
+    let spa = config.spa_prefix.clone().map(|prefix| (prefix, config.spa_index.clone()));
+    let admin = config.admin_token.clone().map(|token| (token, admin::config(&config)));
+    let test_sleep = config.enable_test_routes.then_some(config.test_sleep);
+    let stats_path = config.stats_path.clone();
+    let health = config.health_path.clone().map(|path| (path, config.health_probe_timeout));
+    let (proxy_routes, proxy_timeout) = (config.proxy_routes.clone(), config.proxy_timeout);
+
+    let mut server = Server::new(config);
+    server.register(Method::Get, "/", |_: &mut Context| {
+        Response::from_file(StatusCode::Ok, "hello.html")
+    });
+    if let Some(delay) = test_sleep {
+        server.register(Method::Get, "/sleep", move |context: &mut Context| {
+            if context.request.time_remaining().is_some_and(|remaining| remaining < delay) {
+                return Response::new(StatusCode::ServiceUnavailable);
             }
-        }
+            info!("Processing sleep request");
+            thread::sleep(delay);
+            Response::from_file(StatusCode::Ok, "hello.html")
+        });
+        server.register(Method::Get, "/events", |_: &mut Context| {
+            let (events, stream) = sse::channel();
+            thread::spawn(move || {
+                // Stops once the client has gone and the stream is dropped.
+                while events.send(Event::data(date::http_date(SystemTime::now()))).is_ok() {
+                    thread::sleep(Duration::from_secs(1));
+                }
+            });
+            sse::response(stream)
+        });
     }
 
-    info!("Shutting down server");
-    global::shutdown_tracer_provider();
-}
+    if let Some(path) = stats_path {
+        server.register(Method::Get, &path, stats::handler(server.stats(), started));
+    }
+    if let Some((path, probe_timeout)) = health {
+        server.register(Method::Get, &path, health::handler(server.stats(), probe_timeout));
+    }
 
-#[instrument(skip(stream))]
-fn handle_connection(mut stream: TcpStream, request_id: Uuid) {
-    let start = std::time::Instant::now();
-    
-    // Increment total connections counter
-    counter!("connections_total", 1);
-    
-    let buf_reader = BufReader::new(&mut stream);
-    
-    let request_line = match buf_reader.lines().next() {
-        Some(Ok(line)) => line,
-        Some(Err(e)) => {
-            error!(request_id = ?request_id, "Failed to read request: {}", e);
-            counter!("request_errors_total", 1);
-            counter!("requests_total", 1, "status" => "500", "path" => "error");
-            return;
-        }
-        None => {
-            warn!(request_id = ?request_id, "Empty request received");
-            counter!("request_errors_total", 1);
-            counter!("requests_total", 1, "status" => "400", "path" => "empty");
-            return;
+    for (route, upstream) in proxy_routes {
+        info!("Proxying {} to {}", route, upstream);
+        for method in [Method::Get, Method::Post] {
+            server.register(method, &route, proxy::handler(upstream.clone(), proxy_timeout));
         }
-    };
+    }
 
-    let (status_line, filename) = match &request_line[..] {
-        "GET / HTTP/1.1" => {
-            counter!("requests_total", 1, "path" => "root", "status" => "200");
-            counter!("requests_by_path", 1, "path" => "root");
-            ("HTTP/1.1 200 OK", "hello.html")
-        }
-        "GET /sleep HTTP/1.1" => {
-            info!(request_id = ?request_id, "Processing sleep request");
-            thread::sleep(Duration::from_secs(5));
-            counter!("requests_total", 1, "path" => "sleep", "status" => "200");
-            counter!("requests_by_path", 1, "path" => "sleep");
-            ("HTTP/1.1 200 OK", "hello.html")
-        }
-        _ => {
-            warn!(request_id = ?request_id, "Not found: {}", request_line);
-            counter!("requests_total", 1, "path" => "notfound", "status" => "404");
-            counter!("request_errors_total", 1);
-            ("HTTP/1.1 404 NOT FOUND", "404.html")
+    #[cfg(feature = "websocket")]
+    server.websocket("/ws", rust_web_server::websocket::echo);
+
+    match admin {
+        Some((token, config_handler)) => {
+            server.register(Method::Get, "/admin/config", admin::protect(&token, config_handler));
+            let maintenance = server.maintenance();
+            server.register(Method::Get, "/admin/maintenance", admin::protect(&token, admin::maintenance(maintenance.clone())));
+            server.register(Method::Post, "/admin/maintenance", admin::protect(&token, admin::maintenance(maintenance)));
+            // Registered last so the listing covers every route above.
+            let routes_handler = admin::routes(server.router());
+            server.register(Method::Get, "/admin/routes", admin::protect(&token, routes_handler));
         }
-    };
+        None => info!("ADMIN_TOKEN not set, admin endpoints disabled"),
+    }
 
-    let contents = match fs::read_to_string(filename) {
-        Ok(contents) => contents,
-        Err(e) => {
-            error!(request_id = ?request_id, "Failed to read file {}: {}", filename, e);
-            counter!("file_read_errors_total", 1);
-            return;
+    if let Some(root) = static_root {
+        let mut static_files =
+            StaticFiles::with_breaker(&root, fs_breaker, fs_slow_read).with_cache_policy(static_cache_policy);
+        if let Some((prefix, index)) = spa {
+            static_files = static_files.with_spa_fallback(&prefix, &index);
         }
-    };
+        if static_cache_bytes > 0 {
+            let cache = Arc::new(FileCache::new(static_cache_bytes));
+            // Without a watcher edits would never show up, so if one was asked
+            // for and can't start, serve uncached rather than stale.
+            let watching = !static_watch || match static_cache::watch(&root, Arc::clone(&cache)) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Static file watcher unavailable, serving uncached: {}", e);
+                    false
+                }
+            };
+            if watching {
+                static_files = static_files.with_cache(cache);
+            }
+        }
+        server.fallback(move |context: &mut Context| static_files.serve(context.request).ok_or(HandlerError::NotFound));
+    }
 
-    let length = contents.len();
-    let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        shutdown.shutdown();
+    });
 
-    if let Err(e) = stream.write_all(response.as_bytes()) {
-        error!(request_id = ?request_id, "Failed to write response: {}", e);
-        counter!("response_errors_total", 1);
-        return;
+    // Phases 1 and 2 (stop accepting, drain the pool) happen inside run().
+    if let Err(e) = server.run(listener) {
+        error!("Server failed to start: {}", e);
     }
 
-    if let Err(e) = stream.flush() {
-        error!(request_id = ?request_id, "Failed to flush response: {}", e);
+    info!("Shutdown phase 3: flushing telemetry");
+    global::shutdown_tracer_provider();
+    let Some(metrics_server) = metrics_server else {
+        info!("Shutdown complete");
         return;
+    };
+    if !metrics_linger.is_zero() {
+        info!("Keeping the metrics endpoint up {:?} for a final scrape", metrics_linger);
+        tokio::time::sleep(metrics_linger).await;
     }
 
-    let duration = start.elapsed();
-    let duration_secs = duration.as_secs_f64();
-    histogram!("request_duration_seconds", duration_secs);
-    histogram!("request_duration_by_path", duration_secs, "path" => filename);
-    
-    info!(
-        request_id = ?request_id,
-        path = request_line,
-        status = status_line,
-        duration = ?duration,
-        "Request completed"
-    );
-}
\ No newline at end of file
+    info!("Shutdown phase 4: stopping metrics server");
+    let _ = metrics_server.shutdown.send(());
+    let _ = metrics_server.task.await;
+    info!("Shutdown complete");
+}
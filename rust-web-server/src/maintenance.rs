@@ -0,0 +1,109 @@
+//! A switch that puts the server into maintenance mode at runtime: every
+//! route except the admin and health endpoints answers with a 503 until it
+//! is switched off again.
+
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::info;
+use metrics::{counter, gauge};
+
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// Where the admin endpoints live; always exempt, so operators can still
+/// switch maintenance off.
+const ADMIN_PREFIX: &str = "/admin";
+
+/// Cloneable handle to the maintenance flag; every clone sees the same state.
+#[derive(Debug, Clone)]
+pub struct Maintenance {
+    enabled: Arc<AtomicBool>,
+    /// Paths that keep working, each along with everything below it.
+    exempt: Arc<Vec<String>>,
+}
+
+impl Maintenance {
+    /// Exempts the admin endpoints and, if there is one, the health check
+    /// at `health_path`, so load balancers can still probe.
+    pub fn new(enabled: bool, health_path: Option<&str>) -> Maintenance {
+        let exempt = std::iter::once(ADMIN_PREFIX).chain(health_path).map(String::from).collect();
+        let maintenance = Maintenance {
+            enabled: Arc::default(),
+            exempt: Arc::new(exempt),
+        };
+        maintenance.set(enabled);
+        maintenance
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Takes effect from the next request on.
+    pub fn set(&self, enabled: bool) {
+        if self.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+            info!("Maintenance mode {}", if enabled { "enabled" } else { "disabled" });
+        }
+        gauge!("maintenance_mode", if enabled { 1.0 } else { 0.0 });
+    }
+
+    /// Whether a request for `path` should get the maintenance page.
+    pub(crate) fn applies_to(&self, path: &str) -> bool {
+        self.is_enabled() && !self.exempt.iter().any(|exempt| is_within(path, exempt))
+    }
+}
+
+/// Whether `path` is `prefix` or below it, by whole segments: `/health`
+/// covers `/health/ready` but not `/healthz`.
+fn is_within(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// The 503 sent while in maintenance: `page` if it can be read, otherwise a
+/// one-line plain-text notice.
+pub(crate) fn response(page: &Path, retry_after: u64) -> Response {
+    counter!("maintenance_responses_total", 1);
+    let response = match fs::read(page) {
+        Ok(contents) => Response::new(StatusCode::ServiceUnavailable)
+            .with_header("Content-Type", "text/html")
+            .with_body(contents),
+        Err(_) => Response::new(StatusCode::ServiceUnavailable)
+            .with_header("Content-Type", "text/plain")
+            .with_body("Down for maintenance, please try again later.\n"),
+    };
+    response.with_header("Retry-After", &retry_after.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_admin_and_health_paths_are_exempt() {
+        let maintenance = Maintenance::new(true, Some("/healthz"));
+        for path in ["/admin", "/admin/maintenance", "/healthz", "/healthz/ready"] {
+            assert!(!maintenance.applies_to(path), "{path}");
+        }
+        for path in ["/", "/administrator", "/admin-panel", "/health", "/healthzz", "/api/admin"] {
+            assert!(maintenance.applies_to(path), "{path}");
+        }
+    }
+
+    #[test]
+    fn without_a_health_path_only_admin_is_exempt() {
+        let maintenance = Maintenance::new(true, None);
+        assert!(!maintenance.applies_to("/admin/routes"));
+        assert!(maintenance.applies_to("/health"));
+    }
+
+    #[test]
+    fn nothing_applies_while_switched_off() {
+        let maintenance = Maintenance::new(false, Some("/health"));
+        assert!(!maintenance.applies_to("/"));
+        maintenance.clone().set(true);
+        assert!(maintenance.applies_to("/"));
+    }
+}
@@ -0,0 +1,56 @@
+//! Where the server's request-path metrics go.
+//!
+//! The server records through a [`Metrics`] value rather than calling the
+//! `metrics` crate's macros directly, so an embedder can swap in its own sink
+//! with `Server::metrics`, or [`NoopMetrics`] to record nothing. The default,
+//! [`GlobalRecorder`], forwards to whatever recorder the `metrics` crate has
+//! installed (the Prometheus exporter in the binary), which is what the
+//! macros do. The thread pool, shutdown and the helpers outside the request
+//! path (static files, sessions and so on) still use the macros.
+
+use metrics::{Key, Label};
+
+/// Label pairs attached to one measurement.
+pub type Labels<'a> = &'a [(&'static str, &'a str)];
+
+pub trait Metrics: Send + Sync {
+    /// Adds `value` to a counter.
+    fn counter(&self, name: &'static str, value: u64, labels: Labels);
+    /// Sets a gauge to `value`.
+    fn gauge(&self, name: &'static str, value: f64, labels: Labels);
+    /// Records one observation in a histogram.
+    fn histogram(&self, name: &'static str, value: f64, labels: Labels);
+}
+
+/// Records through the `metrics` crate's global recorder.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GlobalRecorder;
+
+fn key(name: &'static str, labels: Labels) -> Key {
+    let labels: Vec<Label> = labels.iter().map(|(k, v)| Label::new(*k, v.to_string())).collect();
+    Key::from_parts(name, labels)
+}
+
+impl Metrics for GlobalRecorder {
+    fn counter(&self, name: &'static str, value: u64, labels: Labels) {
+        metrics::recorder().register_counter(&key(name, labels)).increment(value);
+    }
+
+    fn gauge(&self, name: &'static str, value: f64, labels: Labels) {
+        metrics::recorder().register_gauge(&key(name, labels)).set(value);
+    }
+
+    fn histogram(&self, name: &'static str, value: f64, labels: Labels) {
+        metrics::recorder().register_histogram(&key(name, labels)).record(value);
+    }
+}
+
+/// Discards everything, for tests and embedders that don't collect metrics.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {
+    fn counter(&self, _: &'static str, _: u64, _: Labels) {}
+    fn gauge(&self, _: &'static str, _: f64, _: Labels) {}
+    fn histogram(&self, _: &'static str, _: f64, _: Labels) {}
+}
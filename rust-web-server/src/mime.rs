@@ -0,0 +1,148 @@
+use std::path::Path;
+
+/// Guesses a `Content-Type` from a file's extension.
+pub fn content_type_for(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js") | Some("mjs") => "text/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Adds `; charset=<charset>` to text types (`text/*` and JSON) that don't
+/// declare one. Returns `None` when the type should be left as it is.
+pub fn with_charset(content_type: &str, charset: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let essence = params.next().unwrap_or("").trim();
+    let textual = essence
+        .get(..5)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("text/"))
+        || essence.eq_ignore_ascii_case("application/json");
+    let declared = params.any(|param| {
+        param
+            .trim()
+            .get(..8)
+            .is_some_and(|name| name.eq_ignore_ascii_case("charset="))
+    });
+    (textual && !declared).then(|| format!("{}; charset={}", content_type.trim_end(), charset))
+}
+
+/// Picks the offered media type the client prefers, going by the q-values in
+/// its `Accept` header. Each offer is weighed by the most specific range that
+/// covers it (`text/html` over `text/*` over `*/*`); ties go to the earlier
+/// offer. Returns `None` when nothing offered is acceptable.
+pub fn negotiate<'a>(accept: &str, offers: &[&'a str]) -> Option<&'a str> {
+    let ranges: Vec<(&str, &str, f32)> = accept
+        .split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let (kind, subtype) = params.next()?.trim().split_once('/')?;
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((kind.trim(), subtype.trim(), q))
+        })
+        .collect();
+
+    let mut best: Option<(&str, f32)> = None;
+    for &offer in offers {
+        let essence = offer.split(';').next().unwrap_or("").trim();
+        let Some((kind, subtype)) = essence.split_once('/') else { continue };
+        let weight = ranges
+            .iter()
+            .filter_map(|&(range_kind, range_subtype, q)| {
+                let specificity = if range_kind == "*" && range_subtype == "*" {
+                    1
+                } else if range_kind.eq_ignore_ascii_case(kind) && range_subtype == "*" {
+                    2
+                } else if range_kind.eq_ignore_ascii_case(kind) && range_subtype.eq_ignore_ascii_case(subtype) {
+                    3
+                } else {
+                    return None;
+                };
+                Some((specificity, q))
+            })
+            .max_by_key(|&(specificity, _)| specificity)
+            .map(|(_, q)| q);
+        if let Some(q) = weight {
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((offer, q));
+            }
+        }
+    }
+    best.map(|(offer, _)| offer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn charset_is_added_to_text_types_only() {
+        assert_eq!(with_charset("text/html", "utf-8").as_deref(), Some("text/html; charset=utf-8"));
+        assert_eq!(with_charset("TEXT/Plain", "utf-8").as_deref(), Some("TEXT/Plain; charset=utf-8"));
+        assert_eq!(with_charset("application/json", "utf-8").as_deref(), Some("application/json; charset=utf-8"));
+        assert_eq!(
+            with_charset("text/csv; header=present", "utf-8").as_deref(),
+            Some("text/csv; header=present; charset=utf-8")
+        );
+        for content_type in ["image/png", "application/octet-stream", "application/javascript", "font/woff2", "textual/x", "text"] {
+            assert_eq!(with_charset(content_type, "utf-8"), None, "{content_type}");
+        }
+    }
+
+    #[test]
+    fn a_declared_charset_is_left_alone() {
+        assert_eq!(with_charset("text/html; charset=iso-8859-1", "utf-8"), None);
+        assert_eq!(with_charset("text/html;CHARSET=utf-8", "utf-8"), None);
+    }
+
+    const OFFERS: &[&str] = &["text/html", "application/json"];
+
+    #[test]
+    fn the_highest_q_value_wins() {
+        assert_eq!(negotiate("text/html;q=0.5, application/json", OFFERS), Some("application/json"));
+        assert_eq!(negotiate("application/json;q=0.2,text/html;q=0.9", OFFERS), Some("text/html"));
+        assert_eq!(negotiate("application/json; q=0.8, */*; q=0.1", OFFERS), Some("application/json"));
+    }
+
+    #[test]
+    fn the_most_specific_range_sets_an_offers_weight() {
+        // text/html is excluded outright even though text/* allows it.
+        assert_eq!(negotiate("text/*;q=0.9, text/html;q=0", &["text/html", "text/plain"]), Some("text/plain"));
+        assert_eq!(negotiate("*/*;q=0.1, application/*;q=0.7", OFFERS), Some("application/json"));
+    }
+
+    #[test]
+    fn equal_weights_go_to_the_first_offer() {
+        assert_eq!(negotiate("*/*", OFFERS), Some("text/html"));
+        assert_eq!(negotiate("application/json, text/html", OFFERS), Some("text/html"));
+    }
+
+    #[test]
+    fn nothing_acceptable_is_none() {
+        assert_eq!(negotiate("image/png", OFFERS), None);
+        assert_eq!(negotiate("text/html;q=0, application/json;q=0.0", OFFERS), None);
+        assert_eq!(negotiate("garbage", OFFERS), None);
+    }
+}
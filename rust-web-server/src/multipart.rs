@@ -0,0 +1,537 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use metrics::counter;
+use uuid::Uuid;
+
+use crate::headers::Headers;
+use crate::request::{ParseError, Request};
+
+/// Size limits for `multipart/form-data` bodies. Once a part grows past
+/// `spill_threshold` it goes on to a temp file as it is read instead of
+/// being kept in memory. That only saves memory when the parts are parsed
+/// from the connection ([`parse_stream`], `Context::multipart` on a
+/// streaming route); a buffered `request.body` is already all in memory.
+#[derive(Debug, Clone, Copy)]
+pub struct MultipartLimits {
+    pub max_part_bytes: usize,
+    /// Part headers and data together, across all parts.
+    pub max_total_bytes: usize,
+    pub spill_threshold: usize,
+    /// Parts accepted in one body; past that it is refused with a 400.
+    pub max_parts: usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        MultipartLimits {
+            max_part_bytes: 512 * 1024,
+            max_total_bytes: 1024 * 1024,
+            spill_threshold: 64 * 1024,
+            max_parts: 100,
+        }
+    }
+}
+
+/// A temp file removed again when dropped.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Creates an empty temp file, returning it with a handle to write to.
+    /// The temp dir is shared, so the file must be new (never a file or
+    /// symlink planted at the name) and, on Unix, readable by the owner only.
+    fn create() -> io::Result<(TempFile, File)> {
+        let path = env::temp_dir().join(format!("upload-{}", Uuid::new_v4()));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(&path)?;
+        Ok((TempFile { path }, file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[derive(Debug)]
+pub enum PartData {
+    Memory(Vec<u8>),
+    File(TempFile),
+}
+
+#[derive(Debug)]
+pub struct Part {
+    pub headers: Headers,
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub len: usize,
+    pub data: PartData,
+}
+
+impl Part {
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type")
+    }
+
+    /// Returns the part's contents, reading them back from disk if spilled.
+    pub fn bytes(&self) -> io::Result<Vec<u8>> {
+        match &self.data {
+            PartData::Memory(bytes) => Ok(bytes.clone()),
+            PartData::File(file) => fs::read(file.path()),
+        }
+    }
+}
+
+/// Extracts the `boundary` parameter from a multipart `Content-Type`.
+fn boundary(content_type: &str) -> Option<&str> {
+    let mut params = content_type.split(';');
+    let media_type = params.next()?.trim();
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim_matches('"'))
+        .filter(|value| !value.is_empty())
+}
+
+/// Reads a `name="value"` parameter out of a `Content-Disposition` header.
+fn disposition_param(disposition: &str, param: &str) -> Option<String> {
+    disposition
+        .split(';')
+        .skip(1)
+        .filter_map(|p| p.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case(param))
+        .map(|(_, value)| value.trim_matches('"').to_string())
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|index| index + from)
+}
+
+fn invalid(reason: &str) -> ParseError {
+    ParseError::InvalidBody(format!("multipart: {}", reason))
+}
+
+/// How much of the body is read at a time.
+const READ_BYTES: usize = 8 * 1024;
+
+/// Longest run of part headers accepted.
+const MAX_PART_HEADER_BYTES: usize = 16 * 1024;
+
+/// The unparsed bytes read so far, topped up from the body on demand.
+struct Scanner<R> {
+    body: R,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> Scanner<R> {
+    /// Reads more of the body onto the end of the buffer. False at the end.
+    fn fill(&mut self) -> io::Result<bool> {
+        let len = self.buf.len();
+        self.buf.resize(len + READ_BYTES, 0);
+        let read = loop {
+            match self.body.read(&mut self.buf[len..]) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                read => break read,
+            }
+        };
+        self.buf.truncate(len + *read.as_ref().unwrap_or(&0));
+        Ok(read? > 0)
+    }
+
+    /// Fills until `needle` turns up, giving up once more than `limit`
+    /// bytes are buffered without it.
+    fn find(&mut self, needle: &[u8], limit: usize) -> Result<Option<usize>, ParseError> {
+        loop {
+            if let Some(index) = find(&self.buf, needle, 0) {
+                return Ok(Some(index));
+            }
+            if self.buf.len() > limit || !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Fills until at least `len` bytes are buffered, or the body ends.
+    fn want(&mut self, len: usize) -> io::Result<()> {
+        while self.buf.len() < len && self.fill()? {}
+        Ok(())
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.buf.drain(..len);
+    }
+}
+
+/// A part's contents as they are read: in memory up to the spill
+/// threshold, then in a temp file.
+struct PartWriter {
+    len: usize,
+    memory: Vec<u8>,
+    file: Option<(TempFile, BufWriter<File>)>,
+}
+
+impl PartWriter {
+    fn new() -> PartWriter {
+        PartWriter {
+            len: 0,
+            memory: Vec::new(),
+            file: None,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8], total: &mut usize, limits: &MultipartLimits) -> Result<(), ParseError> {
+        self.len += bytes.len();
+        if self.len > limits.max_part_bytes {
+            counter!("multipart_limit_rejections_total", 1);
+            return Err(ParseError::BodyTooLarge {
+                limit: limits.max_part_bytes,
+            });
+        }
+        count_total(total, bytes.len(), limits)?;
+
+        if self.file.is_none() && self.len > limits.spill_threshold {
+            let (temp, file) = TempFile::create()?;
+            let mut file = BufWriter::new(file);
+            file.write_all(&self.memory)?;
+            self.memory = Vec::new();
+            self.file = Some((temp, file));
+        }
+        match &mut self.file {
+            Some((_, file)) => file.write_all(bytes)?,
+            None => self.memory.extend_from_slice(bytes),
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<PartData, ParseError> {
+        match self.file {
+            Some((temp, mut file)) => {
+                file.flush()?;
+                Ok(PartData::File(temp))
+            }
+            None => Ok(PartData::Memory(self.memory)),
+        }
+    }
+}
+
+/// Adds `len` bytes to the running total of part headers and data.
+fn count_total(total: &mut usize, len: usize, limits: &MultipartLimits) -> Result<(), ParseError> {
+    *total += len;
+    if *total > limits.max_total_bytes {
+        counter!("multipart_limit_rejections_total", 1);
+        return Err(ParseError::BodyTooLarge {
+            limit: limits.max_total_bytes,
+        });
+    }
+    Ok(())
+}
+
+/// Splits a buffered `multipart/form-data` body into its parts.
+pub fn parse(request: &Request, limits: &MultipartLimits) -> Result<Vec<Part>, ParseError> {
+    parse_stream(request.header("Content-Type"), request.body.as_slice(), limits)
+}
+
+/// Splits a `multipart/form-data` body into its parts as it is read from
+/// `body`, so only a part's first `spill_threshold` bytes and a read's
+/// worth of lookahead are ever held; the rest of a large part is written to
+/// its temp file as it arrives. Stops after the closing boundary, leaving
+/// the epilogue unread.
+pub fn parse_stream<R: Read>(
+    content_type: Option<&str>,
+    body: R,
+    limits: &MultipartLimits,
+) -> Result<Vec<Part>, ParseError> {
+    let boundary = match content_type.and_then(boundary) {
+        Some(boundary) => boundary,
+        None => {
+            return Err(ParseError::ContentType {
+                expected: "multipart/form-data",
+            })
+        }
+    };
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let next_delimiter = format!("\r\n--{}", boundary).into_bytes();
+    let mut scanner = Scanner { body, buf: Vec::new() };
+
+    // Skip the preamble, keeping only what could be the start of the
+    // opening boundary.
+    loop {
+        if let Some(start) = find(&scanner.buf, &delimiter, 0) {
+            scanner.consume(start + delimiter.len());
+            break;
+        }
+        scanner.consume(scanner.buf.len().saturating_sub(delimiter.len() - 1));
+        if !scanner.fill()? {
+            return Err(invalid("missing opening boundary"));
+        }
+    }
+
+    let mut parts = Vec::new();
+    let mut total = 0;
+    loop {
+        scanner.want(2)?;
+        match scanner.buf.get(..2) {
+            Some(b"--") => break,
+            Some(b"\r\n") => {}
+            _ => return Err(invalid("malformed boundary line")),
+        }
+        if parts.len() >= limits.max_parts {
+            counter!("multipart_limit_rejections_total", 1);
+            return Err(invalid(&format!("more than {} parts", limits.max_parts)));
+        }
+
+        // Searched from the boundary line's own CRLF, so a part with no
+        // headers at all is just the blank line.
+        let headers_end = match scanner.find(b"\r\n\r\n", MAX_PART_HEADER_BYTES)? {
+            Some(end) => end,
+            None if scanner.buf.len() > MAX_PART_HEADER_BYTES => return Err(invalid("part headers too large")),
+            None => return Err(invalid("unterminated part headers")),
+        };
+        let mut headers = Headers::new();
+        let head = String::from_utf8_lossy(scanner.buf.get(2..headers_end).unwrap_or_default());
+        for line in head.split("\r\n").filter(|line| !line.is_empty()) {
+            match line.split_once(':') {
+                Some((name, value)) => headers.append(name.trim(), value.trim()),
+                None => return Err(invalid("malformed part header")),
+            }
+        }
+        count_total(&mut total, headers_end + 4, limits)?;
+        scanner.consume(headers_end + 4);
+
+        let mut data = PartWriter::new();
+        loop {
+            if let Some(end) = find(&scanner.buf, &next_delimiter, 0) {
+                data.write(&scanner.buf[..end], &mut total, limits)?;
+                scanner.consume(end + next_delimiter.len());
+                break;
+            }
+            // Everything but a possible partial delimiter at the end is data.
+            let safe = scanner.buf.len().saturating_sub(next_delimiter.len() - 1);
+            data.write(&scanner.buf[..safe], &mut total, limits)?;
+            scanner.consume(safe);
+            if !scanner.fill()? {
+                return Err(invalid("missing closing boundary"));
+            }
+        }
+
+        let len = data.len;
+        let disposition = headers.get("Content-Disposition").unwrap_or("");
+        parts.push(Part {
+            name: disposition_param(disposition, "name"),
+            filename: disposition_param(disposition, "filename"),
+            headers,
+            len,
+            data: data.finish()?,
+        });
+    }
+
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDARY: &str = "----WebKitFormBoundary7MA4YWxkTrZu0gW";
+
+    fn content_type() -> String {
+        format!("multipart/form-data; boundary={}", BOUNDARY)
+    }
+
+    /// A form as a browser sends it: two text fields and a file upload.
+    fn payload(file: &[u8]) -> Vec<u8> {
+        let mut body = format!(
+            "--{b}\r\n\
+             Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+             Holiday photos\r\n\
+             --{b}\r\n\
+             Content-Disposition: form-data; name=\"description\"\r\n\r\n\
+             Two lines\r\nof text\r\n\
+             --{b}\r\n\
+             Content-Disposition: form-data; name=\"photo\"; filename=\"beach.jpg\"\r\n\
+             Content-Type: image/jpeg\r\n\r\n",
+            b = BOUNDARY
+        )
+        .into_bytes();
+        body.extend_from_slice(file);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+        body
+    }
+
+    /// Hands out at most `step` bytes per read, so delimiters straddle reads.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.step.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    /// Arbitrary bytes containing CRLFs, dashes and a near-miss delimiter.
+    fn file_bytes(len: usize) -> Vec<u8> {
+        let mut file: Vec<u8> = (0..len).map(|i| (i * 31 % 251) as u8).collect();
+        file[10..14].copy_from_slice(b"\r\n--");
+        file
+    }
+
+    #[test]
+    fn parses_a_browser_form_with_a_file() {
+        let file = file_bytes(2000);
+        let body = payload(&file);
+        let parts = parse_stream(Some(&content_type()), Trickle { data: &body, step: 1 }, &MultipartLimits::default()).unwrap();
+        assert_eq!(parts.len(), 3);
+
+        assert_eq!(parts[0].name.as_deref(), Some("title"));
+        assert_eq!(parts[0].bytes().unwrap(), b"Holiday photos");
+        assert_eq!(parts[1].bytes().unwrap(), b"Two lines\r\nof text");
+        assert!(parts[1].filename.is_none());
+
+        let photo = &parts[2];
+        assert_eq!(photo.name.as_deref(), Some("photo"));
+        assert_eq!(photo.filename.as_deref(), Some("beach.jpg"));
+        assert_eq!(photo.content_type(), Some("image/jpeg"));
+        assert_eq!(photo.len, file.len());
+        assert!(matches!(photo.data, PartData::Memory(_)));
+        assert_eq!(photo.bytes().unwrap(), file);
+    }
+
+    #[test]
+    fn large_parts_are_written_to_a_temp_file_as_they_arrive() {
+        let file = file_bytes(300 * 1024);
+        let body = payload(&file);
+        let limits = MultipartLimits {
+            spill_threshold: 1024,
+            ..MultipartLimits::default()
+        };
+        for step in [13, 4096, usize::MAX] {
+            let parts = parse_stream(Some(&content_type()), Trickle { data: &body, step }, &limits).unwrap();
+            assert_eq!(parts[0].bytes().unwrap(), b"Holiday photos", "step {step}");
+            let path = match &parts[2].data {
+                PartData::File(temp) => temp.path().to_path_buf(),
+                PartData::Memory(_) => panic!("step {step}: part over the threshold was kept in memory"),
+            };
+            assert_eq!(fs::read(&path).unwrap(), file, "step {step}");
+            drop(parts);
+            assert!(!path.exists(), "temp file outlived its part");
+        }
+    }
+
+    #[test]
+    fn buffered_requests_parse_the_same_way() {
+        let body = payload(b"tiny");
+        let raw = format!("POST /upload HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n", content_type(), body.len());
+        let mut input = raw.into_bytes();
+        input.extend_from_slice(&body);
+        let mut reader = input.as_slice();
+        let mut request = Request::parse(&mut reader).unwrap();
+        request.read_body(&mut reader, 1 << 20).unwrap();
+
+        let parts = request.multipart(&MultipartLimits::default()).unwrap();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[2].bytes().unwrap(), b"tiny");
+    }
+
+    #[test]
+    fn limits_are_enforced_while_reading() {
+        let body = payload(&file_bytes(5000));
+        let per_part = MultipartLimits {
+            max_part_bytes: 4096,
+            ..MultipartLimits::default()
+        };
+        let error = parse_stream(Some(&content_type()), body.as_slice(), &per_part).unwrap_err();
+        assert!(matches!(error, ParseError::BodyTooLarge { limit: 4096 }));
+
+        let total = MultipartLimits {
+            max_total_bytes: 5010,
+            ..MultipartLimits::default()
+        };
+        let error = parse_stream(Some(&content_type()), body.as_slice(), &total).unwrap_err();
+        assert!(matches!(error, ParseError::BodyTooLarge { limit: 5010 }));
+    }
+
+    /// A body of `count` parts, each with `header` as an extra header line.
+    fn many_parts(count: usize, header: &str) -> Vec<u8> {
+        let mut body = String::new();
+        for i in 0..count {
+            body.push_str(&format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"f{i}\"\r\n{header}\r\n\r\nx\r\n"));
+        }
+        body.push_str(&format!("--{BOUNDARY}--\r\n"));
+        body.into_bytes()
+    }
+
+    #[test]
+    fn part_headers_count_toward_the_total() {
+        let body = many_parts(4, &format!("X-Padding: {}", "a".repeat(4000)));
+        let limits = MultipartLimits {
+            max_total_bytes: 8 * 1024,
+            ..MultipartLimits::default()
+        };
+        let error = parse_stream(Some(&content_type()), body.as_slice(), &limits).unwrap_err();
+        assert!(matches!(error, ParseError::BodyTooLarge { limit: 8192 }), "{error:?}");
+        assert_eq!(parse_stream(Some(&content_type()), body.as_slice(), &MultipartLimits::default()).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn the_number_of_parts_is_limited() {
+        let limits = MultipartLimits {
+            max_parts: 10,
+            ..MultipartLimits::default()
+        };
+        assert_eq!(parse_stream(Some(&content_type()), many_parts(10, "X: y").as_slice(), &limits).unwrap().len(), 10);
+        let error = parse_stream(Some(&content_type()), many_parts(11, "X: y").as_slice(), &limits).unwrap_err();
+        assert!(matches!(error, ParseError::InvalidBody(ref reason) if reason.contains("more than 10 parts")), "{error:?}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spilled_files_are_private_to_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let (temp, _) = TempFile::create().unwrap();
+        let mode = fs::metadata(temp.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn malformed_bodies_are_rejected() {
+        let limits = MultipartLimits::default();
+        let error = parse_stream(Some("text/plain"), &b""[..], &limits).unwrap_err();
+        assert!(matches!(error, ParseError::ContentType { .. }));
+
+        let truncated = payload(b"data");
+        let truncated = &truncated[..truncated.len() - 12];
+        let cases: [&[u8]; 3] = [
+            b"no boundary here",
+            truncated,
+            b"------WebKitFormBoundary7MA4YWxkTrZu0gW\r\nContent-Disposition: form-data",
+        ];
+        for body in cases {
+            let error = parse_stream(Some(&content_type()), body, &limits).unwrap_err();
+            assert!(matches!(error, ParseError::InvalidBody(_)), "{error:?}");
+        }
+    }
+}
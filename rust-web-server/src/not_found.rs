@@ -0,0 +1,165 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde_json::json;
+
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// How a request nothing could answer is told so.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum NotFoundMode {
+    /// The `404.html` page.
+    #[default]
+    Page,
+    /// A JSON error body, for APIs.
+    Json,
+    /// A 302 to the given URL.
+    Redirect(String),
+}
+
+impl fmt::Display for NotFoundMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotFoundMode::Page => f.write_str("page"),
+            NotFoundMode::Json => f.write_str("json"),
+            NotFoundMode::Redirect(location) => write!(f, "redirect:{}", location),
+        }
+    }
+}
+
+impl FromStr for NotFoundMode {
+    type Err = String;
+
+    /// `page`, `json` or `redirect:<url>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("redirect", location)) if !location.trim().is_empty() => {
+                Ok(NotFoundMode::Redirect(location.trim().to_string()))
+            }
+            None if s == "page" => Ok(NotFoundMode::Page),
+            None if s == "json" => Ok(NotFoundMode::Json),
+            _ => Err(format!("unknown not-found mode: {}", s)),
+        }
+    }
+}
+
+/// Picks the not-found response for a path: the mode of the longest
+/// matching prefix rule, or the default. A prefix matches itself and
+/// anything below it, so `/api` covers `/api/users` but not `/apis`; a
+/// trailing `/*` is accepted and means the same.
+#[derive(Debug, Clone, Default)]
+pub struct NotFoundPolicy {
+    default: NotFoundMode,
+    rules: Vec<(String, NotFoundMode)>,
+}
+
+impl NotFoundPolicy {
+    pub fn new(default: NotFoundMode) -> NotFoundPolicy {
+        NotFoundPolicy {
+            default,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn rule(mut self, prefix: &str, mode: NotFoundMode) -> NotFoundPolicy {
+        let prefix = prefix.trim_end_matches('*').trim_end_matches('/');
+        self.rules.push((prefix.to_string(), mode));
+        self
+    }
+
+    /// Adds rules written as `prefix=mode` pairs separated by `;`, e.g.
+    /// `/api=json; /old=redirect:/`. Malformed entries are returned so the
+    /// caller can report them.
+    pub fn with_rules(mut self, rules: &str) -> (NotFoundPolicy, Vec<String>) {
+        let mut invalid = Vec::new();
+        for entry in rules.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+            match entry.split_once('=').map(|(prefix, mode)| (prefix.trim(), mode.trim().parse())) {
+                Some((prefix, Ok(mode))) if prefix.starts_with('/') => self = self.rule(prefix, mode),
+                _ => invalid.push(entry.to_string()),
+            }
+        }
+        (self, invalid)
+    }
+
+    pub fn default_mode(&self) -> &NotFoundMode {
+        &self.default
+    }
+
+    /// Prefix rules as `(prefix, mode)` pairs, in the order they were added.
+    pub fn rules(&self) -> &[(String, NotFoundMode)] {
+        &self.rules
+    }
+
+    pub fn mode_for(&self, path: &str) -> &NotFoundMode {
+        self.rules
+            .iter()
+            .filter(|(prefix, _)| match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(&self.default, |(_, mode)| mode)
+    }
+
+    pub fn response_for(&self, path: &str) -> Response {
+        match self.mode_for(path) {
+            NotFoundMode::Page => Response::from_file(StatusCode::NotFound, "404.html"),
+            NotFoundMode::Json => {
+                let body = json!({ "error": "not found", "status": 404, "path": path });
+                Response::new(StatusCode::NotFound)
+                    .with_header("Content-Type", "application/json")
+                    .with_body(body.to_string())
+            }
+            NotFoundMode::Redirect(location) => Response::redirect(StatusCode::Found, location),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_parse_from_their_names() {
+        assert_eq!("page".parse(), Ok(NotFoundMode::Page));
+        assert_eq!("json".parse(), Ok(NotFoundMode::Json));
+        assert_eq!("redirect: /home".parse(), Ok(NotFoundMode::Redirect("/home".to_string())));
+        for invalid in ["", "JSON", "redirect:", "redirect", "html:x"] {
+            assert!(invalid.parse::<NotFoundMode>().is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn the_longest_matching_prefix_picks_the_mode() {
+        let (policy, invalid) =
+            NotFoundPolicy::new(NotFoundMode::Page).with_rules("/api/*=json; /api/legacy=redirect:/; nonsense; /x=html");
+        assert_eq!(invalid, ["nonsense", "/x=html"]);
+        assert_eq!(policy.mode_for("/api"), &NotFoundMode::Json);
+        assert_eq!(policy.mode_for("/api/users/7"), &NotFoundMode::Json);
+        assert_eq!(policy.mode_for("/api/legacy/thing"), &NotFoundMode::Redirect("/".to_string()));
+        assert_eq!(policy.mode_for("/apis"), &NotFoundMode::Page);
+        assert_eq!(policy.mode_for("/"), &NotFoundMode::Page);
+    }
+
+    #[test]
+    fn each_mode_builds_its_response() {
+        let policy = NotFoundPolicy::new(NotFoundMode::Page)
+            .rule("/api", NotFoundMode::Json)
+            .rule("/old", NotFoundMode::Redirect("/new".to_string()));
+
+        let page = policy.response_for("/missing");
+        assert_eq!(page.status, StatusCode::NotFound);
+        assert_eq!(page.body, std::fs::read("404.html").unwrap());
+
+        let json = policy.response_for("/api/missing");
+        assert_eq!(json.status, StatusCode::NotFound);
+        assert_eq!(json.headers.get("Content-Type"), Some("application/json"));
+        let body: serde_json::Value = serde_json::from_slice(&json.body).unwrap();
+        assert_eq!(body, json!({ "error": "not found", "status": 404, "path": "/api/missing" }));
+
+        let redirect = policy.response_for("/old/page");
+        assert_eq!(redirect.status, StatusCode::Found);
+        assert_eq!(redirect.headers.get("Location"), Some("/new"));
+    }
+}
@@ -0,0 +1,125 @@
+//! Keeping trace export quiet when the OTLP collector is down.
+//!
+//! The batch processor hands every batch to its exporter and reports each
+//! failure through OpenTelemetry's global error handler, which prints to
+//! stderr. With no collector running that is a line every few seconds,
+//! forever. [`GuardedExporter`] retries a failed batch a bounded number of
+//! times, then counts the failure against a [`CircuitBreaker`]; while the
+//! circuit is open batches are dropped without a connection attempt, and a
+//! probe after the cooldown finds out whether the collector is back.
+//! [`install_error_handler`] routes what the SDK still reports (a full
+//! span queue, say) into the log, throttled.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use metrics::{counter, gauge};
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use tracing::{info, warn};
+
+use crate::circuit_breaker::{BreakerConfig, CircuitBreaker};
+
+/// Attempts per batch before it counts as a failed export.
+const MAX_ATTEMPTS: u32 = 3;
+/// Wait before the first retry, doubled for each one after.
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+/// Most one SDK error line per this long; the rest are only counted.
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Wraps a span exporter with retries and a circuit breaker.
+///
+/// Failed batches are dropped, not kept for later, so a long outage costs
+/// those traces rather than memory. `otlp_exporter_up` is 1 while exports
+/// succeed and 0 from the first failure until one succeeds again.
+pub struct GuardedExporter<E> {
+    inner: Arc<tokio::sync::Mutex<E>>,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl<E> fmt::Debug for GuardedExporter<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GuardedExporter").field("breaker", &self.breaker).finish()
+    }
+}
+
+impl<E: SpanExporter + 'static> GuardedExporter<E> {
+    pub fn new(inner: E, breaker: BreakerConfig) -> GuardedExporter<E> {
+        gauge!("otlp_exporter_up", 1.0);
+        GuardedExporter {
+            inner: Arc::new(tokio::sync::Mutex::new(inner)),
+            breaker: Arc::new(CircuitBreaker::new("otlp", breaker)),
+        }
+    }
+}
+
+impl<E: SpanExporter + 'static> SpanExporter for GuardedExporter<E> {
+    fn export(&mut self, batch: Vec<SpanData>) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+        let (inner, breaker) = (Arc::clone(&self.inner), Arc::clone(&self.breaker));
+        Box::pin(async move {
+            let spans = batch.len() as u64;
+            if !breaker.allow() {
+                counter!("otlp_spans_dropped_total", spans);
+                return Ok(());
+            }
+            let mut backoff = RETRY_BACKOFF;
+            for attempt in 1..=MAX_ATTEMPTS {
+                let export = inner.lock().await.export(batch.clone());
+                match export.await {
+                    Ok(()) => {
+                        breaker.record_success();
+                        gauge!("otlp_exporter_up", 1.0);
+                        return Ok(());
+                    }
+                    Err(e) if attempt == MAX_ATTEMPTS => {
+                        warn!("Dropping {} span(s) after {} failed export attempts: {}", spans, MAX_ATTEMPTS, e);
+                    }
+                    Err(_) => {
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 2;
+                    }
+                }
+            }
+            breaker.record_failure();
+            gauge!("otlp_exporter_up", 0.0);
+            counter!("otlp_export_failures_total", 1);
+            counter!("otlp_spans_dropped_total", spans);
+            // Already logged; returning the error would print it again.
+            Ok(())
+        })
+    }
+
+    fn shutdown(&mut self) {
+        if let Ok(mut inner) = self.inner.try_lock() {
+            inner.shutdown();
+        }
+    }
+}
+
+/// Replaces OpenTelemetry's print-everything error handler with one that
+/// logs at most once per minute and counts every error in `otel_errors_total`.
+pub fn install_error_handler() {
+    let last_logged: Mutex<Option<(Instant, u64)>> = Mutex::new(None);
+    let installed = opentelemetry::global::set_error_handler(move |error| {
+        counter!("otel_errors_total", 1);
+        let mut last_logged = last_logged.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match &mut *last_logged {
+            Some((at, suppressed)) if at.elapsed() < ERROR_LOG_INTERVAL => *suppressed += 1,
+            Some((at, suppressed)) => {
+                warn!("OpenTelemetry error: {} ({} more since the last one logged)", error, suppressed);
+                *at = Instant::now();
+                *suppressed = 0;
+            }
+            None => {
+                warn!("OpenTelemetry error: {}", error);
+                info!("Further OpenTelemetry errors are logged at most once per {:?}", ERROR_LOG_INTERVAL);
+                *last_logged = Some((Instant::now(), 0));
+            }
+        }
+    });
+    if let Err(e) = installed {
+        warn!("Failed to install OpenTelemetry error handler: {}", e);
+    }
+}
@@ -0,0 +1,378 @@
+//! Forwarding requests to an upstream HTTP/1.1 server (`PROXY_ROUTES`), so
+//! `/api/*` can be answered by an application listening on
+//! `http://localhost:3000`.
+//!
+//! - The method, path, query, headers and body are passed on unchanged,
+//!   except for the hop-by-hop headers that belong to the client's
+//!   connection (see [`Headers::remove_hop_by_hop`]). `Host` is kept, and
+//!   the client's address is appended to `X-Forwarded-For`.
+//! - Every request gets a new upstream connection, closed after the answer.
+//! - The answer is streamed back as it arrives, except a body of known
+//!   length up to `BUFFERED_BODY_BYTES`, which is read first and sent with
+//!   its length.
+//! - An upstream that can't be reached, or that answers with something this
+//!   server can't relay (not HTTP/1.x, an upgrade, a status it has no name
+//!   for), gets the client a 502; one that stays silent past the timeout a
+//!   504. Once the head has been sent a failure can only cut the body short:
+//!   the connection is closed without the terminating chunk.
+//! - Only `http://` upstreams are supported.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::iter;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use metrics::counter;
+use tracing::warn;
+
+use crate::headers::Headers;
+use crate::request::{BodyReader, Framing, Method, Request};
+use crate::response::Response;
+use crate::status::StatusCode;
+use crate::Context;
+
+/// Upstream bodies of known length up to this are sent on with their
+/// `Content-Length` instead of being streamed chunked.
+const BUFFERED_BODY_BYTES: usize = 64 * 1024;
+/// Most bytes of response head accepted from an upstream.
+const MAX_HEAD_BYTES: u64 = 64 * 1024;
+/// Largest read a streamed body is relayed in.
+const CHUNK_BYTES: usize = 16 * 1024;
+
+/// An upstream server, written `http://host[:port]` (port 80 by default).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upstream {
+    host: String,
+    port: u16,
+}
+
+impl Upstream {
+    /// `host:port`, with an IPv6 address in brackets.
+    fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}", self.authority())
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    /// A URL with nothing after the authority but an optional `/`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid upstream URL: {}", s);
+        let authority = s.strip_prefix("http://").ok_or_else(|| format!("not an http:// URL: {}", s))?;
+        let authority = authority.strip_suffix('/').unwrap_or(authority);
+        if authority.contains(['/', '?', '#', '@']) {
+            return Err(invalid());
+        }
+        let (host, port) = match authority.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+            Some((host, "")) => (host, None),
+            Some((host, rest)) => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid())?,
+            None => 80,
+        };
+        if host.is_empty() || host.contains(['[', ']']) {
+            return Err(invalid());
+        }
+        Ok(Upstream {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// A handler forwarding to `upstream`, for a route like `/api/*`.
+/// `timeout` bounds connecting and each wait for the upstream to accept or
+/// send more, and is cut short by the request's own deadline; zero means
+/// only the deadline applies.
+pub fn handler(upstream: Upstream, timeout: Duration) -> impl Fn(&mut Context) -> Response + Send + Sync + 'static {
+    move |context: &mut Context| {
+        let request = context.request;
+        let timeout = match ((!timeout.is_zero()).then_some(timeout), request.time_remaining()) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        };
+        match forward(&upstream, timeout, context) {
+            Ok(response) => response,
+            Err(e) => {
+                warn!(
+                    request_id = ?context.request_id,
+                    "Proxying {} {} to {} failed: {}",
+                    request.method,
+                    request.path,
+                    upstream,
+                    e
+                );
+                counter!("proxy_errors_total", 1, "upstream" => upstream.to_string(), "kind" => e.kind());
+                Response::new(e.status())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ProxyError {
+    /// No connection could be made.
+    Connect(io::Error),
+    /// Sending the request or reading the answer failed, or timed out.
+    Io(io::Error),
+    /// The answer isn't one that can be relayed.
+    Invalid(String),
+}
+
+impl ProxyError {
+    /// The `kind` label of `proxy_errors_total`.
+    fn kind(&self) -> &'static str {
+        match self {
+            ProxyError::Connect(e) | ProxyError::Io(e) if is_timeout(e) => "timeout",
+            ProxyError::Connect(_) => "connect",
+            ProxyError::Io(_) => "io",
+            ProxyError::Invalid(_) => "invalid",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self.kind() {
+            "timeout" => StatusCode::GatewayTimeout,
+            _ => StatusCode::BadGateway,
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::Connect(e) => write!(f, "connecting: {}", e),
+            ProxyError::Io(e) => write!(f, "{}", e),
+            ProxyError::Invalid(message) => write!(f, "invalid response: {}", message),
+        }
+    }
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(e: io::Error) -> Self {
+        ProxyError::Io(e)
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn forward(upstream: &Upstream, timeout: Option<Duration>, context: &Context) -> Result<Response, ProxyError> {
+    let request = context.request;
+    let mut stream = connect(upstream, timeout).map_err(ProxyError::Connect)?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    stream.write_all(&request_head(upstream, context)?)?;
+    stream.write_all(&request.body)?;
+
+    let mut reader = BufReader::new(stream);
+    let (status, mut headers) = read_head(&mut reader)?;
+    let framing = body_framing(request, status, &headers)?;
+    // Kept for a HEAD or 304 answer, which describes a body it doesn't carry.
+    let declared = headers.get("Content-Length").and_then(|length| length.trim().parse().ok());
+    headers.remove_hop_by_hop();
+    headers.remove("Content-Length");
+    let mut response = Response::new(status);
+    response.headers = headers;
+    match framing {
+        Some(Framing::Done) => Ok(match declared {
+            Some(length) => response.with_content_length(length),
+            None => response,
+        }),
+        Some(Framing::Length(length)) if length <= BUFFERED_BODY_BYTES => {
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body)?;
+            Ok(response.with_body(body))
+        }
+        framing => Ok(response.with_fallible_stream(UpstreamBody {
+            reader,
+            framing,
+            upstream: upstream.to_string(),
+        })),
+    }
+}
+
+fn connect(upstream: &Upstream, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    if timeout.is_some_and(|timeout| timeout.is_zero()) {
+        return Err(io::ErrorKind::TimedOut.into());
+    }
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "host has no addresses");
+    for addr in (upstream.host.as_str(), upstream.port).to_socket_addrs()? {
+        let connected = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        match connected {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// The request line and headers to send upstream. The body has already been
+/// read, so it goes with a `Content-Length` however it arrived, and the
+/// client's `Expect: 100-continue` has been answered here.
+fn request_head(upstream: &Upstream, context: &Context) -> io::Result<Vec<u8>> {
+    let request = context.request;
+    let mut headers = request.headers.clone();
+    headers.remove_hop_by_hop();
+    headers.remove("Expect");
+    headers.remove("Content-Length");
+    if !request.body.is_empty() || request.method == Method::Post {
+        headers.insert("Content-Length", &request.body.len().to_string());
+    }
+    if !headers.contains("Host") {
+        headers.insert("Host", &upstream.authority());
+    }
+    if let Some(remote_addr) = context.remote_addr {
+        let chain: Vec<String> = request
+            .headers
+            .get_all("X-Forwarded-For")
+            .map(|hops| hops.trim().to_string())
+            .chain(iter::once(remote_addr.ip().to_string()))
+            .collect();
+        headers.insert("X-Forwarded-For", &chain.join(", "));
+    }
+    headers.insert("Connection", "close");
+
+    let mut head = match &request.query {
+        Some(query) => format!("{} {}?{} HTTP/1.1\r\n", request.method, request.path, query),
+        None => format!("{} {} HTTP/1.1\r\n", request.method, request.path),
+    }
+    .into_bytes();
+    headers.write_to(&mut head)?;
+    head.extend_from_slice(b"\r\n");
+    Ok(head)
+}
+
+/// Reads the upstream's status line and headers, skipping interim answers
+/// such as `100 Continue`.
+fn read_head<R: BufRead>(reader: &mut R) -> Result<(StatusCode, Headers), ProxyError> {
+    loop {
+        let mut head = reader.by_ref().take(MAX_HEAD_BYTES);
+        let status_line = read_line(&mut head)?;
+        let code = status_code(&status_line).ok_or_else(|| ProxyError::Invalid(format!("status line {:?}", status_line)))?;
+        let mut headers = Headers::new();
+        loop {
+            let line = read_line(&mut head)?;
+            if line.is_empty() {
+                break;
+            }
+            match line.split_once(':') {
+                Some((name, value)) if !name.is_empty() && !name.contains([' ', '\t']) => headers.append(name, value.trim()),
+                _ => return Err(ProxyError::Invalid(format!("header line {:?}", line))),
+            }
+        }
+        match code {
+            101 => return Err(ProxyError::Invalid("protocol upgrade".to_string())),
+            100..=199 => continue,
+            _ => {
+                let status = StatusCode::from_u16(code).ok_or_else(|| ProxyError::Invalid(format!("status {}", code)))?;
+                return Ok((status, headers));
+            }
+        }
+    }
+}
+
+/// One line of the head without its line ending, failing if the head ends
+/// (or reaches `MAX_HEAD_BYTES`) first.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, ProxyError> {
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    if line.pop() != Some(b'\n') {
+        return Err(ProxyError::Invalid("head cut short or too large".to_string()));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| ProxyError::Invalid("head isn't UTF-8".to_string()))
+}
+
+/// The code of an `HTTP/1.x NNN reason` status line.
+fn status_code(line: &str) -> Option<u16> {
+    let (version, rest) = line.split_once(' ')?;
+    let code = rest.split(' ').next()?;
+    if !version.starts_with("HTTP/1.") || code.len() != 3 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    code.parse().ok()
+}
+
+/// Where the upstream's body ends: `None` when it runs until the connection
+/// closes.
+fn body_framing(request: &Request, status: StatusCode, headers: &Headers) -> Result<Option<Framing>, ProxyError> {
+    if request.method == Method::Head || matches!(status.as_u16(), 204 | 304) {
+        return Ok(Some(Framing::Done));
+    }
+    if headers.contains("Transfer-Encoding") {
+        let chunked = headers.tokens("Transfer-Encoding").last().is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"));
+        return Ok(chunked.then_some(Framing::Chunked { remaining: 0, first: true }));
+    }
+    let mut lengths = headers.get_all("Content-Length").flat_map(|value| value.split(',')).map(str::trim);
+    let Some(length) = lengths.next() else {
+        return Ok(None);
+    };
+    let invalid = || ProxyError::Invalid(format!("Content-Length {:?}", headers.get("Content-Length").unwrap_or("")));
+    if !length.bytes().all(|b| b.is_ascii_digit()) || lengths.any(|other| other != length) {
+        return Err(invalid());
+    }
+    length.parse().map(|length| Some(Framing::Length(length))).map_err(|_| invalid())
+}
+
+/// The rest of an upstream body, relayed as it is read.
+struct UpstreamBody {
+    reader: BufReader<TcpStream>,
+    framing: Option<Framing>,
+    upstream: String,
+}
+
+impl Iterator for UpstreamBody {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let mut chunk = vec![0; CHUNK_BYTES];
+        let read = match self.framing {
+            Some(Framing::Done) => return None,
+            Some(framing) => {
+                let mut body = BodyReader::new(&mut self.reader, framing, usize::MAX);
+                let read = body.read(&mut chunk);
+                self.framing = Some(body.framing());
+                read
+            }
+            None => self.reader.read(&mut chunk),
+        };
+        match read {
+            Ok(0) => None,
+            Ok(n) => {
+                chunk.truncate(n);
+                Some(Ok(chunk))
+            }
+            Err(e) => {
+                counter!("proxy_errors_total", 1, "upstream" => self.upstream.clone(), "kind" => "body");
+                self.framing = Some(Framing::Done);
+                // Not the client's timeout or disconnect, whatever the cause.
+                Some(Err(io::Error::other(format!("upstream {} failed mid-body: {}", self.upstream, e))))
+            }
+        }
+    }
+}
@@ -0,0 +1,1122 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::cookie::parse_cookies;
+use crate::headers::Headers;
+use crate::mime::negotiate;
+use crate::multipart::{self, MultipartLimits, Part};
+use crate::query::{parse_query, percent_decode};
+use crate::status::StatusCode;
+use serde::de::DeserializeOwned;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Trace,
+    Connect,
+}
+
+impl Method {
+    /// Parses a method name. Names are case-sensitive, so `get` is not GET.
+    pub fn parse(token: &str) -> Option<Method> {
+        match token {
+            "GET" => Some(Method::Get),
+            "HEAD" => Some(Method::Head),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            "PATCH" => Some(Method::Patch),
+            "OPTIONS" => Some(Method::Options),
+            "TRACE" => Some(Method::Trace),
+            "CONNECT" => Some(Method::Connect),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+        }
+    }
+}
+
+/// Whether `s` is a `token` (RFC 9110 section 5.6.2), the syntax of every
+/// header name, and of every method name whether or not the server knows it.
+pub(crate) fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Whether a request path decodes to control characters (`%00`, `%0a`,
+/// `%7f` and the like), which are no more welcome than raw ones.
+pub(crate) fn has_encoded_control(path: &str) -> bool {
+    percent_decode(path, false).bytes().any(|b| b.is_ascii_control())
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Io(io::Error),
+    Empty,
+    Malformed(String),
+    /// A well-formed method the server doesn't implement, lowercase
+    /// spellings of known methods included.
+    UnknownMethod(String),
+    InvalidContentLength(String),
+    /// Headers that leave where the body ends open to interpretation, e.g.
+    /// both `Content-Length` and `Transfer-Encoding`. A proxy in front could
+    /// read them differently and slip a second request inside the body.
+    AmbiguousFraming(String),
+    BodyTooLarge { limit: usize },
+    HeadersTooLarge { limit: usize },
+    /// The request line and headers didn't all arrive within the limit.
+    HeadersTimedOut { limit: Duration },
+    ContentType { expected: &'static str },
+    InvalidBody(String),
+}
+
+impl ParseError {
+    /// The status to answer with when this error ends a request.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ParseError::Io(_) => StatusCode::InternalServerError,
+            ParseError::BodyTooLarge { .. } => StatusCode::PayloadTooLarge,
+            ParseError::HeadersTooLarge { .. } => StatusCode::RequestHeaderFieldsTooLarge,
+            ParseError::HeadersTimedOut { .. } => StatusCode::RequestTimeout,
+            ParseError::UnknownMethod(_) => StatusCode::NotImplemented,
+            _ => StatusCode::BadRequest,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "failed to read request: {}", e),
+            ParseError::Empty => f.write_str("empty request"),
+            ParseError::Malformed(line) => write!(f, "malformed request line: {}", line),
+            ParseError::UnknownMethod(method) => write!(f, "unsupported method: {}", method),
+            ParseError::InvalidContentLength(value) => write!(f, "invalid Content-Length: {}", value),
+            ParseError::AmbiguousFraming(reason) => write!(f, "ambiguous body framing: {}", reason),
+            ParseError::BodyTooLarge { limit } => write!(f, "body exceeds the {} byte limit", limit),
+            ParseError::HeadersTooLarge { limit } => write!(f, "request head exceeds the {} byte limit", limit),
+            ParseError::HeadersTimedOut { limit } => write!(f, "request head not received within {:?}", limit),
+            ParseError::ContentType { expected } => write!(f, "expected Content-Type {}", expected),
+            ParseError::InvalidBody(e) => write!(f, "invalid body: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> Self {
+        ParseError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub path: String,
+    /// The raw query string, without the leading `?`.
+    pub query: Option<String>,
+    pub version: String,
+    pub headers: Headers,
+    /// When the server stops waiting on this request. Long-running handlers
+    /// should check it and give up with a 503 once it has passed.
+    pub deadline: Option<Instant>,
+    /// The client's IP: the peer's, or the one forwarded by a trusted proxy
+    /// (`Config::trusted_proxies`). Set by the server when it routes the
+    /// request, if the transport knows the peer.
+    pub client_ip: Option<IpAddr>,
+    pub body: Vec<u8>,
+}
+
+/// Default cap on the request line plus headers.
+pub const DEFAULT_MAX_HEADER_BYTES: usize = 64 * 1024;
+
+impl Request {
+    /// Reads the request line and headers, leaving the body in the reader.
+    pub fn parse<R: BufRead>(reader: &mut R) -> Result<Request, ParseError> {
+        Request::parse_with_limit(reader, DEFAULT_MAX_HEADER_BYTES)
+    }
+
+    /// Like [`Request::parse_with_limit`], but fails with `HeadersTimedOut`
+    /// if the head is still incomplete `timeout` after the call (zero means
+    /// no limit). Per-read timeouts alone let a client that sends a byte at
+    /// a time hold the connection indefinitely; this bounds the head as a
+    /// whole. It is checked between reads, so it can be overshot by one
+    /// read timeout.
+    pub fn parse_with_timeout<R: BufRead>(
+        reader: &mut R,
+        max_header_bytes: usize,
+        timeout: Duration,
+    ) -> Result<Request, ParseError> {
+        if timeout.is_zero() {
+            return Request::parse_with_limit(reader, max_header_bytes);
+        }
+        let mut timed = HeadDeadline {
+            inner: reader,
+            deadline: Instant::now() + timeout,
+            expired: false,
+            started: false,
+        };
+        Request::parse_with_limit(&mut timed, max_header_bytes)
+            .map_err(|e| if timed.expired { ParseError::HeadersTimedOut { limit: timeout } } else { e })
+    }
+
+    /// Like [`Request::parse`], but fails with `HeadersTooLarge` once the
+    /// request line and headers together pass `max_header_bytes`. The head
+    /// may span any number of buffer fills below that.
+    pub fn parse_with_limit<R: BufRead>(reader: &mut R, max_header_bytes: usize) -> Result<Request, ParseError> {
+        let too_large = ParseError::HeadersTooLarge { limit: max_header_bytes };
+        let mut limited = reader.take(max_header_bytes as u64);
+
+        let request_line = match next_line(&mut limited)? {
+            Some(line) => line,
+            None => return Err(ParseError::Empty),
+        };
+        if limited.limit() == 0 {
+            return Err(too_large);
+        }
+
+        let mut parts = request_line.split(' ');
+        let (method, path, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(path), Some(version), None) => (method, path, version),
+            _ => return Err(ParseError::Malformed(request_line)),
+        };
+        let method = match Method::parse(method) {
+            Some(method) => method,
+            None if is_token(method) => return Err(ParseError::UnknownMethod(method.to_string())),
+            None => return Err(ParseError::Malformed(request_line)),
+        };
+        // Control characters (NUL included) in the target would end up in
+        // logs and filesystem lookups; refuse them before either, whether
+        // sent raw or percent-encoded in the path (`%00`, `%0a`).
+        if path.bytes().any(|b| b.is_ascii_control()) {
+            return Err(ParseError::Malformed(request_line.escape_debug().to_string()));
+        }
+        let (path, query) = match path.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (path.to_string(), None),
+        };
+        if has_encoded_control(&path) {
+            return Err(ParseError::Malformed(request_line));
+        }
+        let version = version.to_string();
+
+        let mut headers = Headers::new();
+        while let Some(line) = next_line(&mut limited)? {
+            if line.is_empty() {
+                break;
+            }
+            // Out of budget before the blank line that ends the head.
+            if limited.limit() == 0 {
+                return Err(too_large);
+            }
+            // No whitespace around the name, so `Content-Length : 5` can't be
+            // read as one header here and as another by a proxy in front.
+            match line.split_once(':') {
+                Some((name, value)) if is_token(name) => headers.append(name, value.trim()),
+                _ => return Err(ParseError::Malformed(line.escape_debug().to_string())),
+            }
+        }
+
+        Ok(Request {
+            method,
+            path,
+            query,
+            version,
+            headers,
+            deadline: None,
+            client_ip: None,
+            body: Vec::new(),
+        })
+    }
+
+    /// Reads the body framed by `Transfer-Encoding: chunked` or
+    /// `Content-Length`, refusing anything over `max_len` bytes.
+    pub fn read_body<R: BufRead>(&mut self, reader: &mut R, max_len: usize) -> Result<(), ParseError> {
+        match self.body_framing(max_len)? {
+            Framing::Chunked { .. } => self.body = read_chunked(reader, max_len)?,
+            Framing::Length(0) | Framing::Done => {}
+            Framing::Length(length) => {
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body)?;
+                self.body = body;
+            }
+        }
+        Ok(())
+    }
+
+    /// How the body that follows the head is framed, checked against
+    /// `max_len` the same way [`Request::read_body`] would, but without
+    /// reading it.
+    ///
+    /// Anything a proxy might frame differently is refused rather than
+    /// guessed at: `Transfer-Encoding` together with `Content-Length`, a
+    /// `Transfer-Encoding` whose last coding isn't `chunked`, differing
+    /// `Content-Length` values, and lengths that aren't plain digits (so no
+    /// `+5` or ` 5 5`). Repeats of one length are allowed, as RFC 9112 does.
+    pub(crate) fn body_framing(&self, max_len: usize) -> Result<Framing, ParseError> {
+        let codings: Vec<&str> = self
+            .headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|coding| !coding.is_empty())
+            .collect();
+        let lengths: Vec<&str> = self
+            .headers
+            .get_all("Content-Length")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+
+        if self.headers.contains("Transfer-Encoding") {
+            if !lengths.is_empty() {
+                return Err(ParseError::AmbiguousFraming(
+                    "both Content-Length and Transfer-Encoding".to_string(),
+                ));
+            }
+            return match codings.last() {
+                Some(last) if last.eq_ignore_ascii_case("chunked") => Ok(Framing::Chunked { remaining: 0, first: true }),
+                _ => Err(ParseError::AmbiguousFraming(format!(
+                    "Transfer-Encoding {:?} doesn't end in chunked",
+                    codings.join(", ")
+                ))),
+            };
+        }
+
+        let Some(&first) = lengths.first() else {
+            return Ok(Framing::Length(0));
+        };
+        if let Some(invalid) = lengths.iter().find(|value| value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit())) {
+            return Err(ParseError::InvalidContentLength(invalid.to_string()));
+        }
+        if lengths.iter().any(|value| value.trim_start_matches('0') != first.trim_start_matches('0')) {
+            return Err(ParseError::AmbiguousFraming(format!(
+                "conflicting Content-Length values {}",
+                lengths.join(", ")
+            )));
+        }
+        match first.parse::<usize>() {
+            Ok(length) if length > max_len => Err(ParseError::BodyTooLarge { limit: max_len }),
+            Ok(length) => Ok(Framing::Length(length)),
+            // Only digits, so too big to count: certainly over the limit.
+            Err(_) => Err(ParseError::BodyTooLarge { limit: max_len }),
+        }
+    }
+
+    /// The media type of the body, without parameters such as `charset`.
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("Content-Type")
+            .map(|value| value.split(';').next().unwrap_or("").trim())
+    }
+
+    pub fn query_params(&self) -> HashMap<String, String> {
+        self.query.as_deref().map(parse_query).unwrap_or_default()
+    }
+
+    /// Decodes an `application/x-www-form-urlencoded` body.
+    pub fn form(&self) -> Result<HashMap<String, String>, ParseError> {
+        let expected = "application/x-www-form-urlencoded";
+        if !self.content_type().is_some_and(|t| t.eq_ignore_ascii_case(expected)) {
+            return Err(ParseError::ContentType { expected });
+        }
+        match std::str::from_utf8(&self.body) {
+            Ok(body) => Ok(parse_query(body)),
+            Err(e) => Err(ParseError::InvalidBody(e.to_string())),
+        }
+    }
+
+    /// Splits a buffered `multipart/form-data` body into its parts. The body
+    /// is already in memory here; stream it with `Context::multipart` on a
+    /// route registered with `register_streaming` to avoid that.
+    pub fn multipart(&self, limits: &MultipartLimits) -> Result<Vec<Part>, ParseError> {
+        multipart::parse(self, limits)
+    }
+
+    /// Deserializes a JSON body. Fails if the request isn't `application/json`
+    /// or the body doesn't decode as `T`.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, ParseError> {
+        if !self.content_type().is_some_and(|t| t.eq_ignore_ascii_case("application/json")) {
+            return Err(ParseError::ContentType {
+                expected: "application/json",
+            });
+        }
+        serde_json::from_slice(&self.body).map_err(|e| ParseError::InvalidBody(e.to_string()))
+    }
+
+    /// The offered media type that best matches the `Accept` header; see
+    /// [`mime::negotiate`](crate::mime::negotiate). Without an `Accept`
+    /// header the client takes anything, so the first offer wins. `None`
+    /// means the handler should answer 406 Not Acceptable.
+    pub fn negotiate<'a>(&self, offers: &[&'a str]) -> Option<&'a str> {
+        match self.headers.get_all("Accept").collect::<Vec<_>>() {
+            accept if accept.is_empty() => offers.first().copied(),
+            accept => negotiate(&accept.join(","), offers),
+        }
+    }
+
+    /// Returns the first value of the named header, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Collects the cookies from every `Cookie` header on the request.
+    pub fn cookies(&self) -> HashMap<String, String> {
+        let mut cookies = HashMap::new();
+        for header in self.headers.get_all("Cookie") {
+            cookies.extend(parse_cookies(header));
+        }
+        cookies
+    }
+
+    /// Time left before the request deadline, or `None` if it has no deadline.
+    /// Returns zero once the deadline has passed.
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn deadline_exceeded(&self) -> bool {
+        self.time_remaining() == Some(Duration::ZERO)
+    }
+}
+
+/// Longest chunk-size or trailer line accepted in a chunked body.
+const MAX_CHUNK_LINE: u64 = 4096;
+
+/// Bytes of chunk extensions, padding and trailers accepted in one chunked
+/// body. They don't count toward the body size limit, so without a budget
+/// of their own a client could send them for as long as it liked.
+const MAX_CHUNK_FRAMING_BYTES: usize = DEFAULT_MAX_HEADER_BYTES;
+
+/// Bytes of each size line free of the framing budget: sixteen hex digits
+/// are all a size needs.
+const CHUNK_SIZE_DIGITS: usize = 16;
+
+fn charge_framing(budget: &mut usize, len: usize) -> Result<(), ParseError> {
+    match budget.checked_sub(len) {
+        Some(left) => {
+            *budget = left;
+            Ok(())
+        }
+        None => Err(ParseError::InvalidBody(format!(
+            "chunk extensions and trailers exceed {} bytes",
+            MAX_CHUNK_FRAMING_BYTES
+        ))),
+    }
+}
+
+/// Reads a chunk-size line, ignoring any chunk extensions but charging
+/// them to `budget`.
+fn read_chunk_size<R: BufRead + ?Sized>(reader: &mut R, budget: &mut usize) -> Result<usize, ParseError> {
+    let line = read_crlf_line(reader)?;
+    charge_framing(budget, line.len().saturating_sub(CHUNK_SIZE_DIGITS))?;
+    let size = line.split(';').next().unwrap_or("").trim();
+    let valid = !size.is_empty() && size.bytes().all(|b| b.is_ascii_hexdigit());
+    match usize::from_str_radix(size, 16) {
+        Ok(size) if valid => Ok(size),
+        _ => Err(ParseError::InvalidBody(format!("invalid chunk size: {}", line))),
+    }
+}
+
+/// Reads the trailer lines after the last chunk, up to the empty line, and
+/// drops them.
+fn skip_trailers<R: BufRead + ?Sized>(reader: &mut R, budget: &mut usize) -> Result<(), ParseError> {
+    loop {
+        let line = read_crlf_line(reader)?;
+        if line.is_empty() {
+            return Ok(());
+        }
+        charge_framing(budget, line.len() + 2)?;
+    }
+}
+
+fn read_crlf_line<R: BufRead + ?Sized>(reader: &mut R) -> Result<String, ParseError> {
+    let mut line = Vec::new();
+    Read::take(&mut *reader, MAX_CHUNK_LINE).read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\r\n") {
+        return Err(ParseError::InvalidBody("malformed chunk framing".to_string()));
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|_| ParseError::InvalidBody("malformed chunk framing".to_string()))
+}
+
+/// Reads one line of the request head, however many reads it arrives in,
+/// without its `\n` or `\r\n`; `None` at end of input. Only the line's own
+/// bytes are consumed, so a body or pipelined request stays buffered. A
+/// line cut short by the size budget is returned for the caller to refuse;
+/// one cut short by the end of the stream, or not UTF-8, is malformed.
+fn next_line<R: BufRead>(reader: &mut io::Take<R>) -> Result<Option<String>, ParseError> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.ends_with(b"\n") {
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+    } else if reader.limit() > 0 {
+        let line = String::from_utf8_lossy(&line);
+        return Err(ParseError::Malformed(format!("{} (connection closed mid-line)", line.escape_debug())));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| ParseError::Malformed(String::from_utf8_lossy(e.as_bytes()).into_owned()))
+}
+
+/// Fails every read once `deadline` has passed, remembering that it did so
+/// the error can be told apart from the socket's own read timeout.
+///
+/// Once the head has started arriving, the socket timing out doesn't end it
+/// either: the read is retried until `deadline`, so a client pausing
+/// mid-line for longer than the socket's read timeout but within the header
+/// timeout is still heard out.
+struct HeadDeadline<'a, R> {
+    inner: &'a mut R,
+    deadline: Instant,
+    expired: bool,
+    /// Whether any of the head has been read yet.
+    started: bool,
+}
+
+impl<R> HeadDeadline<'_, R> {
+    fn check(&mut self) -> io::Result<()> {
+        if Instant::now() >= self.deadline {
+            self.expired = true;
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request head deadline passed"));
+        }
+        Ok(())
+    }
+
+    fn should_retry(&self, e: &io::Error) -> bool {
+        self.started && matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+    }
+}
+
+impl<R: Read> Read for HeadDeadline<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            self.check()?;
+            match self.inner.read(buf) {
+                Err(e) if self.should_retry(&e) => continue,
+                Ok(n) => {
+                    self.started |= n > 0;
+                    return Ok(n);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<R: BufRead> BufRead for HeadDeadline<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        loop {
+            self.check()?;
+            match self.inner.fill_buf().map(|_| ()) {
+                Err(e) if self.should_retry(&e) => continue,
+                Err(e) => return Err(e),
+                Ok(()) => break,
+            }
+        }
+        // Buffered now, so this doesn't read again.
+        let buf = self.inner.fill_buf()?;
+        self.started |= !buf.is_empty();
+        Ok(buf)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+/// Decodes a chunked body: hex size lines, each followed by that many bytes
+/// and a CRLF, up to a zero-size chunk and optional trailers, which are
+/// read and dropped.
+fn read_chunked<R: BufRead>(reader: &mut R, max_len: usize) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    let mut budget = MAX_CHUNK_FRAMING_BYTES;
+    loop {
+        let size = read_chunk_size(reader, &mut budget)?;
+        if size == 0 {
+            break;
+        }
+        // A size near usize::MAX would overflow the sum.
+        if size > max_len.saturating_sub(body.len()) {
+            return Err(ParseError::BodyTooLarge { limit: max_len });
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        if !read_crlf_line(reader)?.is_empty() {
+            return Err(ParseError::InvalidBody("chunk data longer than its size".to_string()));
+        }
+    }
+
+    skip_trailers(reader, &mut budget)?;
+    Ok(body)
+}
+
+/// Where a streamed body is up to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Framing {
+    /// Bytes left of a `Content-Length` body.
+    Length(usize),
+    /// Bytes left of the current chunk; `first` until the first size line.
+    Chunked { remaining: usize, first: bool },
+    Done,
+}
+
+/// A request body read from the connection as the handler consumes it, for
+/// routes registered with `Router::register_streaming`. Reading stops at the
+/// end of the body, never running into a pipelined request behind it, and
+/// fails once more than the body size limit has been read or the request's
+/// deadline has passed.
+pub struct BodyReader<'a> {
+    reader: &'a mut dyn BufRead,
+    framing: Framing,
+    read: usize,
+    max_len: usize,
+    /// What is left of `MAX_CHUNK_FRAMING_BYTES` for a chunked body.
+    framing_budget: usize,
+    deadline: Option<Instant>,
+}
+
+impl fmt::Debug for BodyReader<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyReader")
+            .field("framing", &self.framing)
+            .field("read", &self.read)
+            .field("max_len", &self.max_len)
+            .finish()
+    }
+}
+
+impl<'a> BodyReader<'a> {
+    pub(crate) fn new(reader: &'a mut dyn BufRead, framing: Framing, max_len: usize) -> BodyReader<'a> {
+        BodyReader {
+            reader,
+            framing,
+            read: 0,
+            max_len,
+            framing_budget: MAX_CHUNK_FRAMING_BYTES,
+            deadline: None,
+        }
+    }
+
+    pub(crate) fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Whether the whole body has been read, so the connection is positioned
+    /// at the next request.
+    pub fn is_done(&self) -> bool {
+        self.framing == Framing::Done
+    }
+
+    /// Bytes of body read so far.
+    pub fn bytes_read(&self) -> usize {
+        self.read
+    }
+
+    /// Where the body is up to, for resuming with a new reader later.
+    pub(crate) fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Moves on to the next chunk, reading the end of the previous one and
+    /// the trailers after the last.
+    fn next_chunk(&mut self, first: bool) -> Result<(), ParseError> {
+        if !first && !read_crlf_line(self.reader)?.is_empty() {
+            return Err(ParseError::InvalidBody("chunk data longer than its size".to_string()));
+        }
+        let size = read_chunk_size(self.reader, &mut self.framing_budget)?;
+        if size == 0 {
+            skip_trailers(self.reader, &mut self.framing_budget)?;
+            self.framing = Framing::Done;
+        } else {
+            self.framing = Framing::Chunked { remaining: size, first: false };
+        }
+        Ok(())
+    }
+
+    fn read_data(&mut self, buf: &mut [u8], remaining: usize) -> io::Result<usize> {
+        if self.read + remaining.min(buf.len()) > self.max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                ParseError::BodyTooLarge { limit: self.max_len }.to_string(),
+            ));
+        }
+        let len = remaining.min(buf.len());
+        let n = self.reader.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.read += n;
+        self.framing = match self.framing {
+            Framing::Length(_) if n == remaining => Framing::Done,
+            Framing::Length(_) => Framing::Length(remaining - n),
+            _ => Framing::Chunked { remaining: remaining - n, first: false },
+        };
+        Ok(n)
+    }
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.framing == Framing::Done {
+            return Ok(0);
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request deadline passed while reading the body"));
+        }
+        loop {
+            match self.framing {
+                Framing::Done => return Ok(0),
+                Framing::Length(0) => self.framing = Framing::Done,
+                Framing::Length(remaining) => return self.read_data(buf, remaining),
+                Framing::Chunked { remaining: 0, first } => self.next_chunk(first).map_err(|e| match e {
+                    ParseError::Io(e) => e,
+                    e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+                })?,
+                Framing::Chunked { remaining, .. } => return self.read_data(buf, remaining),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    /// Parses `raw` and reads its body with a 1 MB limit.
+    fn request(raw: &str) -> Request {
+        let mut reader = raw.as_bytes();
+        let mut request = Request::parse(&mut reader).unwrap();
+        request.read_body(&mut reader, 1 << 20).unwrap();
+        request
+    }
+
+    fn post(content_type: &str, body: &str) -> Request {
+        request(&format!(
+            "POST /submit HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        ))
+    }
+
+    #[test]
+    fn content_length_counts_bytes_not_characters() {
+        let body = "héllo wörld ✓ 日本語 🦀";
+        assert_ne!(body.len(), body.chars().count());
+        let raw = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}GET /next HTTP/1.1\r\n\r\n",
+            body.len(),
+            body
+        );
+        let mut reader = raw.as_bytes();
+        let mut request = Request::parse(&mut reader).unwrap();
+        request.read_body(&mut reader, 1 << 20).unwrap();
+        assert_eq!(request.body, body.as_bytes());
+
+        // Exactly the body was consumed, so the next request parses.
+        assert_eq!(Request::parse(&mut reader).unwrap().path, "/next");
+    }
+
+    #[test]
+    fn non_utf8_bodies_are_read_byte_for_byte() {
+        let body = [0xff, 0x00, 0xc3, 0x28, b'\r', b'\n', 0x80];
+        let mut raw = format!("PUT /blob HTTP/1.1\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        raw.extend_from_slice(&body);
+        let mut reader = raw.as_slice();
+        let mut request = Request::parse(&mut reader).unwrap();
+        request.read_body(&mut reader, 1 << 20).unwrap();
+        assert_eq!(request.body, body);
+        assert!(reader.is_empty());
+    }
+
+    fn chunked(body: &str, max_len: usize) -> Result<Vec<u8>, ParseError> {
+        let raw = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{}", body);
+        let mut reader = raw.as_bytes();
+        let mut request = Request::parse(&mut reader).unwrap();
+        request.read_body(&mut reader, max_len)?;
+        assert!(reader.is_empty(), "left unread: {:?}", String::from_utf8_lossy(reader));
+        Ok(request.body)
+    }
+
+    #[test]
+    fn chunked_bodies_are_joined_across_chunks() {
+        let body = chunked("5\r\nhello\r\n1;ext=1\r\n \r\nA\r\n0123456789\r\n0\r\n\r\n", 1024).unwrap();
+        assert_eq!(body, b"hello 0123456789");
+    }
+
+    #[test]
+    fn chunked_trailers_are_read_and_dropped() {
+        let body = chunked("3\r\nabc\r\n0\r\nChecksum: 123\r\nExpires: never\r\n\r\n", 1024).unwrap();
+        assert_eq!(body, b"abc");
+    }
+
+    #[test]
+    fn endless_trailers_run_out_of_framing_budget() {
+        let trailers = "X-Pad: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n".repeat(2000);
+        let raw = format!("1\r\na\r\n0\r\n{trailers}\r\n");
+        let error = chunked(&raw, 1024).unwrap_err();
+        assert!(matches!(error, ParseError::InvalidBody(ref reason) if reason.contains("trailers")), "{error:?}");
+        assert_eq!(error.status(), StatusCode::BadRequest);
+
+        let mut reader = raw.as_bytes();
+        let mut body = BodyReader::new(&mut reader, Framing::Chunked { remaining: 0, first: true }, 1024);
+        assert!(body.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn chunk_extensions_share_the_framing_budget() {
+        let raw = format!("{}0\r\n\r\n", format!("1;{}\r\na\r\n", "e".repeat(4000)).repeat(20));
+        let error = chunked(&raw, 1024).unwrap_err();
+        assert!(matches!(error, ParseError::InvalidBody(_)), "{error:?}");
+        // Padded sizes count too, beyond the digits a size needs.
+        let raw = format!("{}0\r\n\r\n", format!("{}1\r\na\r\n", "0".repeat(4000)).repeat(20));
+        assert!(matches!(chunked(&raw, 1024), Err(ParseError::InvalidBody(_))));
+    }
+
+    #[test]
+    fn many_small_chunks_stay_within_the_framing_budget() {
+        let raw = format!("{}0\r\nChecksum: 1\r\n\r\n", "0000000000000001;x=y\r\na\r\n".repeat(10_000));
+        assert_eq!(chunked(&raw, 1 << 20).unwrap().len(), 10_000);
+    }
+
+    #[test]
+    fn a_huge_chunk_size_is_too_large_rather_than_an_overflow() {
+        let error = chunked("1\r\na\r\nffffffffffffffff\r\n", 1024).unwrap_err();
+        assert!(matches!(error, ParseError::BodyTooLarge { limit: 1024 }), "{error:?}");
+
+        let error = chunked("400\r\n", 1023).unwrap_err();
+        assert!(matches!(error, ParseError::BodyTooLarge { limit: 1023 }), "{error:?}");
+    }
+
+    #[test]
+    fn malformed_chunk_framing_is_rejected() {
+        for body in ["z\r\nabc\r\n0\r\n\r\n", "3\r\nabcdef\r\n0\r\n\r\n", "3\r\nabc\r\n", "3\nabc\n0\n\n"] {
+            let error = chunked(body, 1024).unwrap_err();
+            assert_eq!(error.status(), StatusCode::BadRequest, "{body:?}: {error:?}");
+        }
+    }
+
+    #[test]
+    fn a_head_larger_than_the_read_buffer_spans_refills() {
+        // One 12 KB cookie line and a run of smaller headers, so both a
+        // single line and the head as a whole cross 8 KB buffer boundaries.
+        let cookie = "c".repeat(12 * 1024);
+        let mut raw = format!("GET /big HTTP/1.1\r\nCookie: session={cookie}\r\n");
+        for i in 0..200 {
+            raw.push_str(&format!("X-Filler-{i}: {}\r\n", "f".repeat(40)));
+        }
+        raw.push_str("\r\nGET /next HTTP/1.1\r\n\r\n");
+        assert!(raw.len() > 2 * 8192 && raw.len() < DEFAULT_MAX_HEADER_BYTES);
+
+        let mut reader = io::BufReader::with_capacity(8192, raw.as_bytes());
+        let request = Request::parse(&mut reader).unwrap();
+        assert_eq!(request.path, "/big");
+        assert_eq!(request.headers.get("Cookie").unwrap().len(), "session=".len() + cookie.len());
+        assert_eq!(request.headers.get("X-Filler-199"), Some("f".repeat(40).as_str()));
+        assert_eq!(Request::parse(&mut reader).unwrap().path, "/next");
+    }
+
+    #[test]
+    fn a_head_over_the_limit_is_refused() {
+        let raw = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "c".repeat(16 * 1024));
+        let mut reader = io::BufReader::with_capacity(8192, raw.as_bytes());
+        let error = Request::parse_with_limit(&mut reader, 16 * 1024).unwrap_err();
+        assert!(matches!(error, ParseError::HeadersTooLarge { limit: 16384 }), "{error:?}");
+    }
+
+    #[test]
+    fn negotiation_reads_every_accept_header() {
+        let offers = ["text/html", "application/json"];
+        let html = request("GET / HTTP/1.1\r\nAccept: application/json;q=0.4\r\nAccept: text/html\r\n\r\n");
+        assert_eq!(html.negotiate(&offers), Some("text/html"));
+
+        let none = request("GET / HTTP/1.1\r\nAccept: image/*\r\n\r\n");
+        assert_eq!(none.negotiate(&offers), None);
+
+        let anything = request("GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(anything.negotiate(&offers), Some("text/html"));
+    }
+
+    fn parse_error(raw: &str) -> ParseError {
+        Request::parse(&mut raw.as_bytes()).unwrap_err()
+    }
+
+    #[test]
+    fn known_methods_parse() {
+        for method in ["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS", "TRACE", "CONNECT"] {
+            let parsed = request(&format!("{method} / HTTP/1.1\r\n\r\n")).method;
+            assert_eq!(parsed.as_str(), method);
+        }
+    }
+
+    #[test]
+    fn unknown_and_lowercase_methods_are_not_implemented() {
+        for method in ["PURGE", "get", "Post", "M-SEARCH", "BREW"] {
+            let error = parse_error(&format!("{method} / HTTP/1.1\r\n\r\n"));
+            assert!(matches!(&error, ParseError::UnknownMethod(m) if m == method), "{method}: {error:?}");
+            assert_eq!(error.status(), StatusCode::NotImplemented);
+        }
+    }
+
+    #[test]
+    fn malformed_methods_are_bad_requests() {
+        for raw in ["G(ET / HTTP/1.1\r\n\r\n", "GE\"T / HTTP/1.1\r\n\r\n", " / HTTP/1.1\r\n\r\n", "GET  / HTTP/1.1\r\n\r\n"] {
+            let error = parse_error(raw);
+            assert!(matches!(error, ParseError::Malformed(_)), "{raw:?}: {error:?}");
+            assert_eq!(error.status(), StatusCode::BadRequest);
+        }
+    }
+
+    /// Hands out `chunk` bytes per read, sleeping `delay` before each.
+    struct Slow<'a> {
+        data: &'a [u8],
+        chunk: usize,
+        delay: Duration,
+    }
+
+    impl Read for Slow<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            std::thread::sleep(self.delay);
+            let n = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn a_head_dribbled_past_the_header_timeout_times_out() {
+        let raw = b"GET /slow HTTP/1.1\r\nHost: a\r\nX-Padding: aaaaaaaaaaaaaaaa\r\n\r\n";
+        let slow = Slow { data: raw, chunk: 2, delay: Duration::from_millis(10) };
+        let mut reader = io::BufReader::new(slow);
+        let timeout = Duration::from_millis(100);
+        let error = Request::parse_with_timeout(&mut reader, DEFAULT_MAX_HEADER_BYTES, timeout).unwrap_err();
+        assert!(matches!(error, ParseError::HeadersTimedOut { limit } if limit == timeout), "{error:?}");
+        assert_eq!(error.status(), StatusCode::RequestTimeout);
+    }
+
+    #[test]
+    fn a_head_dribbled_within_the_header_timeout_parses() {
+        let raw = b"GET /slow HTTP/1.1\r\nHost: a\r\n\r\n";
+        let slow = Slow { data: raw, chunk: 4, delay: Duration::from_millis(1) };
+        let mut reader = io::BufReader::new(slow);
+        let request = Request::parse_with_timeout(&mut reader, DEFAULT_MAX_HEADER_BYTES, Duration::from_secs(10)).unwrap();
+        assert_eq!(request.path, "/slow");
+    }
+
+    /// Hands out `data` in two reads with a socket read timeout between
+    /// them, once `split` bytes are in.
+    struct Pausing<'a> {
+        data: &'a [u8],
+        split: usize,
+        paused: bool,
+    }
+
+    impl Read for Pausing<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.split == 0 && !self.paused {
+                self.paused = true;
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "read timed out"));
+            }
+            let end = if self.split > 0 { self.split } else { self.data.len() };
+            let n = end.min(buf.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            self.split -= self.split.min(n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn a_read_timeout_mid_line_is_waited_out_within_the_header_timeout() {
+        let raw = b"GET /paused HTTP/1.1\r\nHost: a\r\n\r\n";
+        let mut reader = io::BufReader::new(Pausing { data: raw, split: 9, paused: false });
+        let request = Request::parse_with_timeout(&mut reader, DEFAULT_MAX_HEADER_BYTES, Duration::from_secs(10)).unwrap();
+        assert_eq!(request.path, "/paused");
+        assert_eq!(request.header("Host"), Some("a"));
+    }
+
+    #[test]
+    fn a_read_timeout_before_the_head_starts_is_not_retried() {
+        let raw = b"GET / HTTP/1.1\r\n\r\n";
+        let mut reader = io::BufReader::new(Pausing { data: raw, split: 0, paused: false });
+        let error = Request::parse_with_timeout(&mut reader, DEFAULT_MAX_HEADER_BYTES, Duration::from_secs(10)).unwrap_err();
+        assert!(matches!(error, ParseError::Io(ref e) if e.kind() == io::ErrorKind::WouldBlock), "{error:?}");
+    }
+
+    #[test]
+    fn a_line_cut_off_by_the_end_of_the_stream_is_malformed() {
+        for raw in ["GET / HT", "GET / HTTP/1.1\r\nHost: a", "GET / HTTP/1.1\r\nHost: a\r\nX-Cut: b\r"] {
+            let error = parse_error(raw);
+            assert!(matches!(error, ParseError::Malformed(ref line) if line.contains("mid-line")), "{raw:?}: {error:?}");
+            assert_eq!(error.status(), StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn control_characters_in_the_target_are_rejected() {
+        for target in ["/a\0b", "/a\nb", "/a\rb", "/a\tb", "/a\x7fb", "/?q=\x01"] {
+            let error = parse_error(&format!("GET {target} HTTP/1.1\r\n\r\n"));
+            assert!(matches!(error, ParseError::Malformed(_)), "{target:?}: {error:?}");
+        }
+    }
+
+    #[test]
+    fn percent_encoded_control_characters_in_the_path_are_rejected() {
+        for target in ["/file%00.txt", "/a%0aSet-Cookie:x", "/a%0D%0Ab", "/a%1f", "/a%7F", "/a%7f/b?x=1"] {
+            let error = parse_error(&format!("GET {target} HTTP/1.1\r\n\r\n"));
+            assert!(matches!(error, ParseError::Malformed(_)), "{target:?}: {error:?}");
+            assert_eq!(error.status(), StatusCode::BadRequest);
+        }
+        // Ordinary escapes, and control escapes in the query, are left to
+        // the handler.
+        assert_eq!(request("GET /a%20b%2F%7e HTTP/1.1\r\n\r\n").path, "/a%20b%2F%7e");
+        assert_eq!(request("GET /search?q=%0a HTTP/1.1\r\n\r\n").query.as_deref(), Some("q=%0a"));
+    }
+
+    #[test]
+    fn header_names_must_be_tokens() {
+        for header in ["Content-Length : 5", " Content-Length: 5", "Content Length: 5", "\tHost: a", ": empty", "Bad\"Name: x"] {
+            let error = parse_error(&format!("POST / HTTP/1.1\r\n{header}\r\n\r\n"));
+            assert!(matches!(error, ParseError::Malformed(_)), "{header:?}: {error:?}");
+            assert_eq!(error.status(), StatusCode::BadRequest);
+        }
+        let request = request("GET / HTTP/1.1\r\nX-Odd_Name.v2: a:b \r\n\r\n");
+        assert_eq!(request.header("X-Odd_Name.v2"), Some("a:b"));
+    }
+
+    fn framing_error(headers: &str) -> ParseError {
+        let raw = format!("POST / HTTP/1.1\r\n{headers}\r\n\r\n");
+        let mut reader = raw.as_bytes();
+        let mut request = Request::parse(&mut reader).unwrap();
+        request.read_body(&mut reader, 1 << 20).unwrap_err()
+    }
+
+    #[test]
+    fn smuggling_framing_combinations_are_rejected() {
+        for headers in [
+            "Content-Length: 5\r\nTransfer-Encoding: chunked",
+            "Transfer-Encoding: chunked\r\nContent-Length: 0",
+            "Content-Length: 5\r\nContent-Length: 6",
+            "Content-Length: 5, 6",
+            "Transfer-Encoding: gzip",
+            "Transfer-Encoding: chunked, gzip",
+        ] {
+            let error = framing_error(headers);
+            assert!(matches!(error, ParseError::AmbiguousFraming(_)), "{headers:?}: {error:?}");
+            assert_eq!(error.status(), StatusCode::BadRequest, "{headers:?}");
+        }
+    }
+
+    #[test]
+    fn content_lengths_must_be_plain_digits() {
+        for length in ["abc", "+5", "-1", "5 5", "0x10", "", "5.0"] {
+            let error = framing_error(&format!("Content-Length: {length}"));
+            assert_eq!(error.status(), StatusCode::BadRequest, "{length:?}: {error:?}");
+        }
+        // Digits too many for any body are too large rather than malformed.
+        let error = framing_error("Content-Length: 99999999999999999999999");
+        assert_eq!(error.status(), StatusCode::PayloadTooLarge, "{error:?}");
+        // A repeat of the same length is allowed.
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nok";
+        assert_eq!(request(raw).body, b"ok");
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Order {
+        item: String,
+        quantity: u32,
+    }
+
+    #[test]
+    fn json_decodes_a_valid_body() {
+        let request = post("application/json; charset=utf-8", r#"{"item":"tea","quantity":2}"#);
+        let order: Order = request.json().unwrap();
+        assert_eq!(order, Order { item: "tea".to_string(), quantity: 2 });
+    }
+
+    #[test]
+    fn json_rejects_invalid_and_empty_bodies_with_400() {
+        for body in [r#"{"item":"tea","quantity":"two"}"#, "{not json", ""] {
+            let error = post("application/json", body).json::<Order>().unwrap_err();
+            assert!(matches!(error, ParseError::InvalidBody(_)), "{body:?}: {error:?}");
+            assert_eq!(error.status(), StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn json_requires_the_json_content_type() {
+        let error = post("text/plain", r#"{"item":"tea","quantity":2}"#).json::<Order>().unwrap_err();
+        assert!(matches!(error, ParseError::ContentType { expected: "application/json" }));
+        assert_eq!(error.status(), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn form_decodes_percent_escapes_and_plus_signs() {
+        let form = post(
+            "application/x-www-form-urlencoded",
+            "name=Zo%C3%AB+Smith&email=zoe%40example.com&note=50%25+off%21&empty=&flag",
+        )
+        .form()
+        .unwrap();
+        assert_eq!(form["name"], "Zoë Smith");
+        assert_eq!(form["email"], "zoe@example.com");
+        assert_eq!(form["note"], "50% off!");
+        assert_eq!(form["empty"], "");
+        assert_eq!(form["flag"], "");
+        assert_eq!(form.len(), 5);
+    }
+
+    #[test]
+    fn form_requires_the_form_content_type() {
+        let error = post("application/json", "a=1").form().unwrap_err();
+        assert!(matches!(error, ParseError::ContentType { expected: "application/x-www-form-urlencoded" }));
+        assert_eq!(error.status(), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn body_over_the_limit_is_refused_before_it_is_read() {
+        let raw = "POST / HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 11\r\n\r\n";
+        let mut reader = raw.as_bytes();
+        let mut request = Request::parse(&mut reader).unwrap();
+        let error = request.read_body(&mut reader, 10).unwrap_err();
+        assert!(matches!(error, ParseError::BodyTooLarge { limit: 10 }));
+        assert_eq!(error.status(), StatusCode::PayloadTooLarge);
+    }
+}
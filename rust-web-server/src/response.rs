@@ -0,0 +1,436 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::time::Instant;
+use tracing::error;
+use metrics::counter;
+use serde::Serialize;
+
+use crate::cookie::Cookie;
+use crate::headers::Headers;
+use crate::status::StatusCode;
+
+/// Bodies up to this size go out in the same write as the head, so a small
+/// response isn't split into two segments (the second one held back by
+/// Nagle's algorithm until the first is acknowledged).
+const INLINE_BODY_BYTES: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    content_length: Option<u64>,
+    stream: Option<BodyStream>,
+    /// Whether a streamed body is sent chunked. HTTP/1.0 clients don't
+    /// understand chunked coding, so theirs is delimited by closing.
+    chunked: bool,
+}
+
+/// Body chunks produced while the response is being sent, each written and
+/// flushed as soon as it is available.
+pub struct BodyStream(Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>);
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BodyStream")
+    }
+}
+
+impl BodyStream {
+    /// Writes the chunks with chunked transfer coding, ending with the
+    /// terminating chunk so the connection can be reused. The first failed
+    /// write (usually the client going away) ends the stream, as do a chunk
+    /// that failed to be produced and `deadline` passing, without the
+    /// terminating chunk. Each chunk's length is added to `sent` once it is
+    /// written, so a stream cut short still reports what went out.
+    pub(crate) fn write_to<W: Write>(self, writer: &mut W, deadline: Option<Instant>, sent: &mut u64) -> io::Result<()> {
+        for chunk in self.0 {
+            let chunk = chunk?;
+            if chunk.is_empty() {
+                continue;
+            }
+            check_deadline(deadline)?;
+            write!(writer, "{:x}\r\n", chunk.len())?;
+            writer.write_all(&chunk)?;
+            writer.write_all(b"\r\n")?;
+            writer.flush()?;
+            *sent += chunk.len() as u64;
+        }
+        writer.write_all(b"0\r\n\r\n")?;
+        writer.flush()
+    }
+
+    /// Writes the chunks as they are, for a body that ends when the
+    /// connection is closed.
+    pub(crate) fn write_unframed<W: Write>(self, writer: &mut W, deadline: Option<Instant>, sent: &mut u64) -> io::Result<()> {
+        for chunk in self.0 {
+            check_deadline(deadline)?;
+            let chunk = chunk?;
+            writer.write_all(&chunk)?;
+            writer.flush()?;
+            *sent += chunk.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+fn check_deadline(deadline: Option<Instant>) -> io::Result<()> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "connection deadline exceeded"))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Vec::new(),
+            content_length: None,
+            stream: None,
+            chunked: true,
+        }
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Advertises a `Content-Length` without carrying the body, for answering
+    /// HEAD requests from metadata alone.
+    pub fn with_content_length(mut self, length: u64) -> Response {
+        self.content_length = Some(length);
+        self
+    }
+
+    /// The `Content-Length` this response will be sent with.
+    pub fn content_length(&self) -> u64 {
+        self.content_length.unwrap_or(self.body.len() as u64)
+    }
+
+    /// Drops the body but keeps the `Content-Length` a GET would have sent.
+    /// A streamed body is dropped without being produced.
+    pub fn without_body(mut self) -> Response {
+        if self.content_length.is_none() && self.stream.is_none() {
+            self.content_length = Some(self.body.len() as u64);
+        }
+        self.body = Vec::new();
+        self.stream = None;
+        self
+    }
+
+    /// Sends the body as it is produced instead of all at once, for
+    /// responses of unknown length. HTTP/1.1 only.
+    pub fn with_stream(mut self, chunks: impl Iterator<Item = Vec<u8>> + Send + 'static) -> Response {
+        self.stream = Some(BodyStream(Box::new(chunks.map(Ok))));
+        self
+    }
+
+    /// Like [`Response::with_stream`], for a source that can fail midway: an
+    /// error ends the body without its terminating chunk and closes the
+    /// connection, so the client can tell the body is incomplete.
+    pub(crate) fn with_fallible_stream(
+        mut self,
+        chunks: impl Iterator<Item = io::Result<Vec<u8>>> + Send + 'static,
+    ) -> Response {
+        self.stream = Some(BodyStream(Box::new(chunks)));
+        self
+    }
+
+    pub(crate) fn is_streamed(&self) -> bool {
+        self.stream.is_some() && self.allows_body()
+    }
+
+    /// Sends a streamed body without chunked coding, delimited by closing
+    /// the connection afterwards.
+    pub(crate) fn close_delimited(&mut self) {
+        self.chunked = false;
+    }
+
+    /// Removes the streamed body so it can be written after the head. A
+    /// status that can't have a body has none to write.
+    pub(crate) fn take_stream(&mut self) -> Option<BodyStream> {
+        let stream = self.stream.take();
+        stream.filter(|_| self.allows_body())
+    }
+
+    /// Sets a header, replacing any previous value with the same name.
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Adds a header alongside any existing values with the same name.
+    pub fn append_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn set_cookie(self, cookie: &Cookie) -> Response {
+        self.append_header("Set-Cookie", &cookie.to_string())
+    }
+
+    /// Serializes `value` as a 200 JSON response, or a 500 if it can't be serialized.
+    pub fn json<T: Serialize>(value: &T) -> Response {
+        match serde_json::to_string(value) {
+            Ok(body) => Response::new(StatusCode::Ok)
+                .with_header("Content-Type", "application/json")
+                .with_body(body),
+            Err(e) => {
+                error!("Failed to serialize JSON response: {}", e);
+                counter!("json_serialize_errors_total", 1);
+                Response::new(StatusCode::InternalServerError)
+            }
+        }
+    }
+
+    /// An empty-bodied redirect to `location`.
+    ///
+    /// # Panics
+    ///
+    /// If `status` is not 301, 302, 307 or 308.
+    pub fn redirect(status: StatusCode, location: &str) -> Response {
+        assert!(status.is_redirect(), "{} is not a redirect status", status);
+        Response::new(status).with_header("Location", location)
+    }
+
+    /// Builds a response from a file on disk, answering 500 if it can't be read.
+    pub fn from_file(status: StatusCode, filename: &str) -> Response {
+        match fs::read(filename) {
+            Ok(contents) => Response::new(status).with_body(contents),
+            Err(e) => {
+                error!("Failed to read file {}: {}", filename, e);
+                counter!("file_read_errors_total", 1);
+                Response::new(StatusCode::InternalServerError)
+            }
+        }
+    }
+
+    /// Whether the status may have a body at all. 1xx, 204 and 304
+    /// responses end with their head, so a body a handler gave one is
+    /// never written; the client would read it as the next response.
+    fn allows_body(&self) -> bool {
+        !matches!(self.status.as_u16(), 0..=199 | 204 | 304)
+    }
+
+    /// Whether the head carries a `Content-Length`. 1xx and 204 responses
+    /// never do, and a 304 only when it was given the length a 200 would
+    /// have had (a bare `0` would misdescribe the cached representation).
+    fn sends_content_length(&self) -> bool {
+        match self.status.as_u16() {
+            ..=199 | 204 => false,
+            304 => self.content_length.is_some(),
+            _ => true,
+        }
+    }
+
+    /// Writes the full response. Every response is framed, so keep-alive
+    /// clients can tell where it ends: `Content-Length` is the byte length
+    /// of the final body (or the length kept for a bodiless HEAD answer),
+    /// so any value a handler set is replaced. A streamed response is sent
+    /// chunked instead (or close-delimited, see `close_delimited`); only its
+    /// head is written here, the chunks follow from [`BodyStream::write_to`].
+    ///
+    /// A body bigger than `INLINE_BODY_BYTES` is written on its own after
+    /// the head rather than copied behind it. Nothing is flushed; callers
+    /// flush once the whole response is written.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut response = format!("HTTP/1.1 {}\r\n", self.status).into_bytes();
+        if self.stream.is_some() && self.allows_body() {
+            if self.chunked {
+                response.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+            }
+        } else if self.sends_content_length() {
+            write!(response, "Content-Length: {}\r\n", self.content_length())?;
+        }
+        self.headers.write_except(&mut response, &["Content-Length", "Transfer-Encoding"])?;
+        response.extend_from_slice(b"\r\n");
+        let body: &[u8] = if self.allows_body() { &self.body } else { &[] };
+        if body.len() <= INLINE_BODY_BYTES {
+            response.extend_from_slice(body);
+            return writer.write_all(&response);
+        }
+        writer.write_all(&response)?;
+        writer.write_all(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        id: u32,
+        name: String,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn json_round_trips_a_struct() {
+        let user = User {
+            id: 7,
+            name: "Zoë \"z\"".to_string(),
+            tags: vec!["admin".to_string()],
+        };
+        let response = Response::json(&user);
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(response.headers.get("Content-Type"), Some("application/json"));
+        assert_eq!(response.content_length(), response.body.len() as u64);
+        assert_eq!(serde_json::from_slice::<User>(&response.body).unwrap(), user);
+
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.contains(&format!("Content-Length: {}\r\n", response.body.len())));
+    }
+
+    #[test]
+    fn content_length_matches_the_bytes_written_for_multibyte_bodies() {
+        let body = "naïve café — 日本語 🦀";
+        for size in [body.len(), INLINE_BODY_BYTES + 1] {
+            let text = body.repeat(size / body.len() + 1);
+            let mut written = Vec::new();
+            Response::new(StatusCode::Ok).with_body(text.clone()).write_to(&mut written).unwrap();
+
+            let head_end = written.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            let head = std::str::from_utf8(&written[..head_end]).unwrap();
+            assert!(head.contains(&format!("Content-Length: {}\r\n", text.len())), "{head}");
+            assert_eq!(&written[head_end..], text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn redirect_sets_location_and_sends_no_body() {
+        for status in [
+            StatusCode::MovedPermanently,
+            StatusCode::Found,
+            StatusCode::TemporaryRedirect,
+            StatusCode::PermanentRedirect,
+        ] {
+            let response = Response::redirect(status, "/new?page=2");
+            assert_eq!(response.status, status);
+            assert_eq!(response.headers.get("Location"), Some("/new?page=2"));
+            assert!(response.body.is_empty());
+            assert_eq!(response.content_length(), 0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a redirect status")]
+    fn redirect_refuses_a_non_redirect_status() {
+        Response::redirect(StatusCode::Ok, "/new");
+    }
+
+    #[test]
+    fn json_that_cannot_be_serialized_is_a_500() {
+        // JSON object keys have to be strings.
+        let value: HashMap<(u8, u8), u8> = [((1, 2), 3)].into_iter().collect();
+        let response = Response::json(&value);
+        assert_eq!(response.status, StatusCode::InternalServerError);
+        assert!(response.body.is_empty());
+    }
+
+    fn head_of(response: &Response) -> String {
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+        let head_end = written.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        String::from_utf8(written[..head_end].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn content_length_is_set_from_the_body_whatever_the_handler_said() {
+        let response = Response::new(StatusCode::Ok)
+            .with_header("Content-Length", "999")
+            .with_header("Transfer-Encoding", "chunked")
+            .with_body("hello");
+        let head = head_of(&response);
+        assert!(head.contains("Content-Length: 5\r\n"), "{head}");
+        assert!(!head.contains("999") && !head.contains("Transfer-Encoding"), "{head}");
+
+        let empty = head_of(&Response::new(StatusCode::Ok));
+        assert!(empty.contains("Content-Length: 0\r\n"), "{empty}");
+    }
+
+    /// Records each write, so a test can see how a response was split.
+    #[derive(Default)]
+    struct Writes(Vec<(*const u8, usize)>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push((buf.as_ptr(), buf.len()));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn large_bodies_are_written_after_the_head_without_a_copy() {
+        let response = Response::new(StatusCode::Ok).with_body(vec![b'x'; INLINE_BODY_BYTES + 1]);
+        let mut writes = Writes::default();
+        response.write_to(&mut writes).unwrap();
+        assert_eq!(writes.0.len(), 2);
+        assert_eq!(writes.0[0].1, head_of(&response).len());
+        assert_eq!(writes.0[1], (response.body.as_ptr(), response.body.len()));
+
+        let small = Response::new(StatusCode::Ok).with_body(vec![b'x'; INLINE_BODY_BYTES]);
+        let mut writes = Writes::default();
+        small.write_to(&mut writes).unwrap();
+        assert_eq!(writes.0.len(), 1);
+    }
+
+    #[test]
+    fn bodiless_statuses_get_no_content_length() {
+        let head = head_of(&Response::new(StatusCode::NoContent));
+        assert!(!head.contains("Content-Length"), "{head}");
+        let head = head_of(&Response::new(StatusCode::NotModified));
+        assert!(!head.contains("Content-Length"), "{head}");
+        let head = head_of(&Response::new(StatusCode::NotModified).with_content_length(42));
+        assert!(head.contains("Content-Length: 42\r\n"), "{head}");
+    }
+
+    #[test]
+    fn bodiless_statuses_never_write_a_body() {
+        for status in [StatusCode::NoContent, StatusCode::NotModified] {
+            let mut written = Vec::new();
+            Response::new(status).with_body("leaked").write_to(&mut written).unwrap();
+            assert!(written.ends_with(b"\r\n\r\n"), "{:?}", String::from_utf8_lossy(&written));
+
+            let mut response = Response::new(status).with_stream(vec![b"leaked".to_vec()].into_iter());
+            let head = head_of(&response);
+            assert!(!head.contains("Transfer-Encoding"), "{head}");
+            assert!(response.take_stream().is_none());
+        }
+    }
+
+    #[test]
+    fn streamed_bodies_are_chunked_instead() {
+        let response = Response::new(StatusCode::Ok)
+            .with_header("Content-Length", "3")
+            .with_stream(vec![b"abc".to_vec()].into_iter());
+        let head = head_of(&response);
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{head}");
+        assert!(!head.contains("Content-Length"), "{head}");
+    }
+
+    #[test]
+    fn header_values_with_line_breaks_are_not_written() {
+        let response = Response::new(StatusCode::Ok)
+            .with_header("Location", "/next\r\nSet-Cookie: session=stolen")
+            .with_header("X-Id", "7");
+        let head = head_of(&response);
+        assert!(!head.contains("Set-Cookie"), "{head}");
+        assert!(!head.contains("Location"), "{head}");
+        assert!(head.contains("X-Id: 7\r\n"), "{head}");
+    }
+}
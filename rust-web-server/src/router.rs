@@ -0,0 +1,465 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::query::percent_decode;
+use crate::request::{Method, Request};
+use crate::response::Response;
+use crate::status::StatusCode;
+use crate::{Context, HandlerError, HandlerOutput};
+
+pub type Handler = Arc<dyn Fn(&mut Context) -> Result<Response, HandlerError> + Send + Sync>;
+
+/// The route label of requests no route matched, which the fallback handles.
+pub(crate) const FALLBACK_ROUTE: &str = "fallback";
+
+/// How `/about` and `/about/` relate. The root path `/` is never rewritten.
+///
+/// - `Strict` (the default) keeps them as different routes.
+/// - `Equivalent` lets either form match a route registered under the other.
+/// - `Add` and `Strip` redirect to the form with or without the slash, so
+///   each resource has a single canonical URL. Only a form that some route
+///   serves is redirected to; anything else goes to the fallback unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrailingSlash {
+    #[default]
+    Strict,
+    Equivalent,
+    Add,
+    Strip,
+}
+
+impl TrailingSlash {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            TrailingSlash::Strict => "strict",
+            TrailingSlash::Equivalent => "equivalent",
+            TrailingSlash::Add => "add",
+            TrailingSlash::Strip => "strip",
+        }
+    }
+
+    /// The path a request should be redirected to, if it isn't canonical.
+    /// Paths starting `//` or `/\` are never redirected: as a `Location`
+    /// browsers read them as a protocol-relative URL on another host.
+    pub fn redirect_for(&self, path: &str) -> Option<String> {
+        if path == "/" || path.starts_with("//") || path.starts_with("/\\") {
+            return None;
+        }
+        match self {
+            TrailingSlash::Add if !path.ends_with('/') => Some(format!("{}/", path)),
+            TrailingSlash::Strip if path.ends_with('/') => {
+                let stripped = path.trim_end_matches('/');
+                (!stripped.is_empty()).then(|| stripped.to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for TrailingSlash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(TrailingSlash::Strict),
+            "equivalent" => Ok(TrailingSlash::Equivalent),
+            "add" => Ok(TrailingSlash::Add),
+            "strip" => Ok(TrailingSlash::Strip),
+            _ => Err(format!("unknown trailing-slash mode: {}", s)),
+        }
+    }
+}
+
+/// Maps (method, path) pairs to handlers, with a fallback for unmatched requests.
+///
+/// A path segment written `:name` matches any single non-empty segment and
+/// is captured into the handler's [`Context::params`]. A final `*` segment
+/// matches the rest of the path, any number of segments or none (so `/api/*`
+/// covers `/api` and `/api/users/1`), captured undecoded as `*`. Exact routes
+/// win over patterns, and patterns over wildcards; among patterns the one
+/// with the fewest parameters wins, among wildcards the longest.
+///
+/// Routes may override the server's request timeout; the deadline handlers
+/// see through [`Request::time_remaining`] is then based on it.
+pub struct Router {
+    routes: HashMap<(Method, String), Handler>,
+    timeouts: HashMap<String, Duration>,
+    streaming: HashSet<(Method, String)>,
+    /// Routes run on the blocking pool, by registered path.
+    blocking: HashSet<String>,
+    fallback: Handler,
+    trailing_slash: TrailingSlash,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: HashMap::new(),
+            timeouts: HashMap::new(),
+            streaming: HashSet::new(),
+            blocking: HashSet::new(),
+            fallback: Arc::new(|_: &mut Context| Err(HandlerError::NotFound)),
+            trailing_slash: TrailingSlash::default(),
+        }
+    }
+
+    pub fn set_trailing_slash(&mut self, trailing_slash: TrailingSlash) {
+        self.trailing_slash = trailing_slash;
+    }
+
+    pub fn trailing_slash(&self) -> TrailingSlash {
+        self.trailing_slash
+    }
+
+    /// Gives requests to `path` (as registered, so `/users/:id` for a
+    /// pattern) their own deadline, whatever the method.
+    pub fn set_timeout(&mut self, path: &str, timeout: Duration) {
+        self.timeouts.insert(path.to_string(), timeout);
+    }
+
+    /// The timeout for a route returned by [`Router::route`], if it has one.
+    pub fn timeout_for(&self, route: &str) -> Option<Duration> {
+        self.timeouts.get(route).copied()
+    }
+
+    /// Marks a route (every method on `path`) as slow or blocking, so it
+    /// runs on the server's blocking pool when one is configured and can't
+    /// hold up the workers answering everything else.
+    pub fn set_blocking(&mut self, path: &str) {
+        self.blocking.insert(path.to_string());
+    }
+
+    /// Whether the route a request goes to was marked with [`Router::set_blocking`].
+    pub fn is_blocking(&self, request: &Request) -> bool {
+        if self.blocking.is_empty() {
+            return false;
+        }
+        let (route, _, _) = self.route(request);
+        self.blocking.contains(route)
+    }
+
+    pub fn register<F, R>(&mut self, method: Method, path: &str, handler: F)
+    where
+        F: Fn(&mut Context) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        let handler: Handler = Arc::new(move |context: &mut Context| handler(context).into_result());
+        self.routes.insert((method, path.to_string()), handler);
+    }
+
+    /// Registers a handler that reads the request body itself, through
+    /// [`Context::body_reader`], instead of having it buffered up front.
+    pub fn register_streaming<F, R>(&mut self, method: Method, path: &str, handler: F)
+    where
+        F: Fn(&mut Context) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        self.register(method, path, handler);
+        self.streaming.insert((method, path.to_string()));
+    }
+
+    /// Whether the route a request goes to reads its body as a stream.
+    pub fn streams_body(&self, request: &Request) -> bool {
+        if self.streaming.is_empty() {
+            return false;
+        }
+        let (route, _, _) = self.route(request);
+        self.streaming.contains(&(request.method, route.to_string()))
+    }
+
+    /// Moves every route of `router` under `prefix`, so `/users/:id`
+    /// mounted at `/api/v1` is served at `/api/v1/users/:id` (and the
+    /// sub-router's `/` at `/api/v1` itself). Route timeouts, streaming and
+    /// blocking registrations move with their routes; the sub-router's
+    /// fallback and trailing-slash mode are not used. The prefix may contain
+    /// `:name` segments, captured like any other. Routers can be mounted at
+    /// overlapping prefixes (`/api` and `/api/v1`), as long as no two end up
+    /// with the same route.
+    ///
+    /// # Panics
+    ///
+    /// If `prefix` doesn't start with `/`, or a mounted route is already
+    /// registered here.
+    pub fn mount(&mut self, prefix: &str, router: Router) {
+        assert!(prefix.starts_with('/'), "mount prefix {:?} must start with '/'", prefix);
+        let prefix = prefix.trim_end_matches('/');
+        let full_path = |path: &str| match (prefix, path) {
+            ("", path) => path.to_string(),
+            (prefix, "/") => prefix.to_string(),
+            (prefix, path) => format!("{}{}", prefix, path),
+        };
+        for ((method, path), handler) in router.routes {
+            let mounted = full_path(&path);
+            assert!(
+                !self.routes.contains_key(&(method, mounted.clone())),
+                "{} {} is already registered",
+                method,
+                mounted
+            );
+            if router.streaming.contains(&(method, path)) {
+                self.streaming.insert((method, mounted.clone()));
+            }
+            self.routes.insert((method, mounted), handler);
+        }
+        for (path, timeout) in router.timeouts {
+            self.timeouts.insert(full_path(&path), timeout);
+        }
+        for path in router.blocking {
+            self.blocking.insert(full_path(&path));
+        }
+    }
+
+    /// Answers GET and HEAD for `from` with a redirect to `to`.
+    ///
+    /// # Panics
+    ///
+    /// If `status` is not a redirect status.
+    pub fn redirect(&mut self, from: &str, to: &str, status: StatusCode) {
+        assert!(status.is_redirect(), "{} is not a redirect status", status);
+        let location = to.to_string();
+        self.register(Method::Get, from, move |_: &mut Context| Response::redirect(status, &location));
+    }
+
+    pub fn fallback<F, R>(&mut self, handler: F)
+    where
+        F: Fn(&mut Context) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        self.fallback = Arc::new(move |context: &mut Context| handler(context).into_result());
+    }
+
+    /// Returns the matched route's path (used as a metrics label, so patterns
+    /// are reported unexpanded), its handler and the captured parameters.
+    /// HEAD requests use the GET handler unless a HEAD route is registered;
+    /// the server drops the body before writing.
+    pub fn route(&self, request: &Request) -> (&str, &Handler, HashMap<String, String>) {
+        self.lookup_route(request.method, &request.path)
+            .unwrap_or((FALLBACK_ROUTE, &self.fallback, HashMap::new()))
+    }
+
+    /// Where the trailing-slash mode redirects `request`, if anywhere. Only
+    /// a canonical form some route serves is redirected to, so a path
+    /// neither form of which routes falls through to the fallback as is.
+    pub fn canonical_redirect(&self, request: &Request) -> Option<String> {
+        let canonical = self.trailing_slash.redirect_for(&request.path)?;
+        self.lookup_route(request.method, &canonical).is_some().then_some(canonical)
+    }
+
+    fn lookup_route(&self, method: Method, path: &str) -> Option<(&str, &Handler, HashMap<String, String>)> {
+        let found = self.lookup(method, path);
+        if found.is_none() && method == Method::Head {
+            return self.lookup(Method::Get, path);
+        }
+        found
+    }
+}
+
+impl Router {
+    /// Methods with a handler for `path`, for an `Allow` header. HEAD is
+    /// implied by GET. Paths only the fallback serves report GET and HEAD.
+    pub fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let mut methods: Vec<Method> = self
+            .routes
+            .keys()
+            .filter(|(_, route)| match_pattern(route, path).is_some())
+            .map(|(method, _)| *method)
+            .collect();
+        if methods.is_empty() {
+            methods.push(Method::Get);
+        }
+        if methods.contains(&Method::Get) && !methods.contains(&Method::Head) {
+            methods.push(Method::Head);
+        }
+        methods.sort_by_key(|method| method.as_str());
+        // An exact route and a pattern can both cover the path.
+        methods.dedup();
+        methods
+    }
+
+    /// Every registered (method, path) pair, sorted by path and then method.
+    pub fn routes(&self) -> Vec<(Method, &str)> {
+        let mut routes: Vec<(Method, &str)> = self
+            .routes
+            .keys()
+            .map(|(method, path)| (*method, path.as_str()))
+            .collect();
+        routes.sort_by_key(|(method, path)| (*path, method.as_str()));
+        routes
+    }
+
+    fn lookup(&self, method: Method, path: &str) -> Option<(&str, &Handler, HashMap<String, String>)> {
+        let found = self.find(method, path);
+        if found.is_some() || self.trailing_slash != TrailingSlash::Equivalent || path == "/" {
+            return found;
+        }
+        let other = match path.strip_suffix('/') {
+            Some(stripped) => stripped.to_string(),
+            None => format!("{}/", path),
+        };
+        self.find(method, &other)
+    }
+
+    fn find(&self, method: Method, path: &str) -> Option<(&str, &Handler, HashMap<String, String>)> {
+        if let Some(((_, route), handler)) = self.routes.get_key_value(&(method, path.to_string())) {
+            return Some((route, handler, HashMap::new()));
+        }
+        self.routes
+            .iter()
+            .filter(|((route_method, route), _)| *route_method == method && (route.contains("/:") || route.ends_with("/*")))
+            .filter_map(|((_, route), handler)| Some((route.as_str(), handler, match_pattern(route, path)?)))
+            .min_by_key(|(route, _, params)| (route.ends_with("/*"), params.len(), Reverse(route.len()), *route))
+    }
+}
+
+/// Matches `path` against a route, capturing its `:name` segments
+/// (percent-decoded) and a final `*`. A route without either only matches
+/// itself.
+fn match_pattern(route: &str, path: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    let mut route_segments = route.split('/').peekable();
+    let mut path_segments = path.split('/');
+    loop {
+        match (route_segments.next(), path_segments.next()) {
+            (None, None) => return Some(params),
+            (Some("*"), segment) if route_segments.peek().is_none() => {
+                let rest = segment.map(|segment| path_segments.fold(segment.to_string(), |rest, s| rest + "/" + s));
+                params.insert("*".to_string(), rest.unwrap_or_default());
+                return Some(params);
+            }
+            (Some(expected), Some(segment)) => match expected.strip_prefix(':') {
+                Some(name) if !segment.is_empty() => {
+                    params.insert(name.to_string(), percent_decode(segment, false));
+                }
+                None if expected == segment => {}
+                _ => return None,
+            },
+            _ => return None,
+        }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(path: &str) -> Request {
+        let raw = format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", path);
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn ok(_: &mut Context) -> Response {
+        Response::new(StatusCode::Ok)
+    }
+
+    /// The route `path` goes to, or "fallback".
+    fn routed<'a>(router: &'a Router, path: &str) -> &'a str {
+        router.route(&get(path)).0
+    }
+
+    #[test]
+    fn redirect_for_adds_or_strips_the_slash() {
+        assert_eq!(TrailingSlash::Add.redirect_for("/about"), Some("/about/".to_string()));
+        assert_eq!(TrailingSlash::Add.redirect_for("/about/"), None);
+        assert_eq!(TrailingSlash::Strip.redirect_for("/about/"), Some("/about".to_string()));
+        assert_eq!(TrailingSlash::Strip.redirect_for("/about//"), Some("/about".to_string()));
+        assert_eq!(TrailingSlash::Strip.redirect_for("/about"), None);
+        for mode in [TrailingSlash::Strict, TrailingSlash::Equivalent, TrailingSlash::Add, TrailingSlash::Strip] {
+            assert_eq!(mode.redirect_for("/"), None, "{}", mode.as_str());
+        }
+        assert_eq!(TrailingSlash::Strict.redirect_for("/about/"), None);
+        assert_eq!(TrailingSlash::Equivalent.redirect_for("/about"), None);
+        // These would leave the site as protocol-relative Locations.
+        for path in ["//evil.example", "//evil.example/", "/\\evil.example", "///evil.example/"] {
+            assert_eq!(TrailingSlash::Add.redirect_for(path), None, "{path}");
+            assert_eq!(TrailingSlash::Strip.redirect_for(path), None, "{path}");
+        }
+    }
+
+    #[test]
+    fn equivalent_mode_matches_either_form() {
+        let mut router = Router::new();
+        router.register(Method::Get, "/about", ok);
+        router.register(Method::Get, "/docs/", ok);
+        router.register(Method::Get, "/users/:id", ok);
+
+        assert_eq!(routed(&router, "/about/"), "fallback");
+        router.set_trailing_slash(TrailingSlash::Equivalent);
+        assert_eq!(routed(&router, "/about"), "/about");
+        assert_eq!(routed(&router, "/about/"), "/about");
+        assert_eq!(routed(&router, "/docs"), "/docs/");
+        assert_eq!(routed(&router, "/users/7/"), "/users/:id");
+        assert_eq!(routed(&router, "/abou"), "fallback");
+    }
+
+    fn users() -> Router {
+        let mut users = Router::new();
+        users.register(Method::Get, "/", ok);
+        users.register(Method::Get, "/users/:id", ok);
+        users.register_streaming(Method::Post, "/users/:id/avatar", ok);
+        users.set_timeout("/users/:id", Duration::from_millis(250));
+        users
+    }
+
+    #[test]
+    fn mounted_routes_are_served_under_the_prefix() {
+        let mut router = Router::new();
+        router.register(Method::Get, "/users/:id", ok);
+        router.mount("/api/v1/", users());
+
+        assert_eq!(routed(&router, "/api/v1"), "/api/v1");
+        assert_eq!(routed(&router, "/api/v1/users/7"), "/api/v1/users/:id");
+        assert_eq!(routed(&router, "/users/7"), "/users/:id");
+        assert_eq!(routed(&router, "/api/v1/users"), "fallback");
+        assert_eq!(routed(&router, "/api/users/7"), "fallback");
+
+        let (_, _, params) = router.route(&get("/api/v1/users/7"));
+        assert_eq!(params.get("id").map(String::as_str), Some("7"));
+    }
+
+    #[test]
+    fn settings_move_with_mounted_routes() {
+        let mut router = Router::new();
+        router.mount("/api", users());
+        assert_eq!(router.timeout_for("/api/users/:id"), Some(Duration::from_millis(250)));
+        assert_eq!(router.timeout_for("/users/:id"), None);
+
+        let raw = "POST /api/users/7/avatar HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\n\r\n";
+        assert!(router.streams_body(&Request::parse(&mut raw.as_bytes()).unwrap()));
+    }
+
+    #[test]
+    fn overlapping_prefixes_and_prefix_params_route_independently() {
+        let mut v2 = Router::new();
+        v2.register(Method::Get, "/users/:id", ok);
+        let mut router = Router::new();
+        router.mount("/api", users());
+        router.mount("/api/v2", v2);
+        let mut tenants = Router::new();
+        tenants.register(Method::Get, "/users/:id", ok);
+        router.mount("/t/:tenant", tenants);
+
+        assert_eq!(routed(&router, "/api/users/1"), "/api/users/:id");
+        assert_eq!(routed(&router, "/api/v2/users/1"), "/api/v2/users/:id");
+        let (route, _, params) = router.route(&get("/t/acme/users/9"));
+        assert_eq!(route, "/t/:tenant/users/:id");
+        assert_eq!(params.get("tenant").map(String::as_str), Some("acme"));
+        assert_eq!(params.get("id").map(String::as_str), Some("9"));
+    }
+
+    #[test]
+    #[should_panic(expected = "GET /api/users/:id is already registered")]
+    fn mounting_over_an_existing_route_panics() {
+        let mut router = Router::new();
+        router.register(Method::Get, "/api/users/:id", ok);
+        router.mount("/api", users());
+    }
+}
@@ -0,0 +1,1552 @@
+use std::{
+    io::{prelude::*, BufReader, ErrorKind},
+    net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    collections::HashMap,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn, error, instrument};
+use metrics::counter;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use uuid::Uuid;
+
+use crate::body_log;
+use crate::compression::{self, compress_response};
+use crate::config::Config;
+use crate::limits::{AcceptRateLimiter, Admission, CapGuard, CapMode, ConnectionCap, ConnectionLimiter};
+use crate::maintenance::{self, Maintenance};
+use crate::metrics_backend::{GlobalRecorder, Metrics};
+use crate::mime::with_charset;
+use crate::not_found::NotFoundPolicy;
+use crate::request::{BodyReader, Framing, Method, ParseError, Request};
+use crate::response::Response;
+use crate::router::{Handler, Router, FALLBACK_ROUTE};
+use crate::stats::{ActiveConnection, ServerStats};
+use crate::status::StatusCode;
+#[cfg(feature = "websocket")]
+use crate::websocket::{WebSocket, WebSocketHandler};
+use crate::{
+    panic_message, Context, HandlerError, HandlerOutput, HookError, PoolConfig, PoolHandle, PoolStats, ShutdownHook,
+    StartupHook, ThreadPool,
+};
+
+/// Turns a handler's error into the response sent for it.
+pub type ErrorHandler = Arc<dyn Fn(&HandlerError, &Request) -> Response + Send + Sync>;
+
+pub struct Server {
+    router: Router,
+    error_handler: ErrorHandler,
+    config: Config,
+    shutdown: ShutdownHandle,
+    maintenance: Maintenance,
+    metrics: Arc<dyn Metrics>,
+    stats: Arc<ServerStats>,
+    startup_hooks: Vec<StartupHook>,
+    shutdown_hooks: Vec<ShutdownHook>,
+    #[cfg(feature = "websocket")]
+    websockets: HashMap<String, WebSocketHandler>,
+}
+
+impl Server {
+    pub fn new(config: Config) -> Server {
+        Server {
+            router: Router::new(),
+            error_handler: default_error_handler(config.not_found.clone()),
+            maintenance: Maintenance::new(config.maintenance, config.health_path.as_deref()),
+            metrics: Arc::new(GlobalRecorder),
+            stats: Arc::new(ServerStats::default()),
+            startup_hooks: Vec::new(),
+            shutdown_hooks: Vec::new(),
+            config,
+            shutdown: ShutdownHandle::default(),
+            #[cfg(feature = "websocket")]
+            websockets: HashMap::new(),
+        }
+    }
+
+    /// A handle that stops `run` from another thread (e.g. a signal handler).
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Live request and connection counts, for [`crate::stats::handler`].
+    pub fn stats(&self) -> Arc<ServerStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// The maintenance-mode switch, starting out as `config.maintenance`.
+    pub fn maintenance(&self) -> Maintenance {
+        self.maintenance.clone()
+    }
+
+    pub fn register<F, R>(&mut self, method: Method, path: &str, handler: F)
+    where
+        F: Fn(&mut Context) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        self.router.register(method, path, handler);
+    }
+
+    /// Overrides the request timeout for one route. `ROUTE_TIMEOUTS_MS`
+    /// entries take precedence over timeouts set here.
+    pub fn route_timeout(&mut self, path: &str, timeout: Duration) {
+        self.router.set_timeout(path, timeout);
+    }
+
+    /// Marks a route as slow or blocking, to be answered on the blocking
+    /// pool (`BLOCKING_POOL_SIZE`). Without that pool this changes nothing.
+    pub fn blocking_route(&mut self, path: &str) {
+        self.router.set_blocking(path);
+    }
+
+    /// See [`Router::register_streaming`].
+    pub fn register_streaming<F, R>(&mut self, method: Method, path: &str, handler: F)
+    where
+        F: Fn(&mut Context) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        self.router.register_streaming(method, path, handler);
+    }
+
+    /// See [`Router::mount`].
+    pub fn mount(&mut self, prefix: &str, router: Router) {
+        self.router.mount(prefix, router);
+    }
+
+    pub fn redirect(&mut self, from: &str, to: &str, status: StatusCode) {
+        self.router.redirect(from, to, status);
+    }
+
+    /// Accepts WebSocket upgrades on `path` and runs `handler` on each
+    /// upgraded connection. Plain requests for `path` still go to the router.
+    #[cfg(feature = "websocket")]
+    pub fn websocket<F>(&mut self, path: &str, handler: F)
+    where
+        F: Fn(&mut WebSocket) + Send + Sync + 'static,
+    {
+        self.websockets.insert(path.to_string(), Arc::new(handler));
+    }
+
+    pub fn router(&self) -> &Router {
+        &self.router
+    }
+
+    pub fn fallback<F, R>(&mut self, handler: F)
+    where
+        F: Fn(&mut Context) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        self.router.fallback(handler);
+    }
+
+    /// Replaces how a `HandlerError` becomes a response, e.g. for branded
+    /// error pages. Internal errors are logged before this is called. It
+    /// takes over not-found responses too, so `config.not_found` no longer
+    /// applies.
+    pub fn error_handler<F>(&mut self, handler: F)
+    where
+        F: Fn(&HandlerError, &Request) -> Response + Send + Sync + 'static,
+    {
+        self.error_handler = Arc::new(handler);
+    }
+
+    /// Sends the server's request-path metrics to `metrics` instead of the
+    /// `metrics` crate's global recorder.
+    pub fn metrics<M: Metrics + 'static>(&mut self, metrics: M) {
+        self.metrics = Arc::new(metrics);
+    }
+
+    /// Runs `hook` before the accept loop starts, e.g. to open a database
+    /// pool. An error stops `run`, which then returns it.
+    pub fn on_startup<F>(&mut self, hook: F)
+    where
+        F: FnOnce() -> Result<(), HookError> + Send + 'static,
+    {
+        self.startup_hooks.push(Box::new(hook));
+    }
+
+    /// Runs `hook` after the pool has drained, or after a startup hook has
+    /// failed. Hooks run last registered first, each even if one before it
+    /// panicked. Only `run` calls them, not a [`ConnectionHandler`].
+    pub fn on_shutdown<F>(&mut self, hook: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shutdown_hooks.push(Box::new(hook));
+    }
+
+    /// Runs the accept loop. Consumes the server so the routes are frozen
+    /// before the first connection is handed to a worker.
+    ///
+    /// Returns once shutdown has been requested through a [`ShutdownHandle`]:
+    /// accepting stops first, then the pool drains the connections it already
+    /// has. Idle keep-alive connections are closed right away, and requests
+    /// still in flight are answered with `Connection: close`. Shutdown hooks
+    /// run last. Anything that should outlive the server (such as the metrics
+    /// endpoint) is still up when this returns.
+    ///
+    /// Fails without accepting anything if a startup hook does.
+    pub fn run(mut self, listener: TcpListener) -> Result<(), HookError> {
+        // Dropped on every way out of here, panics included.
+        let shutdown_hooks = ShutdownHooks(mem::take(&mut self.shutdown_hooks));
+        for (index, hook) in mem::take(&mut self.startup_hooks).into_iter().enumerate() {
+            if let Err(e) = hook() {
+                error!("Startup hook {} failed, not starting: {}", index + 1, e);
+                return Err(e);
+            }
+        }
+
+        ignore_sigpipe();
+        let config = &self.config;
+        let pool = ThreadPool::with_config(PoolConfig {
+            size: config.pool_size,
+            queue_capacity: config.queue_capacity,
+            queue_full_policy: config.queue_full_policy,
+            pin_workers: config.pin_workers,
+            shutdown_timeout: config.shutdown_timeout,
+            stuck_threshold: config.stuck_worker_threshold,
+        });
+        self.metrics.counter("thread_pool_size", config.pool_size as u64, &[]);
+        // Blocking routes get workers of their own, so a burst of slow
+        // requests can't take every worker from the quick ones.
+        let blocking_pool = (config.blocking_pool_size > 0).then(|| {
+            info!("Serving blocking routes on a pool of {} workers", config.blocking_pool_size);
+            ThreadPool::with_config(PoolConfig {
+                size: config.blocking_pool_size,
+                queue_capacity: config.queue_capacity,
+                queue_full_policy: config.queue_full_policy,
+                pin_workers: false,
+                shutdown_timeout: config.shutdown_timeout,
+                stuck_threshold: config.stuck_worker_threshold,
+            })
+        });
+        if config.warm_up_workers {
+            pool.warm_up();
+            blocking_pool.iter().for_each(ThreadPool::warm_up);
+        }
+        let lanes = blocking_pool.as_ref().map(|blocking| Lanes {
+            fast: pool.handle(),
+            blocking: blocking.handle(),
+        });
+
+        let shutdown = self.shutdown.clone();
+        self.stats.set_pool(pool.stats());
+        self.stats.set_probe(pool.handle());
+        let state = Arc::new(self.into_state(pool.stats(), lanes, true));
+        let config = &state.config;
+        let accept_rate = AcceptRateLimiter::new(config.accept_rate, config.accept_burst);
+        let cap = Arc::new(ConnectionCap::new(config.max_connections));
+
+        let addr = listener.local_addr().ok();
+        if let Some(addr) = addr {
+            shutdown.listening_on(addr);
+        }
+        log_summary(addr, &state);
+
+        let acceptor = Acceptor {
+            pool: &pool,
+            state: &state,
+            accept_rate: &accept_rate,
+            cap: &cap,
+            shutdown: &shutdown,
+        };
+        let threads = config.accept_threads.max(1);
+        if threads > 1 {
+            info!("Accepting on {} threads", threads);
+        }
+        // Every acceptor calls accept() on the same listener; the kernel
+        // hands each new connection to one of them.
+        thread::scope(|scope| {
+            for id in 1..threads {
+                let acceptor = &acceptor;
+                let listener = &listener;
+                thread::Builder::new()
+                    .name(format!("acceptor-{}", id))
+                    .spawn_scoped(scope, move || acceptor.run(listener))
+                    .expect("failed to spawn acceptor thread");
+            }
+            acceptor.run(&listener);
+        });
+
+        info!("Shutdown phase 1: stopped accepting connections");
+        drop(listener);
+        info!(
+            "Shutdown phase 2: draining worker pool ({} queued, {} active)",
+            state.pool_stats.queued(),
+            state.pool_stats.active()
+        );
+        drop(pool);
+        drop(blocking_pool);
+        info!("Worker pool drained");
+        drop(shutdown_hooks);
+        Ok(())
+    }
+
+    /// Freezes the routes and settings into the state shared by every
+    /// connection. TLS is only loaded when `tls` is set.
+    fn into_state(mut self, pool_stats: Arc<PoolStats>, lanes: Option<Lanes>, tls: bool) -> ServerState {
+        let config = self.config;
+        self.router.set_trailing_slash(config.trailing_slash);
+        for (path, timeout) in &config.route_timeouts {
+            self.router.set_timeout(path, *timeout);
+        }
+        for path in &config.blocking_routes {
+            self.router.set_blocking(path);
+        }
+
+        #[cfg(feature = "http2")]
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) if tls => {
+                let tls = crate::http2::load_tls_config(cert, key).expect("failed to load TLS certificate and key");
+                info!("Serving TLS with ALPN h2 and http/1.1");
+                Some(tls)
+            }
+            _ => None,
+        };
+        #[cfg(not(feature = "http2"))]
+        if tls && (config.tls_cert.is_some() || config.tls_key.is_some()) {
+            warn!("TLS_CERT_FILE/TLS_KEY_FILE are set but the server was built without the http2 feature; serving plain HTTP");
+        }
+
+        ServerState {
+            log_sampler: LogSampler::new(config.log_sample_rate),
+            limiter: Arc::new(ConnectionLimiter::new(config.max_connections_per_ip)),
+            pool_stats,
+            #[cfg(feature = "http2")]
+            tls,
+            #[cfg(feature = "websocket")]
+            websockets: self.websockets,
+            router: self.router,
+            lanes,
+            shutdown: self.shutdown,
+            maintenance: self.maintenance,
+            error_handler: self.error_handler,
+            metrics: self.metrics,
+            stats: self.stats,
+            config,
+        }
+    }
+
+    /// Freezes the server into a handler for connections accepted some
+    /// other way than `run` (a Unix socket, an in-memory stream in a
+    /// benchmark). Connections are served as plain HTTP/1.x on the caller's
+    /// thread; TLS settings are ignored.
+    pub fn connection_handler(self) -> ConnectionHandler {
+        let pool_stats = Arc::new(PoolStats::new(self.config.pool_size));
+        self.stats.set_pool(Arc::clone(&pool_stats));
+        ConnectionHandler {
+            state: Arc::new(self.into_state(pool_stats, None, false)),
+        }
+    }
+}
+
+/// Serves HTTP/1.x connections on any byte stream. See
+/// [`Server::connection_handler`].
+#[derive(Clone)]
+pub struct ConnectionHandler {
+    pub(crate) state: Arc<ServerState>,
+}
+
+impl ConnectionHandler {
+    /// Answers requests on `stream` until the client closes it or the
+    /// connection stops being kept alive.
+    pub fn serve<S: Read + Write>(&self, stream: S) {
+        let deadline = Deadline::after(Instant::now(), self.state.config.connection_timeout);
+        let mut reader = BufReader::new(stream);
+        serve_http1(&mut reader, Uuid::new_v4(), None, None, deadline, &mut Progress::default(), &self.state);
+    }
+}
+
+/// Answers errors with `HandlerError::into_response`, except that not-found
+/// follows the configured policy.
+fn default_error_handler(not_found: NotFoundPolicy) -> ErrorHandler {
+    Arc::new(move |error: &HandlerError, request: &Request| match error {
+        HandlerError::NotFound => not_found.response_for(&request.path),
+        error => error.into_response(),
+    })
+}
+
+/// Makes writes to a connection the client has closed fail with `EPIPE`,
+/// which the write paths already handle, instead of killing the process.
+/// Rust binaries ignore SIGPIPE before `main` by default, but a host program
+/// embedding the server (or one built with `-Zon-broken-pipe=kill`) may not.
+fn ignore_sigpipe() {
+    #[cfg(unix)]
+    // SAFETY: setting a signal's disposition to SIG_IGN installs no handler
+    // code, and nothing else in the server relies on SIGPIPE.
+    unsafe {
+        if libc::signal(libc::SIGPIPE, libc::SIG_IGN) == libc::SIG_ERR {
+            warn!("Failed to ignore SIGPIPE: {}", std::io::Error::last_os_error());
+        }
+    }
+}
+
+/// One line with the settings an operator most often needs to confirm,
+/// logged once everything is up and just before the first accept. The full
+/// set is at `/admin/config`; secrets only show whether they are set.
+fn log_summary(addr: Option<SocketAddr>, state: &ServerState) {
+    let config = &state.config;
+    #[cfg(feature = "http2")]
+    let tls = state.tls.is_some();
+    #[cfg(not(feature = "http2"))]
+    let tls = false;
+    info!(
+        listen = addr.map(tracing::field::display),
+        tls,
+        workers = config.pool_size,
+        blocking_workers = config.blocking_pool_size,
+        queue_capacity = config.queue_capacity,
+        accept_threads = config.accept_threads.max(1),
+        max_connections = config.max_connections,
+        max_connections_per_ip = config.max_connections_per_ip,
+        request_timeout = ?config.request_timeout,
+        header_timeout = ?config.header_timeout,
+        keepalive_timeout = ?config.keepalive_timeout,
+        read_timeout = ?config.read_timeout,
+        write_timeout = ?config.write_timeout,
+        connection_timeout = ?config.connection_timeout,
+        max_body_bytes = config.max_body_bytes,
+        max_header_bytes = config.max_header_bytes,
+        static_root = config.static_root.as_deref().map(|root| tracing::field::display(root.display())),
+        metrics = %SocketAddr::new(config.metrics_bind, config.metrics_port),
+        metrics_token = config.metrics_token.is_some(),
+        admin = config.admin_token.is_some(),
+        maintenance = state.maintenance.is_enabled(),
+        "Server ready"
+    );
+}
+
+/// Turns on TCP keepalive probes for an accepted connection. The interval
+/// and retry count are only set where the OS allows it per socket.
+fn set_tcp_keepalive(stream: &TcpStream, config: &Config) -> std::io::Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(config.tcp_keepalive_idle);
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+    let keepalive = keepalive
+        .with_interval(config.tcp_keepalive_interval)
+        .with_retries(config.tcp_keepalive_retries);
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// Binds a listener with an explicit accept backlog, which
+/// `TcpListener::bind` leaves at the OS default.
+pub fn bind(addr: SocketAddr, backlog: i32) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Matches what std does, so a restart doesn't trip over TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    Ok(socket.into())
+}
+
+/// One accept loop. Several may run against the same listener.
+struct Acceptor<'a> {
+    pool: &'a ThreadPool,
+    state: &'a Arc<ServerState>,
+    accept_rate: &'a AcceptRateLimiter,
+    cap: &'a Arc<ConnectionCap>,
+    shutdown: &'a ShutdownHandle,
+}
+
+impl Acceptor<'_> {
+    fn run(&self, listener: &TcpListener) {
+        let (config, metrics) = (&self.state.config, &self.state.metrics);
+        loop {
+            // Blocking mode claims the slot before accepting, so connections
+            // past the cap stay in the kernel backlog until one frees up.
+            let slot = match config.max_connections_mode {
+                CapMode::Block => match self.wait_for_slot() {
+                    Some(slot) => Some(slot),
+                    None => break,
+                },
+                CapMode::Reject => None,
+            };
+            let stream = listener.accept().map(|(stream, _)| stream);
+            if self.shutdown.is_requested() {
+                // Only one blocked acceptor is woken per connection, so pass
+                // the wake-up on to the next one.
+                self.shutdown.wake();
+                break;
+            }
+            match stream {
+                Ok(mut stream) => {
+                    let accepted = Instant::now();
+                    metrics.counter("connections_total", 1, &[]);
+                    let connection_id = Uuid::new_v4();
+    
+                    info!(connection_id = ?connection_id, "New connection accepted");
+
+                    let slot = match slot.or_else(|| self.cap.try_acquire()) {
+                        Some(slot) => slot,
+                        None => {
+                            warn!(connection_id = ?connection_id, "At the {} connection cap, turning connection away", config.max_connections);
+                            metrics.counter("connection_cap_rejections_total", 1, &[]);
+                            self.turn_away(&mut stream);
+                            continue;
+                        }
+                    };
+    
+                    match self.accept_rate.acquire(config.accept_max_delay) {
+                        Admission::Accepted => {}
+                        Admission::Delayed(_) => metrics.counter("accept_throttled_total", 1, &[("action", "delayed")]),
+                        Admission::Rejected => {
+                            warn!(connection_id = ?connection_id, "Accept rate exceeded, turning connection away");
+                            metrics.counter("accept_throttled_total", 1, &[("action", "rejected")]);
+                            self.turn_away(&mut stream);
+                            continue;
+                        }
+                    }
+    
+                    // Connections from a trusted proxy are capped per forwarded
+                    // client instead, once their requests say who that is.
+                    let guard = match stream.peer_addr() {
+                        Ok(peer) if config.trusted_proxies.contains(peer.ip()) => None,
+                        Ok(peer) => match self.state.limiter.try_acquire(peer.ip()) {
+                            Some(guard) => Some(guard),
+                            None => {
+                                warn!(connection_id = ?connection_id, "Too many concurrent connections from {}", peer.ip());
+                                metrics.counter("per_ip_limit_rejections_total", 1, &[]);
+                                self.turn_away(&mut stream);
+                                continue;
+                            }
+                        },
+                        Err(_) => None,
+                    };
+    
+                    if config.tcp_keepalive {
+                        if let Err(e) = set_tcp_keepalive(&stream, config) {
+                            warn!(connection_id = ?connection_id, "Failed to enable TCP keepalive: {}", e);
+                        }
+                    }
+
+                    // Kept so a rejected connection can still be told to retry.
+                    let rejection_stream = stream.try_clone();
+                    let state = Arc::clone(self.state);
+                    let job = self.pool.execute(move || {
+                        let _guard = guard;
+                        let _slot = slot;
+                        #[cfg(feature = "http2")]
+                        if let Some(tls) = state.tls.clone() {
+                            crate::http2::handle_tls_connection(stream, connection_id, accepted, state, tls);
+                            return;
+                        }
+                        handle_connection(stream, connection_id, accepted, state);
+                    });
+                    if let Err(e) = job {
+                        warn!(connection_id = ?connection_id, "Turning connection away: {}", e);
+                        if let Ok(mut stream) = rejection_stream {
+                            self.turn_away(&mut stream);
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to establish connection: {}", e);
+                    metrics.counter("connection_errors_total", 1, &[]);
+                }
+            }
+        }
+    }
+
+    /// Tells a connection turned away before reaching a worker to retry
+    /// shortly. On a TLS listener it is only closed: a plaintext answer
+    /// would land in the middle of the client's handshake.
+    fn turn_away(&self, stream: &mut TcpStream) {
+        #[cfg(feature = "http2")]
+        if self.state.tls.is_some() {
+            return;
+        }
+        let _ = Response::new(StatusCode::ServiceUnavailable)
+            .with_header("Retry-After", "1")
+            .with_header("Connection", "close")
+            .write_to(stream);
+    }
+
+    /// Waits for a free connection slot. Returns `None` if shutdown begins
+    /// first; the wait is in short steps so it notices.
+    fn wait_for_slot(&self) -> Option<CapGuard> {
+        if let Some(slot) = self.cap.try_acquire() {
+            return Some(slot);
+        }
+        let metrics = &self.state.metrics;
+        metrics.counter("connection_cap_waits_total", 1, &[]);
+        let started = Instant::now();
+        while !self.shutdown.is_requested() {
+            if let Some(slot) = self.cap.acquire_timeout(Duration::from_millis(100)) {
+                metrics.histogram("connection_cap_wait_seconds", started.elapsed().as_secs_f64(), &[]);
+                return Some(slot);
+            }
+        }
+        None
+    }
+}
+
+/// Runs the shutdown hooks when dropped, in reverse order of registration.
+struct ShutdownHooks(Vec<ShutdownHook>);
+
+impl Drop for ShutdownHooks {
+    fn drop(&mut self) {
+        if self.0.is_empty() {
+            return;
+        }
+        info!("Running {} shutdown hook(s)", self.0.len());
+        for hook in self.0.drain(..).rev() {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(hook)) {
+                error!("Shutdown hook panicked: {}", panic_message(payload.as_ref()));
+            }
+        }
+    }
+}
+
+/// Stops a running [`Server`]. Cloneable and usable from any thread.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownState>,
+}
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    addr: Mutex<Option<SocketAddr>>,
+    /// Kept-alive connections currently waiting for their next request.
+    idle: Mutex<HashMap<Uuid, TcpStream>>,
+}
+
+impl ShutdownHandle {
+    /// Asks the server to stop accepting and drain. Takes effect immediately
+    /// if the accept loop is running, or as soon as it starts.
+    pub fn shutdown(&self) {
+        if self.inner.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        info!("Shutdown requested");
+        self.wake();
+        self.close_idle();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// The accept loop is parked in accept(); a throwaway connection wakes it
+    /// so it can see the flag.
+    fn wake(&self) {
+        if let Some(mut addr) = *self.inner.addr.lock().unwrap() {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+        }
+    }
+
+    fn listening_on(&self, addr: SocketAddr) {
+        *self.inner.addr.lock().unwrap() = Some(addr);
+    }
+
+    /// Shuts the read side of every idle connection, so workers waiting on
+    /// them see end-of-stream now instead of at the keep-alive timeout.
+    fn close_idle(&self) {
+        let idle = std::mem::take(&mut *self.inner.idle.lock().unwrap());
+        if idle.is_empty() {
+            return;
+        }
+        for socket in idle.values() {
+            let _ = socket.shutdown(Shutdown::Read);
+        }
+        info!("Closed {} idle keep-alive connection(s)", idle.len());
+        counter!("idle_connections_closed_on_shutdown_total", idle.len() as u64);
+    }
+
+    /// Registers a connection as idle until the guard is dropped. Returns
+    /// `None` once shutdown has begun, when the connection should just close.
+    fn track_idle(&self, connection_id: Uuid, socket: &TcpStream) -> Option<IdleGuard<'_>> {
+        if let Ok(socket) = socket.try_clone() {
+            self.inner.idle.lock().unwrap().insert(connection_id, socket);
+        }
+        let guard = IdleGuard {
+            handle: self,
+            connection_id,
+        };
+        // Checked after registering: a shutdown that started in between has
+        // either seen this connection or set the flag before we look.
+        (!self.is_requested()).then_some(guard)
+    }
+}
+
+struct IdleGuard<'a> {
+    handle: &'a ShutdownHandle,
+    connection_id: Uuid,
+}
+
+impl Drop for IdleGuard<'_> {
+    fn drop(&mut self) {
+        self.handle.inner.idle.lock().unwrap().remove(&self.connection_id);
+    }
+}
+
+/// Everything the workers share, frozen once the accept loop starts.
+pub(crate) struct ServerState {
+    router: Router,
+    shutdown: ShutdownHandle,
+    maintenance: Maintenance,
+    error_handler: ErrorHandler,
+    pub(crate) metrics: Arc<dyn Metrics>,
+    pub(crate) stats: Arc<ServerStats>,
+    pub(crate) config: Config,
+    limiter: Arc<ConnectionLimiter>,
+    log_sampler: LogSampler,
+    pool_stats: Arc<PoolStats>,
+    #[cfg(feature = "http2")]
+    tls: Option<Arc<tokio_rustls::rustls::ServerConfig>>,
+    #[cfg(feature = "websocket")]
+    websockets: HashMap<String, WebSocketHandler>,
+    /// Set when blocking routes have a pool of their own.
+    lanes: Option<Lanes>,
+}
+
+impl ServerState {
+    /// Whether the pool is past a configured load-shedding threshold.
+    fn overloaded(&self) -> bool {
+        let config = &self.config;
+        (config.shed_queue_depth > 0 && self.pool_stats.queued() >= config.shed_queue_depth)
+            || (config.shed_utilization > 0.0 && self.pool_stats.utilization() >= config.shed_utilization)
+    }
+}
+
+/// Decides which completed requests get an access log line: one in every
+/// `rate` successful requests, and every request that didn't succeed.
+struct LogSampler {
+    rate: u64,
+    seen: AtomicU64,
+}
+
+impl LogSampler {
+    fn new(rate: u64) -> LogSampler {
+        LogSampler {
+            rate,
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    fn should_log(&self, status: StatusCode) -> bool {
+        if !status.is_success() || self.rate <= 1 {
+            return true;
+        }
+        self.seen.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.rate)
+    }
+}
+
+/// Serves one plain-TCP connection. `connection_id` identifies it in the span
+/// and in the access log of every request it carries.
+#[instrument(skip(stream, accepted, state))]
+fn handle_connection(stream: TcpStream, connection_id: Uuid, accepted: Instant, state: Arc<ServerState>) {
+    let config = &state.config;
+
+    // Increment total connections counter
+    state.metrics.counter("connections_total", 1, &[]);
+    let active = state.stats.connection_opened();
+
+    if let Err(e) = stream.set_read_timeout(Some(config.read_timeout)) {
+        warn!("Failed to set read timeout: {}", e);
+    }
+    if let Err(e) = stream.set_write_timeout(Some(config.write_timeout)) {
+        warn!("Failed to set write timeout: {}", e);
+    }
+
+    // One reader for the whole connection, so bytes of pipelined requests
+    // buffered while reading one request are there for the next.
+    let stream = Arc::new(stream);
+    let connection = PlainConnection {
+        reader: BufReader::new(SharedStream(Arc::clone(&stream))),
+        remote_addr: stream.peer_addr().ok(),
+        stream,
+        connection_id,
+        deadline: Deadline::after(accepted, config.connection_timeout),
+        progress: Progress {
+            lane: state.lanes.as_ref().map(|_| Lane::Fast),
+            ..Progress::default()
+        },
+        _active: active,
+    };
+    serve_plain(connection, state);
+}
+
+/// A plain-TCP connection and everything needed to carry on serving it, so
+/// it can move to a worker of the other pool between requests.
+struct PlainConnection {
+    stream: Arc<TcpStream>,
+    reader: BufReader<SharedStream>,
+    connection_id: Uuid,
+    remote_addr: Option<SocketAddr>,
+    deadline: Deadline,
+    progress: Progress,
+    _active: ActiveConnection,
+}
+
+/// Serves `connection` on this worker until it closes, or until a request
+/// on it belongs to the other pool and the connection moves there. If that
+/// pool's queue is full the connection stays here for good.
+fn serve_plain(mut connection: PlainConnection, state: Arc<ServerState>) {
+    let PlainConnection {
+        stream,
+        reader,
+        connection_id,
+        remote_addr,
+        deadline,
+        progress,
+        ..
+    } = &mut connection;
+    serve_http1(reader, *connection_id, *remote_addr, Some(stream), *deadline, progress, &state);
+
+    if let (Some(lanes), Some(lane), Some(_)) = (&state.lanes, progress.lane, &progress.pending) {
+        let to = lane.other();
+        progress.lane = Some(to);
+        match lanes.pool(to).try_execute((connection, Arc::clone(&state)), |(connection, state)| {
+            serve_plain(connection, state)
+        }) {
+            Ok(()) => {
+                state.metrics.counter("pool_handoffs_total", 1, &[("to", to.as_str())]);
+                return;
+            }
+            Err((returned, _)) => {
+                connection = returned;
+                warn!(connection_id = ?connection.connection_id, "The {} pool is full; serving the connection where it is", to.as_str());
+                state.metrics.counter("pool_handoff_failures_total", 1, &[("to", to.as_str())]);
+                connection.progress.lane = None;
+                return serve_plain(connection, state);
+            }
+        }
+    }
+
+    close_gracefully(&connection.stream);
+}
+
+/// A [`TcpStream`] shared between a connection's reader and the code that
+/// needs the socket itself, so the two can move between threads together.
+struct SharedStream(Arc<TcpStream>);
+
+impl Read for SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+impl Write for SharedStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+/// The two pools of a server with `blocking_pool_size` set.
+pub(crate) struct Lanes {
+    fast: PoolHandle,
+    blocking: PoolHandle,
+}
+
+impl Lanes {
+    fn pool(&self, lane: Lane) -> &PoolHandle {
+        match lane {
+            Lane::Fast => &self.fast,
+            Lane::Blocking => &self.blocking,
+        }
+    }
+}
+
+/// Which pool a request is answered on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Lane {
+    Fast,
+    Blocking,
+}
+
+impl Lane {
+    fn other(self) -> Lane {
+        match self {
+            Lane::Fast => Lane::Blocking,
+            Lane::Blocking => Lane::Fast,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Lane::Fast => "fast",
+            Lane::Blocking => "blocking",
+        }
+    }
+}
+
+/// How far a connection has got, carried with it from pool to pool.
+#[derive(Default)]
+pub(crate) struct Progress {
+    /// Requests answered so far.
+    served: usize,
+    /// The pool the connection is on, if it may move to the other one.
+    lane: Option<Lane>,
+    /// A request read here but to be answered on the other pool.
+    pending: Option<Pending>,
+}
+
+/// A request read but not yet answered.
+pub(crate) struct Pending {
+    request: Request,
+    framing: Option<Framing>,
+    /// When parsing finished, what the request's duration is timed from.
+    received: Instant,
+    request_id: Uuid,
+}
+
+/// When a connection has to be closed by, whatever it is doing
+/// (`Config::connection_timeout`).
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Deadline(Option<Instant>);
+
+impl Deadline {
+    /// `limit` after `start`, or none if `limit` is zero.
+    pub(crate) fn after(start: Instant, limit: Duration) -> Deadline {
+        Deadline((!limit.is_zero()).then(|| start + limit))
+    }
+
+    fn passed(self) -> bool {
+        self.0.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    fn remaining(self) -> Option<Duration> {
+        self.0.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// Records a connection closed for outliving its deadline, in `phase`.
+fn deadline_exceeded(connection_id: Uuid, phase: &'static str, state: &ServerState) {
+    warn!(
+        connection_id = ?connection_id,
+        "Closing connection open longer than {:?} (while {})",
+        state.config.connection_timeout,
+        phase
+    );
+    state.metrics.counter("connection_deadline_exceeded_total", 1, &[("phase", phase)]);
+}
+
+/// Answers requests on one HTTP/1.x connection until either side is done
+/// with it, or its deadline passes. Responses are written through
+/// `reader.get_mut()`. `socket` is the TCP connection underneath, which
+/// shutdown closes while it is idle.
+///
+/// With `progress.lane` set, returns early with `progress.pending` set when
+/// the next request belongs to the other pool; called again, it answers
+/// that request first.
+pub(crate) fn serve_http1<S: Read + Write>(
+    reader: &mut BufReader<S>,
+    connection_id: Uuid,
+    remote_addr: Option<SocketAddr>,
+    socket: Option<&TcpStream>,
+    deadline: Deadline,
+    progress: &mut Progress,
+    state: &ServerState,
+) {
+    loop {
+        if progress.pending.is_none() {
+            let first = progress.served == 0;
+            let waited = !deadline.passed() && wait_for_request(reader, connection_id, socket, deadline, first, state);
+            if !waited {
+                if deadline.passed() {
+                    deadline_exceeded(connection_id, "idle", state);
+                }
+                break;
+            }
+        }
+        let request_id = progress.pending.as_ref().map_or_else(Uuid::new_v4, |pending| pending.request_id);
+        let keep_alive = handle_request(reader, request_id, connection_id, remote_addr, state, progress, deadline);
+        if progress.pending.is_some() {
+            return;
+        }
+        progress.served += 1;
+        if !keep_alive {
+            break;
+        }
+    }
+    let served = progress.served;
+
+    // How much keep-alive is actually buying: few connections carrying more
+    // than one request points at clients not reusing them, or a timeout too short.
+    state.metrics.histogram("requests_per_connection", served as f64, &[]);
+    if served > 1 {
+        state.metrics.counter("connections_reused_total", 1, &[]);
+    }
+}
+
+/// Waits, as an idle connection, for the next request to start arriving:
+/// up to `keepalive_timeout` since the last byte, after which the socket is
+/// put back on `read_timeout` for reading the request. Returns false if the
+/// client closed the connection or went quiet, or if shutdown began in the
+/// meantime; a client that closes before its `first` request is left for the
+/// parser to report. Pipelined requests already buffered are still answered.
+/// A socket isn't waited on past the connection deadline.
+fn wait_for_request<S: Read>(
+    reader: &mut BufReader<S>,
+    connection_id: Uuid,
+    socket: Option<&TcpStream>,
+    deadline: Deadline,
+    first: bool,
+    state: &ServerState,
+) -> bool {
+    if !reader.buffer().is_empty() {
+        return true;
+    }
+    let _idle = match socket {
+        Some(socket) => match state.shutdown.track_idle(connection_id, socket) {
+            Some(guard) => Some(guard),
+            None => return false,
+        },
+        None if state.shutdown.is_requested() => return false,
+        None => None,
+    };
+    let idle_timeout = match deadline.remaining() {
+        // A zero read timeout is an error, hence the floor.
+        Some(remaining) => state.config.keepalive_timeout.min(remaining.max(Duration::from_millis(1))),
+        None => state.config.keepalive_timeout,
+    };
+    let set = socket.is_some_and(|socket| socket.set_read_timeout(Some(idle_timeout)).is_ok());
+    let arrived = match reader.fill_buf() {
+        Ok(buf) => !buf.is_empty() || first,
+        Err(_) => false,
+    };
+    if let (true, Some(socket)) = (set, socket) {
+        let _ = socket.set_read_timeout(Some(state.config.read_timeout));
+    }
+    arrived
+}
+
+/// Reads, routes and answers one request, or the one left pending by a move
+/// between pools. Returns whether the connection should stay open for another.
+#[instrument(skip(reader, connection_id, remote_addr, state, progress, deadline))]
+fn handle_request<S: Read + Write>(
+    reader: &mut BufReader<S>,
+    request_id: Uuid,
+    connection_id: Uuid,
+    remote_addr: Option<SocketAddr>,
+    state: &ServerState,
+    progress: &mut Progress,
+    deadline: Deadline,
+) -> bool {
+    let config = &state.config;
+    // Requests still allowed after this one; the one that leaves none is
+    // answered with `Connection: close`.
+    let allowance = config.keepalive_max_requests.saturating_sub(progress.served + 1);
+
+    let Pending {
+        mut request,
+        framing,
+        received: start,
+        ..
+    } = match progress.pending.take() {
+        Some(pending) => pending,
+        None => match read_request(reader, request_id, connection_id, progress.served, deadline, state) {
+            Some(pending) => pending,
+            None => return false,
+        },
+    };
+    if let Some(lane) = progress.lane {
+        let wanted = if state.router.is_blocking(&request) { Lane::Blocking } else { Lane::Fast };
+        if wanted != lane {
+            progress.pending = Some(Pending {
+                request,
+                framing,
+                received: start,
+                request_id,
+            });
+            return false;
+        }
+    }
+
+    #[cfg(feature = "websocket")]
+    if let Some(handler) = state.websockets.get(&request.path) {
+        if crate::websocket::is_upgrade(&request) {
+            return upgrade_websocket(reader, &request, handler, request_id, state);
+        }
+    }
+    let mut body = framing.map(|framing| BodyReader::new(reader, framing, config.max_body_bytes));
+    let (route, mut response) = dispatch(&mut request, start, body.as_mut(), request_id, remote_addr, state);
+    // The connection can only be reused once the whole body has been read.
+    let body_unread = body.is_some_and(|body| !body.is_done());
+    if deadline.passed() {
+        deadline_exceeded(connection_id, "handling", state);
+        return false;
+    }
+
+    let handler_closes = response.headers.has_token("Connection", "close");
+    // HTTP/1.0 has no chunked coding, so a streamed body can only end with the connection.
+    let close_delimited = response.is_streamed() && request.version != "HTTP/1.1";
+    if close_delimited {
+        response.close_delimited();
+    }
+    let keep_alive = wants_keep_alive(&request)
+        && !handler_closes
+        && !close_delimited
+        && allowance > 0
+        && !body_unread
+        && !state.shutdown.is_requested();
+    response
+        .headers
+        .insert("Connection", if keep_alive { "keep-alive" } else { "close" });
+    if keep_alive {
+        let keep_alive = format!("timeout={}, max={}", config.keepalive_timeout.as_secs(), allowance);
+        response.headers.insert("Keep-Alive", &keep_alive);
+    }
+
+    count_response(&request, &response, &route, request_id, state);
+    if config.log_bodies {
+        body_log::log_exchange(&request, &response, request_id, config.log_body_max_bytes);
+    }
+
+    let stream = reader.get_mut();
+    if let Err(e) = response.write_to(stream).and_then(|()| stream.flush()) {
+        write_failed(&e, request_id, state);
+        return false;
+    }
+    if let Some(chunks) = response.take_stream() {
+        let mut sent = 0;
+        let written = if close_delimited {
+            chunks.write_unframed(stream, deadline.0, &mut sent)
+        } else {
+            chunks.write_to(stream, deadline.0, &mut sent)
+        };
+        // Only known now; `count_response` saw an empty body.
+        state.metrics.counter("response_bytes_total", sent, &[("path", &route)]);
+        if let Err(e) = written {
+            if deadline.passed() {
+                deadline_exceeded(connection_id, "streaming", state);
+            } else {
+                write_failed(&e, request_id, state);
+            }
+            return false;
+        }
+    }
+
+    log_completion(&request, &response, route, start.elapsed(), request_id, connection_id, state);
+
+    keep_alive
+}
+
+/// Reads the next request on a connection, and its body unless the route
+/// streams it. Errors are answered (when there is anyone to answer) and
+/// counted here, and give `None`.
+fn read_request<S: Read + Write>(
+    reader: &mut BufReader<S>,
+    request_id: Uuid,
+    connection_id: Uuid,
+    served: usize,
+    deadline: Deadline,
+    state: &ServerState,
+) -> Option<Pending> {
+    let config = &state.config;
+    // Streaming routes get the body framing checked here but read it themselves.
+    let parsed = Request::parse_with_timeout(reader, config.max_header_bytes, config.header_timeout).and_then(|mut request| {
+        if state.router.streams_body(&request) {
+            let framing = request.body_framing(config.max_body_bytes)?;
+            return Ok((request, Some(framing)));
+        }
+        request.read_body(reader, config.max_body_bytes)?;
+        Ok((request, None))
+    });
+    // Timed from the end of parsing so idle keep-alive time isn't counted.
+    let start = Instant::now();
+    let (request, framing) = match parsed {
+        Ok(parsed) => parsed,
+        // A kept-alive client closing or going quiet between requests is normal.
+        Err(ParseError::Empty) if served > 0 => return None,
+        Err(ParseError::Io(e)) if served > 0 && is_timeout(&e) => return None,
+        Err(ParseError::Io(e)) => {
+            error!(request_id = ?request_id, "Failed to read request: {}", e);
+            state.metrics.counter("request_errors_total", 1, &[]);
+            state.metrics.counter("requests_total", 1, &[("status", "500"), ("path", "error")]);
+            return None;
+        }
+        Err(ParseError::Empty) => {
+            warn!(request_id = ?request_id, "Empty request received");
+            state.metrics.counter("request_errors_total", 1, &[]);
+            state.metrics.counter("requests_total", 1, &[("status", "400"), ("path", "empty")]);
+            return None;
+        }
+        Err(e) => {
+            warn!(request_id = ?request_id, "Bad request: {}", e);
+            let status = e.status();
+            let label = match e {
+                // Same label HTTP/2 uses for methods it doesn't know.
+                ParseError::UnknownMethod(_) => "unsupported",
+                ParseError::HeadersTimedOut { .. } => {
+                    state.metrics.counter("header_timeouts_total", 1, &[]);
+                    "timeout"
+                }
+                _ => "malformed",
+            };
+            state.metrics.counter("request_errors_total", 1, &[]);
+            let status_code = status.as_u16().to_string();
+            state.metrics.counter("requests_total", 1, &[("status", &status_code), ("path", label)]);
+            let response = Response::new(status).with_header("Connection", "close");
+            if let Err(e) = response.write_to(reader.get_mut()) {
+                write_failed(&e, request_id, state);
+            }
+            return None;
+        }
+    };
+    if deadline.passed() {
+        deadline_exceeded(connection_id, "reading", state);
+        return None;
+    }
+    Some(Pending {
+        request,
+        framing,
+        received: start,
+        request_id,
+    })
+}
+
+/// Completes (or refuses) a WebSocket handshake and runs the session. The
+/// connection is never reused afterwards.
+#[cfg(feature = "websocket")]
+fn upgrade_websocket<S: Read + Write>(
+    reader: &mut BufReader<S>,
+    request: &Request,
+    handler: &WebSocketHandler,
+    request_id: Uuid,
+    state: &ServerState,
+) -> bool {
+    let (upgraded, response) = match crate::websocket::handshake(request) {
+        Ok(response) => (true, response),
+        Err(response) => (false, response.with_header("Connection", "close")),
+    };
+    count_response(request, &response, &request.path, request_id, state);
+    let stream = reader.get_mut();
+    if let Err(e) = response.write_to(stream).and_then(|()| stream.flush()) {
+        write_failed(&e, request_id, state);
+        return false;
+    }
+    if !upgraded {
+        return false;
+    }
+
+    info!(request_id = ?request_id, "WebSocket session started on {}", request.path);
+    state.metrics.counter("websocket_sessions_total", 1, &[("path", &request.path)]);
+    let started = Instant::now();
+    let mut socket = WebSocket::new(reader, state.config.max_body_bytes);
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| handler(&mut socket))) {
+        error!(request_id = ?request_id, "WebSocket handler for {} panicked: {}", request.path, panic_message(payload.as_ref()));
+        state.metrics.counter("handler_panics_total", 1, &[("path", &request.path)]);
+    }
+    info!(request_id = ?request_id, "WebSocket session ended after {:?}", started.elapsed());
+    false
+}
+
+/// Routes a parsed request and produces its response: a maintenance 503, a
+/// canonical-path redirect, a 503 if the route is being shed, or whatever
+/// the handler returns. Shared by every protocol the server speaks. Returns the route
+/// label used for metrics alongside the response.
+///
+/// Also sets the request's client IP, and its deadline, counted from
+/// `received`, using the matched route's timeout if it has one.
+pub(crate) fn dispatch(
+    request: &mut Request,
+    received: Instant,
+    body: Option<&mut BodyReader<'_>>,
+    request_id: Uuid,
+    remote_addr: Option<SocketAddr>,
+    state: &ServerState,
+) -> (String, Response) {
+    let router = &state.router;
+    let config = &state.config;
+    request.deadline = Some(received + config.request_timeout);
+    request.client_ip = remote_addr.map(|addr| config.trusted_proxies.client_ip(addr.ip(), &request.headers));
+
+    // Held while the request is handled; see the accept loop.
+    let _forwarded_guard = match (remote_addr, request.client_ip) {
+        (Some(peer), Some(client)) if config.trusted_proxies.contains(peer.ip()) => {
+            match state.limiter.try_acquire(client) {
+                Some(guard) => Some(guard),
+                None => {
+                    warn!(request_id = ?request_id, "Too many concurrent requests from {} via {}", client, peer.ip());
+                    state.metrics.counter("per_ip_limit_rejections_total", 1, &[]);
+                    return ("rejected".to_string(), Response::new(StatusCode::ServiceUnavailable));
+                }
+            }
+        }
+        _ => None,
+    };
+    if let Some(prefix) = config.access_control.denied_by(&request.path, request.client_ip) {
+        warn!(
+            request_id = ?request_id,
+            client_ip = request.client_ip.map(tracing::field::display),
+            "Denying {} {} to a client outside the networks allowed {}",
+            request.method,
+            request.path,
+            prefix
+        );
+        state.metrics.counter("acl_denied_total", 1, &[("prefix", prefix)]);
+        return ("forbidden".to_string(), Response::new(StatusCode::Forbidden));
+    }
+    if request.method == Method::Trace {
+        return ("trace".to_string(), trace_response(request, state));
+    }
+    if state.maintenance.applies_to(&request.path) {
+        let mut response = maintenance::response(&config.maintenance_page, config.maintenance_retry_after);
+        if request.method == Method::Head {
+            response = response.without_body();
+        }
+        return ("maintenance".to_string(), response);
+    }
+
+    let canonical = router.canonical_redirect(request);
+    let (route, handler, params) = router.route(request);
+    if let Some(timeout) = router.timeout_for(route) {
+        request.deadline = Some(received + timeout);
+    }
+    // A handler answering HEAD from metadata has no body to compress, so
+    // it couldn't report the length, encoding and validator a GET would
+    // get. When the response may be compressed it is built as for GET, and
+    // the body dropped below.
+    let head = request.method == Method::Head;
+    let as_get = (head && compression::negotiated(request, &config.compression).is_some()).then(|| Request {
+        method: Method::Get,
+        ..request.clone()
+    });
+    let request = as_get.as_ref().unwrap_or(request);
+    let body = body.map(|body| {
+        body.set_deadline(request.deadline);
+        body as &mut dyn Read
+    });
+    let route = if canonical.is_some() { "redirect".to_string() } else { route.to_string() };
+    let shed = config.shed_routes.contains(&route) && state.overloaded();
+    let mut response = if let Some(canonical) = canonical {
+        let location = match &request.query {
+            Some(query) => format!("{}?{}", canonical, query),
+            None => canonical,
+        };
+        // 308 keeps the method and body for anything but a plain fetch.
+        let status = match request.method {
+            Method::Get | Method::Head => StatusCode::MovedPermanently,
+            _ => StatusCode::PermanentRedirect,
+        };
+        state.metrics.counter("trailing_slash_redirects_total", 1, &[]);
+        Response::redirect(status, &location)
+    } else if shed {
+        warn!(request_id = ?request_id, "Shedding {} {} under load", request.method, request.path);
+        state.metrics.counter("load_shed_total", 1, &[("path", &route)]);
+        Response::new(StatusCode::ServiceUnavailable).with_header("Retry-After", "1")
+    } else {
+        let context = Context {
+            params,
+            remote_addr,
+            ..Context::new(request, request_id)
+        }
+        .with_body(body);
+        call_handler(handler, context, &route, state)
+    };
+    // The fallback may serve real content (static files, say); only what it
+    // answers 404 is reported as not found.
+    let route = if route == FALLBACK_ROUTE && response.status == StatusCode::NotFound {
+        "notfound".to_string()
+    } else {
+        route
+    };
+    if let Some(charset) = &config.default_charset {
+        let content_type = response.headers.get("Content-Type");
+        if let Some(content_type) = content_type.and_then(|content_type| with_charset(content_type, charset)) {
+            response.headers.insert("Content-Type", &content_type);
+        }
+    }
+    response = compress_response(request, response, &config.compression, config.compression_min_bytes);
+    if head {
+        response = response.without_body();
+    }
+    (route, response)
+}
+
+/// Request headers carrying credentials. A TRACE echo never reflects them,
+/// so it can't be used to read what scripts can't otherwise see (cross-site
+/// tracing), and body logging redacts them.
+pub(crate) const CREDENTIAL_HEADERS: [&str; 4] = ["Authorization", "Proxy-Authorization", "Cookie", "X-Api-Key"];
+
+/// TRACE is refused with a 405 unless `enable_trace` is set, in which case the
+/// request line and headers (minus credentials) are echoed back.
+fn trace_response(request: &Request, state: &ServerState) -> Response {
+    if !state.config.enable_trace {
+        state.metrics.counter("trace_rejected_total", 1, &[]);
+        let allow = state
+            .router
+            .allowed_methods(&request.path)
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Response::new(StatusCode::MethodNotAllowed).with_header("Allow", &allow);
+    }
+
+    let mut echo = format!("{} {}", request.method, request.path);
+    if let Some(query) = &request.query {
+        echo.push('?');
+        echo.push_str(query);
+    }
+    echo.push_str(&format!(" {}\r\n", request.version));
+    for (name, value) in request.headers.iter() {
+        if !CREDENTIAL_HEADERS.iter().any(|redacted| name.eq_ignore_ascii_case(redacted)) {
+            echo.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    Response::new(StatusCode::Ok)
+        .with_header("Content-Type", "message/http")
+        .with_body(echo)
+}
+
+pub(crate) fn count_response(request: &Request, response: &Response, route: &str, request_id: Uuid, state: &ServerState) {
+    state.stats.request_served();
+    let metrics = &state.metrics;
+    let status = response.status.as_u16().to_string();
+    metrics.counter("requests_total", 1, &[("path", route), ("status", &status)]);
+    // Body bytes only, so HEAD answers add nothing.
+    metrics.counter("response_bytes_total", response.body.len() as u64, &[("path", route)]);
+    if response.status.is_success() {
+        metrics.counter("requests_by_path", 1, &[("path", route)]);
+    } else {
+        warn!(request_id = ?request_id, "{} {} answered {}", request.method, request.path, response.status);
+        metrics.counter("request_errors_total", 1, &[]);
+    }
+}
+
+/// Records the latency of a response that was written out, plus the
+/// (sampled) access log line.
+pub(crate) fn log_completion(
+    request: &Request,
+    response: &Response,
+    route: String,
+    duration: Duration,
+    request_id: Uuid,
+    connection_id: Uuid,
+    state: &ServerState,
+) {
+    let duration_secs = duration.as_secs_f64();
+    let metrics = &state.metrics;
+    metrics.histogram("request_duration_seconds", duration_secs, &[("status_class", response.status.class())]);
+    metrics.histogram("request_duration_by_path", duration_secs, &[("path", &route)]);
+
+    // Slow requests are always logged, whatever the sample rate.
+    let slow = !state.config.slow_request_threshold.is_zero() && duration >= state.config.slow_request_threshold;
+    if slow {
+        metrics.counter("slow_requests_total", 1, &[("path", &route)]);
+        warn!(
+            request_id = ?request_id,
+            connection_id = ?connection_id,
+            method = %request.method,
+            path = request.path,
+            status = %response.status,
+            duration = ?duration,
+            "Slow request"
+        );
+    }
+
+    if state.log_sampler.should_log(response.status) {
+        info!(
+            request_id = ?request_id,
+            connection_id = ?connection_id,
+            client_ip = request.client_ip.map(tracing::field::display),
+            method = %request.method,
+            path = request.path,
+            status = %response.status,
+            duration = ?duration,
+            "Request completed"
+        );
+    }
+}
+
+/// HTTP/1.1 connections persist unless the client asks to close; HTTP/1.0
+/// ones only when the client asks to keep them.
+fn wants_keep_alive(request: &Request) -> bool {
+    if request.headers.has_token("Connection", "close") {
+        return false;
+    }
+    request.version == "HTTP/1.1" || request.headers.has_token("Connection", "keep-alive")
+}
+
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Logs a failed response write. A write that times out means the client
+/// stopped reading (possibly on purpose, to hold a worker); one that hits a
+/// closed socket just means it went away. Both end the connection.
+pub(crate) fn write_failed(e: &std::io::Error, request_id: Uuid, state: &ServerState) {
+    if is_timeout(e) {
+        warn!(request_id = ?request_id, "Aborting slow client: response write timed out");
+        state.metrics.counter("slow_client_aborts_total", 1, &[]);
+    } else if matches!(e.kind(), ErrorKind::BrokenPipe | ErrorKind::ConnectionReset) {
+        info!(request_id = ?request_id, "Client closed the connection before the response was written");
+        state.metrics.counter("client_disconnects_total", 1, &[]);
+    } else {
+        error!(request_id = ?request_id, "Failed to write response: {}", e);
+        state.metrics.counter("response_errors_total", 1, &[]);
+    }
+}
+
+/// How long and how much to keep reading after our FIN, waiting for the
+/// client to close its side.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+const DRAIN_LIMIT: usize = 64 * 1024;
+
+/// Half-closes the connection and drains whatever the client still sends, so
+/// closing with unread data doesn't turn our FIN into an RST. The drain is
+/// bounded in time and bytes so a client that keeps sending can't hold the
+/// worker.
+fn close_gracefully(mut stream: &TcpStream) {
+    if stream.shutdown(Shutdown::Write).is_err() {
+        return;
+    }
+
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    let mut drained = 0;
+    let mut buf = [0; 4096];
+    while drained < DRAIN_LIMIT {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || stream.set_read_timeout(Some(remaining)).is_err() {
+            break;
+        }
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => drained += n,
+        }
+    }
+}
+
+/// Runs the handler, turning a panic into a 500 so the client still gets an answer.
+fn call_handler(handler: &Handler, mut context: Context, route: &str, state: &ServerState) -> Response {
+    let (request, request_id) = (context.request, context.request_id);
+    match panic::catch_unwind(AssertUnwindSafe(|| handler(&mut context))) {
+        Ok(Ok(response)) => context.finish(response),
+        Ok(Err(error)) => {
+            if let HandlerError::Internal(cause) = &error {
+                error!(request_id = ?request_id, "Handler for {} {} failed: {}", request.method, request.path, cause);
+                state.metrics.counter("handler_errors_total", 1, &[("path", route)]);
+            }
+            context.finish((state.error_handler)(&error, request))
+        }
+        Err(payload) => {
+            error!(
+                request_id = ?request_id,
+                "Handler for {} {} panicked: {}",
+                request.method,
+                request.path,
+                panic_message(payload.as_ref())
+            );
+            state.metrics.counter("handler_panics_total", 1, &[("path", route)]);
+            Response::new(StatusCode::InternalServerError)
+        }
+    }
+}
+
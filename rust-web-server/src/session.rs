@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use metrics::counter;
+use uuid::Uuid;
+
+use crate::cookie::{Cookie, CookieSigner, SameSite};
+use crate::response::Response;
+use crate::{Context, HandlerError, HandlerOutput};
+
+pub type SessionData = HashMap<String, String>;
+
+pub const SESSION_COOKIE: &str = "session_id";
+
+/// Storage for session data. The in-memory store is the default; anything
+/// shared between processes (e.g. Redis) can implement this instead.
+pub trait SessionStore: Send + Sync {
+    /// Returns the data for a live session and refreshes its idle timer.
+    fn load(&self, id: &str) -> Option<SessionData>;
+    fn save(&self, id: &str, data: SessionData);
+    /// Drops sessions idle for longer than the TTL, returning how many went.
+    fn evict_expired(&self) -> usize;
+}
+
+struct Entry {
+    data: SessionData,
+    last_seen: Instant,
+}
+
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, Entry>>,
+    ttl: Duration,
+}
+
+impl MemorySessionStore {
+    pub fn new(ttl: Duration) -> MemorySessionStore {
+        MemorySessionStore {
+            sessions: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, id: &str) -> Option<SessionData> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let entry = sessions.get_mut(id)?;
+        if entry.last_seen.elapsed() > self.ttl {
+            sessions.remove(id);
+            return None;
+        }
+        entry.last_seen = Instant::now();
+        Some(entry.data.clone())
+    }
+
+    fn save(&self, id: &str, data: SessionData) {
+        let entry = Entry {
+            data,
+            last_seen: Instant::now(),
+        };
+        self.sessions.lock().unwrap().insert(id.to_string(), entry);
+    }
+
+    fn evict_expired(&self) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, entry| entry.last_seen.elapsed() <= self.ttl);
+        before - sessions.len()
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    MissingSecret,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::MissingSecret => f.write_str("SESSION_SECRET must be set to use sessions"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+/// Session middleware: wraps handlers so they can read and write per-session
+/// data, issuing a signed session cookie on a client's first visit.
+#[derive(Clone)]
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    signer: CookieSigner,
+}
+
+impl Sessions {
+    pub fn new(store: Arc<dyn SessionStore>, signer: CookieSigner) -> Sessions {
+        Sessions { store, signer }
+    }
+
+    /// Builds sessions signed with `SESSION_SECRET`, refusing to start
+    /// without one.
+    pub fn from_env(store: Arc<dyn SessionStore>) -> Result<Sessions, SessionError> {
+        match CookieSigner::from_env() {
+            Some(signer) => Ok(Sessions::new(store, signer)),
+            None => Err(SessionError::MissingSecret),
+        }
+    }
+
+    pub fn in_memory(ttl: Duration) -> Result<Sessions, SessionError> {
+        Sessions::from_env(Arc::new(MemorySessionStore::new(ttl)))
+    }
+
+    pub fn wrap<F, R>(&self, handler: F) -> impl Fn(&mut Context) -> Result<Response, HandlerError> + Send + Sync + 'static
+    where
+        F: Fn(&mut Context, &mut SessionData) -> R + Send + Sync + 'static,
+        R: HandlerOutput,
+    {
+        let store = Arc::clone(&self.store);
+        let signer = self.signer.clone();
+        move |context: &mut Context| {
+            let existing = context
+                .request
+                .cookies()
+                .remove(SESSION_COOKIE)
+                .and_then(|signed| {
+                    let id = signer.verify(&signed);
+                    if id.is_none() {
+                        warn!("Rejected session cookie with an invalid signature");
+                        counter!("session_cookie_rejections_total", 1);
+                    }
+                    id
+                })
+                .and_then(|id| store.load(&id).map(|data| (id, data)));
+
+            let (id, mut data, is_new) = match existing {
+                Some((id, data)) => (id, data, false),
+                None => {
+                    counter!("sessions_created_total", 1);
+                    (Uuid::new_v4().to_string(), SessionData::new(), true)
+                }
+            };
+
+            // Saved even when the handler fails, like any other response.
+            let response = handler(context, &mut data).into_result();
+            store.save(&id, data);
+
+            if is_new {
+                let cookie = Cookie::new(SESSION_COOKIE, &signer.sign(&id))
+                    .path("/")
+                    .http_only()
+                    .same_site(SameSite::Lax);
+                context.response_headers.append("Set-Cookie", &cookie.to_string());
+            }
+            response
+        }
+    }
+
+    /// Starts a thread that evicts idle sessions every `interval`. The thread
+    /// exits once the store has been dropped.
+    pub fn spawn_eviction(&self, interval: Duration) -> thread::JoinHandle<()> {
+        let store: Weak<dyn SessionStore> = Arc::downgrade(&self.store);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let store = match store.upgrade() {
+                Some(store) => store,
+                None => break,
+            };
+            let evicted = store.evict_expired();
+            if evicted > 0 {
+                info!("Evicted {} idle sessions", evicted);
+                counter!("sessions_evicted_total", evicted as u64);
+            }
+        })
+    }
+}
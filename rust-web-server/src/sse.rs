@@ -0,0 +1,72 @@
+//! Server-sent events: a `text/event-stream` response fed from a channel.
+//!
+//! The handler returns [`response`] straight away and keeps the [`Sender`]
+//! (usually on a thread of its own) to push events. The stream ends when
+//! every sender is dropped, after which the connection can be kept alive as
+//! usual; if the client goes away first, the next send fails instead. The
+//! worker serving the connection stays busy for as long as the stream lasts.
+
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// One event. Multi-line data is split over several `data:` lines, which
+/// the browser joins back together.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    event: Option<String>,
+    id: Option<String>,
+    data: String,
+}
+
+impl Event {
+    pub fn data(data: impl Into<String>) -> Event {
+        Event {
+            data: data.into(),
+            ..Event::default()
+        }
+    }
+
+    /// Sets the event type, dispatched to `addEventListener(name, ...)`.
+    pub fn event(mut self, name: &str) -> Event {
+        self.event = Some(name.to_string());
+        self
+    }
+
+    /// Sets the id a reconnecting client sends back in `Last-Event-ID`.
+    pub fn id(mut self, id: &str) -> Event {
+        self.id = Some(id.to_string());
+        self
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", event)?;
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", id)?;
+        }
+        for line in self.data.split('\n') {
+            writeln!(f, "data: {}", line.trim_end_matches('\r'))?;
+        }
+        writeln!(f)
+    }
+}
+
+pub fn channel() -> (Sender<Event>, Receiver<Event>) {
+    mpsc::channel()
+}
+
+/// A 200 streaming each event from `events` as it arrives.
+pub fn response(events: Receiver<Event>) -> Response {
+    Response::new(StatusCode::Ok)
+        .with_header("Content-Type", "text/event-stream")
+        .with_header("Cache-Control", "no-cache")
+        // Tells nginx and similar proxies not to hold events back.
+        .with_header("X-Accel-Buffering", "no")
+        .with_stream(events.into_iter().map(|event| event.to_string().into_bytes()))
+}
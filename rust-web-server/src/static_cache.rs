@@ -0,0 +1,219 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::mem;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+use metrics::counter;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+/// How long the watcher waits for change events to settle before invalidating.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Longest a change waits to be invalidated when events never settle, e.g.
+/// a log file under the static root being appended to.
+const MAX_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// In-memory copies of static files, bounded by total size. The oldest entries
+/// are evicted first once the budget is reached.
+#[derive(Debug)]
+pub struct FileCache {
+    max_bytes: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<PathBuf, Arc<Vec<u8>>>,
+    order: VecDeque<PathBuf>,
+    bytes: usize,
+}
+
+impl FileCache {
+    pub fn new(max_bytes: usize) -> FileCache {
+        FileCache {
+            max_bytes,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<Arc<Vec<u8>>> {
+        let hit = self.inner.lock().unwrap().entries.get(path).cloned();
+        let result = if hit.is_some() { "hit" } else { "miss" };
+        counter!("static_cache_lookups_total", 1, "result" => result);
+        hit
+    }
+
+    /// Caches `contents`, unless the file alone is bigger than the whole budget.
+    pub fn insert(&self, path: &Path, contents: Arc<Vec<u8>>) {
+        if contents.len() > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(path);
+        while inner.bytes + contents.len() > self.max_bytes {
+            match inner.order.pop_front() {
+                Some(oldest) => {
+                    if let Some(evicted) = inner.entries.remove(&oldest) {
+                        inner.bytes -= evicted.len();
+                    }
+                }
+                None => break,
+            }
+        }
+        inner.bytes += contents.len();
+        inner.order.push_back(path.to_path_buf());
+        inner.entries.insert(path.to_path_buf(), contents);
+    }
+
+    /// Drops `path` and anything cached beneath it, so a changed directory
+    /// invalidates every file in it.
+    pub fn invalidate(&self, path: &Path) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let stale: Vec<PathBuf> = inner
+            .entries
+            .keys()
+            .filter(|cached| cached.starts_with(path))
+            .cloned()
+            .collect();
+        for cached in &stale {
+            inner.remove(cached);
+        }
+        stale.len()
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner = Inner::default();
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, path: &Path) {
+        if let Some(old) = self.entries.remove(path) {
+            self.bytes -= old.len();
+            self.order.retain(|cached| cached != path);
+        }
+    }
+}
+
+/// Changed paths collected since the first event of a burst, each once.
+#[derive(Debug, Default)]
+struct Pending {
+    paths: HashSet<PathBuf>,
+    since: Option<Instant>,
+}
+
+impl Pending {
+    fn add(&mut self, paths: Vec<PathBuf>, now: Instant) {
+        self.since.get_or_insert(now);
+        self.paths.extend(paths);
+    }
+
+    /// How long to wait for more events before handling these: `None` with
+    /// nothing pending, zero once the burst has lasted [`MAX_DEBOUNCE`].
+    fn wait(&self, now: Instant) -> Option<Duration> {
+        let since = self.since?;
+        Some(DEBOUNCE.min(MAX_DEBOUNCE.saturating_sub(now.saturating_duration_since(since))))
+    }
+
+    fn take(&mut self) -> HashSet<PathBuf> {
+        self.since = None;
+        mem::take(&mut self.paths)
+    }
+}
+
+/// Watches `root` and invalidates cache entries as files under it change.
+/// Bursts of events (an editor save, a deploy unpacking files) are collected
+/// until things go quiet for [`DEBOUNCE`], or for at most [`MAX_DEBOUNCE`],
+/// and handled in one pass.
+///
+/// Cache keys are the paths `StaticFiles` resolves, so `root` must be given the
+/// same way the static root was configured.
+pub fn watch(root: &Path, cache: Arc<FileCache>) -> notify::Result<()> {
+    let canonical_root = root.canonicalize()?;
+    let root = root.to_path_buf();
+    let (tx, rx) = mpsc::channel();
+    let mut watcher: RecommendedWatcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&canonical_root, RecursiveMode::Recursive)?;
+    info!("Watching {} for static file changes", root.display());
+
+    thread::Builder::new()
+        .name("static-watcher".into())
+        .spawn(move || {
+            // Moved in so the watcher lives as long as the thread.
+            let _watcher = watcher;
+            let mut pending = Pending::default();
+            loop {
+                let event = match pending.wait(Instant::now()) {
+                    None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    Some(wait) if wait.is_zero() => Err(RecvTimeoutError::Timeout),
+                    Some(wait) => rx.recv_timeout(wait),
+                };
+                match event {
+                    Ok(Ok(event)) => pending.add(event.paths, Instant::now()),
+                    Ok(Err(e)) => {
+                        // Events may have been lost; start from a clean slate.
+                        warn!("File watcher error, clearing static cache: {}", e);
+                        cache.clear();
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        let mut invalidated = 0;
+                        for changed in pending.take() {
+                            let relative = changed.strip_prefix(&canonical_root).unwrap_or(&changed);
+                            invalidated += cache.invalidate(&root.join(relative));
+                        }
+                        debug!("Invalidated {} static cache entries", invalidated);
+                        counter!("static_cache_invalidations_total", invalidated as u64);
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        })
+        .map_err(notify::Error::io)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_quiet_burst_is_handled_after_the_debounce() {
+        let start = Instant::now();
+        let mut pending = Pending::default();
+        assert_eq!(pending.wait(start), None);
+        pending.add(vec![PathBuf::from("a")], start);
+        assert_eq!(pending.wait(start), Some(DEBOUNCE));
+        assert_eq!(pending.take().len(), 1);
+        assert_eq!(pending.wait(start), None);
+    }
+
+    #[test]
+    fn a_burst_that_never_settles_is_handled_after_the_maximum_delay() {
+        let start = Instant::now();
+        let mut pending = Pending::default();
+        let mut now = start;
+        // An event every 100 ms would keep restarting a plain debounce.
+        while now < start + MAX_DEBOUNCE {
+            pending.add(vec![PathBuf::from("log/app.log")], now);
+            assert!(pending.wait(now).unwrap() <= DEBOUNCE);
+            now += Duration::from_millis(100);
+        }
+        assert_eq!(pending.wait(now), Some(Duration::ZERO));
+        // The same path, changed twenty times, is invalidated once.
+        assert_eq!(pending.take().len(), 1);
+    }
+
+    #[test]
+    fn the_deadline_counts_from_the_first_event() {
+        let start = Instant::now();
+        let mut pending = Pending::default();
+        pending.add(vec![PathBuf::from("a")], start);
+        pending.add(vec![PathBuf::from("b")], start + Duration::from_millis(1900));
+        assert_eq!(pending.wait(start + Duration::from_millis(1900)), Some(Duration::from_millis(100)));
+    }
+}
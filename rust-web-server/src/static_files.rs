@@ -0,0 +1,528 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tracing::{error, warn};
+use metrics::counter;
+
+use crate::cache_control::CachePolicy;
+use crate::circuit_breaker::{BreakerConfig, CircuitBreaker};
+use crate::date::http_date;
+use crate::mime::content_type_for;
+use crate::static_cache::FileCache;
+use crate::query::percent_decode;
+use crate::request::{Method, Request};
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// Serves files from a directory on disk.
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+    breaker: Arc<CircuitBreaker>,
+    breaker_config: BreakerConfig,
+    slow_read: Duration,
+    cache: Option<Arc<FileCache>>,
+    cache_policy: CachePolicy,
+    spa: Option<SpaFallback>,
+}
+
+/// Serves a single-page app's index for client-side routes, see
+/// [`StaticFiles::with_spa_fallback`].
+#[derive(Debug, Clone)]
+struct SpaFallback {
+    prefix: String,
+    index: String,
+}
+
+impl SpaFallback {
+    /// Paths under the prefix whose last segment has no extension: `/app`
+    /// and `/app/settings/profile`, not `/app/missing.js` or `/apple`.
+    fn applies_to(&self, path: &str) -> bool {
+        let under_prefix = match path.strip_prefix(self.prefix.as_str()) {
+            Some(rest) => self.prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        };
+        under_prefix && !path.rsplit('/').next().is_some_and(|segment| segment.contains('.'))
+    }
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles::with_breaker(root, BreakerConfig::default(), Duration::from_secs(1))
+    }
+
+    /// Guards disk reads with a circuit breaker. Read errors, and reads slower
+    /// than `slow_read`, count as failures; while the circuit is open file
+    /// requests fail fast with a 503.
+    pub fn with_breaker(root: impl Into<PathBuf>, breaker_config: BreakerConfig, slow_read: Duration) -> StaticFiles {
+        StaticFiles {
+            root: root.into(),
+            breaker: Arc::new(CircuitBreaker::new("fs", breaker_config)),
+            breaker_config,
+            slow_read,
+            cache: None,
+            cache_policy: CachePolicy::default(),
+            spa: None,
+        }
+    }
+
+    /// Keeps file contents in `cache` instead of reading them on every request.
+    pub fn with_cache(mut self, cache: Arc<FileCache>) -> StaticFiles {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Sets how long clients may cache what is served.
+    pub fn with_cache_policy(mut self, cache_policy: CachePolicy) -> StaticFiles {
+        self.cache_policy = cache_policy;
+        self
+    }
+
+    /// Answers requests under `prefix` that match no file with the file at
+    /// `index` (a path under the root, such as `/app/index.html`), so a
+    /// single-page app's client-side routes load the app. Paths whose last
+    /// segment has an extension are taken to be assets and still get a 404
+    /// when missing.
+    pub fn with_spa_fallback(mut self, prefix: &str, index: &str) -> StaticFiles {
+        self.spa = Some(SpaFallback {
+            prefix: prefix.to_string(),
+            index: index.to_string(),
+        });
+        self
+    }
+
+    /// Maps a request path onto a file under the root. Returns `None` for
+    /// paths that try to escape it or decode to control characters.
+    pub fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let decoded = percent_decode(request_path, false);
+        if decoded.chars().any(char::is_control) {
+            return None;
+        }
+        let mut path = self.root.clone();
+        for component in Path::new(decoded.trim_start_matches('/')).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        if path.is_dir() {
+            path.push("index.html");
+        }
+        Some(path)
+    }
+
+    /// Serves the file for the request, or `None` if there isn't one so the
+    /// caller can fall through to its not-found handling.
+    ///
+    /// When the client accepts gzip and a `<file>.gz` sits next to the file,
+    /// the precompressed copy is sent as-is with `Content-Encoding: gzip`.
+    /// HEAD requests are answered from the file's metadata without reading it
+    /// (unless the server is going to compress the response, in which case
+    /// it asks for the GET response to measure).
+    /// A GET with a single `Range` gets a 206 for that slice, unless an
+    /// `If-Range` validator shows the client's copy is out of date.
+    ///
+    /// With an SPA fallback set, client-side routes get the app's index.
+    pub fn serve(&self, request: &Request) -> Option<Response> {
+        if request.method != Method::Get && request.method != Method::Head {
+            return None;
+        }
+        match self.serve_path(request, &request.path) {
+            None => match &self.spa {
+                Some(spa) if spa.applies_to(&request.path) => {
+                    counter!("static_spa_fallbacks_total", 1);
+                    self.serve_path(request, &spa.index)
+                }
+                _ => None,
+            },
+            found => found,
+        }
+    }
+
+    /// Serves the file at `request_path`, which is the request's own path
+    /// unless the SPA index is standing in for it.
+    fn serve_path(&self, request: &Request, request_path: &str) -> Option<Response> {
+        // No file is named with a NUL or a newline (`%00`, `%0a`), and a NUL
+        // fails the lookup outright.
+        if percent_decode(request_path, false).chars().any(char::is_control) {
+            return Some(Response::new(StatusCode::BadRequest));
+        }
+        let path = self.resolve(request_path)?;
+
+        if !self.breaker.allow() {
+            counter!("fs_circuit_rejections_total", 1);
+            let retry_after = self.breaker_config.cooldown.as_secs().max(1).to_string();
+            return Some(Response::new(StatusCode::ServiceUnavailable).with_header("Retry-After", &retry_after));
+        }
+
+        let started = Instant::now();
+        let result = self.read_variant(request, &path);
+        let elapsed = started.elapsed();
+        match &result {
+            Ok(_) if elapsed > self.slow_read => {
+                warn!("Slow read of {} took {:?}", path.display(), elapsed);
+                self.breaker.record_failure();
+            }
+            Ok(_) => self.breaker.record_success(),
+            // The filesystem answered; the request named something it can't
+            // serve. Recorded as a success so a half-open probe still ends.
+            Err(e) if is_request_error(e) => self.breaker.record_success(),
+            Err(e) => {
+                error!("Failed to read file {}: {}", path.display(), e);
+                counter!("file_read_errors_total", 1);
+                self.breaker.record_failure();
+            }
+        }
+
+        match result {
+            Ok(response) => {
+                let cache_control = self.cache_policy.header_for(request_path, &path);
+                response.map(|response| {
+                    let response = if request.method == Method::Get {
+                        apply_range(request, response)
+                    } else {
+                        response
+                    };
+                    response
+                        .with_header("Accept-Ranges", "bytes")
+                        .with_header("Cache-Control", &cache_control)
+                })
+            }
+            Err(e) => match e.kind() {
+                io::ErrorKind::PermissionDenied => Some(Response::new(StatusCode::Forbidden)),
+                io::ErrorKind::InvalidInput => Some(Response::new(StatusCode::BadRequest)),
+                _ if is_request_error(&e) => None,
+                _ => Some(Response::new(StatusCode::InternalServerError)),
+            },
+        }
+    }
+
+    fn read_variant(&self, request: &Request, path: &Path) -> io::Result<Option<Response>> {
+        let content_type = content_type_for(path);
+
+        let head = request.method == Method::Head;
+
+        if accepts_gzip(request.header("Accept-Encoding")) {
+            let mut gz_path = OsString::from(path.as_os_str());
+            gz_path.push(".gz");
+            if let Some(response) = self.read(Path::new(&gz_path), head)? {
+                counter!("static_precompressed_total", 1);
+                return Ok(Some(
+                    response
+                        .with_header("Content-Type", content_type)
+                        .with_header("Content-Encoding", "gzip")
+                        .with_header("Vary", "Accept-Encoding"),
+                ));
+            }
+        }
+
+        Ok(self.read(path, head)?.map(|response| response.with_header("Content-Type", content_type)))
+    }
+
+    /// Reads a file, treating a missing one as `None` rather than an error.
+    /// With `head` set only its metadata is looked at.
+    fn read(&self, path: &Path, head: bool) -> io::Result<Option<Response>> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut response = Response::new(StatusCode::Ok).with_header("ETag", &etag(&metadata));
+        if let Ok(modified) = metadata.modified() {
+            response = response.with_header("Last-Modified", &http_date(modified));
+        }
+        if head {
+            return Ok(Some(response.with_content_length(metadata.len())));
+        }
+
+        if let Some(contents) = self.cache.as_ref().and_then(|cache| cache.get(path)) {
+            return Ok(Some(response.with_body(contents.as_slice())));
+        }
+        match fs::read(path) {
+            Ok(contents) => {
+                let contents = Arc::new(contents);
+                if let Some(cache) = &self.cache {
+                    cache.insert(path, Arc::clone(&contents));
+                }
+                Ok(Some(response.with_body(contents.as_slice())))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Errors caused by the path asked for (a file under a file, a name too
+/// long, which std reports as `InvalidFilename`, one the server may not
+/// read) rather than by the disk, so they don't count towards opening the
+/// circuit.
+fn is_request_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound
+            | io::ErrorKind::NotADirectory
+            | io::ErrorKind::InvalidFilename
+            | io::ErrorKind::InvalidInput
+            | io::ErrorKind::PermissionDenied
+    )
+}
+
+/// A validator built from size and modification time, so it can be computed
+/// without reading the file.
+fn etag(metadata: &fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", metadata.len(), modified)
+}
+
+/// A single range from a `Range` header, resolved against the body length.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// First and last byte, inclusive.
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+}
+
+/// Cuts a full 200 down to the requested range. Anything this doesn't handle
+/// (several ranges, other units, malformed values) gets the whole body, which
+/// the spec allows.
+fn apply_range(request: &Request, mut response: Response) -> Response {
+    let Some(range) = request.header("Range") else { return response };
+    if let Some(validator) = request.header("If-Range") {
+        if !if_range_matches(validator.trim(), &response) {
+            counter!("range_requests_total", 1, "result" => "stale");
+            return response;
+        }
+    }
+
+    let len = response.body.len() as u64;
+    match parse_range(range, len) {
+        Some(ByteRange::Satisfiable(start, end)) => {
+            counter!("range_requests_total", 1, "result" => "partial");
+            response.status = StatusCode::PartialContent;
+            response.body = response.body[start as usize..=end as usize].to_vec();
+            response.with_header("Content-Range", &format!("bytes {}-{}/{}", start, end, len))
+        }
+        Some(ByteRange::Unsatisfiable) => {
+            counter!("range_requests_total", 1, "result" => "unsatisfiable");
+            Response::new(StatusCode::RangeNotSatisfiable).with_header("Content-Range", &format!("bytes */{}", len))
+        }
+        None => response,
+    }
+}
+
+/// `If-Range` holds either an entity tag, which must match the ETag exactly
+/// (weak tags never do), or a date, which must equal `Last-Modified`.
+fn if_range_matches(validator: &str, response: &Response) -> bool {
+    if validator.starts_with("W/") {
+        false
+    } else if validator.starts_with('"') {
+        response.headers.get("ETag") == Some(validator)
+    } else {
+        response.headers.get("Last-Modified") == Some(validator)
+    }
+}
+
+fn parse_range(header: &str, len: u64) -> Option<ByteRange> {
+    let (unit, spec) = header.trim().split_once('=')?;
+    if !unit.eq_ignore_ascii_case("bytes") || spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.trim().split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // Suffix range: the final `last` bytes.
+        let suffix: u64 = last.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        return Some(ByteRange::Satisfiable(len.saturating_sub(suffix), len - 1));
+    }
+
+    let start: u64 = first.parse().ok()?;
+    let end = if last.is_empty() {
+        u64::MAX
+    } else {
+        let end: u64 = last.parse().ok()?;
+        if end < start {
+            return None;
+        }
+        end
+    };
+    if start >= len {
+        return Some(ByteRange::Unsatisfiable);
+    }
+    Some(ByteRange::Satisfiable(start, end.min(len - 1)))
+}
+
+/// Whether an `Accept-Encoding` header allows gzip (an explicit `q=0` opts out).
+fn accepts_gzip(accept_encoding: Option<&str>) -> bool {
+    let accept_encoding = match accept_encoding {
+        Some(value) => value,
+        None => return false,
+    };
+    accept_encoding.split(',').any(|entry| {
+        let mut params = entry.split(';');
+        let coding = params.next().unwrap_or("").trim();
+        let q_zero = params.any(|p| {
+            p.trim()
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        (coding.eq_ignore_ascii_case("gzip") || coding == "*") && !q_zero
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory under the system temp dir, removed when dropped.
+    struct TempRoot(PathBuf);
+
+    impl TempRoot {
+        fn new() -> TempRoot {
+            let root = std::env::temp_dir().join(format!("static-files-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&root).unwrap();
+            fs::write(root.join("hello.txt"), "hello").unwrap();
+            TempRoot(root)
+        }
+    }
+
+    impl Drop for TempRoot {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn get(files: &StaticFiles, path: &str) -> Option<Response> {
+        get_with(files, path, &[])
+    }
+
+    fn get_with(files: &StaticFiles, path: &str, headers: &[(&str, &str)]) -> Option<Response> {
+        let mut raw = format!("GET {} HTTP/1.1\r\nHost: a\r\n", path);
+        for (name, value) in headers {
+            raw.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        raw.push_str("\r\n");
+        files.serve(&Request::parse(&mut raw.as_bytes()).unwrap())
+    }
+
+    /// Opens after two failures, so a handful of bad requests would show.
+    fn strict(root: &TempRoot) -> StaticFiles {
+        let config = BreakerConfig {
+            failure_threshold: 2,
+            ..BreakerConfig::default()
+        };
+        StaticFiles::with_breaker(&root.0, config, Duration::from_secs(60))
+    }
+
+    #[test]
+    fn encoded_control_characters_are_a_400_and_not_a_disk_failure() {
+        let root = TempRoot::new();
+        let files = strict(&root);
+        // The parser refuses these too, so build the requests past it, as a
+        // handler rewriting the path could.
+        let mut request = Request::parse(&mut "GET / HTTP/1.1\r\nHost: a\r\n\r\n".as_bytes()).unwrap();
+        for path in ["/%00", "/hello.txt%00", "/%0a", "/a%0d%0ab", "/%1f", "/%7f"] {
+            request.path = path.to_string();
+            for _ in 0..5 {
+                assert_eq!(files.serve(&request).unwrap().status, StatusCode::BadRequest, "{path}");
+            }
+        }
+        assert!(files.resolve("/%00").is_none());
+        assert_eq!(get(&files, "/hello.txt").unwrap().status, StatusCode::Ok);
+    }
+
+    #[test]
+    fn paths_that_cant_exist_are_not_found_without_opening_the_circuit() {
+        let root = TempRoot::new();
+        let files = strict(&root);
+        let long = format!("/{}", "a".repeat(4096));
+        for path in ["/missing.txt", "/hello.txt/inside", long.as_str()] {
+            for _ in 0..5 {
+                assert!(get(&files, path).is_none(), "{path}");
+            }
+        }
+        assert_eq!(get(&files, "/hello.txt").unwrap().body, b"hello");
+    }
+
+    #[test]
+    fn if_range_with_the_current_validator_gets_the_range() {
+        let root = TempRoot::new();
+        let files = StaticFiles::new(&root.0);
+        let full = get(&files, "/hello.txt").unwrap();
+        let etag = full.headers.get("ETag").unwrap().to_string();
+        let modified = full.headers.get("Last-Modified").unwrap().to_string();
+
+        for validator in [etag.as_str(), modified.as_str()] {
+            let response = get_with(&files, "/hello.txt", &[("Range", "bytes=1-3"), ("If-Range", validator)]).unwrap();
+            assert_eq!(response.status, StatusCode::PartialContent, "{validator}");
+            assert_eq!(response.body, b"ell");
+            assert_eq!(response.headers.get("Content-Range"), Some("bytes 1-3/5"));
+        }
+    }
+
+    #[test]
+    fn if_range_with_a_stale_validator_gets_the_whole_file() {
+        let root = TempRoot::new();
+        let files = StaticFiles::new(&root.0);
+        let etag = get(&files, "/hello.txt").unwrap().headers.get("ETag").unwrap().to_string();
+        let weak = format!("W/{}", etag);
+        for validator in ["\"stale\"", weak.as_str(), "Thu, 01 Jan 1970 00:00:00 GMT"] {
+            let response = get_with(&files, "/hello.txt", &[("Range", "bytes=1-3"), ("If-Range", validator)]).unwrap();
+            assert_eq!(response.status, StatusCode::Ok, "{validator}");
+            assert_eq!(response.body, b"hello");
+            assert!(response.headers.get("Content-Range").is_none());
+        }
+    }
+
+    #[test]
+    fn paths_escaping_the_root_are_not_resolved() {
+        let root = TempRoot::new();
+        let files = StaticFiles::new(&root.0);
+        assert!(files.resolve("/../etc/passwd").is_none());
+        assert!(files.resolve("/%2e%2e/etc/passwd").is_none());
+        assert_eq!(files.resolve("/./hello.txt"), Some(root.0.join("hello.txt")));
+    }
+
+    fn spa(root: &TempRoot) -> StaticFiles {
+        fs::create_dir_all(root.0.join("app")).unwrap();
+        fs::write(root.0.join("app/index.html"), "<app>").unwrap();
+        fs::write(root.0.join("app/main.js"), "main()").unwrap();
+        StaticFiles::new(&root.0).with_spa_fallback("/app", "/app/index.html")
+    }
+
+    #[test]
+    fn client_routes_under_the_prefix_get_the_index() {
+        let root = TempRoot::new();
+        let files = spa(&root);
+        for path in ["/app", "/app/", "/app/some/route", "/app/users/7"] {
+            let response = get(&files, path).unwrap_or_else(|| panic!("{path} not found"));
+            assert_eq!(response.status, StatusCode::Ok, "{path}");
+            assert_eq!(response.body, b"<app>", "{path}");
+        }
+        // Real files are still served as themselves.
+        assert_eq!(get(&files, "/app/main.js").unwrap().body, b"main()");
+    }
+
+    #[test]
+    fn missing_assets_and_other_prefixes_are_not_found() {
+        let root = TempRoot::new();
+        let files = spa(&root);
+        for path in ["/app/missing.js", "/app/img/logo.png", "/apple", "/other/route"] {
+            assert!(get(&files, path).is_none(), "{path}");
+        }
+    }
+}
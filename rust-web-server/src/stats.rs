@@ -0,0 +1,98 @@
+//! A few live counters for the `/stats` endpoint: a snapshot readable by a
+//! person with curl, without scraping Prometheus.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use crate::response::Response;
+use crate::{Context, PoolHandle, PoolStats};
+
+/// Request and connection counts, shared by the server and the endpoint.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    requests: AtomicU64,
+    connections: AtomicU64,
+    active_connections: AtomicUsize,
+    /// Set once the server starts running and has a pool.
+    pool: OnceLock<Arc<PoolStats>>,
+    /// The same pool, for the health check's probe job.
+    probe: OnceLock<PoolHandle>,
+}
+
+/// Counts a connection as active until dropped.
+pub(crate) struct ActiveConnection(Arc<ServerStats>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ServerStats {
+    pub(crate) fn connection_opened(self: &Arc<Self>) -> ActiveConnection {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(Arc::clone(self))
+    }
+
+    pub(crate) fn request_served(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_pool(&self, pool: Arc<PoolStats>) {
+        let _ = self.pool.set(pool);
+    }
+
+    pub(crate) fn set_probe(&self, pool: PoolHandle) {
+        let _ = self.probe.set(pool);
+    }
+
+    /// Whether the worker pool runs a no-op job within `timeout`, or `None`
+    /// when the server has no pool of its own (see
+    /// [`Server::connection_handler`](crate::Server::connection_handler)).
+    pub(crate) fn probe_pool(&self, timeout: Duration) -> Option<bool> {
+        self.probe.get().map(|pool| pool.probe(timeout))
+    }
+
+    /// Responses sent, on every protocol.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn pool(&self) -> Option<&PoolStats> {
+        self.pool.get().map(Arc::as_ref)
+    }
+}
+
+/// A handler answering with the stats as JSON, uptime counted from `started`.
+pub fn handler(stats: Arc<ServerStats>, started: Instant) -> impl Fn(&mut Context) -> Response + Send + Sync + 'static {
+    move |_: &mut Context| {
+        let pool = stats.pool().map(|pool| {
+            json!({
+                "size": pool.size(),
+                "active": pool.active(),
+                "queued": pool.queued(),
+                "utilization": pool.utilization(),
+            })
+        });
+        Response::json(&json!({
+            "uptime_secs": started.elapsed().as_secs(),
+            "requests_total": stats.requests(),
+            "connections_total": stats.connections(),
+            "active_connections": stats.active_connections(),
+            "pool": pool,
+        }))
+        .with_header("Cache-Control", "no-store")
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt;
+use opentelemetry::trace::{SpanId, TraceContextExt, TraceId};
+use tracing::{Event, Subscriber};
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Wraps an event formatter so every line logged inside a span carries the
+/// OpenTelemetry `trace_id` and `span_id` of that span, the same ids the OTLP
+/// exporter sends, so a log line can be looked up in the trace UI directly.
+///
+/// Text output gets `trace_id=... span_id=...` in front of the line; JSON
+/// output gets them as the first two keys of the object.
+#[derive(Debug, Clone)]
+pub struct TraceIds<F> {
+    inner: F,
+    json: bool,
+}
+
+impl<F> TraceIds<F> {
+    pub fn text(inner: F) -> TraceIds<F> {
+        TraceIds { inner, json: false }
+    }
+
+    pub fn json(inner: F) -> TraceIds<F> {
+        TraceIds { inner, json: true }
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for TraceIds<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let ids = current_ids(ctx);
+        if !self.json {
+            if let Some((trace_id, span_id)) = ids {
+                write!(writer, "trace_id={} span_id={} ", trace_id, span_id)?;
+            }
+            return self.inner.format_event(ctx, writer, event);
+        }
+
+        let mut line = String::new();
+        self.inner.format_event(ctx, Writer::new(&mut line), event)?;
+        match (ids, line.strip_prefix('{')) {
+            (Some((trace_id, span_id)), Some(rest)) => {
+                write!(writer, "{{\"trace_id\":\"{}\",\"span_id\":\"{}\",{}", trace_id, span_id, rest)
+            }
+            _ => writer.write_str(&line),
+        }
+    }
+}
+
+/// The ids of the current span as tracing-opentelemetry assigned them. A root
+/// span gets its own trace id; children inherit the one in their parent context.
+fn current_ids<S, N>(ctx: &FmtContext<'_, S, N>) -> Option<(TraceId, SpanId)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    let span = ctx.lookup_current()?;
+    let extensions = span.extensions();
+    let data = extensions.get::<OtelData>()?;
+    let trace_id = data
+        .builder
+        .trace_id
+        .unwrap_or_else(|| data.parent_cx.span().span_context().trace_id());
+    let span_id = data.builder.span_id?;
+    Some((trace_id, span_id))
+}
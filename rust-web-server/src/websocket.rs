@@ -0,0 +1,322 @@
+//! WebSocket upgrades (RFC 6455) on HTTP/1.1 connections, behind the
+//! `websocket` feature.
+//!
+//! Routes added with `Server::websocket` answer a valid upgrade request with
+//! `101 Switching Protocols` and then run their handler on the connection
+//! until it returns; the connection is closed afterwards. Limitations:
+//!
+//! - The session occupies a pool worker for as long as it lasts.
+//! - The connection keeps its request read timeout (`read_timeout`), so a
+//!   client that stays silent for longer than that is disconnected.
+//! - No extensions (no `permessage-deflate`) and no subprotocol selection.
+//! - Upgrades are only offered on HTTP/1.1, not on HTTP/2 connections.
+
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::sync::Arc;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+
+use crate::request::{Method, Request};
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// Appended to the client's key before hashing, per RFC 6455 section 1.3.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub type WebSocketHandler = Arc<dyn Fn(&mut WebSocket) + Send + Sync>;
+
+/// A complete data message, reassembled from its fragments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Close codes from RFC 6455 section 7.4.1.
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const TOO_BIG: u16 = 1009;
+}
+
+/// The `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.trim().as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(sha1.finalize())
+}
+
+/// Checks an upgrade request, returning the 101 to send, or the error
+/// response to send instead of upgrading.
+pub(crate) fn handshake(request: &Request) -> Result<Response, Response> {
+    let headers = &request.headers;
+    if request.method != Method::Get
+        || !headers.has_token("Upgrade", "websocket")
+        || !headers.has_token("Connection", "upgrade")
+    {
+        return Err(Response::new(StatusCode::BadRequest));
+    }
+    if request.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Err(Response::new(StatusCode::UpgradeRequired).with_header("Sec-WebSocket-Version", "13"));
+    }
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if STANDARD.decode(key.trim()).is_ok_and(|nonce| nonce.len() == 16) => key,
+        _ => return Err(Response::new(StatusCode::BadRequest)),
+    };
+    Ok(Response::new(StatusCode::SwitchingProtocols)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", &accept_key(key)))
+}
+
+/// Reads through the connection's buffer and writes to the stream under it.
+trait Transport {
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()>;
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()>;
+}
+
+impl<S: Read + Write> Transport for BufReader<S> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        Read::read_exact(self, buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let stream = self.get_mut();
+        stream.write_all(buf)?;
+        stream.flush()
+    }
+}
+
+/// One side of an upgraded connection. Pings are answered and a close from
+/// the client is acknowledged inside [`WebSocket::recv`].
+pub struct WebSocket<'a> {
+    io: &'a mut dyn Transport,
+    max_message: usize,
+    closed: bool,
+}
+
+impl<'a> WebSocket<'a> {
+    pub(crate) fn new<S: Read + Write>(reader: &'a mut BufReader<S>, max_message: usize) -> WebSocket<'a> {
+        WebSocket {
+            io: reader,
+            max_message,
+            closed: false,
+        }
+    }
+
+    /// Waits for the next data message. Returns `None` once the connection
+    /// has been closed, by either side; protocol errors close it with the
+    /// appropriate code before being returned.
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        while !self.closed {
+            let frame = self.read_frame()?;
+            match frame.opcode {
+                OP_CONTINUATION => {
+                    let Some((_, payload)) = message.as_mut() else {
+                        return self.fail(close_code::PROTOCOL_ERROR, "continuation without a message");
+                    };
+                    if payload.len() + frame.payload.len() > self.max_message {
+                        return self.fail(close_code::TOO_BIG, "message too large");
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                }
+                OP_TEXT | OP_BINARY => {
+                    if message.is_some() {
+                        return self.fail(close_code::PROTOCOL_ERROR, "new message inside a fragmented one");
+                    }
+                    message = Some((frame.opcode, frame.payload));
+                }
+                OP_CLOSE => {
+                    // Echo the status code back, as the close handshake expects.
+                    let code = frame.payload.get(..2).map(|code| u16::from_be_bytes([code[0], code[1]]));
+                    self.send_close(code.unwrap_or(close_code::NORMAL))?;
+                    return Ok(None);
+                }
+                OP_PING => {
+                    self.write_frame(OP_PONG, &frame.payload)?;
+                    continue;
+                }
+                OP_PONG => continue,
+                _ => return self.fail(close_code::PROTOCOL_ERROR, "unknown opcode"),
+            }
+
+            if frame.fin {
+                return match message.take() {
+                    Some((OP_TEXT, payload)) => match String::from_utf8(payload) {
+                        Ok(text) => Ok(Some(Message::Text(text))),
+                        Err(_) => self.fail(close_code::INVALID_DATA, "text message is not UTF-8"),
+                    },
+                    Some((_, payload)) => Ok(Some(Message::Binary(payload))),
+                    None => unreachable!("a finished frame always completes a message"),
+                };
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.write_frame(OP_TEXT, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(OP_BINARY, data),
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.write_frame(OP_TEXT, text.as_bytes())
+    }
+
+    /// Starts the close handshake. Later `recv` calls return `None`.
+    pub fn close(&mut self, code: u16) -> io::Result<()> {
+        self.send_close(code)
+    }
+
+    fn send_close(&mut self, code: u16) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        self.write_frame(OP_CLOSE, &code.to_be_bytes())
+    }
+
+    fn fail<T>(&mut self, code: u16, reason: &str) -> io::Result<T> {
+        let _ = self.send_close(code);
+        Err(io::Error::new(ErrorKind::InvalidData, reason.to_string()))
+    }
+
+    fn read_frame(&mut self) -> io::Result<Frame> {
+        let mut head = [0u8; 2];
+        self.io.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        if head[0] & 0x70 != 0 {
+            return self.fail(close_code::PROTOCOL_ERROR, "reserved bits set");
+        }
+        // Clients must mask everything they send.
+        if head[1] & 0x80 == 0 {
+            return self.fail(close_code::PROTOCOL_ERROR, "unmasked client frame");
+        }
+
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut len = [0u8; 2];
+                self.io.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0u8; 8];
+                self.io.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+        let control = opcode & 0x08 != 0;
+        if control && (len > 125 || !fin) {
+            return self.fail(close_code::PROTOCOL_ERROR, "invalid control frame");
+        }
+        if len > self.max_message as u64 {
+            return self.fail(close_code::TOO_BIG, "frame too large");
+        }
+
+        let mut mask = [0u8; 4];
+        self.io.read_exact(&mut mask)?;
+        let mut payload = vec![0u8; len as usize];
+        self.io.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Frame { fin, opcode, payload })
+    }
+
+    /// Writes one unfragmented, unmasked frame.
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.io.write_all(&frame)
+    }
+}
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// A handler that sends every message straight back.
+pub fn echo(socket: &mut WebSocket) {
+    while let Ok(Some(message)) = socket.recv() {
+        if socket.send(&message).is_err() {
+            break;
+        }
+    }
+}
+
+/// Whether a request is asking to switch to WebSocket at all, so routes can
+/// tell an upgrade attempt from a plain request for the same path.
+pub(crate) fn is_upgrade(request: &Request) -> bool {
+    request
+        .header("Upgrade")
+        .is_some_and(|value| value.split(',').any(|token| token.trim().eq_ignore_ascii_case("websocket")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade(headers: &str) -> Request {
+        let raw = format!("GET /chat HTTP/1.1\r\nHost: server.example.com\r\n{headers}\r\n");
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    const RFC_HEADERS: &str = "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n";
+
+    #[test]
+    fn accept_key_matches_the_rfc_example() {
+        // RFC 6455 section 1.3.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn a_valid_upgrade_switches_protocols() {
+        let response = handshake(&upgrade(RFC_HEADERS)).unwrap();
+        assert_eq!(response.status, StatusCode::SwitchingProtocols);
+        assert_eq!(response.headers.get("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert_eq!(response.headers.get("Upgrade"), Some("websocket"));
+    }
+
+    #[test]
+    fn a_bad_key_or_version_is_refused() {
+        let short_key = RFC_HEADERS.replace("dGhlIHNhbXBsZSBub25jZQ==", "c2hvcnQ=");
+        assert_eq!(handshake(&upgrade(&short_key)).unwrap_err().status, StatusCode::BadRequest);
+
+        let old_version = RFC_HEADERS.replace("Version: 13", "Version: 8");
+        let response = handshake(&upgrade(&old_version)).unwrap_err();
+        assert_eq!(response.status, StatusCode::UpgradeRequired);
+        assert_eq!(response.headers.get("Sec-WebSocket-Version"), Some("13"));
+
+        let no_connection = RFC_HEADERS.replace("Connection: Upgrade\r\n", "");
+        assert_eq!(handshake(&upgrade(&no_connection)).unwrap_err().status, StatusCode::BadRequest);
+    }
+}
@@ -0,0 +1,48 @@
+//! Path prefixes restricted to client networks, checked before routing.
+
+mod common;
+
+use common::{parse_responses, TestServer};
+use rust_web_server::acl::AccessControl;
+use rust_web_server::forwarded::TrustedProxies;
+use rust_web_server::{Config, Context, Method, Response, StatusCode};
+
+fn server(rules: &str) -> TestServer {
+    let config = Config {
+        access_control: AccessControl::new().with_rules(rules).0,
+        trusted_proxies: TrustedProxies::parse("127.0.0.0/8").0,
+        ..Config::default()
+    };
+    TestServer::start(config, |server| {
+        server.register(Method::Get, "/admin/config", |_: &mut Context| Response::new(StatusCode::Ok).with_body("secret"));
+        server.register(Method::Get, "/open", |_: &mut Context| Response::new(StatusCode::Ok));
+    })
+}
+
+fn status(server: &TestServer, path: &str, forwarded_for: &str) -> u16 {
+    let raw = format!("GET {path} HTTP/1.1\r\nHost: a\r\nX-Forwarded-For: {forwarded_for}\r\nConnection: close\r\n\r\n");
+    parse_responses(&server.exchange(raw.as_bytes()))[0].status
+}
+
+#[test]
+fn an_allowed_client_reaches_the_prefix() {
+    let server = server("/admin/*=10.0.0.0/8");
+    assert_eq!(status(&server, "/admin/config", "10.20.30.40"), 200);
+}
+
+#[test]
+fn a_denied_client_gets_forbidden() {
+    let server = server("/admin/*=10.0.0.0/8");
+    let raw = b"GET /admin/config HTTP/1.1\r\nHost: a\r\nX-Forwarded-For: 198.51.100.7\r\nConnection: close\r\n\r\n";
+    let response = parse_responses(&server.exchange(raw)).into_iter().next().unwrap();
+    assert_eq!(response.status, 403);
+    assert!(!response.body_str().contains("secret"));
+    // Even paths with no route under the prefix are refused, not 404.
+    assert_eq!(status(&server, "/admin/missing", "198.51.100.7"), 403);
+}
+
+#[test]
+fn paths_outside_every_rule_stay_open() {
+    let server = server("/admin/*=10.0.0.0/8");
+    assert_eq!(status(&server, "/open", "198.51.100.7"), 200);
+}
@@ -0,0 +1,101 @@
+//! The token-protected `/admin` endpoints.
+
+mod common;
+
+use std::path::PathBuf;
+
+use common::{handler_with, serve_one, Parsed};
+use rust_web_server::{admin, Config, ConnectionHandler, Context, Method, Response, StatusCode};
+use serde_json::Value;
+
+const TOKEN: &str = "admin-s3cret";
+
+fn admin_server(config: Config) -> ConnectionHandler {
+    let exposed = config.clone();
+    handler_with(config, move |server| {
+        server.register(Method::Get, "/admin/config", admin::protect(TOKEN, admin::config(&exposed)));
+    })
+}
+
+fn get(handler: &ConnectionHandler, path: &str, authorization: Option<&str>) -> Parsed {
+    let authorization = authorization.map(|value| format!("Authorization: {value}\r\n")).unwrap_or_default();
+    serve_one(handler, format!("GET {path} HTTP/1.1\r\nHost: a\r\n{authorization}Connection: close\r\n\r\n"))
+}
+
+#[test]
+fn config_dump_masks_secrets_and_shows_everything_else() {
+    let config = Config {
+        admin_token: Some(TOKEN.to_string()),
+        metrics_token: Some("metrics-s3cret".to_string()),
+        tls_key: Some(PathBuf::from("/etc/tls/server.key")),
+        pool_size: 3,
+        ..Config::default()
+    };
+    let handler = admin_server(config);
+    let response = get(&handler, "/admin/config", Some(&format!("Bearer {TOKEN}")));
+    assert_eq!(response.status, 200);
+    assert!(response.header("Content-Type").is_some_and(|t| t.starts_with("application/json")));
+    let body = response.body_str();
+    assert!(!body.contains("s3cret"), "secret leaked: {body}");
+
+    let json: Value = serde_json::from_str(body).unwrap();
+    assert_eq!(json["admin_token"], "[redacted]");
+    assert_eq!(json["metrics_token"], "[redacted]");
+    assert_eq!(json["tls_key"], "/etc/tls/server.key");
+    assert_eq!(json["pool_size"], 3);
+}
+
+#[test]
+fn unset_secrets_show_as_null() {
+    let handler = admin_server(Config::default());
+    let response = get(&handler, "/admin/config", Some(&format!("Bearer {TOKEN}")));
+    let json: Value = serde_json::from_slice(&response.body).unwrap();
+    assert!(json["metrics_token"].is_null());
+    assert!(json["admin_token"].is_null());
+}
+
+#[test]
+fn config_dump_needs_the_token() {
+    let handler = admin_server(Config::default());
+    for authorization in [None, Some("Bearer wrong"), Some(TOKEN), Some("Basic YWRtaW46YWRtaW4=")] {
+        let response = get(&handler, "/admin/config", authorization);
+        assert_eq!(response.status, 401, "{authorization:?}");
+        assert_eq!(response.header("WWW-Authenticate"), Some("Bearer"));
+        assert!(!response.body_str().contains("pool_size"));
+    }
+}
+
+fn routes_server() -> ConnectionHandler {
+    handler_with(Config::default(), |server| {
+        server.register(Method::Post, "/orders/:id", |_: &mut Context| Response::new(StatusCode::Ok));
+        server.register(Method::Get, "/orders/:id", |_: &mut Context| Response::new(StatusCode::Ok));
+        server.register(Method::Get, "/health", |_: &mut Context| Response::new(StatusCode::Ok));
+        let routes = admin::routes(server.router());
+        server.register(Method::Get, "/admin/routes", admin::protect(TOKEN, routes));
+    })
+}
+
+#[test]
+fn route_listing_includes_registered_routes_in_order() {
+    let handler = routes_server();
+    let response = get(&handler, "/admin/routes", Some(&format!("Bearer {TOKEN}")));
+    assert_eq!(response.status, 200);
+    let json: Value = serde_json::from_slice(&response.body).unwrap();
+    let routes: Vec<(String, String)> = json
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|route| (route["method"].as_str().unwrap().to_string(), route["path"].as_str().unwrap().to_string()))
+        .collect();
+    let expected = [("GET", "/admin/routes"), ("GET", "/health"), ("GET", "/orders/:id"), ("POST", "/orders/:id")];
+    let expected: Vec<(String, String)> = expected.iter().map(|(m, p)| (m.to_string(), p.to_string())).collect();
+    assert_eq!(routes, expected);
+}
+
+#[test]
+fn route_listing_needs_the_token() {
+    let handler = routes_server();
+    let response = get(&handler, "/admin/routes", None);
+    assert_eq!(response.status, 401);
+    assert!(!response.body_str().contains("/orders"));
+}
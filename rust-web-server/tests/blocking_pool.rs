@@ -0,0 +1,56 @@
+//! Blocking routes run on their own pool, so saturating it leaves the main
+//! pool free for everything else.
+
+mod common;
+
+use std::io::Write;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use common::{parse_responses, read_to_close, TestServer};
+use rust_web_server::{Config, Context, Method, Response, StatusCode};
+
+#[test]
+fn fast_requests_proceed_while_the_blocking_pool_is_saturated() {
+    let config = Config {
+        pool_size: 2,
+        blocking_pool_size: 1,
+        blocking_routes: vec!["/slow".to_string()],
+        ..Config::default()
+    };
+    let (started, slow_started) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    let (started, released) = (Mutex::new(started), Arc::new(Mutex::new(released)));
+    let server = TestServer::start(config, move |server| {
+        server.register(Method::Get, "/slow", move |_: &mut Context| {
+            started.lock().unwrap().send(()).unwrap();
+            let _ = released.lock().unwrap().recv_timeout(Duration::from_secs(10));
+            Response::new(StatusCode::Ok).with_body("slow")
+        });
+        server.register(Method::Get, "/fast", |_: &mut Context| Response::new(StatusCode::Ok).with_body("fast"));
+    });
+
+    // One slow request running on the blocking worker, two more queued behind it.
+    let mut slow: Vec<_> = (0..3)
+        .map(|_| {
+            let mut stream = server.connect();
+            stream.write_all(b"GET /slow HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+            stream
+        })
+        .collect();
+    slow_started.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    for _ in 0..5 {
+        let asked = Instant::now();
+        let output = server.exchange(b"GET /fast HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+        assert_eq!(parse_responses(&output)[0].body_str(), "fast");
+        assert!(asked.elapsed() < Duration::from_secs(2), "fast request waited {:?}", asked.elapsed());
+    }
+
+    drop(release);
+    for stream in &mut slow {
+        let output = read_to_close(stream);
+        assert_eq!(parse_responses(&output)[0].body_str(), "slow");
+    }
+}
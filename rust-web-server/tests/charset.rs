@@ -0,0 +1,44 @@
+//! `DEFAULT_CHARSET` is appended to text responses and nothing else.
+
+mod common;
+
+use common::{handler_with, serve_one};
+use rust_web_server::{Config, ConnectionHandler, Context, Method, Response, StatusCode};
+
+fn typed_server(config: Config) -> ConnectionHandler {
+    handler_with(config, |server| {
+        server.register(Method::Get, "/:kind/:subtype", |context: &mut Context| {
+            let content_type = format!("{}/{}", context.param("kind").unwrap(), context.param("subtype").unwrap());
+            Response::new(StatusCode::Ok).with_header("Content-Type", &content_type)
+        })
+    })
+}
+
+fn content_type(handler: &ConnectionHandler, content_type: &str) -> String {
+    let response = serve_one(handler, format!("GET /{content_type} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"));
+    response.header("Content-Type").unwrap().to_string()
+}
+
+#[test]
+fn text_types_get_the_default_charset() {
+    let handler = typed_server(Config::default());
+    assert_eq!(content_type(&handler, "text/html"), "text/html; charset=utf-8");
+    assert_eq!(content_type(&handler, "application/json"), "application/json; charset=utf-8");
+    assert_eq!(content_type(&handler, "image/png"), "image/png");
+    assert_eq!(content_type(&handler, "application/octet-stream"), "application/octet-stream");
+}
+
+#[test]
+fn the_charset_is_configurable_and_can_be_turned_off() {
+    let latin1 = typed_server(Config {
+        default_charset: Some("iso-8859-1".to_string()),
+        ..Config::default()
+    });
+    assert_eq!(content_type(&latin1, "text/plain"), "text/plain; charset=iso-8859-1");
+
+    let off = typed_server(Config {
+        default_charset: None,
+        ..Config::default()
+    });
+    assert_eq!(content_type(&off, "text/plain"), "text/plain");
+}
@@ -0,0 +1,308 @@
+//! Helpers shared by the integration tests: serving a connection from a
+//! buffer, running a real server on an ephemeral port, recording metrics
+//! and splitting the bytes that come back into responses.
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use rust_web_server::metrics_backend::{Labels, Metrics};
+use rust_web_server::{Config, ConnectionHandler, Server, ShutdownHandle};
+
+/// Reads the requests from a fixed buffer and collects what is written.
+pub struct MemoryStream {
+    input: Cursor<Vec<u8>>,
+    pub output: Vec<u8>,
+}
+
+impl MemoryStream {
+    pub fn new(input: impl Into<Vec<u8>>) -> Self {
+        MemoryStream {
+            input: Cursor::new(input.into()),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serves `input` as one connection and returns everything written back.
+pub fn serve(handler: &ConnectionHandler, input: impl Into<Vec<u8>>) -> Vec<u8> {
+    let mut stream = MemoryStream::new(input);
+    handler.serve(&mut stream);
+    stream.output
+}
+
+/// Serves `input` as one connection and returns the single response to it.
+pub fn serve_one(handler: &ConnectionHandler, input: impl Into<Vec<u8>>) -> Parsed {
+    let output = serve(handler, input);
+    let responses = parse_responses(&output);
+    assert_eq!(responses.len(), 1, "expected one response, got {:?}", String::from_utf8_lossy(&output));
+    responses.into_iter().next().unwrap()
+}
+
+/// A server with the default config, set up by `setup`, served from memory.
+pub fn handler(setup: impl FnOnce(&mut Server)) -> ConnectionHandler {
+    handler_with(Config::default(), setup)
+}
+
+pub fn handler_with(config: Config, setup: impl FnOnce(&mut Server)) -> ConnectionHandler {
+    let mut server = Server::new(config);
+    server.metrics(rust_web_server::NoopMetrics);
+    setup(&mut server);
+    server.connection_handler()
+}
+
+/// Keeps every counter increment, summed per name and label set.
+#[derive(Clone, Default)]
+pub struct RecordingMetrics {
+    counters: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl RecordingMetrics {
+    /// The total for `name` across all label sets.
+    pub fn counter(&self, name: &str) -> u64 {
+        let counters = self.counters.lock().unwrap();
+        counters
+            .iter()
+            .filter(|(key, _)| key.split('{').next() == Some(name))
+            .map(|(_, value)| value)
+            .sum()
+    }
+
+    /// The total for `name` with exactly these labels, written `k=v,k=v`.
+    pub fn counter_with(&self, name: &str, labels: &str) -> u64 {
+        let counters = self.counters.lock().unwrap();
+        counters.get(&format!("{name}{{{labels}}}")).copied().unwrap_or(0)
+    }
+}
+
+impl Metrics for RecordingMetrics {
+    fn counter(&self, name: &'static str, value: u64, labels: Labels) {
+        let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{k}={v}")).collect();
+        let key = format!("{name}{{{}}}", labels.join(","));
+        *self.counters.lock().unwrap().entry(key).or_default() += value;
+    }
+
+    fn gauge(&self, _: &'static str, _: f64, _: Labels) {}
+    fn histogram(&self, _: &'static str, _: f64, _: Labels) {}
+}
+
+/// One response split out of a connection's output.
+#[derive(Debug)]
+pub struct Parsed {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Parsed {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn body_str(&self) -> &str {
+        std::str::from_utf8(&self.body).expect("body is not UTF-8")
+    }
+}
+
+/// Splits `bytes` into responses, framing bodies by Content-Length or
+/// chunked encoding. Responses to HEAD need [`parse_head_response`].
+pub fn parse_responses(mut bytes: &[u8]) -> Vec<Parsed> {
+    let mut responses = Vec::new();
+    while !bytes.is_empty() {
+        let (response, rest) = parse_response(bytes, false);
+        responses.push(response);
+        bytes = rest;
+    }
+    responses
+}
+
+/// Parses a response to HEAD: framing headers but no body.
+pub fn parse_head_response(bytes: &[u8]) -> Parsed {
+    let (response, rest) = parse_response(bytes, true);
+    assert!(rest.is_empty(), "bytes after a HEAD response: {:?}", String::from_utf8_lossy(rest));
+    response
+}
+
+fn parse_response(bytes: &[u8], head: bool) -> (Parsed, &[u8]) {
+    let end = find(bytes, b"\r\n\r\n").unwrap_or_else(|| panic!("no response head in {:?}", String::from_utf8_lossy(bytes)));
+    let head_text = std::str::from_utf8(&bytes[..end]).expect("response head is not UTF-8");
+    let mut lines = head_text.split("\r\n");
+    let status_line = lines.next().unwrap();
+    let status = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .unwrap_or_else(|| panic!("bad status line {status_line:?}"));
+    let headers: Vec<(String, String)> = lines
+        .map(|line| {
+            let (name, value) = line.split_once(':').expect("header without a colon");
+            (name.to_string(), value.trim().to_string())
+        })
+        .collect();
+    let mut response = Parsed { status, headers, body: Vec::new() };
+    let rest = &bytes[end + 4..];
+    if head || status == 204 || status == 304 || (100..200).contains(&status) {
+        return (response, rest);
+    }
+    if response.header("Transfer-Encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked")) {
+        let (body, rest) = dechunk(rest);
+        response.body = body;
+        return (response, rest);
+    }
+    match response.header("Content-Length") {
+        Some(length) => {
+            let length: usize = length.parse().expect("bad Content-Length");
+            response.body = rest[..length].to_vec();
+            (response, &rest[length..])
+        }
+        // Delimited by the end of the connection.
+        None => {
+            response.body = rest.to_vec();
+            (response, &[])
+        }
+    }
+}
+
+fn dechunk(mut bytes: &[u8]) -> (Vec<u8>, &[u8]) {
+    let mut body = Vec::new();
+    loop {
+        let line_end = find(bytes, b"\r\n").expect("unterminated chunk size");
+        let size_text = std::str::from_utf8(&bytes[..line_end]).unwrap();
+        let size = usize::from_str_radix(size_text.split(';').next().unwrap().trim(), 16).expect("bad chunk size");
+        bytes = &bytes[line_end + 2..];
+        if size == 0 {
+            // Trailers, if any, end with an empty line.
+            let end = if bytes.starts_with(b"\r\n") {
+                2
+            } else {
+                find(bytes, b"\r\n\r\n").expect("unterminated trailers") + 4
+            };
+            return (body, &bytes[end..]);
+        }
+        body.extend_from_slice(&bytes[..size]);
+        bytes = &bytes[size + 2..];
+    }
+}
+
+pub fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// A directory under the system temp dir, removed when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        let dir = env::temp_dir().join(format!("rws-test-{}-{}", std::process::id(), NEXT_DIR.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    /// Writes `contents` to `name` under the directory.
+    pub fn file(&self, name: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A server running `Server::run` on 127.0.0.1 with an ephemeral port,
+/// shut down and joined when dropped.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    pub fn start(config: Config, setup: impl FnOnce(&mut Server)) -> TestServer {
+        let listener = rust_web_server::bind("127.0.0.1:0".parse().unwrap(), 128).expect("bind failed");
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::new(config);
+        server.metrics(rust_web_server::NoopMetrics);
+        setup(&mut server);
+        let shutdown = server.shutdown_handle();
+        let thread = thread::spawn(move || server.run(listener).expect("server failed to start"));
+        TestServer {
+            addr,
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(self.addr).expect("connect failed");
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        stream
+    }
+
+    /// Sends `raw` on a new connection and reads until the server closes it.
+    pub fn exchange(&self, raw: &[u8]) -> Vec<u8> {
+        let mut stream = self.connect();
+        stream.write_all(raw).unwrap();
+        read_to_close(&mut stream)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.shutdown();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Reads until the peer closes, tolerating a reset after its last write.
+pub fn read_to_close(stream: &mut TcpStream) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => output.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionReset => break,
+            Err(e) => panic!("read failed after {} bytes: {e}", output.len()),
+        }
+    }
+    output
+}
@@ -0,0 +1,33 @@
+//! Vary on compressible responses, compressed or not.
+
+mod common;
+
+use common::{handler_with, serve_one};
+use rust_web_server::compression::Encoding;
+use rust_web_server::{Config, Context, Method, Response, StatusCode};
+
+fn config() -> Config {
+    Config {
+        compression: vec![Encoding::Gzip],
+        compression_min_bytes: 100,
+        ..Config::default()
+    }
+}
+
+#[test]
+fn every_compressible_response_varies_on_accept_encoding() {
+    let handler = handler_with(config(), |server| {
+        server.register(Method::Get, "/small", |_: &mut Context| {
+            Response::new(StatusCode::Ok).with_header("Content-Type", "text/plain").with_body("tiny")
+        });
+        server.register(Method::Get, "/large", |_: &mut Context| {
+            Response::new(StatusCode::Ok).with_header("Content-Type", "text/plain").with_body("a".repeat(1000))
+        });
+    });
+    for (path, accept, encoded) in [("/small", "gzip", false), ("/large", "gzip", true), ("/large", "identity", false)] {
+        let raw = format!("GET {path} HTTP/1.1\r\nHost: a\r\nAccept-Encoding: {accept}\r\nConnection: close\r\n\r\n");
+        let response = serve_one(&handler, raw);
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"), "{path} with {accept}");
+        assert_eq!(response.header("Content-Encoding").is_some(), encoded, "{path} with {accept}");
+    }
+}
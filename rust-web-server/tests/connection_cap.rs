@@ -0,0 +1,90 @@
+//! `MAX_CONNECTIONS` in its two modes: turning extra connections away, or
+//! leaving them in the backlog until a slot frees up. And the per-IP cap,
+//! which always turns them away.
+
+mod common;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use common::{find, read_to_close, TestServer};
+use rust_web_server::limits::CapMode;
+use rust_web_server::{Config, Context, Method, Response, StatusCode};
+
+fn capped(mode: CapMode) -> TestServer {
+    let config = Config {
+        max_connections: 1,
+        max_connections_mode: mode,
+        pool_size: 4,
+        ..Config::default()
+    };
+    TestServer::start(config, |server| {
+        server.register(Method::Get, "/", |_: &mut Context| Response::new(StatusCode::Ok).with_body("ok"));
+    })
+}
+
+/// Opens a connection, gets one response and keeps the connection open.
+fn hold(server: &TestServer) -> TcpStream {
+    let mut stream = server.connect();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !(find(&response, b"\r\n\r\n").is_some() && response.ends_with(b"ok")) {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "closed early");
+        response.extend_from_slice(&buf[..n]);
+    }
+    assert!(response.starts_with(b"HTTP/1.1 200 "));
+    stream
+}
+
+#[test]
+fn block_mode_serves_a_connection_past_the_cap_once_one_finishes() {
+    let server = capped(CapMode::Block);
+    let first = hold(&server);
+
+    let mut second = server.connect();
+    second.write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+    second.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let error = second.read(&mut [0u8; 64]).unwrap_err();
+    assert!(matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut), "{error:?}");
+
+    drop(first);
+    second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let output = read_to_close(&mut second);
+    assert!(output.starts_with(b"HTTP/1.1 200 "), "{:?}", String::from_utf8_lossy(&output));
+}
+
+#[test]
+fn reject_mode_turns_a_connection_past_the_cap_away() {
+    let server = capped(CapMode::Reject);
+    let _first = hold(&server);
+
+    let mut second = server.connect();
+    let output = read_to_close(&mut second);
+    let output = String::from_utf8_lossy(&output);
+    assert!(output.starts_with("HTTP/1.1 503 "), "{output}");
+    assert!(output.contains("Retry-After: 1\r\n"), "{output}");
+    assert!(output.contains("Connection: close\r\n"), "{output}");
+}
+
+#[test]
+fn the_per_ip_cap_turns_connections_away_with_a_retry() {
+    let config = Config {
+        max_connections_per_ip: 1,
+        pool_size: 4,
+        ..Config::default()
+    };
+    let server = TestServer::start(config, |server| {
+        server.register(Method::Get, "/", |_: &mut Context| Response::new(StatusCode::Ok).with_body("ok"));
+    });
+    let _first = hold(&server);
+
+    let mut second = server.connect();
+    let output = read_to_close(&mut second);
+    let output = String::from_utf8_lossy(&output);
+    assert!(output.starts_with("HTTP/1.1 503 "), "{output}");
+    assert!(output.contains("Retry-After: 1\r\n"), "{output}");
+    assert!(output.contains("Connection: close\r\n"), "{output}");
+}
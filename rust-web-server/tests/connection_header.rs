@@ -0,0 +1,35 @@
+//! Persistence decided by any token of a multi-token `Connection` header.
+
+mod common;
+
+use common::{handler, parse_responses, serve};
+use rust_web_server::{Context, Method, Response, StatusCode};
+
+/// How many of two pipelined requests get answered, the first sent with
+/// `version` and `connection`.
+fn answered(version: &str, connection: &str) -> usize {
+    let handler = handler(|server| {
+        server.register(Method::Get, "/", |_: &mut Context| Response::new(StatusCode::Ok));
+    });
+    let raw = format!("GET / {version}\r\nHost: a\r\nConnection: {connection}\r\n\r\nGET / HTTP/1.1\r\nHost: a\r\n\r\n");
+    parse_responses(&serve(&handler, raw)).len()
+}
+
+#[test]
+fn close_anywhere_in_the_list_ends_the_connection() {
+    assert_eq!(answered("HTTP/1.1", "X-Trace, close"), 1);
+    assert_eq!(answered("HTTP/1.1", "keep-alive,CLOSE"), 1);
+    assert_eq!(answered("HTTP/1.0", "keep-alive, close"), 1);
+}
+
+#[test]
+fn keep_alive_anywhere_in_the_list_keeps_an_http_1_0_connection() {
+    assert_eq!(answered("HTTP/1.0", "X-Trace, Keep-Alive"), 2);
+    assert_eq!(answered("HTTP/1.0", "X-Trace"), 1);
+}
+
+#[test]
+fn other_tokens_leave_http_1_1_persistent() {
+    assert_eq!(answered("HTTP/1.1", "X-Trace, TE"), 2);
+    assert_eq!(answered("HTTP/1.1", "closed"), 2);
+}
@@ -0,0 +1,41 @@
+//! Request targets carrying control characters, raw or percent-encoded, are
+//! refused before any handler or file lookup sees them.
+
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use common::{handler, serve_one};
+use rust_web_server::{Context, Method, Response, StatusCode};
+
+#[test]
+fn null_bytes_and_newlines_in_the_path_are_a_400() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&calls);
+    let handler = handler(move |server| {
+        server.register(Method::Get, "/files/:name", move |_: &mut Context| {
+            counted.fetch_add(1, Ordering::Relaxed);
+            Response::new(StatusCode::Ok)
+        });
+    });
+    let targets: [&[u8]; 6] = [
+        b"/files/a\0.txt",
+        b"/files/a%00.txt",
+        b"/files/a\nb",
+        b"/files/a%0aX-Injected:%20yes",
+        b"/files/a%0D%0Ab",
+        b"/files/a\x7f",
+    ];
+    for target in targets {
+        let mut raw = b"GET ".to_vec();
+        raw.extend_from_slice(target);
+        raw.extend_from_slice(b" HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+        let response = serve_one(&handler, raw);
+        assert_eq!(response.status, 400, "{:?}", String::from_utf8_lossy(target));
+    }
+    assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+    let response = serve_one(&handler, "GET /files/a%20b.txt HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.status, 200);
+}
@@ -0,0 +1,33 @@
+//! The client address seen by handlers behind a trusted proxy.
+
+mod common;
+
+use common::{parse_responses, TestServer};
+use rust_web_server::forwarded::TrustedProxies;
+use rust_web_server::{Config, Context, Method, Response, StatusCode};
+
+fn client_ip(trusted_proxies: &str) -> String {
+    let config = Config {
+        trusted_proxies: TrustedProxies::parse(trusted_proxies).0,
+        ..Config::default()
+    };
+    let server = TestServer::start(config, |server| {
+        server.register(Method::Get, "/ip", |context: &mut Context| {
+            let ip = context.request.client_ip.map(|ip| ip.to_string()).unwrap_or_default();
+            Response::new(StatusCode::Ok).with_body(ip)
+        });
+    });
+    let output = server.exchange(b"GET /ip HTTP/1.1\r\nHost: a\r\nX-Forwarded-For: 198.51.100.7\r\nConnection: close\r\n\r\n");
+    parse_responses(&output)[0].body_str().to_string()
+}
+
+#[test]
+fn a_trusted_peer_forwards_the_client_address() {
+    assert_eq!(client_ip("127.0.0.0/8"), "198.51.100.7");
+}
+
+#[test]
+fn forwarding_headers_from_untrusted_peers_are_ignored() {
+    assert_eq!(client_ip(""), "127.0.0.1");
+    assert_eq!(client_ip("10.0.0.0/8"), "127.0.0.1");
+}
@@ -0,0 +1,79 @@
+//! How a handler's `HandlerError` becomes the response the client sees.
+
+mod common;
+
+use std::io;
+
+use common::{serve_one, Parsed, RecordingMetrics};
+use rust_web_server::{Config, ConnectionHandler, Context, HandlerError, Method, Response, Server, StatusCode};
+
+fn failing_server(metrics: RecordingMetrics, customize: impl FnOnce(&mut Server)) -> ConnectionHandler {
+    let mut server = Server::new(Config::default());
+    server.metrics(metrics);
+    server.register(Method::Get, "/missing", |_: &mut Context| -> Result<Response, HandlerError> {
+        Err(HandlerError::NotFound)
+    });
+    server.register(Method::Get, "/bad", |_: &mut Context| -> Result<Response, HandlerError> {
+        Err(HandlerError::BadRequest("quantity must be positive".to_string()))
+    });
+    server.register(Method::Get, "/secret", |_: &mut Context| -> Result<Response, HandlerError> {
+        Err(HandlerError::Unauthorized)
+    });
+    server.register(Method::Get, "/broken", |_: &mut Context| -> Result<Response, HandlerError> {
+        Err(HandlerError::internal(io::Error::other("db password is hunter2")))
+    });
+    customize(&mut server);
+    server.connection_handler()
+}
+
+fn get(handler: &ConnectionHandler, path: &str) -> Parsed {
+    serve_one(handler, format!("GET {path} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"))
+}
+
+#[test]
+fn each_variant_has_its_default_response() {
+    let metrics = RecordingMetrics::default();
+    let handler = failing_server(metrics.clone(), |_| {});
+
+    let missing = get(&handler, "/missing");
+    assert_eq!(missing.status, 404);
+    assert_eq!(missing.body, std::fs::read("404.html").unwrap());
+
+    let bad = get(&handler, "/bad");
+    assert_eq!(bad.status, 400);
+    assert_eq!(bad.body_str(), "quantity must be positive");
+    assert!(bad.header("Content-Type").is_some_and(|t| t.starts_with("text/plain")));
+
+    let secret = get(&handler, "/secret");
+    assert_eq!(secret.status, 401);
+    assert!(secret.body.is_empty());
+
+    let broken = get(&handler, "/broken");
+    assert_eq!(broken.status, 500);
+    assert!(!broken.body_str().contains("hunter2"));
+    assert_eq!(metrics.counter_with("handler_errors_total", "path=/broken"), 1);
+    // Only internal errors count as failures.
+    assert_eq!(metrics.counter("handler_errors_total"), 1);
+}
+
+#[test]
+fn a_custom_error_handler_replaces_the_mapping() {
+    let handler = failing_server(RecordingMetrics::default(), |server| {
+        server.error_handler(|error: &HandlerError, request: &rust_web_server::Request| {
+            let status = match error {
+                HandlerError::NotFound => StatusCode::NotFound,
+                HandlerError::BadRequest(_) => StatusCode::BadRequest,
+                HandlerError::Unauthorized => StatusCode::Unauthorized,
+                HandlerError::Internal(_) => StatusCode::InternalServerError,
+            };
+            Response::new(status).with_body(format!("branded: {} on {}", status.as_u16(), request.path))
+        });
+    });
+    for (path, status) in [("/missing", 404), ("/bad", 400), ("/secret", 401), ("/broken", 500)] {
+        let response = get(&handler, path);
+        assert_eq!(response.status, status, "{path}");
+        assert_eq!(response.body_str(), format!("branded: {status} on {path}"));
+    }
+    // Unrouted paths go through it as well.
+    assert_eq!(get(&handler, "/nowhere").body_str(), "branded: 404 on /nowhere");
+}
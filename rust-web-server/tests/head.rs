@@ -0,0 +1,85 @@
+//! HEAD gets the headers GET would, byte for byte, without the body.
+
+mod common;
+
+use common::{handler_with, parse_head_response, parse_responses, serve, Parsed, TempDir};
+use rust_web_server::compression::Encoding;
+use rust_web_server::{Config, ConnectionHandler, Context, HandlerError, StaticFiles};
+
+fn static_server(root: &TempDir, config: Config) -> ConnectionHandler {
+    let files = StaticFiles::new(&root.0);
+    handler_with(config, move |server| {
+        server.fallback(move |context: &mut Context| files.serve(context.request).ok_or(HandlerError::NotFound))
+    })
+}
+
+fn fetch(handler: &ConnectionHandler, method: &str, path: &str, accept_encoding: Option<&str>) -> Parsed {
+    let accept = accept_encoding.map(|value| format!("Accept-Encoding: {value}\r\n")).unwrap_or_default();
+    let request = format!("{method} {path} HTTP/1.1\r\nHost: a\r\n{accept}Connection: close\r\n\r\n");
+    let output = serve(handler, request);
+    if method == "HEAD" {
+        parse_head_response(&output)
+    } else {
+        parse_responses(&output).remove(0)
+    }
+}
+
+const COMPARED: [&str; 7] = ["Content-Length", "Content-Type", "Content-Encoding", "ETag", "Last-Modified", "Vary", "Cache-Control"];
+
+fn assert_same_headers(handler: &ConnectionHandler, path: &str, accept_encoding: Option<&str>) -> (Parsed, Parsed) {
+    let get = fetch(handler, "GET", path, accept_encoding);
+    let head = fetch(handler, "HEAD", path, accept_encoding);
+    assert_eq!(get.status, 200);
+    assert_eq!(head.status, 200);
+    for name in COMPARED {
+        assert_eq!(head.header(name), get.header(name), "{name} for {path} with {accept_encoding:?}");
+    }
+    assert_eq!(head.header("Content-Length"), Some(get.body.len().to_string().as_str()));
+    (get, head)
+}
+
+#[test]
+fn head_matches_get_for_a_static_file() {
+    let root = TempDir::new();
+    root.file("page.html", "<p>hello</p>".repeat(200));
+    root.file("logo.png", [0x89, b'P', b'N', b'G'].repeat(500));
+    let handler = static_server(&root, Config::default());
+    for path in ["/page.html", "/logo.png"] {
+        let (_, head) = assert_same_headers(&handler, path, None);
+        assert!(head.body.is_empty());
+    }
+}
+
+#[test]
+fn head_matches_get_with_compression_on() {
+    let root = TempDir::new();
+    root.file("page.html", "<p>hello</p>".repeat(200));
+    root.file("tiny.txt", "hi");
+    let config = Config {
+        compression: vec![Encoding::Brotli, Encoding::Gzip],
+        compression_min_bytes: 64,
+        ..Config::default()
+    };
+    let handler = static_server(&root, config);
+
+    for accept_encoding in [Some("gzip"), Some("br, gzip"), Some("identity"), None] {
+        assert_same_headers(&handler, "/page.html", accept_encoding);
+        assert_same_headers(&handler, "/tiny.txt", accept_encoding);
+    }
+
+    let (get, head) = assert_same_headers(&handler, "/page.html", Some("gzip"));
+    assert_eq!(head.header("Content-Encoding"), Some("gzip"));
+    assert!(head.header("ETag").is_some_and(|etag| etag.ends_with("-gzip\"")));
+    assert!(get.body.len() < 2400, "body wasn't compressed");
+}
+
+#[test]
+fn head_matches_get_for_a_precompressed_file() {
+    let root = TempDir::new();
+    root.file("app.js", "console.log(1);".repeat(100));
+    root.file("app.js.gz", [0x1f, 0x8b, 8, 0, 1, 2, 3]);
+    let handler = static_server(&root, Config::default());
+    let (get, _) = assert_same_headers(&handler, "/app.js", Some("gzip"));
+    assert_eq!(get.header("Content-Encoding"), Some("gzip"));
+    assert_eq!(get.body.len(), 7);
+}
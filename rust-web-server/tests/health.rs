@@ -0,0 +1,68 @@
+//! The health check's opt-in pool probe.
+
+mod common;
+
+use std::io::Write;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use common::{parse_responses, read_to_close, Parsed, TestServer};
+use rust_web_server::{health, Config, Context, Method, Response, StatusCode};
+use serde_json::Value;
+
+fn health_of(server: &TestServer) -> (Parsed, Value) {
+    let output = server.exchange(b"GET /health HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    let response = parse_responses(&output).into_iter().next().unwrap();
+    let body = serde_json::from_slice(&response.body).unwrap();
+    (response, body)
+}
+
+#[test]
+fn a_saturated_pool_fails_the_probe() {
+    let config = Config {
+        pool_size: 2,
+        ..Config::default()
+    };
+    let (started, busy) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    let (started, released) = (Mutex::new(started), Arc::new(Mutex::new(released)));
+    let server = TestServer::start(config, move |server| {
+        server.register(Method::Get, "/health", health::handler(server.stats(), Duration::from_millis(200)));
+        server.register(Method::Get, "/busy", move |_: &mut Context| {
+            started.lock().unwrap().send(()).unwrap();
+            let _ = released.lock().unwrap().recv_timeout(Duration::from_secs(10));
+            Response::new(StatusCode::Ok)
+        });
+    });
+
+    let (response, body) = health_of(&server);
+    assert_eq!(response.status, 200);
+    assert_eq!(body["workers"], "ok");
+
+    // The other worker is taken, so the probe has nowhere to run.
+    let mut busy_stream = server.connect();
+    busy_stream.write_all(b"GET /busy HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+    busy.recv_timeout(Duration::from_secs(5)).unwrap();
+    let (response, body) = health_of(&server);
+    assert_eq!(response.status, 503);
+    assert_eq!(body["workers"], "unresponsive");
+
+    drop(release);
+    read_to_close(&mut busy_stream);
+    // The worker may still be finishing that connection when it closes.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while health_of(&server).0.status != 200 {
+        assert!(Instant::now() < deadline, "the probe never recovered");
+    }
+}
+
+#[test]
+fn without_a_probe_timeout_the_pool_is_not_checked() {
+    let server = TestServer::start(Config::default(), |server| {
+        server.register(Method::Get, "/health", health::handler(server.stats(), Duration::ZERO));
+    });
+    let (response, body) = health_of(&server);
+    assert_eq!(response.status, 200);
+    assert_eq!(body, serde_json::json!({ "status": "ok" }));
+}
@@ -0,0 +1,75 @@
+//! Idle connections are closed on `keepalive_timeout`, while a request that
+//! has started arriving is read under `read_timeout`.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{read_to_close, TestServer};
+use rust_web_server::{Config, Context, Method, Response, StatusCode};
+
+fn server() -> TestServer {
+    let config = Config {
+        keepalive_timeout: Duration::from_millis(300),
+        read_timeout: Duration::from_secs(5),
+        ..Config::default()
+    };
+    TestServer::start(config, |server| {
+        server.register(Method::Get, "/", |_: &mut Context| Response::new(StatusCode::Ok).with_body("hi"));
+        server.register(Method::Post, "/", |_: &mut Context| Response::new(StatusCode::Ok));
+    })
+}
+
+/// Reads one response to a GET `/`, leaving the connection open.
+fn read_response(stream: &mut TcpStream) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !output.ends_with(b"\r\n\r\nhi") {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "closed early: {:?}", String::from_utf8_lossy(&output));
+        output.extend_from_slice(&buf[..n]);
+    }
+    output
+}
+
+#[test]
+fn an_idle_kept_alive_connection_is_closed_after_the_timeout() {
+    let server = server();
+    let mut stream = server.connect();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    read_response(&mut stream);
+
+    // Within the timeout the connection is still there for another request.
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    read_response(&mut stream);
+
+    let idle_since = Instant::now();
+    assert!(read_to_close(&mut stream).is_empty());
+    let idle = idle_since.elapsed();
+    assert!(idle >= Duration::from_millis(250), "closed after {idle:?}");
+    assert!(idle < Duration::from_secs(3), "closed after {idle:?}");
+}
+
+#[test]
+fn a_connection_that_never_sends_a_request_is_closed_too() {
+    let server = server();
+    let mut stream = server.connect();
+    let started = Instant::now();
+    read_to_close(&mut stream);
+    assert!(started.elapsed() < Duration::from_secs(3));
+}
+
+#[test]
+fn a_request_in_progress_may_pause_longer_than_the_idle_timeout() {
+    let server = server();
+    let mut stream = server.connect();
+    stream.write_all(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\nConnection: close\r\n\r\nab").unwrap();
+    thread::sleep(Duration::from_millis(800));
+    stream.write_all(b"cd").unwrap();
+    let output = read_to_close(&mut stream);
+    assert!(output.starts_with(b"HTTP/1.1 200 "), "{:?}", String::from_utf8_lossy(&output));
+}
@@ -0,0 +1,63 @@
+//! `KEEPALIVE_MAX_REQUESTS` over a real connection.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use common::{find, parse_responses, Parsed, TestServer};
+use rust_web_server::{Config, Context, Method, Response, StatusCode};
+
+/// Reads exactly one response, framed by its Content-Length.
+fn read_response(stream: &mut TcpStream) -> Parsed {
+    let mut bytes = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        if let Some(end) = find(&bytes, b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&bytes[..end]).to_ascii_lowercase();
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .map_or(0, |length| length.trim().parse().unwrap());
+            if bytes.len() >= end + 4 + length {
+                return parse_responses(&bytes).into_iter().next().unwrap();
+            }
+        }
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "connection closed mid-response");
+        bytes.extend_from_slice(&buf[..n]);
+    }
+}
+
+#[test]
+fn a_connection_allowed_three_requests_closes_after_the_third() {
+    let config = Config {
+        keepalive_max_requests: 3,
+        ..Config::default()
+    };
+    let server = TestServer::start(config, |server| {
+        server.register(Method::Get, "/", |_: &mut Context| Response::new(StatusCode::Ok).with_body("ok"));
+    });
+    let mut stream = server.connect();
+    let timeout = Config::default().keepalive_timeout.as_secs();
+
+    for remaining in [2, 1] {
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Connection"), Some("keep-alive"));
+        assert_eq!(response.header("Keep-Alive"), Some(format!("timeout={timeout}, max={remaining}").as_str()));
+    }
+
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    let response = read_response(&mut stream);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Connection"), Some("close"));
+    assert_eq!(response.header("Keep-Alive"), None);
+
+    // The server has hung up; a fourth request gets nothing back.
+    let _ = stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+    let mut rest = Vec::new();
+    let _ = stream.read_to_end(&mut rest);
+    assert!(rest.is_empty(), "{:?}", String::from_utf8_lossy(&rest));
+}
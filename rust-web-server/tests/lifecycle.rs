@@ -0,0 +1,64 @@
+//! Startup hooks run before the first connection is accepted, shutdown
+//! hooks after the pool drains, last registered first.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use common::{parse_responses, TestServer};
+use rust_web_server::{Config, Context, HookError, Method, Response, Server, StatusCode};
+
+type Log = Arc<Mutex<Vec<&'static str>>>;
+
+fn record(log: &Log, event: &'static str) -> impl Fn() + Send + 'static {
+    let log = Arc::clone(log);
+    move || log.lock().unwrap().push(event)
+}
+
+/// A startup hook that records `event` and succeeds.
+fn starting(log: &Log, event: &'static str) -> impl FnOnce() -> Result<(), HookError> + Send + 'static {
+    let record = record(log, event);
+    move || {
+        record();
+        Ok(())
+    }
+}
+
+#[test]
+fn hooks_run_around_the_accept_loop_in_order() {
+    let log = Log::default();
+    let server = TestServer::start(Config::default(), |server| {
+        server.on_startup(starting(&log, "startup 1"));
+        server.on_startup(starting(&log, "startup 2"));
+        server.on_shutdown(record(&log, "shutdown 1"));
+        server.on_shutdown(record(&log, "shutdown 2"));
+        let request = record(&log, "request");
+        server.register(Method::Get, "/", move |_: &mut Context| {
+            request();
+            Response::new(StatusCode::Ok)
+        });
+    });
+    let output = server.exchange(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(parse_responses(&output)[0].status, 200);
+    drop(server);
+
+    assert_eq!(*log.lock().unwrap(), ["startup 1", "startup 2", "request", "shutdown 2", "shutdown 1"]);
+}
+
+#[test]
+fn a_failed_startup_still_runs_every_shutdown_hook() {
+    let log = Log::default();
+    let mut server = Server::new(Config::default());
+    server.metrics(rust_web_server::NoopMetrics);
+    server.on_startup(starting(&log, "startup 1"));
+    server.on_startup(|| Err("database unreachable".into()));
+    server.on_startup(starting(&log, "startup 3"));
+    server.on_shutdown(record(&log, "shutdown 1"));
+    server.on_shutdown(|| panic!("teardown failed"));
+    server.on_shutdown(record(&log, "shutdown 3"));
+
+    let listener = rust_web_server::bind("127.0.0.1:0".parse().unwrap(), 16).unwrap();
+    let error = server.run(listener).unwrap_err();
+    assert_eq!(error.to_string(), "database unreachable");
+    assert_eq!(*log.lock().unwrap(), ["startup 1", "shutdown 3", "shutdown 1"]);
+}
@@ -0,0 +1,27 @@
+//! Maintenance mode answers 503 everywhere but the health and admin paths.
+
+mod common;
+
+use common::{handler_with, serve_one};
+use rust_web_server::{Config, Context, Method, Response, StatusCode};
+
+#[test]
+fn the_configured_health_path_stays_up_in_maintenance() {
+    let config = Config {
+        maintenance: true,
+        health_path: Some("/ready".to_string()),
+        ..Config::default()
+    };
+    let handler = handler_with(config, |server| {
+        for path in ["/ready", "/health", "/admin/maintenance", "/readyz", "/orders"] {
+            server.register(Method::Get, path, |_: &mut Context| Response::new(StatusCode::Ok));
+        }
+    });
+    for (path, status) in [("/ready", 200), ("/admin/maintenance", 200), ("/health", 503), ("/readyz", 503), ("/orders", 503)] {
+        let response = serve_one(&handler, format!("GET {path} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"));
+        assert_eq!(response.status, status, "{path}");
+        if status == 503 {
+            assert_eq!(response.header("Retry-After"), Some("300"));
+        }
+    }
+}
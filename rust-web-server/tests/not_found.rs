@@ -0,0 +1,55 @@
+//! Unrouted requests are answered by the configured not-found mode.
+
+mod common;
+
+use common::{handler_with, serve_one, Parsed};
+use rust_web_server::not_found::{NotFoundMode, NotFoundPolicy};
+use rust_web_server::{Config, ConnectionHandler, Context, Method, Response, StatusCode};
+
+fn server(not_found: NotFoundPolicy) -> ConnectionHandler {
+    let config = Config {
+        not_found,
+        ..Config::default()
+    };
+    handler_with(config, |server| {
+        server.register(Method::Get, "/api/users", |_: &mut Context| Response::new(StatusCode::Ok));
+    })
+}
+
+fn get(handler: &ConnectionHandler, path: &str) -> Parsed {
+    serve_one(handler, format!("GET {path} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"))
+}
+
+#[test]
+fn the_page_mode_serves_404_html() {
+    let response = get(&server(NotFoundPolicy::default()), "/nope");
+    assert_eq!(response.status, 404);
+    assert_eq!(response.body, std::fs::read("404.html").unwrap());
+}
+
+#[test]
+fn the_json_mode_answers_with_an_error_body() {
+    let response = get(&server(NotFoundPolicy::new(NotFoundMode::Json)), "/nope");
+    assert_eq!(response.status, 404);
+    assert!(response.header("Content-Type").is_some_and(|t| t.starts_with("application/json")));
+    let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(body["path"], "/nope");
+}
+
+#[test]
+fn the_redirect_mode_sends_a_302() {
+    let response = get(&server(NotFoundPolicy::new(NotFoundMode::Redirect("/home".to_string()))), "/nope");
+    assert_eq!(response.status, 302);
+    assert_eq!(response.header("Location"), Some("/home"));
+}
+
+#[test]
+fn prefix_rules_override_the_default_and_routes_still_win() {
+    let handler = server(NotFoundPolicy::default().rule("/api/*", NotFoundMode::Json));
+    assert_eq!(get(&handler, "/api/users").status, 200);
+    let api = get(&handler, "/api/orders");
+    assert_eq!(api.status, 404);
+    assert!(api.header("Content-Type").is_some_and(|t| t.starts_with("application/json")));
+    let page = get(&handler, "/orders");
+    assert_eq!(page.body, std::fs::read("404.html").unwrap());
+}
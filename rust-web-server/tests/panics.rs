@@ -0,0 +1,27 @@
+//! A handler that panics costs its request a 500, not the connection or
+//! the worker.
+
+mod common;
+
+use common::{parse_responses, serve, RecordingMetrics};
+use rust_web_server::{Config, Context, Method, Response, Server, StatusCode};
+
+#[test]
+fn handler_panic_is_a_500_and_is_counted() {
+    let metrics = RecordingMetrics::default();
+    let mut server = Server::new(Config::default());
+    server.metrics(metrics.clone());
+    server.register(Method::Get, "/boom", |_: &mut Context| -> Response { panic!("handler bug") });
+    server.register(Method::Get, "/ok", |_: &mut Context| Response::new(StatusCode::Ok).with_body("fine"));
+    let handler = server.connection_handler();
+
+    // The connection carries on to the next pipelined request.
+    let output = serve(&handler, "GET /boom HTTP/1.1\r\nHost: a\r\n\r\nGET /ok HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    let responses = parse_responses(&output);
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0].status, 500);
+    assert_eq!(responses[1].status, 200);
+    assert_eq!(responses[1].body_str(), "fine");
+
+    assert_eq!(metrics.counter_with("handler_panics_total", "path=/boom"), 1);
+}
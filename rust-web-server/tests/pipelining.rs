@@ -0,0 +1,90 @@
+//! Requests sent back-to-back on one connection are answered in order,
+//! each response whole before the next begins.
+
+mod common;
+
+use common::{handler, handler_with, parse_responses, serve};
+use rust_web_server::{Config, Context, Method, Response, StatusCode};
+
+fn echo_path(context: &mut Context) -> Response {
+    Response::new(StatusCode::Ok).with_body(context.request.path.clone())
+}
+
+#[test]
+fn two_pipelined_gets_get_two_ordered_responses() {
+    let handler = handler(|server| {
+        server.register(Method::Get, "/first", echo_path);
+        server.register(Method::Get, "/second", echo_path);
+    });
+    let output = serve(&handler, "GET /first HTTP/1.1\r\nHost: a\r\n\r\nGET /second HTTP/1.1\r\nHost: a\r\n\r\n");
+    let responses = parse_responses(&output);
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0].body_str(), "/first");
+    assert_eq!(responses[1].body_str(), "/second");
+}
+
+#[test]
+fn pipelined_post_bodies_are_not_mistaken_for_requests() {
+    let handler = handler(|server| {
+        server.register(Method::Post, "/echo", |context: &mut Context| {
+            Response::new(StatusCode::Ok).with_body(context.request.body.clone())
+        });
+        server.register(Method::Get, "/after", echo_path);
+    });
+    let body = "GET /smuggled HTTP/1.1\r\n\r\n";
+    let input = format!(
+        "POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\r\n{}GET /after HTTP/1.1\r\nHost: a\r\n\r\n",
+        body.len(),
+        body
+    );
+    let responses = parse_responses(&serve(&handler, input));
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0].body_str(), body);
+    assert_eq!(responses[1].body_str(), "/after");
+}
+
+#[test]
+fn a_body_given_to_a_204_does_not_run_into_the_next_response() {
+    let handler = handler(|server| {
+        server.register(Method::Delete, "/item", |_: &mut Context| {
+            Response::new(StatusCode::NoContent).with_body("HTTP/1.1 200 OK\r\n\r\n")
+        });
+        server.register(Method::Get, "/after", echo_path);
+    });
+    let output = serve(&handler, "DELETE /item HTTP/1.1\r\nHost: a\r\n\r\nGET /after HTTP/1.1\r\nHost: a\r\n\r\n");
+    let responses = parse_responses(&output);
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0].status, 204);
+    assert_eq!(responses[1].status, 200);
+    assert_eq!(responses[1].body_str(), "/after");
+}
+
+#[test]
+fn the_keep_alive_request_limit_still_applies() {
+    let config = Config {
+        keepalive_max_requests: 2,
+        ..Config::default()
+    };
+    let handler = handler_with(config, |server| server.register(Method::Get, "/n", echo_path));
+    let output = serve(&handler, "GET /n HTTP/1.1\r\nHost: a\r\n\r\n".repeat(3));
+    let responses = parse_responses(&output);
+    assert_eq!(responses.len(), 2, "the third request should go unanswered");
+    assert_ne!(responses[0].header("Connection"), Some("close"));
+    assert_eq!(responses[1].header("Connection"), Some("close"));
+}
+
+#[test]
+fn responses_without_a_handler_set_length_stay_framed() {
+    let handler = handler(|server| {
+        server.register(Method::Get, "/text", |_: &mut Context| Response::new(StatusCode::Ok).with_body("héllo"));
+        server.register(Method::Get, "/empty", |_: &mut Context| Response::new(StatusCode::Ok));
+    });
+    let input = "GET /text HTTP/1.1\r\nHost: a\r\n\r\nGET /empty HTTP/1.1\r\nHost: a\r\n\r\nGET /text HTTP/1.1\r\nHost: a\r\n\r\n";
+    let responses = parse_responses(&serve(&handler, input));
+    assert_eq!(responses.len(), 3);
+    for response in [&responses[0], &responses[2]] {
+        assert_eq!(response.header("Content-Length"), Some("6"));
+        assert_eq!(response.body_str(), "héllo");
+    }
+    assert_eq!(responses[1].header("Content-Length"), Some("0"));
+}
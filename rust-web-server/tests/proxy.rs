@@ -0,0 +1,157 @@
+//! Forwarding requests to an upstream listening on a local port, and how
+//! its answer (or its absence) reaches the client.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use common::{find, parse_responses, Parsed, TestServer};
+use rust_web_server::proxy::{self, Upstream};
+use rust_web_server::{Config, Method};
+
+/// An upstream that answers one request with `response` and hands over the
+/// request it received, head and body.
+fn upstream(response: &'static [u8]) -> (Upstream, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let upstream = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        let head_end = loop {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "the proxy closed before sending a whole head");
+            received.extend_from_slice(&buf[..n]);
+            if let Some(end) = find(&received, b"\r\n\r\n") {
+                break end + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&received[..head_end]).to_string();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |length| length.parse().unwrap());
+        while received.len() < head_end + length {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "the proxy closed before sending the whole body");
+            received.extend_from_slice(&buf[..n]);
+        }
+        sender.send(String::from_utf8_lossy(&received).to_string()).unwrap();
+        stream.write_all(response).unwrap();
+    });
+    (upstream, receiver)
+}
+
+fn proxy_server(upstream: Upstream, config: Config) -> TestServer {
+    let timeout = config.proxy_timeout;
+    TestServer::start(config, move |server| {
+        for method in [Method::Get, Method::Post] {
+            server.register(method, "/api/*", proxy::handler(upstream.clone(), timeout));
+        }
+    })
+}
+
+fn exchange(server: &TestServer, raw: &str) -> Parsed {
+    let mut responses = parse_responses(&server.exchange(raw.as_bytes()));
+    assert_eq!(responses.len(), 1);
+    responses.remove(0)
+}
+
+const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+#[test]
+fn an_upstream_nothing_listens_on_is_a_bad_gateway() {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let server = proxy_server(format!("http://{addr}").parse().unwrap(), Config::default());
+    let response = exchange(&server, "GET /api/users HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.status, 502);
+}
+
+#[test]
+fn a_silent_upstream_is_a_gateway_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let upstream = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+    // Accepts, then never answers.
+    thread::spawn(move || {
+        let accepted = listener.accept();
+        thread::sleep(Duration::from_secs(5));
+        drop(accepted);
+    });
+    let config = Config {
+        proxy_timeout: Duration::from_millis(200),
+        ..Config::default()
+    };
+    let server = proxy_server(upstream, config);
+    let response = exchange(&server, "GET /api/slow HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.status, 504);
+}
+
+#[test]
+fn hop_by_hop_headers_are_not_forwarded() {
+    let (upstream, received) = upstream(OK);
+    let server = proxy_server(upstream, Config::default());
+    let response = exchange(
+        &server,
+        "GET /api/users?page=2 HTTP/1.1\r\nHost: a\r\nConnection: close, X-Session-Hint\r\n\
+         X-Session-Hint: 42\r\nKeep-Alive: timeout=5\r\nTE: trailers\r\nX-Kept: yes\r\n\r\n",
+    );
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body_str(), "ok");
+
+    let request = received.recv().unwrap();
+    assert!(request.starts_with("GET /api/users?page=2 HTTP/1.1\r\n"), "{request}");
+    assert!(request.contains("Host: a\r\n"), "{request}");
+    assert!(request.contains("X-Kept: yes\r\n"), "{request}");
+    assert!(request.contains("Connection: close\r\n"), "{request}");
+    for dropped in ["X-Session-Hint", "Keep-Alive", "TE:"] {
+        assert!(!request.contains(dropped), "{dropped} was forwarded: {request}");
+    }
+}
+
+#[test]
+fn the_client_is_appended_to_x_forwarded_for() {
+    let (upstream, received) = upstream(OK);
+    let server = proxy_server(upstream, Config::default());
+    exchange(
+        &server,
+        "GET /api/users HTTP/1.1\r\nHost: a\r\nX-Forwarded-For: 203.0.113.9\r\nConnection: close\r\n\r\n",
+    );
+    let request = received.recv().unwrap();
+    assert!(request.contains("X-Forwarded-For: 203.0.113.9, 127.0.0.1\r\n"), "{request}");
+}
+
+#[test]
+fn a_chunked_upstream_body_is_relayed_intact() {
+    let (upstream, _received) = upstream(
+        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Type: text/plain\r\n\r\n\
+          5\r\nhello\r\n1;ext=1\r\n \r\n5\r\nworld\r\n0\r\nX-Trailer: t\r\n\r\n",
+    );
+    let server = proxy_server(upstream, Config::default());
+    let response = exchange(&server, "GET /api/greeting HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Transfer-Encoding"), Some("chunked"));
+    assert_eq!(response.body_str(), "hello world");
+}
+
+#[test]
+fn a_post_body_is_forwarded_with_its_length() {
+    let (upstream, received) = upstream(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n");
+    let server = proxy_server(upstream, Config::default());
+    // Sent chunked, so the length the upstream sees is worked out here.
+    let response = exchange(
+        &server,
+        "POST /api/users HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+         6\r\n{\"id\":\r\n2\r\n7}\r\n0\r\n\r\n",
+    );
+    assert_eq!(response.status, 201);
+
+    let request = received.recv().unwrap();
+    assert!(request.contains("Content-Length: 8\r\n"), "{request}");
+    assert!(!request.contains("Transfer-Encoding"), "{request}");
+    assert!(request.ends_with("\r\n\r\n{\"id\":7}"), "{request}");
+}
@@ -0,0 +1,33 @@
+//! Redirect routes registered with `Server::redirect`.
+
+mod common;
+
+use common::{handler, serve_one};
+use rust_web_server::StatusCode;
+
+#[test]
+fn redirect_routes_send_the_status_and_location_with_an_empty_body() {
+    let handler = handler(|server| {
+        server.redirect("/old", "/new", StatusCode::MovedPermanently);
+        server.redirect("/moved", "https://example.com/elsewhere", StatusCode::TemporaryRedirect);
+    });
+
+    let response = serve_one(&handler, "GET /old HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.status, 301);
+    assert_eq!(response.header("Location"), Some("/new"));
+    assert_eq!(response.header("Content-Length"), Some("0"));
+    assert!(response.body.is_empty());
+
+    let response = serve_one(&handler, "GET /moved HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.status, 307);
+    assert_eq!(response.header("Location"), Some("https://example.com/elsewhere"));
+    assert!(response.body.is_empty());
+}
+
+#[test]
+fn other_paths_are_not_redirected() {
+    let handler = handler(|server| server.redirect("/old", "/new", StatusCode::Found));
+    let response = serve_one(&handler, "GET /older HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.status, 404);
+    assert!(response.header("Location").is_none());
+}
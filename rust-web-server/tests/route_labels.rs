@@ -0,0 +1,59 @@
+//! The `path` label request metrics carry: what the fallback serves is
+//! told apart from what nothing could serve, and streamed bodies count the
+//! bytes that went out.
+
+mod common;
+
+use common::{serve_one, RecordingMetrics};
+use rust_web_server::{Config, ConnectionHandler, Context, Method, Response, Server, StatusCode};
+
+fn labelled_server(metrics: RecordingMetrics) -> ConnectionHandler {
+    let mut server = Server::new(Config::default());
+    server.metrics(metrics);
+    server.register(Method::Get, "/events", |_: &mut Context| {
+        Response::new(StatusCode::Ok).with_stream(vec![b"hello ".to_vec(), b"world".to_vec()].into_iter())
+    });
+    server.fallback(|context: &mut Context| {
+        if context.request.path == "/style.css" {
+            Response::new(StatusCode::Ok).with_body("body {}")
+        } else {
+            Response::new(StatusCode::NotFound)
+        }
+    });
+    server.connection_handler()
+}
+
+fn get(handler: &ConnectionHandler, path: &str) -> u16 {
+    serve_one(handler, format!("GET {path} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")).status
+}
+
+#[test]
+fn fallback_content_is_not_labelled_not_found() {
+    let metrics = RecordingMetrics::default();
+    let handler = labelled_server(metrics.clone());
+
+    assert_eq!(get(&handler, "/style.css"), 200);
+    assert_eq!(metrics.counter_with("requests_total", "path=fallback,status=200"), 1);
+    assert_eq!(metrics.counter_with("requests_by_path", "path=fallback"), 1);
+    assert_eq!(metrics.counter_with("response_bytes_total", "path=fallback"), 7);
+    assert_eq!(metrics.counter_with("requests_total", "path=notfound,status=200"), 0);
+}
+
+#[test]
+fn fallback_404s_are_labelled_not_found() {
+    let metrics = RecordingMetrics::default();
+    let handler = labelled_server(metrics.clone());
+
+    assert_eq!(get(&handler, "/missing.css"), 404);
+    assert_eq!(metrics.counter_with("requests_total", "path=notfound,status=404"), 1);
+    assert_eq!(metrics.counter_with("requests_total", "path=fallback,status=404"), 0);
+}
+
+#[test]
+fn streamed_bodies_count_the_bytes_written() {
+    let metrics = RecordingMetrics::default();
+    let handler = labelled_server(metrics.clone());
+
+    assert_eq!(get(&handler, "/events"), 200);
+    assert_eq!(metrics.counter_with("response_bytes_total", "path=/events"), 11);
+}
@@ -0,0 +1,38 @@
+//! `ROUTE_TIMEOUTS_MS` overrides the request deadline for chosen routes.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{handler_with, serve_one};
+use rust_web_server::{Config, ConnectionHandler, Context, Method, Response, StatusCode};
+
+/// Answers with the milliseconds left before the request's deadline.
+fn remaining(context: &mut Context) -> Response {
+    let remaining = context.request.time_remaining().expect("request has no deadline");
+    Response::new(StatusCode::Ok).with_body(remaining.as_millis().to_string())
+}
+
+fn remaining_ms(handler: &ConnectionHandler, path: &str) -> u128 {
+    let response = serve_one(handler, format!("GET {path} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"));
+    assert_eq!(response.status, 200);
+    response.body_str().parse().unwrap()
+}
+
+#[test]
+fn a_route_timeout_replaces_the_default_deadline() {
+    let config = Config {
+        request_timeout: Duration::from_secs(30),
+        route_timeouts: vec![("/items/:id".to_string(), Duration::from_millis(2000))],
+        ..Config::default()
+    };
+    let handler = handler_with(config, |server| {
+        server.register(Method::Get, "/items/:id", remaining);
+        server.register(Method::Get, "/other", remaining);
+    });
+
+    let short = remaining_ms(&handler, "/items/7");
+    assert!(short <= 2000 && short > 1000, "{short}");
+    let default = remaining_ms(&handler, "/other");
+    assert!(default > 20_000, "{default}");
+}
@@ -0,0 +1,73 @@
+//! Slow and cut-off request heads: a client dribbling its head is cut off
+//! by `header_timeout`, one pausing mid-line is waited out, and one closing
+//! mid-line gets a 400.
+
+mod common;
+
+use std::io::Write;
+use std::net::Shutdown;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{read_to_close, TestServer};
+use rust_web_server::{Config, Context, Method, Response, StatusCode};
+
+#[test]
+fn a_slowloris_head_gets_a_408() {
+    let config = Config {
+        header_timeout: Duration::from_millis(500),
+        read_timeout: Duration::from_secs(5),
+        ..Config::default()
+    };
+    let server = TestServer::start(config, |server| {
+        server.register(Method::Get, "/", |_: &mut Context| Response::new(StatusCode::Ok));
+    });
+    let mut stream = server.connect();
+    let started = Instant::now();
+    // A header byte every 50 ms, which would take 2.5 s in all. Stop once
+    // the answer is in, since writing to a closed socket resets it.
+    stream.set_nonblocking(true).unwrap();
+    for byte in b"GET / HTTP/1.1\r\nHost: a\r\nX-Slow: ".iter().chain(&[b'a'; 16]) {
+        if stream.peek(&mut [0]).is_ok() || stream.write_all(&[*byte]).is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    stream.set_nonblocking(false).unwrap();
+    let output = read_to_close(&mut stream);
+    let output = String::from_utf8_lossy(&output);
+    assert!(output.starts_with("HTTP/1.1 408 "), "{output}");
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn a_pause_mid_line_longer_than_a_socket_read_is_waited_out() {
+    let config = Config {
+        header_timeout: Duration::from_secs(5),
+        read_timeout: Duration::from_millis(100),
+        ..Config::default()
+    };
+    let server = TestServer::start(config, |server| {
+        server.register(Method::Get, "/", |_: &mut Context| Response::new(StatusCode::Ok));
+    });
+    let mut stream = server.connect();
+    stream.write_all(b"GET / HT").unwrap();
+    thread::sleep(Duration::from_millis(400));
+    stream.write_all(b"TP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+    let output = read_to_close(&mut stream);
+    let output = String::from_utf8_lossy(&output);
+    assert!(output.starts_with("HTTP/1.1 200 "), "{output}");
+}
+
+#[test]
+fn a_head_cut_off_mid_line_is_a_bad_request() {
+    let server = TestServer::start(Config::default(), |server| {
+        server.register(Method::Get, "/", |_: &mut Context| Response::new(StatusCode::Ok));
+    });
+    let mut stream = server.connect();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: a").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let output = read_to_close(&mut stream);
+    let output = String::from_utf8_lossy(&output);
+    assert!(output.starts_with("HTTP/1.1 400 "), "{output}");
+}
@@ -0,0 +1,29 @@
+//! Requests a proxy in front could frame differently get a 400 and the
+//! connection closed, so nothing after them is read as a request.
+
+mod common;
+
+use common::{handler, parse_responses, serve};
+use rust_web_server::{Context, Method, Response, StatusCode};
+
+#[test]
+fn ambiguous_requests_are_refused_and_end_the_connection() {
+    let handler = handler(|server| {
+        server.register(Method::Post, "/", |_: &mut Context| Response::new(StatusCode::Ok));
+        server.register(Method::Get, "/smuggled", |_: &mut Context| Response::new(StatusCode::Ok));
+    });
+    let smuggled = "GET /smuggled HTTP/1.1\r\nHost: a\r\n\r\n";
+    for headers in [
+        "Content-Length: 3\r\nTransfer-Encoding: chunked",
+        "Content-Length: 3\r\nContent-Length: 40",
+        "Content-Length: three",
+        "Content-Length : 3",
+        "Transfer-Encoding : chunked",
+    ] {
+        let input = format!("POST / HTTP/1.1\r\nHost: a\r\n{headers}\r\n\r\n0\r\n\r\n{smuggled}");
+        let responses = parse_responses(&serve(&handler, input));
+        assert_eq!(responses.len(), 1, "{headers:?}");
+        assert_eq!(responses[0].status, 400, "{headers:?}");
+        assert_eq!(responses[0].header("Connection"), Some("close"), "{headers:?}");
+    }
+}
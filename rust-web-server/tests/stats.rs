@@ -0,0 +1,40 @@
+//! `GET /stats` over a running server.
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{parse_responses, TestServer};
+use rust_web_server::{stats, Config, Context, Method, Response, StatusCode};
+use serde_json::Value;
+
+#[test]
+fn stats_report_uptime_traffic_and_the_pool() {
+    let config = Config {
+        pool_size: 3,
+        ..Config::default()
+    };
+    let started = Instant::now() - Duration::from_secs(90);
+    let server = TestServer::start(config, |server| {
+        server.register(Method::Get, "/", |_: &mut Context| Response::new(StatusCode::Ok));
+        server.register(Method::Get, "/stats", stats::handler(server.stats(), started));
+    });
+    for _ in 0..3 {
+        let output = server.exchange(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+        assert_eq!(parse_responses(&output)[0].status, 200);
+    }
+
+    let output = server.exchange(b"GET /stats HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    let response = parse_responses(&output).into_iter().next().unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Cache-Control"), Some("no-store"));
+    let stats: Value = serde_json::from_slice(&response.body).unwrap();
+
+    assert!(stats["uptime_secs"].as_u64().unwrap() >= 90, "{stats}");
+    assert!(stats["requests_total"].as_u64().unwrap() >= 3, "{stats}");
+    assert!(stats["connections_total"].as_u64().unwrap() >= 4, "{stats}");
+    // The connection asking is still open.
+    assert!(stats["active_connections"].as_u64().unwrap() >= 1, "{stats}");
+    assert_eq!(stats["pool"]["size"], 3, "{stats}");
+    assert!(stats["pool"]["active"].as_u64().unwrap() >= 1, "{stats}");
+}
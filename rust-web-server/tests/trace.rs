@@ -0,0 +1,44 @@
+//! TRACE is refused unless `enable_trace` opts in to a redacted echo.
+
+mod common;
+
+use common::{handler, handler_with, serve_one};
+use rust_web_server::{Config, Context, Method, Response, StatusCode};
+
+const TRACE: &str = "TRACE /page?x=1 HTTP/1.1\r\nHost: a\r\nCookie: session=secret\r\nAuthorization: Bearer t0ken\r\nX-Custom: kept\r\nConnection: close\r\n\r\n";
+
+fn page(_: &mut Context) -> Response {
+    Response::new(StatusCode::Ok)
+}
+
+#[test]
+fn trace_is_a_405_by_default() {
+    let handler = handler(|server| {
+        server.register(Method::Get, "/page", page);
+        server.register(Method::Post, "/page", page);
+    });
+    let response = serve_one(&handler, TRACE);
+    assert_eq!(response.status, 405);
+    assert_eq!(response.header("Allow"), Some("GET, HEAD, POST"));
+    assert!(!response.body_str().contains("secret"));
+
+    // Even for paths with no route at all, rather than a 404.
+    let response = serve_one(&handler, TRACE.replace("/page?x=1", "/nowhere"));
+    assert_eq!(response.status, 405);
+}
+
+#[test]
+fn enabled_trace_echoes_the_request_without_credentials() {
+    let config = Config {
+        enable_trace: true,
+        ..Config::default()
+    };
+    let handler = handler_with(config, |server| server.register(Method::Get, "/page", page));
+    let response = serve_one(&handler, TRACE);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Type"), Some("message/http"));
+    let echo = response.body_str();
+    assert!(echo.starts_with("TRACE /page?x=1 HTTP/1.1\r\n"), "{echo}");
+    assert!(echo.contains("X-Custom: kept\r\n"));
+    assert!(!echo.contains("secret") && !echo.contains("t0ken"), "{echo}");
+}
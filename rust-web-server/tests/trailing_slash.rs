@@ -0,0 +1,100 @@
+//! `TRAILING_SLASH`: routing either form, or redirecting to the canonical one.
+
+mod common;
+
+use common::{handler_with, serve_one};
+use rust_web_server::{Config, Context, Method, Response, StatusCode, TrailingSlash};
+
+fn server(mode: TrailingSlash) -> rust_web_server::ConnectionHandler {
+    let config = Config {
+        trailing_slash: mode,
+        ..Config::default()
+    };
+    handler_with(config, |server| {
+        let about = |context: &mut Context| Response::new(StatusCode::Ok).with_body(context.request.path.clone());
+        server.register(Method::Get, "/about", about);
+        server.register(Method::Post, "/about", about);
+        server.register(Method::Get, "/about/", about);
+    })
+}
+
+fn request(handler: &rust_web_server::ConnectionHandler, method: &str, path: &str) -> common::Parsed {
+    let length = if method == "POST" { "Content-Length: 0\r\n" } else { "" };
+    serve_one(handler, format!("{method} {path} HTTP/1.1\r\nHost: a\r\n{length}Connection: close\r\n\r\n"))
+}
+
+#[test]
+fn equivalent_mode_serves_both_forms_without_redirecting() {
+    let handler = handler_with(
+        Config {
+            trailing_slash: TrailingSlash::Equivalent,
+            ..Config::default()
+        },
+        |server| server.register(Method::Get, "/contact", |_: &mut Context| Response::new(StatusCode::Ok).with_body("contact")),
+    );
+    for path in ["/contact", "/contact/"] {
+        let response = request(&handler, "GET", path);
+        assert_eq!(response.status, 200, "{path}");
+        assert_eq!(response.body_str(), "contact");
+    }
+}
+
+#[test]
+fn strip_mode_redirects_to_the_slashless_form() {
+    let handler = server(TrailingSlash::Strip);
+    let response = request(&handler, "GET", "/about/?tab=team");
+    assert_eq!(response.status, 301);
+    assert_eq!(response.header("Location"), Some("/about?tab=team"));
+
+    // Methods with bodies get a 308 so the method and body survive.
+    let response = request(&handler, "POST", "/about/");
+    assert_eq!(response.status, 308);
+    assert_eq!(response.header("Location"), Some("/about"));
+
+    assert_eq!(request(&handler, "GET", "/about").status, 200);
+    assert_eq!(request(&handler, "GET", "/").status, 404);
+}
+
+#[test]
+fn add_mode_redirects_to_the_slashed_form() {
+    let handler = server(TrailingSlash::Add);
+    let response = request(&handler, "GET", "/about");
+    assert_eq!(response.status, 301);
+    assert_eq!(response.header("Location"), Some("/about/"));
+    assert_eq!(request(&handler, "GET", "/about/").body_str(), "/about/");
+}
+
+#[test]
+fn strict_mode_keeps_the_forms_apart() {
+    let handler = handler_with(Config::default(), |server| {
+        server.register(Method::Get, "/only", |_: &mut Context| Response::new(StatusCode::Ok))
+    });
+    assert_eq!(request(&handler, "GET", "/only").status, 200);
+    assert_eq!(request(&handler, "GET", "/only/").status, 404);
+}
+
+#[test]
+fn protocol_relative_paths_are_never_redirected() {
+    for (mode, path) in [(TrailingSlash::Add, "//evil.example"), (TrailingSlash::Strip, "//evil.example/")] {
+        let handler = handler_with(
+            Config {
+                trailing_slash: mode,
+                ..Config::default()
+            },
+            |server| server.register(Method::Get, "/*", |_: &mut Context| Response::new(StatusCode::Ok)),
+        );
+        let response = request(&handler, "GET", path);
+        assert_eq!(response.header("Location"), None, "{path}");
+        assert_eq!(response.status, 200, "{path}");
+    }
+}
+
+#[test]
+fn paths_no_route_serves_in_either_form_are_not_redirected() {
+    let response = request(&server(TrailingSlash::Add), "GET", "/missing");
+    assert_eq!(response.status, 404);
+    assert_eq!(response.header("Location"), None);
+    let response = request(&server(TrailingSlash::Strip), "GET", "/missing/");
+    assert_eq!(response.status, 404);
+    assert_eq!(response.header("Location"), None);
+}
@@ -0,0 +1,57 @@
+//! Multipart uploads parsed by handlers, buffered and streamed.
+
+mod common;
+
+use common::{handler, serve_one};
+use rust_web_server::multipart::{MultipartLimits, PartData};
+use rust_web_server::{Context, Method, Response, StatusCode};
+
+fn upload(path: &str, file: &[u8]) -> Vec<u8> {
+    let mut body = b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.bin\"\r\n\r\n".to_vec();
+    body.extend_from_slice(file);
+    body.extend_from_slice(b"\r\n--XyZ--\r\n");
+    let mut request = format!(
+        "POST {path} HTTP/1.1\r\nHost: a\r\nConnection: close\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(&body);
+    request
+}
+
+/// Answers with where the first part ended up and its length.
+fn describe(context: &mut Context) -> Response {
+    let limits = MultipartLimits {
+        spill_threshold: 1024,
+        ..MultipartLimits::default()
+    };
+    match context.multipart(&limits) {
+        Ok(parts) => {
+            let part = &parts[0];
+            let place = match part.data {
+                PartData::File(_) => "file",
+                PartData::Memory(_) => "memory",
+            };
+            assert_eq!(part.bytes().unwrap().len(), part.len);
+            Response::new(StatusCode::Ok).with_body(format!("{place} {}", part.len))
+        }
+        Err(e) => Response::new(e.status()).with_body(e.to_string()),
+    }
+}
+
+#[test]
+fn streamed_and_buffered_uploads_spill_large_parts() {
+    let handler = handler(|server| {
+        server.register(Method::Post, "/buffered", describe);
+        server.register_streaming(Method::Post, "/streamed", describe);
+    });
+    let file = vec![b'x'; 100 * 1024];
+    for path in ["/buffered", "/streamed"] {
+        let response = serve_one(&handler, upload(path, &file));
+        assert_eq!(response.status, 200, "{path}: {}", response.body_str());
+        assert_eq!(response.body_str(), "file 102400", "{path}");
+
+        let response = serve_one(&handler, upload(path, b"small"));
+        assert_eq!(response.body_str(), "memory 5", "{path}");
+    }
+}
@@ -0,0 +1,36 @@
+//! The WebSocket handshake and echo over a whole connection.
+#![cfg(feature = "websocket")]
+
+mod common;
+
+use common::{find, handler, serve};
+use rust_web_server::websocket;
+
+/// A masked client frame with `fin` set.
+fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    assert!(payload.len() < 126);
+    let mask = [0x37, 0xfa, 0x21, 0x3d];
+    let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    frame
+}
+
+#[test]
+fn the_rfc_key_upgrades_and_text_is_echoed() {
+    let handler = handler(|server| server.websocket("/chat", websocket::echo));
+    let mut input = b"GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n".to_vec();
+    input.extend(client_frame(0x1, b"Hello"));
+    input.extend(client_frame(0x8, &1000u16.to_be_bytes()));
+
+    let output = serve(&handler, input);
+    let end = find(&output, b"\r\n\r\n").expect("no handshake response") + 4;
+    let head = String::from_utf8_lossy(&output[..end]);
+    assert!(head.starts_with("HTTP/1.1 101 "), "{head}");
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"), "{head}");
+
+    // The echoed text frame (unmasked, as servers send), then the close reply.
+    let frames = &output[end..];
+    assert_eq!(&frames[..7], b"\x81\x05Hello");
+    assert_eq!(&frames[7..], b"\x88\x02\x03\xe8");
+}